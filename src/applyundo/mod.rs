@@ -0,0 +1,120 @@
+//! Putting chunks saved by `delete-chunks --undo` or by `--undo` of compaction back into their region files
+
+use std::path::{Path, PathBuf};
+
+use crate::{
+    batch,
+    chunk::Codec,
+    region::{self, RegionFile, RegionInfo},
+    rpack,
+};
+
+#[derive(Debug, clap::Args)]
+pub struct ApplyUndoArgs {
+    /// Archive written by `delete-chunks --undo` or by `--undo` of compaction, or a directory of such archives
+    pub undo: PathBuf,
+
+    /// Region file the chunks were deleted from, edited in place. Created if it is gone.
+    /// The world directory for a directory of archives, which are matched to region files by their place
+    #[arg(short, long)]
+    pub input: PathBuf,
+
+    /// Replace chunks the game generated again since. They are kept by default
+    #[arg(long)]
+    pub overwrite: bool,
+}
+
+pub fn run(args: ApplyUndoArgs) -> anyhow::Result<()> {
+    if !args.undo.is_dir() {
+        return apply(&args.undo, &args.input, args.overwrite);
+    }
+    let files = batch::scan(&args.undo, batch::LinkPolicy::Read)?;
+    for job in batch::decompact_jobs_of(&args.undo, &args.input, &files) {
+        if let Some(dir) = job.output.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        apply(&job.input, &job.output, args.overwrite)?;
+    }
+    Ok(())
+}
+
+/// Puts chunks of undo archive into region file
+fn apply(undo: &Path, input: &Path, overwrite: bool) -> anyhow::Result<()> {
+    let mut reader = rpack::RpackReader::new(rpack::volume::open(undo)?)?;
+    let region = region::region_coords_from_path(input);
+    let mut file = match input.exists() {
+        true => RegionFile::open(input)?,
+        false => RegionFile::create_empty(input)?,
+    };
+
+    let mut payload = vec![];
+    let (mut restored, mut kept) = (0, 0);
+    while let Some(chunk) = reader.read_chunk(&mut payload)? {
+        if file.chunk_info(chunk.pos).is_some() && !overwrite {
+            let (x, z) = RegionInfo::chunk_coords(region, chunk.pos);
+            eprintln!("Chunk {x},{z} exists, kept. Pass --overwrite to replace it");
+            kept += 1;
+            continue;
+        }
+        // Chunks too large for the region file go to external files next to it
+        file.update_chunk(chunk.pos, chunk.timestamp, &payload, Codec::Zlib)?;
+        restored += 1;
+    }
+    file.into_inner()?.sync_all()?;
+
+    println!("Restored {restored} chunks into {}", input.display());
+    if kept > 0 {
+        println!("Kept {kept} chunks generated since");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        deletechunks::{self, ChunkSelection, DeleteChunksArgs},
+        testutil::{chunks, TempDir},
+        world, CompactOptions,
+    };
+
+    #[test]
+    fn undo_round_trip() {
+        let dir = TempDir::new("apply-undo");
+        let input = dir.copy_fixture("basic", "r.0.0.mca");
+        let before = chunks(&input);
+
+        deletechunks::run(DeleteChunksArgs {
+            input: input.clone(),
+            selection: ChunkSelection { chunks: vec![(1, 1)], area: vec![(30, 30), (31, 31)] },
+            zero: true,
+            defrag: false,
+            undo: Some(dir.join("undo.rpack")),
+        })
+        .unwrap();
+        assert_eq!(chunks(&input).len(), before.len() - 2);
+
+        run(ApplyUndoArgs { undo: dir.join("undo.rpack"), input: input.clone(), overwrite: false }).unwrap();
+        assert_eq!(chunks(&input), before);
+    }
+
+    #[test]
+    fn pruned_round_trip() {
+        let dir = TempDir::new("apply-undo-pruned");
+        let input = dir.copy_fixture("basic", "r.0.0.mca");
+
+        // Only chunk 0,0 is inside
+        let border = world::WorldBorder { center_x: 0.0, center_z: 0.0, size: 20.0 };
+        let encode = rpack::EncodeOptions { region: Some((0, 0)), border: Some(border), ..Default::default() };
+        let options = CompactOptions { encode, undo: Some(dir.join("undo/r.0.0.mca.rpack")), ..Default::default() };
+        crate::compact_file(&input, Some(dir.join("r.0.0.mca.rpack")), &options).unwrap();
+        let restored = dir.join("restored/r.0.0.mca");
+        std::fs::create_dir_all(restored.parent().unwrap()).unwrap();
+        crate::decompact_file(Some(dir.join("r.0.0.mca.rpack")), &restored, &Default::default()).unwrap();
+        assert_eq!(chunks(&restored).len(), 1);
+
+        // Directories of archives match region files by their place
+        run(ApplyUndoArgs { undo: dir.join("undo"), input: dir.join("restored"), overwrite: false }).unwrap();
+        assert_eq!(chunks(&restored), chunks(&input));
+    }
+}
@@ -0,0 +1,119 @@
+//! Log of chunks dropped or changed while compacting, one JSON object per line, so it can be told months later
+//! why an area of a restored world was generated again or looks different
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use serde::Serialize;
+
+/// Rule changing a chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Rule {
+    /// Dropped outside of the world border, see `--prune-border`
+    PruneBorder,
+    /// Upgraded by `--migrate`
+    Migrate,
+    /// Position in NBT rewritten by `--fix-pos`
+    FixPos,
+}
+
+/// Line of the log
+#[derive(Debug, Clone, Serialize)]
+pub struct Record {
+    /// UNIX time in seconds
+    pub time: u64,
+    pub file: PathBuf,
+    /// Chunk coordinates, absolute when the region file is named like r.<x>.<z>.mca
+    pub x: i32,
+    pub z: i32,
+    pub rule: Rule,
+    /// Size of chunk data before and after, `null` after for dropped chunks. Uncompressed NBT, or as stored in the
+    /// region file for raw archives and regions dropped whole
+    pub before: usize,
+    pub after: Option<usize>,
+}
+
+/// Log file shared by every thread, appended to so runs accumulate. Changes are kept back per file until
+/// [`AuditLog::commit`], so files failing to compact leave nothing in the log
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    writer: Arc<Mutex<BufWriter<File>>>,
+    file: PathBuf,
+    pending: Arc<Mutex<Vec<Record>>>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Unable to open audit log {}", path.display()))?;
+        Ok(Self {
+            writer: Arc::new(Mutex::new(BufWriter::new(file))),
+            file: PathBuf::new(),
+            pending: Default::default(),
+        })
+    }
+
+    /// Same log recording changes of file at `path`
+    pub fn for_file(&self, path: &Path) -> Self {
+        Self {
+            writer: self.writer.clone(),
+            file: path.to_path_buf(),
+            pending: Default::default(),
+        }
+    }
+
+    /// File changes are recorded for, empty for the log as opened
+    pub fn file(&self) -> &Path {
+        &self.file
+    }
+
+    pub fn record(&self, (x, z): (i32, i32), rule: Rule, before: usize, after: Option<usize>) {
+        let time = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |x| x.as_secs());
+        let record = Record { time, file: self.file.clone(), x, z, rule, before, after };
+        self.pending.lock().unwrap().push(record);
+    }
+
+    /// Writes changes recorded so far
+    pub fn commit(&self) -> anyhow::Result<()> {
+        let records = std::mem::take(&mut *self.pending.lock().unwrap());
+        let mut writer = self.writer.lock().unwrap();
+        for record in records {
+            serde_json::to_writer(&mut *writer, &record)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush().context("Unable to write audit log")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn records_by_file() {
+        let dir = TempDir::new("audit");
+        let path = dir.join("audit.jsonl");
+        let log = AuditLog::open(&path).unwrap();
+        let (a, b) = (log.for_file(Path::new("r.0.0.mca")), log.for_file(Path::new("r.1.0.mca")));
+        a.record((1, 2), Rule::Migrate, 100, Some(120));
+        b.record((40, 0), Rule::PruneBorder, 80, None);
+        // Changes of a file failing to compact are never committed
+        a.commit().unwrap();
+
+        let lines = std::fs::read_to_string(&path).unwrap();
+        let lines = lines.lines().map(|x| serde_json::from_str::<serde_json::Value>(x).unwrap()).collect::<Vec<_>>();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["rule"], "migrate");
+        assert_eq!((lines[0]["x"].as_i64(), lines[0]["after"].as_u64()), (Some(1), Some(120)));
+    }
+}
@@ -0,0 +1,749 @@
+//! Directory mode: every region file of a tree is compacted (or every archive decompacted) into a
+//! mirrored tree by a pool of worker threads.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::{OsStr, OsString},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Condvar, Mutex,
+    },
+};
+
+use anyhow::{bail, Context};
+
+use crate::{error, region, rpack, world};
+
+/// Appended to region file name when compacting, removed when decompacting
+pub const ARCHIVE_EXTENSION: &str = "rpack";
+
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    /// Input size, bigger jobs are started first
+    pub size: u64,
+}
+
+/// Kind of file found in a world directory, known from its name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FileKind {
+    /// Region file of a known format, like `.mca` or `.mcr`
+    Region,
+    /// Chunk too large for its region file, `c.<x>.<z>.mcc`
+    ExternalChunk,
+    /// Archive or the first volume of a split one
+    Archive,
+    /// Later volume of a split archive, read together with the first one
+    Volume,
+    /// Anything else, copied only as passthrough
+    Other,
+    /// Link kept as link, see [`LinkPolicy::Preserve`]
+    Link,
+    /// Symlink not followed: broken, to a directory without [`LinkPolicy::Follow`], or to a directory above it
+    Skipped,
+}
+
+impl FileKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            FileKind::Region => "region",
+            FileKind::ExternalChunk => "external chunk",
+            FileKind::Archive => "archive",
+            FileKind::Volume => "volume",
+            FileKind::Other => "other",
+            FileKind::Link => "link",
+            FileKind::Skipped => "skipped link",
+        }
+    }
+
+    pub fn of(path: &Path, providers: &[Box<dyn region::RegionFormatProvider>]) -> Self {
+        let archive = |x: &Path| x.extension().is_some_and(|x| x == ARCHIVE_EXTENSION);
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if archive(path) || rpack::volume::first_volume_base(path).is_some_and(|x| archive(&x)) {
+            FileKind::Archive
+        } else if is_archive(path) {
+            FileKind::Volume
+        } else if region::detect_format(path, providers).is_some() {
+            FileKind::Region
+        } else if name.starts_with("c.") && name.ends_with(".mcc") {
+            FileKind::ExternalChunk
+        } else {
+            FileKind::Other
+        }
+    }
+}
+
+/// How [`scan`] treats symbolic and hard links
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LinkPolicy {
+    /// Symlinked files are read as the files they point to, symlinked directories are skipped with a warning
+    #[default]
+    Read,
+    /// Symlinked directories are walked too, except links back to a directory above them
+    Follow,
+    /// Symlinks are kept as links and hard linked files are stored once, see [`Link`]
+    Preserve,
+}
+
+/// Link kept by [`LinkPolicy::Preserve`] instead of file contents
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Link {
+    /// Symlink with its target as stored
+    Symlink(PathBuf),
+    /// Another name of the file at path, found earlier
+    Hardlink(PathBuf),
+}
+
+/// File found by [`scan`]
+#[derive(Debug, Clone)]
+pub struct Found {
+    pub path: PathBuf,
+    pub kind: FileKind,
+    pub size: u64,
+    /// Set for files of kind [`FileKind::Link`] and [`FileKind::Skipped`]
+    pub link: Option<Link>,
+}
+
+/// Directory to read with real paths of the directories above it, to notice symlink cycles
+type Pending = (PathBuf, Vec<PathBuf>);
+
+/// Device and inode of files with several names
+#[cfg(unix)]
+fn file_id(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Every file under `dir` with its kind and size, sorted by path. Directories are read by several threads at once,
+/// so large worlds on cold caches or network filesystems are not walked one `read_dir` after another
+pub fn scan(dir: &Path, links: LinkPolicy) -> anyhow::Result<Vec<Found>> {
+    let threads = std::thread::available_parallelism().map_or(1, NonZeroUsize::get).min(16);
+    // Directories to read and count of threads reading one, the walk is done when both are empty
+    let root = match links {
+        LinkPolicy::Follow => vec![dir.canonicalize().with_context(|| format!("Unable to read {}", dir.display()))?],
+        _ => vec![],
+    };
+    let state = Mutex::new((vec![(dir.to_path_buf(), root)], 0usize));
+    let wakeup = Condvar::new();
+    let files = Mutex::new(vec![]);
+    let error = Mutex::new(None);
+
+    let read = |(dir, real): &Pending, dirs: &mut Vec<Pending>, files: &mut Vec<(Found, Option<(u64, u64)>)>| -> anyhow::Result<()> {
+        let file = |path, size, link| Found { path, kind: FileKind::Other, size, link };
+        for entry in std::fs::read_dir(dir).with_context(|| format!("Unable to read {}", dir.display()))? {
+            let entry = entry?;
+            let path = entry.path();
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                let real = real.iter().cloned().chain(real.last().map(|x| x.join(entry.file_name()))).collect();
+                dirs.push((path, real));
+                continue;
+            }
+            if !file_type.is_symlink() {
+                let metadata = entry.metadata().with_context(|| format!("Unable to read {}", path.display()))?;
+                let id = (links == LinkPolicy::Preserve).then(|| file_id(&metadata)).flatten();
+                files.push((file(path, metadata.len(), None), id));
+                continue;
+            }
+
+            let target = std::fs::read_link(&path).with_context(|| format!("Unable to read link {}", path.display()))?;
+            if links == LinkPolicy::Preserve {
+                files.push((file(path, 0, Some(Link::Symlink(target))), None));
+                continue;
+            }
+            let skipped = |path| (Found { kind: FileKind::Skipped, ..file(path, 0, Some(Link::Symlink(target.clone()))) }, None);
+            match std::fs::metadata(&path) {
+                Ok(x) if x.is_dir() && links == LinkPolicy::Follow => {
+                    let real_target = path.canonicalize().with_context(|| format!("Unable to resolve {}", path.display()))?;
+                    match real.iter().any(|x| x.starts_with(&real_target)) {
+                        true => files.push(skipped(path)),
+                        false => dirs.push((path, real.iter().cloned().chain([real_target]).collect())),
+                    }
+                },
+                Ok(x) if !x.is_dir() => files.push((file(path, x.len(), None), None)),
+                _ => files.push(skipped(path)),
+            }
+        }
+        Ok(())
+    };
+
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| loop {
+                let dir = {
+                    let mut state = state.lock().unwrap();
+                    loop {
+                        if let Some(dir) = state.0.pop() {
+                            state.1 += 1;
+                            break dir;
+                        }
+                        if state.1 == 0 {
+                            return;
+                        }
+                        state = wakeup.wait(state).unwrap();
+                    }
+                };
+                let (mut dirs, mut found) = (vec![], vec![]);
+                let result = read(&dir, &mut dirs, &mut found);
+
+                let mut state = state.lock().unwrap();
+                state.1 -= 1;
+                match result {
+                    Ok(()) => {
+                        state.0.extend(dirs);
+                        files.lock().unwrap().extend(found);
+                    },
+                    // Nothing more is read after the first error
+                    Err(e) => {
+                        error.lock().unwrap().get_or_insert(e);
+                        state.0.clear();
+                    },
+                }
+                wakeup.notify_all();
+            });
+        }
+    });
+    if let Some(e) = error.into_inner().unwrap() {
+        return Err(e);
+    }
+
+    let mut files = files.into_inner().unwrap();
+    files.sort_by(|a, b| a.0.path.cmp(&b.0.path));
+    // The first name of hard linked file keeps the data
+    let mut names = BTreeMap::new();
+    let providers = region::providers();
+    Ok(files
+        .into_iter()
+        .map(|(mut file, id)| {
+            if let Some(first) = id.and_then(|x| names.get(&x)) {
+                file.link = Some(Link::Hardlink(PathBuf::clone(first)));
+            } else if let Some(id) = id {
+                names.insert(id, file.path.clone());
+            }
+            file.kind = match (file.kind, &file.link) {
+                (FileKind::Skipped, _) => FileKind::Skipped,
+                (_, Some(_)) => FileKind::Link,
+                (_, None) => FileKind::of(&file.path, &providers),
+            };
+            file
+        })
+        .collect())
+}
+
+/// Counts of scanned files by kind
+pub fn count_kinds(files: &[Found]) -> BTreeMap<FileKind, usize> {
+    let mut kinds = BTreeMap::new();
+    for file in files {
+        *kinds.entry(file.kind).or_default() += 1;
+    }
+    kinds
+}
+
+/// Jobs for files under `input`, written to the same place under `output` with a new name. Names are kept as bytes,
+/// files not named in UTF-8 are packed too
+fn jobs<'a>(input: &Path, output: &Path, files: impl Iterator<Item = &'a Found>, name: impl Fn(&OsStr) -> OsString) -> Vec<Job> {
+    files
+        .map(|file| {
+            let relative = file.path.strip_prefix(input).unwrap();
+            Job {
+                output: output.join(relative).with_file_name(name(relative.file_name().unwrap())),
+                size: file.size,
+                input: file.path.clone(),
+            }
+        })
+        .collect()
+}
+
+/// Every region file of known format under `input`, written as `<name>.rpack` under `output`
+pub fn compact_jobs(input: &Path, output: &Path) -> anyhow::Result<Vec<Job>> {
+    Ok(compact_jobs_of(input, output, &scan(input, LinkPolicy::Read)?))
+}
+
+/// [`compact_jobs`] of files already scanned
+pub fn compact_jobs_of(input: &Path, output: &Path, files: &[Found]) -> Vec<Job> {
+    let files = files.iter().filter(|x| x.kind == FileKind::Region);
+    jobs(input, output, files, archive_name)
+}
+
+/// Name of archive of region file named `name`. Region files gzipped as a whole get the archive of the file unpacked
+pub fn archive_name(name: &OsStr) -> OsString {
+    let mut name = region::gunzipped_path(Path::new(name)).into_os_string();
+    name.push(format!(".{ARCHIVE_EXTENSION}"));
+    name
+}
+
+/// Files other than region files and archives copied unchanged in directory mode, like `level.dat` and `playerdata/`
+#[derive(Debug, Clone, Default)]
+pub struct Passthrough {
+    /// Copy every file not matching `skip`
+    pub all: bool,
+    /// Globs of paths relative to the input directory, see [`glob_match`]
+    pub skip: Vec<String>,
+    /// Copied even if matching `skip` or without `all`
+    pub include: Vec<String>,
+    /// Compress files on their own when packing, see [`rpack::file`]. Restoring finds them by name either way
+    pub compress: bool,
+    /// Store files with the same content once when packing, the others become duplicate entries of the manifest
+    pub dedupe: bool,
+}
+
+impl Passthrough {
+    /// Compressed files match under their original name
+    pub fn copies(&self, relative: &str) -> bool {
+        let relative = relative.strip_suffix(&format!(".{}", rpack::file::EXTENSION)).unwrap_or(relative);
+        let matches = |globs: &[String]| globs.iter().any(|x| glob_match(x.as_bytes(), relative.as_bytes()));
+        matches(&self.include) || (self.all && !matches(&self.skip))
+    }
+}
+
+/// Matches `/`-separated path against glob. `*` and `?` stay within a directory, `**` crosses them
+fn glob_match(glob: &[u8], path: &[u8]) -> bool {
+    match glob {
+        [] => path.is_empty(),
+        // Zero or more whole directories
+        [b'*', b'*', b'/', rest @ ..] => (0..=path.len()).any(|i| (i == 0 || path[i - 1] == b'/') && glob_match(rest, &path[i..])),
+        [b'*', b'*', rest @ ..] => (0..=path.len()).any(|i| glob_match(rest, &path[i..])),
+        [b'*', rest @ ..] => (0..=path.len())
+            .take_while(|&i| i == 0 || path[i - 1] != b'/')
+            .any(|i| glob_match(rest, &path[i..])),
+        [b'?', rest @ ..] => path.first().is_some_and(|&x| x != b'/') && glob_match(rest, &path[1..]),
+        [x, rest @ ..] => path.first() == Some(x) && glob_match(rest, &path[1..]),
+    }
+}
+
+/// Archive or any of its volumes
+pub fn is_archive(path: &Path) -> bool {
+    let archive = |x: &Path| x.extension().is_some_and(|x| x == ARCHIVE_EXTENSION);
+    let volume = path
+        .extension()
+        .and_then(|x| x.to_str())
+        .is_some_and(|x| x.len() == 3 && x.bytes().all(|x| x.is_ascii_digit()));
+    archive(path) || (volume && archive(&path.with_extension("")))
+}
+
+/// Scanned files under `input` picked by `rules` and not handled otherwise, copied to the same place under `output`
+pub fn passthrough_jobs(input: &Path, output: &Path, files: &[Found], rules: &Passthrough, handled: impl Fn(&Found) -> bool) -> Vec<Job> {
+    if !rules.all && rules.include.is_empty() {
+        return vec![];
+    }
+    let relative = |x: &Path| {
+        let parts = x.strip_prefix(input).unwrap().components().map(|x| x.as_os_str().to_string_lossy()).collect::<Vec<_>>();
+        parts.join("/")
+    };
+    let files = files.iter().filter(|x| !handled(x) && rules.copies(&relative(&x.path)));
+    jobs(input, output, files, OsStr::to_owned)
+}
+
+/// Copies file of passthrough job
+pub fn copy(job: &Job) -> anyhow::Result<()> {
+    std::fs::copy(&job.input, &job.output).with_context(|| format!("Unable to copy to {}", job.output.display()))?;
+    Ok(())
+}
+
+/// Passthrough files stored so far by size and hash of content, so files with the same content are stored once,
+/// see [`Passthrough::dedupe`]
+#[derive(Debug, Default)]
+pub struct Stored(Mutex<HashMap<(u64, u64), Vec<PathBuf>>>);
+
+impl Stored {
+    /// Input of an earlier job with the same content as input of `job`. Otherwise `job` becomes the holder of its
+    /// content and `None` is returned. Equal hashes are checked byte by byte
+    pub fn holder(&self, job: &Job) -> anyhow::Result<Option<PathBuf>> {
+        let key = (job.size, content_hash(&job.input)?);
+        // Held while comparing, so a file is never taken as holder before it is known to be one
+        let mut stored = self.0.lock().unwrap();
+        let holders = stored.entry(key).or_default();
+        for holder in holders.iter() {
+            if same_content(holder, &job.input)? {
+                return Ok(Some(holder.clone()));
+            }
+        }
+        holders.push(job.input.clone());
+        Ok(None)
+    }
+}
+
+fn content_hash(path: &Path) -> anyhow::Result<u64> {
+    use std::{hash::Hasher, io::Read};
+
+    let mut file = std::fs::File::open(path).with_context(|| format!("Unable to open {}", path.display()))?;
+    let mut hasher = std::hash::DefaultHasher::new();
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        let n = file.read(&mut buffer).with_context(|| format!("Unable to read {}", path.display()))?;
+        if n == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.write(&buffer[..n]);
+    }
+}
+
+fn same_content(a: &Path, b: &Path) -> anyhow::Result<bool> {
+    use std::io::Read;
+
+    let open = |x: &Path| std::fs::File::open(x).with_context(|| format!("Unable to open {}", x.display()));
+    let (mut a, mut b) = (open(a)?, open(b)?);
+    let (mut buffer_a, mut buffer_b) = (vec![0; 1 << 16], vec![0; 1 << 16]);
+    loop {
+        let n = a.read(&mut buffer_a)?;
+        let Ok(()) = b.read_exact(&mut buffer_b[..n]) else { return Ok(false) };
+        if buffer_a[..n] != buffer_b[..n] {
+            return Ok(false);
+        }
+        if n == 0 {
+            return Ok(b.read(&mut buffer_b)? == 0);
+        }
+    }
+}
+
+/// Region file counts by dimension id and storage kind, and count of files outside of world layout
+pub fn dimensions(world: &Path, jobs: &[Job]) -> (BTreeMap<String, BTreeMap<String, usize>>, usize) {
+    let mut dimensions = BTreeMap::<String, BTreeMap<String, usize>>::new();
+    let mut unknown = 0;
+    for job in jobs {
+        match world::dimension(world, &job.input) {
+            Some((dimension, kind)) => *dimensions.entry(dimension).or_default().entry(kind).or_default() += 1,
+            None => unknown += 1,
+        }
+    }
+    (dimensions, unknown)
+}
+
+/// Lists dimensions found when `input` is a world directory, including mod dimensions under
+/// `dimensions/<namespace>/<name>`, so a missing one is noticed before the backup is needed
+pub fn print_dimensions(input: &Path, jobs: &[Job]) {
+    let (dimensions, unknown) = dimensions(input, jobs);
+    if dimensions.is_empty() {
+        return;
+    }
+    for (dimension, kinds) in dimensions {
+        let kinds = kinds.iter().map(|(kind, count)| format!("{count} {kind}")).collect::<Vec<_>>();
+        eprintln!("Dimension {dimension}: {}", kinds.join(", "));
+    }
+    if unknown > 0 {
+        eprintln!("{unknown} region files outside of world layout are packed by path");
+    }
+}
+
+/// Every `.rpack` archive or its first volume `.rpack.001` under `input`, written under `output` without the suffix.
+/// Archives named without region extension, like `r.0.0.rpack`, become `.mca`.
+pub fn decompact_jobs(input: &Path, output: &Path) -> anyhow::Result<Vec<Job>> {
+    Ok(decompact_jobs_of(input, output, &scan(input, LinkPolicy::Read)?))
+}
+
+/// [`decompact_jobs`] of files already scanned
+pub fn decompact_jobs_of(input: &Path, output: &Path, files: &[Found]) -> Vec<Job> {
+    let providers = region::providers();
+    let files = files.iter().filter(|x| x.kind == FileKind::Archive);
+    jobs(input, output, files, |name| region_name(&name.to_string_lossy(), &providers).into())
+}
+
+/// Region file name of archive or its first volume
+pub fn region_name(archive_name: &str, providers: &[Box<dyn region::RegionFormatProvider>]) -> String {
+    let name = archive_name.strip_suffix(".001").unwrap_or(archive_name);
+    let stem = name.strip_suffix(&format!(".{ARCHIVE_EXTENSION}")).unwrap_or(name);
+    match region::detect_format(stem, providers) {
+        Some(_) => stem.to_owned(),
+        None => format!("{stem}.mca"),
+    }
+}
+
+#[derive(Debug)]
+pub enum Outcome {
+    Succeeded,
+    Failed(anyhow::Error),
+    /// Not started, or cancelled while running, because an earlier job failed with fail-fast
+    Skipped,
+}
+
+/// Outcome of every job of a run
+#[derive(Debug)]
+pub struct Report {
+    pub results: Vec<(Job, Outcome)>,
+    pub fail_fast: bool,
+}
+
+impl Report {
+    pub fn count(&self, f: impl Fn(&Outcome) -> bool) -> usize {
+        self.results.iter().filter(|x| f(&x.1)).count()
+    }
+
+    /// Table of every file with its outcome, failures first
+    pub fn print(&self) {
+        let mut rows = self.results.iter().collect::<Vec<_>>();
+        let rank = |x: &Outcome| match x {
+            Outcome::Failed(_) => 0,
+            Outcome::Skipped => 1,
+            Outcome::Succeeded => 2,
+        };
+        rows.sort_by(|a, b| rank(&a.1).cmp(&rank(&b.1)).then_with(|| a.0.input.cmp(&b.0.input)));
+
+        for (job, outcome) in rows {
+            match outcome {
+                Outcome::Succeeded => println!("  ok       {}", job.input.display()),
+                Outcome::Failed(e) => println!("  failed   {}: {e:#}", job.input.display()),
+                Outcome::Skipped => println!("  skipped  {}: not finished after failure", job.input.display()),
+            }
+        }
+        println!(
+            "Succeeded: {}, failed: {}, skipped: {}",
+            self.count(|x| matches!(x, Outcome::Succeeded)),
+            self.count(|x| matches!(x, Outcome::Failed(_))),
+            self.count(|x| matches!(x, Outcome::Skipped)),
+        );
+    }
+
+    /// With fail-fast the error which stopped the run, otherwise a summary error if anything failed
+    pub fn into_result(self) -> anyhow::Result<()> {
+        let failed = self.count(|x| matches!(x, Outcome::Failed(_)));
+        let total = self.results.len();
+        let first = self.results.into_iter().find_map(|x| match x.1 {
+            Outcome::Failed(e) => Some(e.context(x.0.input.display().to_string())),
+            _ => None,
+        });
+
+        match first {
+            Some(e) if self.fail_fast => Err(e),
+            Some(_) => bail!("{failed} of {total} files failed"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Outcomes of finished jobs, printed in job order as soon as all preceding jobs finish
+struct Progress {
+    outcomes: Vec<Option<Outcome>>,
+    printed: usize,
+}
+
+impl Progress {
+    fn print_finished(&mut self, jobs: &[Job]) {
+        while let Some(Some(outcome)) = self.outcomes.get(self.printed) {
+            let path = jobs[self.printed].input.display();
+            self.printed += 1;
+            let status = match outcome {
+                Outcome::Succeeded => "ok",
+                Outcome::Failed(_) => "failed",
+                Outcome::Skipped => "skipped",
+            };
+            eprintln!("[{}/{}] {status} {path}", self.printed, jobs.len());
+        }
+    }
+}
+
+/// Runs jobs on `threads` workers, largest first so a big file does not start last and stretch the run.
+/// Output directories are created as needed. A failed job does not stop others unless `fail_fast` is set,
+/// then jobs not started yet are skipped, as are jobs failing with [`crate::Cancelled`].
+/// With `progress` every finished job is reported to stderr in start order.
+pub fn run(
+    mut jobs: Vec<Job>,
+    threads: NonZeroUsize,
+    fail_fast: bool,
+    progress: bool,
+    f: impl Fn(&Job) -> anyhow::Result<()> + Sync,
+) -> Report {
+    jobs.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.input.cmp(&b.input)));
+
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let state = Mutex::new(Progress {
+        outcomes: jobs.iter().map(|_| None).collect(),
+        printed: 0,
+    });
+
+    let worker = || {
+        while !(fail_fast && failed.load(Ordering::Relaxed)) {
+            let index = next.fetch_add(1, Ordering::Relaxed);
+            let Some(job) = jobs.get(index) else {
+                break;
+            };
+
+            let result = job
+                .output
+                .parent()
+                .map_or(Ok(()), |dir| {
+                    std::fs::create_dir_all(dir).with_context(|| format!("Unable to create {}", dir.display()))
+                })
+                .and_then(|_| f(job));
+
+            let outcome = match result {
+                Ok(()) => Outcome::Succeeded,
+                Err(e) if e.is::<crate::Cancelled>() => Outcome::Skipped,
+                Err(e) => {
+                    failed.store(true, Ordering::Relaxed);
+                    Outcome::Failed(error::with_code(e))
+                },
+            };
+            let mut state = state.lock().unwrap();
+            state.outcomes[index] = Some(outcome);
+            if progress {
+                state.print_finished(&jobs);
+            }
+        }
+    };
+
+    std::thread::scope(|scope| {
+        for _ in 0..threads.get().min(jobs.len()) {
+            scope.spawn(worker);
+        }
+    });
+
+    let outcomes = state.into_inner().unwrap().outcomes.into_iter().map(|x| x.unwrap_or(Outcome::Skipped));
+    Report {
+        results: jobs.into_iter().zip(outcomes).collect(),
+        fail_fast,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn largest_first_and_fail_fast() {
+        let job = |name: &str, size| Job {
+            input: name.into(),
+            output: PathBuf::new(),
+            size,
+        };
+        let jobs = || vec![job("small", 1), job("large", 100), job("broken", 50), job("medium", 10)];
+        let f = |done: &Mutex<Vec<PathBuf>>, job: &Job| {
+            done.lock().unwrap().push(job.input.clone());
+            anyhow::ensure!(job.input != Path::new("broken"), "Broken");
+            Ok(())
+        };
+
+        let done = Mutex::new(vec![]);
+        let report = run(jobs(), NonZeroUsize::MIN, true, false, |job| f(&done, job));
+        assert_eq!(report.count(|x| matches!(x, Outcome::Skipped)), 2);
+        assert_eq!(format!("{:#}", report.into_result().unwrap_err()), "broken: Broken");
+        assert_eq!(done.into_inner().unwrap(), [PathBuf::from("large"), PathBuf::from("broken")]);
+
+        let done = Mutex::new(vec![]);
+        let report = run(jobs(), NonZeroUsize::MIN, false, false, |job| f(&done, job));
+        assert_eq!(report.count(|x| matches!(x, Outcome::Succeeded)), 3);
+        assert_eq!(format!("{:#}", report.into_result().unwrap_err()), "1 of 4 files failed");
+        assert_eq!(done.into_inner().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn passthrough_rules() {
+        assert!(glob_match(b"logs/**", b"logs/2024-05-01-1.log.gz"));
+        assert!(glob_match(b"**/*.dat_old", b"level.dat_old"));
+        assert!(glob_match(b"**/*.dat_old", b"data/raids.dat_old"));
+        assert!(!glob_match(b"*.dat_old", b"data/raids.dat_old"));
+        assert!(glob_match(b"playerdata/????????-*.dat", b"playerdata/01234567-89ab.dat"));
+        assert!(!glob_match(b"logs/**", b"datapacks/logs/x"));
+
+        let rules = Passthrough {
+            all: true,
+            skip: vec!["logs/**".into(), "datapacks/**".into()],
+            include: vec!["datapacks/keep/**".into()],
+            compress: false,
+            dedupe: false,
+        };
+        assert!(rules.copies("level.dat"));
+        assert!(!rules.copies("logs/latest.log"));
+        assert!(!rules.copies("logs/latest.log.rpackf"));
+        assert!(!rules.copies("datapacks/other/pack.mcmeta"));
+        assert!(rules.copies("datapacks/keep/pack.mcmeta"));
+        assert!(Passthrough { include: rules.include.clone(), ..Default::default() }.copies("datapacks/keep/pack.mcmeta"));
+        assert!(!Passthrough { include: rules.include, ..Default::default() }.copies("level.dat"));
+
+        assert!(is_archive(Path::new("r.0.0.mca.rpack")));
+        assert!(is_archive(Path::new("r.0.0.mca.rpack.002")));
+        assert!(!is_archive(Path::new("level.dat")));
+    }
+
+    #[test]
+    fn mod_dimensions() {
+        let world = TempDir::new("batch-world");
+        for dir in ["region", "DIM-1/entities", "dimensions/mymod/mining/region", "dimensions/mymod/mining/poi", "backup/old"] {
+            std::fs::create_dir_all(world.join(dir)).unwrap();
+            std::fs::write(world.join(dir).join("r.0.0.mca"), []).unwrap();
+        }
+
+        std::fs::write(world.join("region/c.0.0.mcc"), []).unwrap();
+        std::fs::write(world.join("region/r.0.0.mca.rpack.002"), []).unwrap();
+        let kinds = count_kinds(&scan(world.path(), LinkPolicy::Read).unwrap());
+        assert_eq!(kinds.into_iter().collect::<Vec<_>>(), [(FileKind::Region, 5), (FileKind::ExternalChunk, 1), (FileKind::Volume, 1)]);
+
+        let jobs = compact_jobs(world.path(), Path::new("out")).unwrap();
+        assert!(jobs
+            .iter()
+            .any(|x| x.output == Path::new("out/dimensions/mymod/mining/region/r.0.0.mca.rpack")));
+
+        let (dimensions, unknown) = dimensions(world.path(), &jobs);
+        let summary = dimensions
+            .iter()
+            .map(|(dimension, kinds)| (dimension.as_str(), kinds.keys().map(String::as_str).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                ("minecraft:overworld", vec!["region"]),
+                ("minecraft:the_nether", vec!["entities"]),
+                ("mymod:mining", vec!["poi", "region"]),
+            ]
+        );
+        assert_eq!(unknown, 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn link_policies() {
+        let world = TempDir::new("batch-links");
+        std::fs::create_dir_all(world.join("datapacks/real")).unwrap();
+        std::fs::write(world.join("datapacks/real/pack.mcmeta"), []).unwrap();
+        std::fs::hard_link(world.join("datapacks/real/pack.mcmeta"), world.join("copy.mcmeta")).unwrap();
+        std::os::unix::fs::symlink("real", world.join("datapacks/linked")).unwrap();
+        std::os::unix::fs::symlink("..", world.join("datapacks/loop")).unwrap();
+
+        let kinds = |links| {
+            let files = scan(world.path(), links).unwrap();
+            let kinds = files.iter().map(|x| (x.path.strip_prefix(world.path()).unwrap().to_str().unwrap().to_owned(), x.kind));
+            kinds.collect::<Vec<_>>()
+        };
+        let owned = |x: &[(&str, FileKind)]| x.iter().map(|&(path, kind)| (path.to_owned(), kind)).collect::<Vec<_>>();
+        assert_eq!(
+            kinds(LinkPolicy::Follow),
+            owned(&[
+                ("copy.mcmeta", FileKind::Other),
+                ("datapacks/linked/pack.mcmeta", FileKind::Other),
+                ("datapacks/loop", FileKind::Skipped),
+                ("datapacks/real/pack.mcmeta", FileKind::Other),
+            ])
+        );
+        assert_eq!(
+            kinds(LinkPolicy::Preserve),
+            owned(&[
+                ("copy.mcmeta", FileKind::Other),
+                ("datapacks/linked", FileKind::Link),
+                ("datapacks/loop", FileKind::Link),
+                ("datapacks/real/pack.mcmeta", FileKind::Link),
+            ])
+        );
+    }
+
+    #[test]
+    fn stored_once() {
+        let dir = TempDir::new("batch-stored");
+        let job = |name: &str, data: &[u8]| {
+            std::fs::write(dir.join(name), data).unwrap();
+            Job { input: dir.join(name), output: PathBuf::new(), size: data.len() as u64 }
+        };
+        let stored = Stored::default();
+        assert_eq!(stored.holder(&job("a.json", b"{}")).unwrap(), None);
+        assert_eq!(stored.holder(&job("b.json", b"[]")).unwrap(), None);
+        assert_eq!(stored.holder(&job("c.json", b"{}")).unwrap(), Some(dir.join("a.json")));
+    }
+}
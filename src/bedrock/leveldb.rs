@@ -0,0 +1,234 @@
+//! Read-only LevelDB reader sufficient for Bedrock worlds.
+//!
+//! Mojang's fork adds zlib (2) and raw deflate (4) block compression. Snappy is not supported as
+//! the game never writes it. MANIFEST is ignored: every table and log file is read and the entry
+//! with the highest sequence number wins, so stale files left by an interrupted compaction are harmless.
+
+use std::{collections::BTreeMap, io::Read, path::Path};
+
+use anyhow::{bail, ensure, Context};
+
+const TABLE_MAGIC: u64 = 0xdb4775248b80fb57;
+const FOOTER_SIZE: usize = 48;
+const BLOCK_TRAILER_SIZE: usize = 5;
+const LOG_BLOCK_SIZE: usize = 32768;
+const LOG_HEADER_SIZE: usize = 7;
+
+/// Latest value of every live key
+pub type Entries = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// Reads all `.ldb`, `.sst` and `.log` files of database directory
+pub fn read_db(dir: impl AsRef<Path>) -> anyhow::Result<Entries> {
+    let dir = dir.as_ref();
+    // Value is None for deleted keys
+    let mut latest = BTreeMap::<Vec<u8>, (u64, Option<Vec<u8>>)>::new();
+    let mut put = |key: &[u8], sequence: u64, value: Option<&[u8]>| match latest.get_mut(key) {
+        Some(old) if old.0 >= sequence => {},
+        Some(old) => *old = (sequence, value.map(<[u8]>::to_vec)),
+        None => {
+            latest.insert(key.to_vec(), (sequence, value.map(<[u8]>::to_vec)));
+        },
+    };
+
+    let mut files = std::fs::read_dir(dir)
+        .with_context(|| format!("Unable to read {}", dir.display()))?
+        .map(|x| x.map(|x| x.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    files.sort();
+
+    for path in files.iter() {
+        let Some(extension) = path.extension().and_then(|x| x.to_str()) else {
+            continue;
+        };
+        let read = match extension {
+            "ldb" | "sst" => read_table(&std::fs::read(path)?, &mut put),
+            "log" => read_log(&std::fs::read(path)?, &mut put),
+            _ => continue,
+        };
+        read.with_context(|| format!("{}", path.display()))?;
+    }
+
+    Ok(latest.into_iter().filter_map(|(key, (_, value))| Some((key, value?))).collect())
+}
+
+fn varint(data: &mut &[u8]) -> anyhow::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = data.split_first().context("Truncated varint")?;
+        *data = rest;
+        value |= ((byte & 0x7F) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("Varint is too long")
+}
+
+fn take<'a>(data: &mut &'a [u8], count: u64) -> anyhow::Result<&'a [u8]> {
+    ensure!(count <= data.len() as u64, "Unexpected end of data");
+    let (head, rest) = data.split_at(count as usize);
+    *data = rest;
+    Ok(head)
+}
+
+fn block_handle(data: &mut &[u8]) -> anyhow::Result<(u64, u64)> {
+    Ok((varint(data)?, varint(data)?))
+}
+
+/// Splits internal key into user key, sequence number and whether it is a value (not a deletion)
+fn internal_key(key: &[u8]) -> anyhow::Result<(&[u8], u64, bool)> {
+    ensure!(key.len() >= 8, "Internal key is too short");
+    let (user_key, trailer) = key.split_at(key.len() - 8);
+    let trailer = u64::from_le_bytes(trailer.try_into().unwrap());
+    Ok((user_key, trailer >> 8, trailer & 0xFF == 1))
+}
+
+fn read_block(file: &[u8], (offset, size): (u64, u64)) -> anyhow::Result<Vec<u8>> {
+    let end = offset
+        .checked_add(size)
+        .filter(|&x| x + BLOCK_TRAILER_SIZE as u64 <= file.len() as u64)
+        .context("Block is beyond end of file")?;
+    let data = &file[offset as usize..end as usize];
+
+    let mut block = vec![];
+    match file[end as usize] {
+        0 => block.extend_from_slice(data),
+        1 => bail!("Snappy compressed blocks are not supported"),
+        2 => _ = flate2::read::ZlibDecoder::new(data).read_to_end(&mut block)?,
+        4 => _ = flate2::read::DeflateDecoder::new(data).read_to_end(&mut block)?,
+        x => bail!("Unknown block compression {x}"),
+    }
+    Ok(block)
+}
+
+/// Calls `f` for every key and value of block
+fn block_entries(block: &[u8], mut f: impl FnMut(&[u8], &[u8]) -> anyhow::Result<()>) -> anyhow::Result<()> {
+    ensure!(block.len() >= 4, "Block is too short");
+    let restarts = u32::from_le_bytes(block[block.len() - 4..].try_into().unwrap()) as usize;
+    let entries_end = restarts
+        .checked_mul(4)
+        .and_then(|x| block.len().checked_sub(4 + x))
+        .context("Invalid restart count")?;
+
+    let mut data = &block[..entries_end];
+    let mut key = vec![];
+    while !data.is_empty() {
+        let shared = varint(&mut data)?;
+        let unshared = varint(&mut data)?;
+        let value_length = varint(&mut data)?;
+        ensure!(shared <= key.len() as u64, "Invalid shared key length");
+
+        key.truncate(shared as usize);
+        key.extend_from_slice(take(&mut data, unshared)?);
+        f(&key, take(&mut data, value_length)?)?;
+    }
+    Ok(())
+}
+
+fn read_table(file: &[u8], put: &mut impl FnMut(&[u8], u64, Option<&[u8]>)) -> anyhow::Result<()> {
+    ensure!(file.len() >= FOOTER_SIZE, "Table is shorter than footer");
+    let footer = &file[file.len() - FOOTER_SIZE..];
+    ensure!(
+        u64::from_le_bytes(footer[FOOTER_SIZE - 8..].try_into().unwrap()) == TABLE_MAGIC,
+        "Not a LevelDB table"
+    );
+
+    let mut handles = footer;
+    let _metaindex = block_handle(&mut handles)?;
+    let index = read_block(file, block_handle(&mut handles)?)?;
+
+    block_entries(&index, |_, mut handle| {
+        let block = read_block(file, block_handle(&mut handle)?)?;
+        block_entries(&block, |key, value| {
+            let (user_key, sequence, is_value) = internal_key(key)?;
+            put(user_key, sequence, is_value.then_some(value));
+            Ok(())
+        })
+    })
+}
+
+fn read_log(file: &[u8], put: &mut impl FnMut(&[u8], u64, Option<&[u8]>)) -> anyhow::Result<()> {
+    let mut record = vec![];
+    for block in file.chunks(LOG_BLOCK_SIZE) {
+        let mut data = block;
+        while data.len() >= LOG_HEADER_SIZE {
+            let length = u16::from_le_bytes([data[4], data[5]]) as usize;
+            let kind = data[6];
+            // Zero header is block padding or preallocated space
+            if kind == 0 && length == 0 {
+                break;
+            }
+            let fragment = data[LOG_HEADER_SIZE..].get(..length).context("Log record is beyond block end")?;
+            data = &data[LOG_HEADER_SIZE + length..];
+
+            match kind {
+                1 => {
+                    record.clear();
+                    record.extend_from_slice(fragment);
+                },
+                2 => {
+                    record.clear();
+                    record.extend_from_slice(fragment);
+                    continue;
+                },
+                3 => {
+                    record.extend_from_slice(fragment);
+                    continue;
+                },
+                4 => record.extend_from_slice(fragment),
+                x => bail!("Unknown log record type {x}"),
+            }
+
+            write_batch(&record, put)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_batch(mut data: &[u8], put: &mut impl FnMut(&[u8], u64, Option<&[u8]>)) -> anyhow::Result<()> {
+    let header = take(&mut data, 12).context("Write batch is too short")?;
+    let sequence = u64::from_le_bytes(header[..8].try_into().unwrap());
+    let count = u32::from_le_bytes(header[8..].try_into().unwrap()) as u64;
+
+    for i in 0..count {
+        let kind = take(&mut data, 1)?[0];
+        let length = varint(&mut data)?;
+        let key = take(&mut data, length)?;
+        match kind {
+            0 => put(key, sequence + i, None),
+            1 => {
+                let length = varint(&mut data)?;
+                put(key, sequence + i, Some(take(&mut data, length)?));
+            },
+            x => bail!("Unknown write batch entry type {x}"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn log_deletion_and_overwrite() {
+        let mut batch = vec![];
+        batch.extend_from_slice(&5u64.to_le_bytes());
+        batch.extend_from_slice(&3u32.to_le_bytes());
+        batch.extend_from_slice(&[1, 1, b'a', 1, b'x']);
+        batch.extend_from_slice(&[1, 1, b'b', 1, b'y']);
+        batch.extend_from_slice(&[0, 1, b'a']);
+
+        let mut log = vec![0; 4];
+        log.extend_from_slice(&(batch.len() as u16).to_le_bytes());
+        log.push(1);
+        log.extend_from_slice(&batch);
+
+        let dir = TempDir::new("leveldb-test");
+        std::fs::write(dir.join("000003.log"), &log).unwrap();
+        let entries = read_db(dir.path());
+
+        assert_eq!(entries.unwrap(), Entries::from([(b"b".to_vec(), b"y".to_vec())]));
+    }
+}
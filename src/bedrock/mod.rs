@@ -0,0 +1,160 @@
+//! Import of Bedrock Edition worlds. Chunk records of the LevelDB `db/` directory are grouped
+//! into 32x32 regions and every chunk becomes an NBT compound carrying its raw records:
+//!
+//! ```text
+//! xPos, zPos: Int
+//! Bedrock: {Dimension: Int, Records: [{Tag: Byte, SubChunk: Byte (0x2f only), Value: ByteArray}]}
+//! ```
+//!
+//! Record values are kept as-is, so a later exporter can write them back unchanged.
+
+use std::{collections::BTreeMap, path::Path};
+
+use crate::nbt::{tag_id, Compound, Tag};
+
+pub mod leveldb;
+
+/// Record tag of sub-chunk block storage, the only one followed by sub-chunk index
+pub const SUBCHUNK_PREFIX: u8 = 0x2f;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ChunkKey {
+    pub x: i32,
+    pub z: i32,
+    /// 0 overworld, 1 nether, 2 end. Overworld keys omit the field
+    pub dimension: i32,
+    pub tag: u8,
+    pub subchunk: Option<i8>,
+}
+
+impl ChunkKey {
+    /// `None` for keys which are not chunk records, like `~local_player` or `VILLAGE_...`
+    pub fn parse(key: &[u8]) -> Option<Self> {
+        let int = |at: usize| i32::from_le_bytes(key[at..at + 4].try_into().unwrap());
+        let (dimension, rest) = match key.len() {
+            9 | 10 => (0, &key[8..]),
+            13 | 14 => (int(8), &key[12..]),
+            _ => return None,
+        };
+
+        let tag = rest[0];
+        let subchunk = match (tag, rest.get(1)) {
+            (SUBCHUNK_PREFIX, Some(&y)) => Some(y as i8),
+            (SUBCHUNK_PREFIX, None) | (_, Some(_)) => return None,
+            (_, None) => None,
+        };
+        if !matches!(tag, 0x2b..=0x41 | 0x76) || !(0..=2).contains(&dimension) {
+            return None;
+        }
+
+        Some(Self {
+            x: int(0),
+            z: int(4),
+            dimension,
+            tag,
+            subchunk,
+        })
+    }
+}
+
+/// Region of a single dimension
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RegionKey {
+    pub dimension: i32,
+    pub x: i32,
+    pub z: i32,
+}
+
+impl RegionKey {
+    /// Directory of region files relative to world root, laid out like Java Edition
+    pub fn directory(&self) -> &'static Path {
+        match self.dimension {
+            1 => Path::new("DIM-1/region"),
+            2 => Path::new("DIM1/region"),
+            _ => Path::new("region"),
+        }
+    }
+}
+
+/// Chunk compounds of every region keyed by header slot
+pub type Regions = BTreeMap<RegionKey, BTreeMap<u16, Compound>>;
+
+/// Groups chunk records of database. Entries which are not chunk records are skipped.
+pub fn regions(entries: &leveldb::Entries) -> Regions {
+    let mut regions = Regions::new();
+
+    for (key, value) in entries.iter() {
+        let Some(key) = ChunkKey::parse(key) else {
+            continue;
+        };
+        let region = RegionKey {
+            dimension: key.dimension,
+            x: key.x.div_euclid(32),
+            z: key.z.div_euclid(32),
+        };
+        let pos = (key.x.rem_euclid(32) + key.z.rem_euclid(32) * 32) as u16;
+
+        let chunk = regions.entry(region).or_default().entry(pos).or_insert_with(|| {
+            let mut bedrock = Compound::default();
+            bedrock.insert("Dimension", Tag::Int(key.dimension));
+            bedrock.insert("Records", Tag::List(tag_id::COMPOUND, vec![]));
+
+            let mut root = Compound::default();
+            root.insert("xPos", Tag::Int(key.x));
+            root.insert("zPos", Tag::Int(key.z));
+            root.insert("Bedrock", Tag::Compound(bedrock));
+            root
+        });
+
+        let mut record = Compound::default();
+        record.insert("Tag", Tag::Byte(key.tag as i8));
+        if let Some(y) = key.subchunk {
+            record.insert("SubChunk", Tag::Byte(y));
+        }
+        record.insert("Value", Tag::ByteArray(value.iter().map(|&x| x as i8).collect()));
+
+        let Some(Tag::Compound(bedrock)) = chunk.get_mut("Bedrock") else {
+            unreachable!()
+        };
+        let Some(Tag::List(_, records)) = bedrock.get_mut("Records") else {
+            unreachable!()
+        };
+        records.push(Tag::Compound(record));
+    }
+
+    regions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_key_parse() {
+        let mut key = vec![];
+        key.extend_from_slice(&(-1i32).to_le_bytes());
+        key.extend_from_slice(&33i32.to_le_bytes());
+        key.push(0x2c);
+        assert_eq!(
+            ChunkKey::parse(&key),
+            Some(ChunkKey { x: -1, z: 33, dimension: 0, tag: 0x2c, subchunk: None })
+        );
+
+        key.truncate(8);
+        key.extend_from_slice(&1i32.to_le_bytes());
+        key.extend_from_slice(&[SUBCHUNK_PREFIX, 0xFC]);
+        assert_eq!(
+            ChunkKey::parse(&key),
+            Some(ChunkKey { x: -1, z: 33, dimension: 1, tag: SUBCHUNK_PREFIX, subchunk: Some(-4) })
+        );
+
+        assert_eq!(ChunkKey::parse(b"~local_player"), None);
+        assert_eq!(ChunkKey::parse(b"Nether"), None);
+
+        let entries = leveldb::Entries::from([(key, vec![1, 2, 3])]);
+        let regions = regions(&entries);
+        let (region, chunks) = regions.first_key_value().unwrap();
+        assert_eq!(*region, RegionKey { dimension: 1, x: -1, z: 1 });
+        assert_eq!(chunks.keys().copied().collect::<Vec<_>>(), [31 + 32]);
+    }
+}
@@ -0,0 +1,81 @@
+use std::{
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::PathBuf,
+};
+
+use anyhow::{ensure, Context};
+
+use crate::rpack;
+
+#[derive(Debug, clap::Args)]
+pub struct CatArgs {
+    /// Archives to merge, in order. Split archives are read from all their volumes
+    #[arg(required = true)]
+    pub inputs: Vec<PathBuf>,
+
+    /// Merged archive. Chunks of later archives win on decompaction with `--dedupe-pos last`
+    #[arg(short, long)]
+    pub output: PathBuf,
+}
+
+/// Concatenates archives as they are, decompaction reads them one after another. The result is written next to
+/// output and renamed into place once complete, so a failed run leaves output as it was
+pub fn run(args: CatArgs) -> anyhow::Result<()> {
+    for input in &args.inputs {
+        crate::check_archive_paths(input, &args.output)?;
+    }
+
+    let mut temp = args.output.clone().into_os_string();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let concat = || {
+        let file = std::fs::File::create(&temp).with_context(|| format!("Unable to create {}", temp.display()))?;
+        let mut writer = BufWriter::new(file);
+        for input in args.inputs.iter() {
+            let mut reader = BufReader::new(rpack::volume::open(input)?);
+            ensure!(
+                reader.fill_buf()?.starts_with(&rpack::MAGIC),
+                "{} is not an rpack archive",
+                input.display()
+            );
+            std::io::copy(&mut reader, &mut writer).with_context(|| format!("Unable to copy {}", input.display()))?;
+        }
+        writer.flush()?;
+        std::fs::rename(&temp, &args.output)
+            .with_context(|| format!("Unable to rename {} to {}", temp.display(), args.output.display()))
+    };
+    concat().inspect_err(|_| {
+        std::fs::remove_file(&temp).ok();
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn output_is_input() {
+        let dir = TempDir::new("cat");
+        let archive = rpack::RpackWriter::new(vec![], rpack::Options::default()).unwrap().finish().unwrap();
+        std::fs::write(dir.join("a.rpack"), &archive).unwrap();
+
+        let cat = |inputs: &[&str], output: &str| {
+            run(CatArgs { inputs: inputs.iter().map(|x| dir.join(x)).collect(), output: dir.join(output) })
+        };
+        assert!(cat(&["a.rpack"], "a.rpack").is_err());
+        let name = dir.path().file_name().unwrap().to_str().unwrap();
+        assert!(cat(&["./a.rpack"], &format!("../{name}/a.rpack")).is_err());
+        assert_eq!(std::fs::read(dir.join("a.rpack")).unwrap(), archive);
+
+        // Output is left as it was when an input fails
+        std::fs::write(dir.join("b.rpack"), b"not an archive").unwrap();
+        std::fs::write(dir.join("c.rpack"), b"previous").unwrap();
+        assert!(cat(&["a.rpack", "b.rpack"], "c.rpack").is_err());
+        assert_eq!(std::fs::read(dir.join("c.rpack")).unwrap(), b"previous");
+        assert!(!dir.join("c.rpack.tmp").exists());
+
+        cat(&["a.rpack", "a.rpack"], "c.rpack").unwrap();
+        assert_eq!(std::fs::read(dir.join("c.rpack")).unwrap(), [archive.clone(), archive].concat());
+    }
+}
@@ -0,0 +1,83 @@
+//! Minimal chunks for empty header slots, for tools that can not handle regions with gaps.
+//! They are written as 1.18.2 chunks, which every later game version upgrades on load, without
+//! heightmaps and light for the game to compute.
+
+use super::pack_palette_indices;
+use crate::nbt::{tag_id, Compound, Tag};
+
+/// Content of generated chunks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Filler {
+    /// Only air, in the void biome
+    Void,
+    /// Bedrock, two dirt and a grass layer at the bottom of the world, like the default superflat preset
+    PlainsFlat,
+}
+
+impl Filler {
+    /// DataVersion of generated chunks, 1.18.2
+    pub const DATA_VERSION: i32 = 2975;
+    /// Sections of the overworld from y=-64 to y=319
+    const SECTIONS: std::ops::RangeInclusive<i8> = -4..=19;
+
+    /// Full chunk at chunk coordinates `x`, `z`
+    pub fn chunk(self, x: i32, z: i32) -> Compound {
+        let biome = match self {
+            Filler::Void => "minecraft:the_void",
+            Filler::PlainsFlat => "minecraft:plains",
+        };
+        let sections = Self::SECTIONS
+            .map(|y| {
+                let mut palette = vec!["minecraft:air"];
+                let mut states = vec![];
+                if self == Filler::PlainsFlat && y == *Self::SECTIONS.start() {
+                    palette.extend(["minecraft:bedrock", "minecraft:dirt", "minecraft:grass_block"]);
+                    // Layers are 256 blocks each, YZX order
+                    let mut indices = vec![0; 4096];
+                    for (layer, index) in [1, 2, 2, 3].into_iter().enumerate() {
+                        indices[layer * 256..(layer + 1) * 256].fill(index);
+                    }
+                    states.push(("data".into(), Tag::LongArray(pack_palette_indices(&indices, palette.len(), 4))));
+                }
+                let palette = palette
+                    .into_iter()
+                    .map(|x| Tag::Compound(Compound(vec![("Name".into(), Tag::String(x.into()))])))
+                    .collect();
+                states.insert(0, ("palette".into(), Tag::List(tag_id::COMPOUND, palette)));
+
+                Tag::Compound(Compound(vec![
+                    ("Y".into(), Tag::Byte(y)),
+                    ("block_states".into(), Tag::Compound(Compound(states))),
+                    (
+                        "biomes".into(),
+                        Tag::Compound(Compound(vec![("palette".into(), Tag::List(tag_id::STRING, vec![Tag::String(biome.into())]))])),
+                    ),
+                ]))
+            })
+            .collect();
+
+        let empty_list = || Tag::List(tag_id::END, vec![]);
+        Compound(vec![
+            ("DataVersion".into(), Tag::Int(Self::DATA_VERSION)),
+            ("xPos".into(), Tag::Int(x)),
+            ("yPos".into(), Tag::Int(*Self::SECTIONS.start() as i32)),
+            ("zPos".into(), Tag::Int(z)),
+            ("Status".into(), Tag::String("minecraft:full".into())),
+            ("LastUpdate".into(), Tag::Long(0)),
+            ("InhabitedTime".into(), Tag::Long(0)),
+            ("isLightOn".into(), Tag::Byte(0)),
+            ("sections".into(), Tag::List(tag_id::COMPOUND, sections)),
+            ("block_entities".into(), empty_list()),
+            ("block_ticks".into(), empty_list()),
+            ("fluid_ticks".into(), empty_list()),
+            (
+                "structures".into(),
+                Tag::Compound(Compound(vec![
+                    ("References".into(), Tag::Compound(Compound::default())),
+                    ("starts".into(), Tag::Compound(Compound::default())),
+                ])),
+            ),
+        ])
+    }
+}
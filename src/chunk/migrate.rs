@@ -0,0 +1,139 @@
+//! Mechanical chunk NBT migrations, like the game's data fixers but only for trivial tag renames and moves.
+//!
+//! A migration sets DataVersion of the chunk to its target, so the game skips its own fixers up to it.
+//! It must therefore cover only versions whose other fixers have already run, usually a single one:
+//! a 1.17 chunk missing the 1.18 height and biome fixes would be corrupted by skipping them.
+
+use std::ops::RangeInclusive;
+
+use anyhow::{bail, ensure, Context};
+
+use super::data_version;
+use crate::nbt::{Compound, Tag};
+
+/// Rewrite bringing chunks of `from` DataVersions to DataVersion `to`
+#[derive(Debug, Clone)]
+pub struct Migration {
+    pub name: &'static str,
+    pub from: RangeInclusive<i32>,
+    pub to: i32,
+    pub apply: fn(&mut Compound) -> anyhow::Result<()>,
+}
+
+/// Registered migrations, applied as a chain while one matches DataVersion of the chunk
+#[derive(Debug, Clone, Default)]
+pub struct Migrations(Vec<Migration>);
+
+impl Migrations {
+    /// 21w43a moved chunk tags out of the `Level` compound and renamed most of them
+    pub const LEVEL_WRAPPER: Migration = Migration {
+        name: "level-wrapper",
+        from: 2841..=2841,
+        to: 2842,
+        apply: remove_level_wrapper,
+    };
+
+    pub fn builtin() -> Self {
+        Self(vec![Self::LEVEL_WRAPPER])
+    }
+
+    /// Adds migration. Ranges of migrations can not overlap, so the chain is unambiguous
+    pub fn register(&mut self, migration: Migration) -> anyhow::Result<()> {
+        ensure!(
+            migration.to > *migration.from.end(),
+            "Migration {} does not advance DataVersion past {}",
+            migration.name,
+            migration.from.end()
+        );
+        if let Some(other) = self
+            .0
+            .iter()
+            .find(|x| x.from.start() <= migration.from.end() && migration.from.start() <= x.from.end())
+        {
+            bail!("Migration {} overlaps DataVersions of {}", migration.name, other.name);
+        }
+        self.0.push(migration);
+        Ok(())
+    }
+
+    /// Applies migrations until none matches DataVersion of the chunk. Returns names of applied ones.
+    /// Chunks without DataVersion are left alone
+    pub fn apply(&self, root: &mut Compound) -> anyhow::Result<Vec<&'static str>> {
+        let mut applied = vec![];
+        while let Some(version) = data_version(root) {
+            // Every migration advances DataVersion, so the chain ends
+            let Some(migration) = self.0.iter().find(|x| x.from.contains(&version)) else { break };
+            (migration.apply)(root).with_context(|| format!("Migration {} failed", migration.name))?;
+            root.insert("DataVersion", Tag::Int(migration.to));
+            applied.push(migration.name);
+        }
+        Ok(applied)
+    }
+}
+
+fn rename(compound: &mut Compound, from: &str, to: &str) {
+    if let Some(entry) = compound.0.iter_mut().find(|x| x.0 == from) {
+        entry.0 = to.into();
+    }
+}
+
+fn remove_level_wrapper(root: &mut Compound) -> anyhow::Result<()> {
+    let Some(level) = root.remove("Level") else { return Ok(()) };
+    let Tag::Compound(mut level) = level else { bail!("Level is not a compound") };
+
+    for (from, to) in [
+        ("TileEntities", "block_entities"),
+        ("TileTicks", "block_ticks"),
+        ("Entities", "entities"),
+        ("Sections", "sections"),
+        ("LiquidTicks", "fluid_ticks"),
+        ("Structures", "structures"),
+    ] {
+        rename(&mut level, from, to);
+    }
+    if let Some(structures) = level.get_mut("structures").and_then(Tag::as_compound_mut) {
+        rename(structures, "Starts", "starts");
+    }
+
+    // Root tags like DataVersion win over stray copies inside Level
+    for (name, tag) in level.0 {
+        if root.get(&name).is_none() {
+            root.0.push((name, tag));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn level_wrapper_chain() {
+        let level = Compound(vec![
+            ("xPos".into(), Tag::Int(1)),
+            ("Sections".into(), Tag::List(0, vec![])),
+            ("Structures".into(), Tag::Compound(Compound(vec![("Starts".into(), Tag::Compound(Compound::default()))]))),
+        ]);
+        let chunk = |version| Compound(vec![("DataVersion".into(), Tag::Int(version)), ("Level".into(), Tag::Compound(level.clone()))]);
+
+        let mut migrations = Migrations::builtin();
+        migrations
+            .register(Migration { name: "bump", from: 2842..=2843, to: 2844, apply: |_| Ok(()) })
+            .unwrap();
+        assert!(migrations.register(Migration { name: "overlap", from: 2843..=2850, to: 2851, apply: |_| Ok(()) }).is_err());
+
+        let mut root = chunk(2841);
+        assert_eq!(migrations.apply(&mut root).unwrap(), ["level-wrapper", "bump"]);
+        assert_eq!(data_version(&root), Some(2844));
+        assert!(root.get("Level").is_none());
+        assert_eq!(root.get("xPos"), Some(&Tag::Int(1)));
+        assert!(root.get("sections").is_some());
+        assert!(root.get("structures").and_then(Tag::as_compound).unwrap().get("starts").is_some());
+
+        // Older chunks still need fixers of the game
+        let mut root = chunk(2730);
+        assert!(migrations.apply(&mut root).unwrap().is_empty());
+        assert_eq!(root, chunk(2730));
+    }
+}
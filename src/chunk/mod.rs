@@ -1,49 +1,214 @@
 #![allow(unused)]
 
-use anyhow::bail;
+use anyhow::{bail, ensure, Context};
 use core::fmt::Debug;
-use std::io::Write;
-use zerocopy::{BigEndian, FromBytes, Immutable, KnownLayout, TryFromBytes, U32};
+use std::io::{Read, Write};
+use zerocopy::{BigEndian, FromBytes, Immutable, KnownLayout, TryFromBytes, Unaligned, U32};
 
-#[derive(TryFromBytes, KnownLayout, Immutable)]
-#[repr(C, align(4))]
+use crate::{
+    error::ErrorCode,
+    limits::Limits,
+    nbt::{Compound, Tag},
+};
+
+mod fill;
+mod migrate;
+mod registry;
+mod transform;
+
+pub use crate::format::Codec;
+pub use fill::Filler;
+pub use migrate::{Migration, Migrations};
+pub use registry::{ChunkCodec, CodecRegistry};
+pub use transform::Transform;
+
+/// Chunk as stored in region file sectors. Parses from any byte slice, fields have no alignment
+#[derive(FromBytes, KnownLayout, Immutable, Unaligned)]
+#[repr(C)]
 pub struct ChunkData {
     length: U32<BigEndian>,
-    pub compression_type: CompressionType,
+    /// One of [`CompressionType`] or an id from [`CodecRegistry`]
+    pub compression_type: u8,
     pub data: [u8],
 }
 
 impl ChunkData {
+    /// Set in compression type when chunk data is stored in separate `c.<x>.<z>.mcc` file
+    pub const EXTERNAL_FLAG: u8 = 0x80;
+
     pub fn length(&self) -> usize {
-        (self.length.get() - 1) as usize
+        // Length includes compression type byte, zero is invalid
+        self.length.get().saturating_sub(1) as usize
+    }
+
+    /// Decompresses with the default limit of decompressed size
+    pub fn decompress(&self, writer: impl Write) -> anyhow::Result<usize> {
+        self.decompress_with_limit(writer, Limits::default().max_decompressed_size)
+    }
+
+    /// Fails if decompressed data is larger than `limit` bytes
+    pub fn decompress_with_limit(&self, writer: impl Write, limit: u64) -> anyhow::Result<usize> {
+        decompress_typed(self.compression_type, self.payload()?, writer, limit)
+    }
+
+    /// Appends decompressed data to `buf`, reserving space up front when the size is known from [`ChunkData::size_hint`].
+    /// `buf` is left as it was on error, except for [`DecompressError::Checksum`] keeping the data
+    pub fn decompress_into(&self, buf: &mut Vec<u8>, max: usize) -> Result<usize, DecompressError> {
+        if let Some(hint) = self.size_hint() {
+            buf.reserve(hint.min(max));
+        }
+
+        let start = buf.len();
+        self.decompress_with_limit(&mut *buf, max as u64).map_err(|e| {
+            let written = buf.len() - start;
+            let e = match e.downcast::<ChecksumMismatch>() {
+                Ok(mismatch) => return DecompressError::Checksum(mismatch),
+                Err(e) => e,
+            };
+            buf.truncate(start);
+            match written > max {
+                true => DecompressError::TooLarge { max },
+                false => DecompressError::Failed(e),
+            }
+        })
+    }
+
+    /// Built-in codec other than the one named by compression type which decompresses data, for chunks written
+    /// by tools mixing up zlib and gzip. Only codecs whose header the data starts with are tried, and uncompressed
+    /// data must parse as NBT. Decompressed data is left in `buf`, which is cleared when no codec fits
+    pub fn sniff_codec(&self, buf: &mut Vec<u8>, limit: u64) -> Option<Codec> {
+        let data = self.payload().ok()?;
+        let codec = [Codec::Zlib, Codec::GZip, Codec::Uncompressed]
+            .into_iter()
+            .filter(|x| x.compression_type() != self.compression_type && x.matches_header(data))
+            .find(|codec| {
+                buf.clear();
+                codec.decompress_with_limit(data, &mut *buf, limit).is_ok()
+                    && (*codec != Codec::Uncompressed || crate::nbt::read(buf).is_ok())
+            });
+        if codec.is_none() {
+            buf.clear();
+        }
+        codec
+    }
+
+    /// Decompressed size as stored by compression: gzip trailer or length of uncompressed data.
+    /// Comes from the file, so it is only good for preallocation
+    pub fn size_hint(&self) -> Option<usize> {
+        let data = self.payload().ok()?;
+        match Codec::from_compression_type(self.compression_type)? {
+            Codec::GZip => Some(u32::from_le_bytes(*data.last_chunk::<4>()?) as usize),
+            Codec::Uncompressed => Some(data.len()),
+            _ => None,
+        }
+    }
+
+    /// Name of the algorithm of chunk with [`CompressionType::Custom`], like `mymod:brotli`
+    pub fn custom_algorithm(&self) -> anyhow::Result<Option<&str>> {
+        match self.compression_type == CompressionType::Custom as u8 {
+            true => Ok(Some(split_custom(self.payload()?)?.0)),
+            false => Ok(None),
+        }
     }
 
-    pub fn decompress(&self, mut writer: impl Write) -> anyhow::Result<usize> {
+    /// Compressed data as specified by length field
+    fn payload(&self) -> anyhow::Result<&[u8]> {
         let (data, _) = self
             .data
             .split_at_checked(self.length())
             .ok_or(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+        Ok(data)
+    }
+}
 
-        match self.compression_type {
-            CompressionType::GZip => {
-                let mut decompressor = flate2::read::GzDecoder::new(data);
-                let copied = std::io::copy(&mut decompressor, &mut writer)?;
-                Ok(copied as usize)
-            },
-            CompressionType::Zlib => {
-                let mut decompressor = flate2::read::ZlibDecoder::new(data);
-                let copied = std::io::copy(&mut decompressor, &mut writer)?;
-                Ok(copied as usize)
-            },
-            CompressionType::Uncompressed => {
-                let copied = std::io::copy(&mut &data[..], &mut writer)?;
-                Ok(copied as usize)
-            },
-            // CompressionType::LZ4 => todo!(),
+/// Decompresses chunk data as stored in region file without length field: compression type byte followed by compressed data.
+/// Fails if decompressed data is larger than `limit` bytes
+pub fn decompress_stored(stored: &[u8], writer: impl Write, limit: u64) -> anyhow::Result<usize> {
+    let (&compression_type, data) = stored.split_first().context("Chunk has no compression type")?;
+    decompress_typed(compression_type, data, writer, limit)
+}
+
+fn decompress_typed(compression_type: u8, data: &[u8], mut writer: impl Write, limit: u64) -> anyhow::Result<usize> {
+    if compression_type == CompressionType::Custom as u8 {
+        let (algorithm, data) = split_custom(data)?;
+        let codec = CodecRegistry::get_custom(algorithm)
+            .ok_or_else(|| ErrorCode::UnknownCompression.error(format!("No codec registered for custom compression {algorithm}")))?;
+        let written = codec.decompress(data, &mut writer, limit)?;
+        ensure!(written as u64 <= limit, ErrorCode::SizeLimit.error(format!("Decompressed chunk exceeds limit of {limit} bytes")));
+        return Ok(written);
+    }
+
+    Codec::from_compression_type(compression_type)
+        .ok_or_else(|| ErrorCode::UnknownCompression.error(format!("Unknown compression type {compression_type}")))?
+        .decompress_with_limit(data, writer, limit)
+}
+
+/// Splits data of chunk with custom compression into algorithm name, prefixed by u16 length, and compressed data
+fn split_custom(data: &[u8]) -> anyhow::Result<(&str, &[u8])> {
+    let (length, data) = data
+        .split_first_chunk::<2>()
+        .context("Chunk with custom compression has no algorithm name")?;
+    let (name, data) = data
+        .split_at_checked(u16::from_be_bytes(*length) as usize)
+        .context("Custom compression algorithm name exceeds chunk data")?;
+    let name = std::str::from_utf8(name).context("Custom compression algorithm name is not valid UTF-8")?;
+    Ok((name, data))
+}
+
+/// Error of [`ChunkData::decompress_into`]
+#[derive(Debug)]
+pub enum DecompressError {
+    /// Decompressed data exceeds `max` bytes
+    TooLarge { max: usize },
+    /// Unknown compression, corrupt data or failed codec
+    Failed(anyhow::Error),
+    /// Data decompressed completely, but does not match the checksum of zlib or gzip stream
+    Checksum(ChecksumMismatch),
+}
+
+impl std::fmt::Display for DecompressError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecompressError::TooLarge { max } => write!(f, "Decompressed chunk exceeds limit of {max} bytes"),
+            DecompressError::Failed(e) => write!(f, "{e:#}"),
+            DecompressError::Checksum(e) => write!(f, "{e}"),
         }
     }
 }
 
+impl std::error::Error for DecompressError {}
+
+/// Zlib or gzip stream decompressed completely, but its trailer does not match the data. Returned by
+/// [`Codec::decompress_with_limit`] after writing all data, so callers recovering damaged files can keep it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChecksumMismatch {
+    pub codec: Codec,
+    /// `Adler-32` for zlib, `CRC-32` or `size` for gzip
+    pub check: &'static str,
+    /// `None` if stream is cut off before its trailer
+    pub stored: Option<u32>,
+    pub computed: u32,
+}
+
+impl std::fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let codec = match self.codec {
+            Codec::GZip => "gzip",
+            _ => "zlib",
+        };
+        match self.stored {
+            Some(stored) => write!(
+                f,
+                "{codec} {} mismatch: stored {stored:#010x}, computed {:#010x}",
+                self.check, self.computed
+            ),
+            None => write!(f, "{codec} stream has no trailer, {} not verified", self.check),
+        }
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
 impl Debug for ChunkData {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("ChunkData")
@@ -63,4 +228,432 @@ pub enum CompressionType {
     Zlib = 2,
     Uncompressed = 3,
     // LZ4 = 4,
+    /// Followed by namespaced algorithm name, see [`ChunkData::custom_algorithm`]
+    Custom = 127,
+}
+
+impl Codec {
+    /// Codec of compression type byte. Registered codecs take precedence over built-in ones
+    pub fn from_compression_type(compression_type: u8) -> Option<Self> {
+        if CodecRegistry::get(compression_type).is_some() {
+            return Some(Codec::Registered(compression_type));
+        }
+        match compression_type {
+            1 => Some(Codec::GZip),
+            2 => Some(Codec::Zlib),
+            3 => Some(Codec::Uncompressed),
+            _ => None,
+        }
+    }
+
+    /// Compression type byte stored in front of chunk data
+    pub fn compression_type(self) -> u8 {
+        match self {
+            Codec::GZip => CompressionType::GZip as u8,
+            Codec::Zlib => CompressionType::Zlib as u8,
+            Codec::Uncompressed => CompressionType::Uncompressed as u8,
+            Codec::Registered(id) => id,
+        }
+    }
+
+    /// Quick check that compressed data starts like output of this codec: gzip magic, zlib header
+    /// or NBT root compound for uncompressed chunks. Registered codecs are not checked
+    pub fn matches_header(self, data: &[u8]) -> bool {
+        match self {
+            Codec::GZip => data.starts_with(&[0x1f, 0x8b]),
+            Codec::Zlib => data
+                .first_chunk::<2>()
+                .is_some_and(|&x| x[0] & 0x0f == 8 && u16::from_be_bytes(x) % 31 == 0),
+            Codec::Uncompressed => data.first() == Some(&crate::nbt::tag_id::COMPOUND),
+            Codec::Registered(_) => true,
+        }
+    }
+
+    fn registered(id: u8) -> anyhow::Result<std::sync::Arc<dyn ChunkCodec>> {
+        CodecRegistry::get(id).ok_or_else(|| ErrorCode::UnknownCompression.error(format!("No codec registered for compression type {id}")))
+    }
+
+    /// Fails if decompressed data is larger than `limit` bytes.
+    /// Trailers of zlib and gzip streams are verified, a mismatch fails with [`ChecksumMismatch`] after writing all data
+    pub fn decompress_with_limit(self, data: &[u8], mut writer: impl Write, limit: u64) -> anyhow::Result<usize> {
+        let decompressor: Box<dyn Read + '_> = match self {
+            Codec::GZip | Codec::Zlib => return self.inflate_verified(data, writer, limit),
+            Codec::Uncompressed => Box::new(data),
+            Codec::Registered(id) => {
+                let written = Self::registered(id)?.decompress(data, &mut writer, limit)?;
+                ensure!(written as u64 <= limit, ErrorCode::SizeLimit.error(format!("Decompressed chunk exceeds limit of {limit} bytes")));
+                return Ok(written);
+            },
+        };
+
+        let copied = std::io::copy(&mut decompressor.take(limit + 1), &mut writer)?;
+        ensure!(copied <= limit, ErrorCode::SizeLimit.error(format!("Decompressed chunk exceeds limit of {limit} bytes")));
+        Ok(copied as usize)
+    }
+
+    /// Inflates deflate data of zlib or gzip stream and checks its trailer separately, so integrity
+    /// failures are told apart from corrupt deflate data
+    fn inflate_verified(self, data: &[u8], writer: impl Write, limit: u64) -> anyhow::Result<usize> {
+        let header = match self {
+            Codec::GZip => gzip_header_len(data)?,
+            _ => zlib_header_len(data)?,
+        };
+        let mut decoder = flate2::bufread::DeflateDecoder::new(&data[header..]);
+        let mut writer = Summing {
+            inner: writer,
+            crc: crc32fast::Hasher::new(),
+            adler: Adler32::default(),
+            gzip: self == Codec::GZip,
+        };
+        let copied = std::io::copy(&mut (&mut decoder).take(limit + 1), &mut writer)?;
+        ensure!(copied <= limit, ErrorCode::SizeLimit.error(format!("Decompressed chunk exceeds limit of {limit} bytes")));
+
+        // Decoder consumes input up to the end of deflate data
+        let trailer = decoder.into_inner();
+        let (check, stored, computed) = match self {
+            Codec::GZip => {
+                let crc = trailer.first_chunk::<4>().map(|&x| u32::from_le_bytes(x));
+                let size = trailer.get(4..8).map(|x| u32::from_le_bytes(x.try_into().unwrap()));
+                match crc == Some(writer.crc.clone().finalize()) {
+                    true => ("size", size, copied as u32),
+                    false => ("CRC-32", crc, writer.crc.finalize()),
+                }
+            },
+            _ => ("Adler-32", trailer.first_chunk::<4>().map(|&x| u32::from_be_bytes(x)), writer.adler.finish()),
+        };
+        if stored != Some(computed) {
+            return Err(ChecksumMismatch { codec: self, check, stored, computed }.into());
+        }
+        Ok(copied as usize)
+    }
+
+    /// Appends compressed `data` to writer
+    pub fn compress(self, data: &[u8], mut writer: impl Write) -> anyhow::Result<()> {
+        match self {
+            Codec::Registered(id) => Self::registered(id)?.compress(data, &mut writer),
+            codec => codec.compress_from(data, writer),
+        }
+    }
+
+    /// Appends compressed data read from `reader` to writer without holding uncompressed data in memory.
+    /// Registered codecs take a slice, so their input is read into memory first
+    pub fn compress_from(self, mut reader: impl Read, mut writer: impl Write) -> anyhow::Result<()> {
+        let level = flate2::Compression::new(3);
+        let mut encoder: Box<dyn Read + '_> = match self {
+            Codec::GZip => Box::new(flate2::read::GzEncoder::new(reader, level)),
+            Codec::Zlib => Box::new(flate2::read::ZlibEncoder::new(reader, level)),
+            Codec::Uncompressed => Box::new(reader),
+            Codec::Registered(id) => {
+                let mut data = vec![];
+                reader.read_to_end(&mut data)?;
+                return Self::registered(id)?.compress(&data, &mut writer);
+            },
+        };
+        std::io::copy(&mut encoder, &mut writer)?;
+        Ok(())
+    }
+}
+
+/// Length of zlib header. Streams needing a preset dictionary are rejected, chunks never use one
+fn zlib_header_len(data: &[u8]) -> anyhow::Result<usize> {
+    ensure!(Codec::Zlib.matches_header(data), "Invalid zlib header");
+    ensure!(data[1] & 0x20 == 0, "Zlib stream requires a preset dictionary");
+    Ok(2)
+}
+
+/// Length of gzip header including optional extra field, file name, comment and header CRC
+fn gzip_header_len(data: &[u8]) -> anyhow::Result<usize> {
+    const FHCRC: u8 = 0x02;
+    const FEXTRA: u8 = 0x04;
+    const FNAME: u8 = 0x08;
+    const FCOMMENT: u8 = 0x10;
+
+    ensure!(data.len() >= 10 && data.starts_with(&[0x1f, 0x8b, 8]), "Invalid gzip header");
+    let flags = data[3];
+    let mut len = 10;
+    if flags & FEXTRA != 0 {
+        let extra = data.get(len..len + 2).context("Gzip header is cut off")?;
+        len += 2 + u16::from_le_bytes([extra[0], extra[1]]) as usize;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let end = data.get(len..).and_then(|x| x.iter().position(|&x| x == 0)).context("Gzip header is cut off")?;
+            len += end + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        len += 2;
+    }
+    ensure!(len <= data.len(), "Gzip header is cut off");
+    Ok(len)
+}
+
+#[derive(Clone, Copy)]
+struct Adler32 {
+    a: u32,
+    b: u32,
+}
+
+impl Default for Adler32 {
+    fn default() -> Self {
+        Self { a: 1, b: 0 }
+    }
+}
+
+impl Adler32 {
+    const MOD: u32 = 65521;
+    /// Bytes summed before sums could overflow
+    const BLOCK: usize = 5552;
+
+    fn update(&mut self, data: &[u8]) {
+        for block in data.chunks(Self::BLOCK) {
+            for &x in block {
+                self.a += x as u32;
+                self.b += self.a;
+            }
+            self.a %= Self::MOD;
+            self.b %= Self::MOD;
+        }
+    }
+
+    fn finish(self) -> u32 {
+        self.b << 16 | self.a
+    }
+}
+
+/// Writer computing checksum of zlib or gzip trailer over written data
+struct Summing<W> {
+    inner: W,
+    crc: crc32fast::Hasher,
+    adler: Adler32,
+    gzip: bool,
+}
+
+impl<W: Write> Write for Summing<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        match self.gzip {
+            true => self.crc.update(&buf[..written]),
+            false => self.adler.update(&buf[..written]),
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Chunk position stored in chunk NBT.
+/// Chunks before 1.18 keep it inside `Level` compound.
+pub fn nbt_position(root: &Compound) -> Option<(i32, i32)> {
+    let level = root
+        .get("Level")
+        .and_then(Tag::as_compound)
+        .unwrap_or(root);
+
+    let x = level.get("xPos")?.as_i64()?;
+    let z = level.get("zPos")?.as_i64()?;
+    Some((x as i32, z as i32))
+}
+
+pub fn data_version(root: &Compound) -> Option<i32> {
+    root.get("DataVersion")?.as_i64().map(|x| x as i32)
+}
+
+/// Ticks players spent in chunk, summed over all players.
+/// Chunks before 1.18 keep it inside `Level` compound.
+pub fn inhabited_time(root: &Compound) -> Option<i64> {
+    let level = root
+        .get("Level")
+        .and_then(Tag::as_compound)
+        .unwrap_or(root);
+
+    level.get("InhabitedTime")?.as_i64()
+}
+
+/// Fails if chunk is older than `min`.
+/// Chunks without DataVersion predate 1.9 and considered older than anything.
+pub fn require_min_data_version(root: &Compound, min: i32) -> anyhow::Result<()> {
+    match data_version(root) {
+        Some(version) => ensure!(version >= min, "DataVersion {version} is lower than required {min}"),
+        None => bail!("No DataVersion in chunk, but at least {min} is required"),
+    }
+    Ok(())
+}
+
+/// Fails if chunk is newer than `max`, a game version loading it would silently downgrade it.
+/// Chunks without DataVersion are accepted as older than anything.
+pub fn require_max_data_version(root: &Compound, max: i32) -> anyhow::Result<()> {
+    if let Some(version) = data_version(root) {
+        ensure!(version <= max, "DataVersion {version} is newer than target {max}");
+    }
+    Ok(())
+}
+
+/// Overwrites position stored in chunk NBT. Returns `false` if chunk has no position tags
+pub fn set_nbt_position(root: &mut Compound, x: i32, z: i32) -> bool {
+    let level = if root.get("Level").is_some_and(|x| x.as_compound().is_some()) {
+        root.get_mut("Level").and_then(Tag::as_compound_mut).unwrap()
+    } else {
+        root
+    };
+
+    if level.get("xPos").is_none() || level.get("zPos").is_none() {
+        return false;
+    }
+
+    level.insert("xPos", Tag::Int(x));
+    level.insert("zPos", Tag::Int(z));
+    true
+}
+
+/// Sections of 1.18+ chunk
+pub fn sections(root: &Compound) -> &[Tag] {
+    root.get("sections")
+        .and_then(Tag::as_list)
+        .unwrap_or_default()
+}
+
+/// Unpacks palette indices stored in long array as since 1.16: entries do not span across longs.
+/// Missing data means that whole container has a single palette entry.
+pub fn unpack_palette_indices(data: Option<&[i64]>, palette_len: usize, count: usize, min_bits: u32) -> Vec<usize> {
+    let Some(data) = data.filter(|_| palette_len > 1) else {
+        return vec![0; count];
+    };
+
+    let bits = (usize::BITS - (palette_len - 1).leading_zeros()).max(min_bits);
+    let per_long = (64 / bits) as usize;
+    let mask = (1u64 << bits) - 1;
+
+    data.iter()
+        .flat_map(|&x| (0..per_long).map(move |i| ((x as u64 >> (i as u32 * bits)) & mask) as usize))
+        .take(count)
+        .collect()
+}
+
+/// Packs palette indices the way [`unpack_palette_indices`] reads them
+pub fn pack_palette_indices(indices: &[usize], palette_len: usize, min_bits: u32) -> Vec<i64> {
+    let bits = (usize::BITS - palette_len.saturating_sub(1).leading_zeros()).max(min_bits);
+    let per_long = (64 / bits) as usize;
+
+    indices
+        .chunks(per_long)
+        .map(|x| x.iter().enumerate().fold(0u64, |long, (i, &index)| long | (index as u64) << (i as u32 * bits)) as i64)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use zerocopy::IntoBytes;
+
+    use super::*;
+
+    struct Reverse;
+
+    impl ChunkCodec for Reverse {
+        fn decompress(&self, data: &[u8], writer: &mut dyn Write, _: u64) -> anyhow::Result<usize> {
+            writer.write_all(&data.iter().rev().copied().collect::<Vec<_>>())?;
+            Ok(data.len())
+        }
+
+        fn compress(&self, data: &[u8], writer: &mut dyn Write) -> anyhow::Result<()> {
+            self.decompress(data, writer, u64::MAX).map(|_| ())
+        }
+    }
+
+    #[test]
+    fn custom_compression() {
+        let mut raw = vec![0, 0, 0, 0, 127, 0, 12];
+        raw.extend_from_slice(b"test:reverse");
+        raw.extend_from_slice(b"olleh");
+        raw.splice(..4, (raw.len() as u32 - 4).to_be_bytes());
+
+        let chunk = ChunkData::try_ref_from_bytes(&raw).unwrap();
+        assert_eq!(chunk.custom_algorithm().unwrap(), Some("test:reverse"));
+
+        let mut out = vec![];
+        let err = chunk.decompress(&mut out).unwrap_err();
+        assert_eq!(err.to_string(), "No codec registered for custom compression test:reverse");
+
+        CodecRegistry::register_custom("test:reverse", Box::new(Reverse));
+        chunk.decompress(&mut out).unwrap();
+        assert_eq!(out, b"hello");
+    }
+
+    #[test]
+    fn decompress_into_limit() {
+        let mut raw = vec![0, 0, 0, 6, 3];
+        raw.extend_from_slice(b"hello");
+        // Parsed at an odd offset
+        let unaligned = [&[0][..], &raw].concat();
+        let chunk = ChunkData::try_ref_from_bytes(&unaligned[1..]).unwrap();
+        assert_eq!(chunk.size_hint(), Some(5));
+
+        let mut out = b"prefix".to_vec();
+        assert!(matches!(chunk.decompress_into(&mut out, 4), Err(DecompressError::TooLarge { max: 4 })));
+        assert_eq!(out, b"prefix");
+
+        assert_eq!(chunk.decompress_into(&mut out, 5).unwrap(), 5);
+        assert_eq!(out, b"prefixhello");
+    }
+
+    #[test]
+    fn registered_codec_round_trip() {
+        assert!(CodecRegistry::register(127, Box::new(Reverse)).is_err());
+        CodecRegistry::register(42, Box::new(Reverse)).unwrap();
+
+        let mut raw = vec![0, 0, 0, 6, 42];
+        Codec::Registered(42).compress(b"hello", &mut raw).unwrap();
+        assert_eq!(&raw[5..], b"olleh");
+
+        let mut out = vec![];
+        ChunkData::try_ref_from_bytes(&raw).unwrap().decompress(&mut out).unwrap();
+        assert_eq!(out, b"hello");
+    }
+
+    #[test]
+    fn checksum_mismatch_keeps_data() {
+        let data = b"hello hello hello".repeat(100);
+        for (codec, check) in [(Codec::Zlib, "Adler-32"), (Codec::GZip, "CRC-32")] {
+            let mut compressed = vec![];
+            codec.compress(&data, &mut compressed).unwrap();
+            let mut out = vec![];
+            codec.decompress_with_limit(&compressed, &mut out, 1 << 20).unwrap();
+            assert_eq!(out, data);
+
+            // First byte of the checksum in trailer
+            let at = compressed.len() - if codec == Codec::GZip { 8 } else { 4 };
+            compressed[at] ^= 1;
+            out.clear();
+            let err = codec.decompress_with_limit(&compressed, &mut out, 1 << 20).unwrap_err();
+            assert_eq!(err.downcast_ref::<ChecksumMismatch>().unwrap().check, check);
+            assert_eq!(out, data);
+
+            compressed.truncate(at);
+            let err = codec.decompress_with_limit(&compressed, &mut vec![], 1 << 20).unwrap_err();
+            assert_eq!(err.downcast_ref::<ChecksumMismatch>().unwrap().stored, None);
+        }
+    }
+
+    #[test]
+    fn sniff_mislabeled_codec() {
+        let data = b"hello hello hello".repeat(100);
+        // Zlib data marked as gzip
+        let mut raw = vec![0, 0, 0, 0, CompressionType::GZip as u8];
+        Codec::Zlib.compress(&data, &mut raw).unwrap();
+        raw.splice(..4, (raw.len() as u32 - 4).to_be_bytes());
+
+        let chunk = ChunkData::try_ref_from_bytes(&raw).unwrap();
+        let mut out = vec![];
+        assert!(chunk.decompress(&mut out).is_err());
+        assert_eq!(chunk.sniff_codec(&mut out, 1 << 20), Some(Codec::Zlib));
+        assert_eq!(out, data);
+
+        // Garbage fits no codec
+        raw.truncate(8);
+        raw.splice(..4, 4u32.to_be_bytes());
+        assert_eq!(ChunkData::try_ref_from_bytes(&raw).unwrap().sniff_codec(&mut out, 1 << 20), None);
+        assert!(out.is_empty());
+    }
 }
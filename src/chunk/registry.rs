@@ -0,0 +1,63 @@
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{bail, ensure};
+
+use super::{ChunkData, CompressionType};
+
+/// Compression of chunk data added by library users, e.g. brotli used by some proxies
+pub trait ChunkCodec: Send + Sync {
+    /// Decompresses `data` into writer, returns number of bytes written.
+    /// Should stop soon after writing more than `limit` bytes, the result is rejected anyway
+    fn decompress(&self, data: &[u8], writer: &mut dyn Write, limit: u64) -> anyhow::Result<usize>;
+
+    /// Compresses `data` into writer. Codecs which can only read chunks keep the default
+    fn compress(&self, data: &[u8], writer: &mut dyn Write) -> anyhow::Result<()> {
+        let _ = (data, writer);
+        bail!("Codec does not support compression")
+    }
+}
+
+struct Registry {
+    by_id: BTreeMap<u8, Arc<dyn ChunkCodec>>,
+    /// Type 127 chunks by algorithm name
+    custom: BTreeMap<String, Arc<dyn ChunkCodec>>,
+}
+
+static REGISTRY: RwLock<Registry> = RwLock::new(Registry {
+    by_id: BTreeMap::new(),
+    custom: BTreeMap::new(),
+});
+
+/// Process-wide codecs used by [`ChunkData::decompress`] and [`crate::region::RegionWriter`] next to the built-in ones.
+/// Write chunks with a registered codec through [`super::Codec::Registered`].
+pub struct CodecRegistry;
+
+impl CodecRegistry {
+    /// Registers codec for compression type `id`. Replaces built-in codec of the same id
+    pub fn register(id: u8, codec: Box<dyn ChunkCodec>) -> anyhow::Result<()> {
+        ensure!(id & ChunkData::EXTERNAL_FLAG == 0, "Compression type {id} collides with external chunk flag");
+        ensure!(
+            id != CompressionType::Custom as u8,
+            "Compression type {id} is reserved for named algorithms, use CodecRegistry::register_custom"
+        );
+        REGISTRY.write().unwrap().by_id.insert(id, codec.into());
+        Ok(())
+    }
+
+    /// Registers codec for type 127 chunks naming `algorithm`, like `mymod:brotli`
+    pub fn register_custom(algorithm: impl Into<String>, codec: Box<dyn ChunkCodec>) {
+        REGISTRY.write().unwrap().custom.insert(algorithm.into(), codec.into());
+    }
+
+    pub fn get(id: u8) -> Option<Arc<dyn ChunkCodec>> {
+        REGISTRY.read().unwrap().by_id.get(&id).cloned()
+    }
+
+    pub fn get_custom(algorithm: &str) -> Option<Arc<dyn ChunkCodec>> {
+        REGISTRY.read().unwrap().custom.get(algorithm).cloned()
+    }
+}
@@ -0,0 +1,279 @@
+//! Rotating and mirroring chunks within their region, for assembling worlds from parts.
+//!
+//! Blocks, biomes, block entities, scheduled ticks and post-processing positions move with the chunk, and block
+//! state properties naming directions (`facing`, `axis`, `rotation`, connections, left and right handedness)
+//! turn with it. Heightmaps and light are dropped for the game to compute again. Structure references and
+//! entities, kept outside of chunks since 1.17, are left alone. Only chunks of 1.18 or later are understood.
+
+use anyhow::{ensure, Context};
+
+use super::{nbt_position, pack_palette_indices, set_nbt_position, unpack_palette_indices};
+use crate::nbt::{Compound, Tag};
+
+/// Horizontal directions in clockwise order seen from above
+const CLOCKWISE: [&str; 4] = ["north", "east", "south", "west"];
+
+/// Mirroring followed by clockwise rotation seen from above, about the center of the region
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Transform {
+    /// Clockwise quarter turns
+    pub quarter_turns: u8,
+    /// Swap east and west
+    pub mirror_x: bool,
+    /// Swap north and south
+    pub mirror_z: bool,
+}
+
+impl Transform {
+    /// Position `x,z` in a square of `size` positions per side, like a chunk in region or a block in section
+    pub fn apply_local(&self, (x, z): (i32, i32), size: i32) -> (i32, i32) {
+        let (mut x, mut z) = (x, z);
+        if self.mirror_x {
+            x = size - 1 - x;
+        }
+        if self.mirror_z {
+            z = size - 1 - z;
+        }
+        for _ in 0..self.quarter_turns % 4 {
+            (x, z) = (size - 1 - z, x);
+        }
+        (x, z)
+    }
+
+    /// Left and right swap when mirrored along exactly one axis
+    fn mirrors(&self) -> bool {
+        self.mirror_x != self.mirror_z
+    }
+
+    fn direction(&self, name: &str) -> Option<&'static str> {
+        let mut i = CLOCKWISE.iter().position(|x| *x == name)?;
+        if self.mirror_x && i % 2 == 1 {
+            i = 4 - i;
+        }
+        if self.mirror_z && i % 2 == 0 {
+            i = (i + 2) % 4;
+        }
+        Some(CLOCKWISE[(i + self.quarter_turns as usize) % 4])
+    }
+
+    /// Property value made of `_`-separated words like `north`, `inner_left` or `south_east` of rails
+    fn words(&self, value: &str) -> String {
+        let mut words = value
+            .split('_')
+            .map(|x| match x {
+                "left" if self.mirrors() => "right",
+                "right" if self.mirrors() => "left",
+                x => self.direction(x).unwrap_or(x),
+            })
+            .collect::<Vec<_>>();
+        // Two directions are named north or south first, and north before south, east before west
+        if let [a, b] = &mut words[..] {
+            let rank = |x: &str| ["north", "south", "east", "west"].iter().position(|y| *y == x);
+            if let (Some(i), Some(j)) = (rank(a), rank(b)) {
+                if i > j {
+                    std::mem::swap(a, b);
+                }
+            }
+        }
+        words.join("_")
+    }
+
+    /// Turns properties of block state compound with `Name` and `Properties`
+    pub fn block_state(&self, state: &mut Compound) {
+        let Some(properties) = state.get_mut("Properties").and_then(Tag::as_compound_mut) else {
+            return;
+        };
+        for (name, value) in &mut properties.0 {
+            let Tag::String(value) = value else { continue };
+            match name.as_str() {
+                "axis" if self.quarter_turns % 2 == 1 => {
+                    *value = match value.as_str() {
+                        "x" => "z".into(),
+                        "z" => "x".into(),
+                        x => x.into(),
+                    };
+                },
+                // Sixteenths of a turn clockwise from south, signs and banners
+                "rotation" => {
+                    let Ok(mut rotation) = value.parse::<i32>() else { continue };
+                    if self.mirror_x {
+                        rotation = 16 - rotation;
+                    }
+                    if self.mirror_z {
+                        rotation = 8 - rotation;
+                    }
+                    *value = (rotation + 4 * self.quarter_turns as i32).rem_euclid(16).to_string();
+                },
+                _ => *value = self.words(value),
+            }
+            // Connections of fences, walls or redstone are named by direction
+            if let Some(direction) = self.direction(name) {
+                *name = direction.into();
+            }
+        }
+    }
+
+    /// Moves blocks of section and turns their states. Light is dropped, it would be wrong
+    fn section(&self, section: &mut Compound) {
+        if let Some(states) = section.get_mut("block_states").and_then(Tag::as_compound_mut) {
+            if let Some(Tag::List(_, palette)) = states.get_mut("palette") {
+                palette.iter_mut().filter_map(Tag::as_compound_mut).for_each(|x| self.block_state(x));
+            }
+            self.container(states, 16, 4);
+        }
+        if let Some(biomes) = section.get_mut("biomes").and_then(Tag::as_compound_mut) {
+            self.container(biomes, 4, 1);
+        }
+        section.remove("BlockLight");
+        section.remove("SkyLight");
+    }
+
+    /// Moves entries of paletted container of `side`³ entries in YZX order
+    fn container(&self, container: &mut Compound, side: usize, min_bits: u32) {
+        let palette_len = container.get("palette").and_then(Tag::as_list).map_or(0, <[Tag]>::len);
+        // Containers with a single palette entry have no data
+        let Some(Tag::LongArray(data)) = container.get_mut("data") else {
+            return;
+        };
+        let count = side * side * side;
+        let indices = unpack_palette_indices(Some(data), palette_len, count, min_bits);
+        if indices.len() != count {
+            return;
+        }
+
+        let mut moved = vec![0; count];
+        for (i, index) in indices.into_iter().enumerate() {
+            let (x, y, z) = (i % side, i / (side * side), i / side % side);
+            let (x, z) = self.apply_local((x as i32, z as i32), side as i32);
+            moved[(y * side + z as usize) * side + x as usize] = index;
+        }
+        *data = pack_palette_indices(&moved, palette_len, min_bits);
+    }
+
+    /// Moves absolute block position inside region
+    fn block(&self, (x, z): (i32, i32), region: (i32, i32)) -> (i32, i32) {
+        let origin = (region.0 * 512, region.1 * 512);
+        let (x, z) = self.apply_local((x - origin.0, z - origin.1), 512);
+        (origin.0 + x, origin.1 + z)
+    }
+
+    /// Moves chunk of region `region` to its place in the transformed region and turns its contents.
+    /// Returns new chunk position
+    pub fn chunk(&self, root: &mut Compound, region: (i32, i32)) -> anyhow::Result<(i32, i32)> {
+        ensure!(
+            root.get("Level").is_none() && root.get("sections").is_some(),
+            "Only chunks of 1.18 or later can be transformed"
+        );
+        let (x, z) = nbt_position(root).context("Chunk has no position")?;
+        let local = (x - region.0 * 32, z - region.1 * 32);
+        ensure!(
+            (0..32).contains(&local.0) && (0..32).contains(&local.1),
+            "Chunk {x},{z} is outside of region {},{}",
+            region.0,
+            region.1
+        );
+        let (x, z) = self.apply_local(local, 32);
+        let (x, z) = (region.0 * 32 + x, region.1 * 32 + z);
+        set_nbt_position(root, x, z);
+
+        if let Some(Tag::List(_, sections)) = root.get_mut("sections") {
+            sections.iter_mut().filter_map(Tag::as_compound_mut).for_each(|x| self.section(x));
+        }
+        for name in ["block_entities", "block_ticks", "fluid_ticks"] {
+            let Some(Tag::List(_, list)) = root.get_mut(name) else { continue };
+            for entry in list.iter_mut().filter_map(Tag::as_compound_mut) {
+                let coord = |name| entry.get(name).and_then(Tag::as_i64).map(|x| x as i32);
+                let (Some(bx), Some(bz)) = (coord("x"), coord("z")) else { continue };
+                let (bx, bz) = self.block((bx, bz), region);
+                entry.insert("x", Tag::Int(bx));
+                entry.insert("z", Tag::Int(bz));
+            }
+        }
+        // Lists of positions packed as x | z << 4 | y << 8 per section
+        if let Some(Tag::List(_, sections)) = root.get_mut("PostProcessing") {
+            for section in sections {
+                let Tag::List(_, positions) = section else { continue };
+                for position in positions {
+                    let Tag::Short(packed) = position else { continue };
+                    let (x, z) = self.apply_local((*packed as i32 & 15, *packed as i32 >> 4 & 15), 16);
+                    *packed = (*packed & !0xFF) | (x | z << 4) as i16;
+                }
+            }
+        }
+
+        root.remove("Heightmaps");
+        if root.get("isLightOn").is_some() {
+            root.insert("isLightOn", Tag::Byte(0));
+        }
+        Ok((x, z))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(name: &str, properties: &[(&str, &str)]) -> Tag {
+        let properties = properties.iter().map(|&(k, v)| (k.into(), Tag::String(v.into()))).collect();
+        Tag::Compound(Compound(vec![
+            ("Name".into(), Tag::String(name.into())),
+            ("Properties".into(), Tag::Compound(Compound(properties))),
+        ]))
+    }
+
+    #[test]
+    fn rotated_chunk() {
+        let air = state("minecraft:air", &[]);
+        let stairs = state("minecraft:oak_stairs", &[("facing", "north"), ("shape", "inner_left")]);
+        let fence = state("minecraft:oak_fence", &[("north", "true"), ("east", "false")]);
+        // Stairs at block 1,0,0 of the section, everything else air
+        let mut indices = vec![0; 4096];
+        indices[1] = 1;
+        let section = Compound(vec![
+            ("Y".into(), Tag::Byte(0)),
+            (
+                "block_states".into(),
+                Tag::Compound(Compound(vec![
+                    ("palette".into(), Tag::List(10, vec![air, stairs, fence])),
+                    ("data".into(), Tag::LongArray(pack_palette_indices(&indices, 3, 4))),
+                ])),
+            ),
+        ]);
+        let mut root = Compound(vec![
+            ("xPos".into(), Tag::Int(-32)),
+            ("zPos".into(), Tag::Int(0)),
+            ("sections".into(), Tag::List(10, vec![Tag::Compound(section)])),
+            ("block_entities".into(), Tag::List(10, vec![Tag::Compound(Compound(vec![
+                ("x".into(), Tag::Int(-511)),
+                ("y".into(), Tag::Int(0)),
+                ("z".into(), Tag::Int(0)),
+            ]))])),
+        ]);
+
+        let transform = Transform { quarter_turns: 1, ..Default::default() };
+        assert_eq!(transform.chunk(&mut root, (-1, 0)).unwrap(), (-1, 0));
+
+        let entity = &root.get("block_entities").unwrap().as_list().unwrap()[0];
+        let coord = |name| entity.as_compound().unwrap().get(name).and_then(Tag::as_i64);
+        assert_eq!((coord("x"), coord("z")), (Some(-1), Some(1)));
+
+        let section = root.get("sections").unwrap().as_list().unwrap()[0].as_compound().unwrap();
+        let states = section.get("block_states").unwrap().as_compound().unwrap();
+        let Some(Tag::LongArray(data)) = states.get("data") else { panic!() };
+        let indices = unpack_palette_indices(Some(data), 3, 4096, 4);
+        assert_eq!(indices.iter().position(|&x| x == 1), Some(15 + 16));
+
+        let palette = states.get("palette").unwrap().as_list().unwrap();
+        assert_eq!(palette[1], state("minecraft:oak_stairs", &[("facing", "east"), ("shape", "inner_left")]));
+        assert_eq!(palette[2], state("minecraft:oak_fence", &[("east", "true"), ("south", "false")]));
+
+        let mirror = Transform { mirror_x: true, ..Default::default() };
+        assert_eq!(mirror.words("inner_left"), "inner_right");
+        assert_eq!(mirror.words("south_east"), "south_west");
+        assert_eq!(transform.words("south_east"), "south_west");
+        assert_eq!(transform.words("north_south"), "east_west");
+
+        let mut root = Compound(vec![("Level".into(), Tag::Compound(Compound::default()))]);
+        assert!(transform.chunk(&mut root, (0, 0)).is_err());
+    }
+}
@@ -0,0 +1,250 @@
+//! Tar and zip archives of worlds, like backups made before this tool, compacted as if they were the directory
+//! they hold. Entries are read in archive order, region files are handed to worker threads as they come,
+//! so nothing is extracted to disk and at most a few region files are held in memory.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    path::{Component, Path, PathBuf},
+    sync::{mpsc, Mutex},
+};
+
+use anyhow::Context;
+
+use crate::{
+    batch::{self, FileKind, Job, Outcome},
+    error::{self, ErrorCode},
+    manifest, region, rpack,
+    session::{self, Operation, Session},
+    timings, Cancelled, CompactOptions,
+};
+
+/// File names of archives read as input directories
+const EXTENSIONS: [&str; 6] = [".tar", ".tar.gz", ".tgz", ".tar.zst", ".tzst", ".zip"];
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// File named like a tar or zip archive, compressed tar archives included
+pub fn is_container(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy().to_lowercase();
+    EXTENSIONS.iter().any(|x| name.ends_with(x))
+}
+
+/// Path of entry relative to the archive root. Entries leaving it, like `../x` or `/x`, get `None`
+fn entry_path(path: &Path) -> Option<PathBuf> {
+    let mut parts = PathBuf::new();
+    for part in path.components() {
+        match part {
+            Component::Normal(x) => parts.push(x),
+            Component::CurDir => {},
+            _ => return None,
+        }
+    }
+    (!parts.as_os_str().is_empty()).then_some(parts)
+}
+
+/// Calls `f` with path and size of every regular file in tar or zip archive at `path`, and a reader of its
+/// content. Tar archives may be gzip or zstd compressed, told by their magic
+fn for_each_file(path: &Path, mut f: impl FnMut(PathBuf, u64, &mut dyn Read) -> anyhow::Result<()>) -> anyhow::Result<()> {
+    let file = File::open(path).with_context(|| format!("Unable to open {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    let unsafe_entry = |name: &dyn std::fmt::Display| eprintln!("Skipping {name}: path leaves the archive");
+
+    if path.extension().is_some_and(|x| x.eq_ignore_ascii_case("zip")) {
+        let mut archive = zip::ZipArchive::new(reader).with_context(|| format!("{} is not a zip archive", path.display()))?;
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i)?;
+            if !entry.is_file() {
+                continue;
+            }
+            match entry.enclosed_name().as_deref().and_then(entry_path) {
+                Some(name) => f(name, entry.size(), &mut entry)?,
+                None => unsafe_entry(&entry.name().to_owned()),
+            }
+        }
+        return Ok(());
+    }
+
+    let magic = reader.fill_buf()?;
+    let decoder: Box<dyn Read> = if region::is_gzipped(magic) {
+        Box::new(flate2::bufread::MultiGzDecoder::new(reader))
+    } else if magic.starts_with(&ZSTD_MAGIC) {
+        Box::new(zstd::Decoder::with_buffer(reader)?)
+    } else {
+        Box::new(reader)
+    };
+    let mut archive = tar::Archive::new(decoder);
+    for entry in archive.entries().with_context(|| format!("{} is not a tar archive", path.display()))? {
+        let mut entry = entry.with_context(|| format!("Unable to read {}", path.display()))?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = entry.path()?.into_owned();
+        match entry_path(&name) {
+            Some(name) => f(name, entry.size(), &mut entry)?,
+            None => unsafe_entry(&name.display()),
+        }
+    }
+    Ok(())
+}
+
+/// Writes archive of region file data as job output, checked like [`crate::compact_file`] does
+fn compact_region(job: &Job, data: Vec<u8>, options: &CompactOptions) -> anyhow::Result<()> {
+    // Region files gzipped as a whole are unpacked on the fly
+    let data = match region::is_gzipped(&data) {
+        true => {
+            let mut unpacked = vec![];
+            flate2::read::MultiGzDecoder::new(&data[..])
+                .read_to_end(&mut unpacked)
+                .with_context(|| format!("Unable to unpack {}", job.input.display()))?;
+            unpacked
+        },
+        false => data,
+    };
+
+    let file = File::create(&job.output).with_context(|| format!("Unable to create {}", job.output.display()))?;
+    let mut writer = std::io::BufWriter::new(timings::Timed::new(file, timings::Phase::Write));
+    crate::print_encoded(&rpack::encode_region(&data[..], &mut writer, &options.encode)?);
+    writer.into_inner().map_err(|e| e.into_error())?;
+
+    if options.encode.rpack.compression != rpack::Compression::None {
+        let size = std::fs::metadata(&job.output)?.len();
+        let ratio = options.encode.ratio;
+        ratio
+            .check(size as f64 / data.len().max(1) as f64, ratio.archive, || {
+                format!("{}: archive of {size} bytes from region file of {} bytes, check codec settings", job.output.display(), data.len())
+            })?
+            .inspect(|warning| eprintln!("{warning}"));
+    }
+    crate::sync_output(&job.output, options.fsync)?;
+    if options.verify {
+        crate::verify_archive(&data[..], &job.output, &options.encode)
+            .map_err(|e| ErrorCode::VerifyFailed.wrap(e, "Archive verification failed"))?;
+    }
+    options.encode.audit.as_ref().map_or(Ok(()), crate::audit::AuditLog::commit)
+}
+
+/// Compacts region files of archive `session.input` into a backup directory at `session.output`, with a manifest
+/// like directory mode writes. Other files are copied as they are with `--passthrough`
+pub fn compact(session: &Session) -> anyhow::Result<batch::Report> {
+    let Operation::Compact(options) = &session.operation else {
+        anyhow::bail!("Archives of worlds can only be compacted, restore their backup directory instead")
+    };
+    let (target, output) = &session::stage(&session.output)?;
+    let providers = region::providers();
+
+    let (sender, receiver) = mpsc::sync_channel::<(Job, Vec<u8>)>(session.threads.get());
+    let receiver = Mutex::new(receiver);
+    let results = Mutex::new(vec![]);
+    let entries = Mutex::new(vec![]);
+    let finished = |job: Job, result: anyhow::Result<manifest::Entry>| {
+        let outcome = match result {
+            Ok(entry) => {
+                entries.lock().unwrap().push(entry);
+                Outcome::Succeeded
+            },
+            Err(e) if e.is::<Cancelled>() => Outcome::Skipped,
+            Err(e) => {
+                crate::cancel_on_failure(&e, session.fail_fast, &options.encode.cancel);
+                Outcome::Failed(error::with_code(e))
+            },
+        };
+        let mut results = results.lock().unwrap();
+        if session.progress {
+            let status = match outcome {
+                Outcome::Succeeded => "ok",
+                Outcome::Failed(_) => "failed",
+                Outcome::Skipped => "skipped",
+            };
+            eprintln!("[{}] {status} {}", results.len() + 1, job.input.display());
+        }
+        results.push((job, outcome));
+    };
+
+    let read = std::thread::scope(|scope| {
+        for _ in 0..session.threads.get() {
+            scope.spawn(|| loop {
+                let Ok((job, data)) = receiver.lock().unwrap().recv() else { break };
+                let result = options.encode.cancel.check().and_then(|_| {
+                    let encode = rpack::EncodeOptions {
+                        region: region::region_coords_from_path(region::gunzipped_path(&job.input)),
+                        format: (session.region_format)(&job.input)?,
+                        audit: options.encode.audit.as_ref().map(|x| x.for_file(&job.input)),
+                        ..options.encode.clone()
+                    };
+                    let options = CompactOptions { encode, ..options.clone() };
+                    crate::check_compact_options(&options.encode)?;
+                    compact_region(&job, data, &options)
+                        .inspect_err(|_| {
+                            std::fs::remove_file(&job.output).ok();
+                        })
+                        .and_then(|_| manifest::entry(Path::new(""), output, &job, false))
+                });
+                finished(job, result);
+            });
+        }
+
+        let result = for_each_file(&session.input, |path, size, reader| {
+            options.encode.cancel.check()?;
+            let kind = FileKind::of(&path, &providers);
+            let relative = path.components().map(|x| x.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/");
+            if kind != FileKind::Region && !session.passthrough.copies(&relative) {
+                return Ok(());
+            }
+            let job = Job {
+                output: match kind {
+                    FileKind::Region => output.join(path.with_file_name(batch::archive_name(path.file_name().unwrap()))),
+                    _ => output.join(&path),
+                },
+                input: path,
+                size,
+            };
+            if let Some(dir) = job.output.parent() {
+                std::fs::create_dir_all(dir).with_context(|| format!("Unable to create {}", dir.display()))?;
+            }
+
+            let mut data = vec![];
+            timings::Timed::new(reader, timings::Phase::Read)
+                .read_to_end(&mut data)
+                .with_context(|| format!("Unable to read {} from {}", job.input.display(), session.input.display()))?;
+            match kind {
+                FileKind::Region => sender.send((job, data)).context("Compaction stopped")?,
+                _ => {
+                    let result = std::fs::write(&job.output, data)
+                        .with_context(|| format!("Unable to write {}", job.output.display()))
+                        .and_then(|_| manifest::entry(Path::new(""), output, &job, true));
+                    finished(job, result);
+                },
+            }
+            Ok(())
+        });
+        // Workers stop once every region file sent is done
+        drop(sender);
+        result
+    });
+    // Reading stopped by a failed file with fail-fast is no error of the archive
+    match read {
+        Err(e) if e.is::<Cancelled>() => {},
+        result => result?,
+    }
+
+    manifest::write(output, entries.into_inner().unwrap())?;
+    let report = batch::Report { results: results.into_inner().unwrap(), fail_fast: session.fail_fast };
+    session::finish(output, target, &report, options.fsync)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_paths_stay_inside() {
+        assert_eq!(entry_path(Path::new("./world/region/r.0.0.mca")), Some(PathBuf::from("world/region/r.0.0.mca")));
+        assert_eq!(entry_path(Path::new("../r.0.0.mca")), None);
+        assert_eq!(entry_path(Path::new("/etc/passwd")), None);
+        assert!(is_container(Path::new("backup-2021.tar.zst")));
+        assert!(is_container(Path::new("World.ZIP")));
+        assert!(!is_container(Path::new("r.0.0.mca.gz")));
+    }
+}
@@ -0,0 +1,103 @@
+//! Rewriting region files without gaps left by removed or shrunk chunks
+
+use std::{
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use crate::{
+    region::{RegionReader, RegionWriter},
+    sync_output, Fsync,
+};
+
+#[derive(Debug, clap::Args)]
+pub struct DefragArgs {
+    /// Region file, replaced by its defragmented copy
+    #[arg(short, long)]
+    pub input: PathBuf,
+
+    /// Keep the original file as `<INPUT>.bak`
+    #[arg(long)]
+    pub keep_backup: bool,
+}
+
+/// Path with suffix appended to file name
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+/// Rewrites region file with its chunks back to back in file order, keeping slots, timestamps and compression.
+/// Returns file sizes before and after. Chunks read from external `.mcc` files move
+/// into the region file unless they are too large for it.
+///
+/// The copy is written next to the file and synced before it replaces the file by rename, so after a crash
+/// either the old or the new file is there in full. With `backup` the old one stays as `<path>.bak`
+pub fn rewrite(path: &Path, backup: bool) -> anyhow::Result<(u64, u64)> {
+    let before = std::fs::metadata(path).with_context(|| format!("Unable to open {}", path.display()))?.len();
+    let mut reader = RegionReader::open(path)?;
+    let mut data = Cursor::new(vec![]);
+    // Chunks too large for the region file go back to external files
+    let mut writer = RegionWriter::new(&mut data)?.with_external_chunks(path);
+    reader
+        .read_all_raw(|info, pos, chunk| writer.write_raw_chunk(pos, info.timestamp.get(), chunk))
+        .with_context(|| format!("Unable to defragment {}", path.display()))?;
+    let after = writer.finish()?;
+
+    let temp = with_suffix(path, ".tmp");
+    std::fs::write(&temp, data.get_ref())
+        .with_context(|| format!("Unable to write {}", temp.display()))
+        .and_then(|_| sync_output(&temp, Fsync::File))
+        .inspect_err(|_| {
+            std::fs::remove_file(&temp).ok();
+        })?;
+
+    if backup {
+        // A second name of the original survives the rename, copied where links are not supported
+        let backup = with_suffix(path, ".bak");
+        std::fs::remove_file(&backup).ok();
+        std::fs::hard_link(path, &backup)
+            .or_else(|_| std::fs::copy(path, &backup).and_then(|_| std::fs::File::open(&backup)?.sync_all()))
+            .with_context(|| format!("Unable to keep backup {}", backup.display()))
+            .inspect_err(|_| {
+                std::fs::remove_file(&temp).ok();
+            })?;
+    }
+    std::fs::rename(&temp, path).with_context(|| format!("Unable to replace {}", path.display()))?;
+    sync_output(path, Fsync::Dir)?;
+    Ok((before, after))
+}
+
+pub fn run(args: DefragArgs) -> anyhow::Result<()> {
+    let (before, after) = rewrite(&args.input, args.keep_backup)?;
+    println!("Defragmented {} from {before} to {after} bytes", args.input.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{chunks, TempDir};
+
+    #[test]
+    fn recovers_interrupted_rewrite() {
+        let dir = TempDir::new("defrag");
+        let path = dir.copy_fixture("basic", "r.0.0.mca");
+        let original = std::fs::read(&path).unwrap();
+
+        // Crash while the copy was written: half a temp file and a stale backup
+        std::fs::write(with_suffix(&path, ".tmp"), &original[..original.len() / 2]).unwrap();
+        std::fs::write(with_suffix(&path, ".bak"), b"stale").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), original);
+
+        let (before, after) = rewrite(&path, true).unwrap();
+        assert_eq!(before, original.len() as u64);
+        assert_eq!(after, std::fs::metadata(&path).unwrap().len());
+        assert!(after < before && !with_suffix(&path, ".tmp").exists());
+        assert_eq!(std::fs::read(with_suffix(&path, ".bak")).unwrap(), original);
+        assert_eq!(chunks(&path), chunks(&with_suffix(&path, ".bak")));
+    }
+}
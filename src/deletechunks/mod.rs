@@ -0,0 +1,187 @@
+//! Removing chunks from region files in place, so the game generates them again
+
+use std::{
+    collections::BTreeSet,
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{ensure, Context};
+
+use crate::{
+    chunk::{self, ChunkData},
+    defrag,
+    region::{self, RegionFile, RegionInfo},
+    rpack, Limits,
+};
+
+/// Chunks of a region file picked on the command line
+#[derive(Debug, clap::Args)]
+pub struct ChunkSelection {
+    /// Chunk coordinates `x,z`. Absolute when the file is named like r.<x>.<z>.mca, local otherwise.
+    /// Give it after other options, everything following it is taken as coordinates to allow negative ones
+    #[arg(long, num_args = 1.., value_parser = crate::parse_coords, allow_hyphen_values = true)]
+    pub chunks: Vec<(i32, i32)>,
+
+    /// Opposite corners `x1,z1 x2,z2` of a box of chunks, inclusive. The box may reach beyond the region
+    #[arg(long = "box", num_args = 2, value_parser = crate::parse_coords, allow_hyphen_values = true)]
+    pub area: Vec<(i32, i32)>,
+}
+
+impl ChunkSelection {
+    /// Header slots of region file at `path`: those listed by `--chunks`, and all picked including boxes
+    pub fn slots(&self, path: &Path) -> anyhow::Result<(BTreeSet<u16>, BTreeSet<u16>)> {
+        ensure!(!self.chunks.is_empty() || !self.area.is_empty(), "Pass chunks with --chunks or --box");
+        let region = region::region_coords_from_path(path);
+        let (min_x, min_z) = RegionInfo::chunk_coords(region, 0);
+        let slot = |x: i32, z: i32| {
+            let (x, z) = (x - min_x, z - min_z);
+            ((0..32).contains(&x) && (0..32).contains(&z)).then(|| (x + z * 32) as u16)
+        };
+
+        let listed = self
+            .chunks
+            .iter()
+            .map(|&(x, z)| slot(x, z).with_context(|| format!("Chunk {x},{z} is not in region {}", path.display())))
+            .collect::<anyhow::Result<BTreeSet<_>>>()?;
+        let mut slots = listed.clone();
+        for corners in self.area.chunks(2) {
+            let (a, b) = (corners[0], corners[1]);
+            // Only the part of the box inside the region is walked
+            for z in a.1.min(b.1).max(min_z)..=a.1.max(b.1).min(min_z + 31) {
+                for x in a.0.min(b.0).max(min_x)..=a.0.max(b.0).min(min_x + 31) {
+                    slots.extend(slot(x, z));
+                }
+            }
+        }
+        Ok((listed, slots))
+    }
+}
+
+#[derive(Debug, clap::Args)]
+pub struct DeleteChunksArgs {
+    /// Region file, edited in place
+    #[arg(short, long)]
+    pub input: PathBuf,
+
+    /// Chunks to delete
+    #[command(flatten)]
+    pub selection: ChunkSelection,
+
+    /// Overwrite sectors no chunk uses anymore with zeros, so deleted chunks can not be recovered from the file
+    #[arg(long, conflicts_with = "defrag")]
+    pub zero: bool,
+
+    /// Rewrite region file without the gaps left by deleted chunks, so it shrinks
+    #[arg(long)]
+    pub defrag: bool,
+
+    /// Archive holding exactly the deleted chunks, written before anything is deleted. `apply-undo` puts them back
+    #[arg(long, value_name = "FILE")]
+    pub undo: Option<PathBuf>,
+}
+
+/// Writes chunks of slots in region file into archive at `path`, external chunks included. Returns chunks written
+fn write_undo(file: &mut RegionFile, input: &Path, slots: &BTreeSet<u16>, path: &Path) -> anyhow::Result<usize> {
+    let region = region::region_coords_from_path(input);
+    let output = std::fs::File::create(path).with_context(|| format!("Unable to create {}", path.display()))?;
+    let mut writer = rpack::RpackWriter::new(BufWriter::new(output), rpack::Options::undo())?;
+
+    let (mut stored, mut nbt) = (vec![], vec![]);
+    let mut count = 0;
+    for &pos in slots {
+        let Some(info) = file.read_stored(pos, &mut stored)? else { continue };
+        let (x, z) = RegionInfo::chunk_coords(region, pos);
+        if stored[0] & ChunkData::EXTERNAL_FLAG != 0 {
+            let external = input.with_file_name(format!("c.{x}.{z}.mcc"));
+            let data = std::fs::read(&external).with_context(|| format!("Unable to read {}", external.display()))?;
+            stored = [&[stored[0] & !ChunkData::EXTERNAL_FLAG], &data[..]].concat();
+        }
+        nbt.clear();
+        chunk::decompress_stored(&stored, &mut nbt, Limits::default().max_decompressed_size)
+            .with_context(|| format!("Chunk {x},{z}"))?;
+        writer.write_chunk(pos, info.timestamp.get(), &nbt)?;
+        count += 1;
+    }
+    let output = writer.finish()?.into_inner().map_err(|e| e.into_error())?;
+    output.sync_all().with_context(|| format!("Unable to sync {}", path.display()))?;
+    Ok(count)
+}
+
+pub fn run(args: DeleteChunksArgs) -> anyhow::Result<()> {
+    let (listed, slots) = args.selection.slots(&args.input)?;
+    let region = region::region_coords_from_path(&args.input);
+
+    let mut file = RegionFile::open(&args.input)?;
+    if let Some(undo) = &args.undo {
+        let count = write_undo(&mut file, &args.input, &slots, undo)?;
+        println!("Wrote {count} chunks to {}", undo.display());
+    }
+    let mut deleted = 0;
+    for pos in slots {
+        let (x, z) = RegionInfo::chunk_coords(region, pos);
+        match file.remove_chunk(pos)? {
+            Some(_) => deleted += 1,
+            None if listed.contains(&pos) => eprintln!("Chunk {x},{z} is not generated"),
+            None => {},
+        }
+
+        // The game keeps chunks too large for region file in c.<x>.<z>.mcc next to it
+        let external = args.input.with_file_name(format!("c.{x}.{z}.mcc"));
+        if region.is_some() && external.is_file() {
+            std::fs::remove_file(&external).with_context(|| format!("Unable to remove {}", external.display()))?;
+        }
+    }
+
+    if args.zero {
+        let zeroed = file.zero_free_sectors()?;
+        println!("Zeroed {zeroed} bytes of free sectors");
+    }
+    drop(file);
+    println!("Deleted {deleted} chunks from {}", args.input.display());
+
+    if args.defrag {
+        let (before, after) = defrag::rewrite(&args.input, false)?;
+        println!("Defragmented from {before} to {after} bytes");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{chunks, TempDir};
+
+    #[test]
+    fn delete_in_place() {
+        let dir = TempDir::new("delete-chunks");
+        let (input, undo) = (dir.copy_fixture("basic", "r.0.0.mca"), dir.join("undo.rpack"));
+        let (original, before) = (std::fs::read(&input).unwrap(), chunks(&input));
+
+        run(DeleteChunksArgs {
+            input: input.clone(),
+            selection: ChunkSelection { chunks: vec![(1, 1)], area: vec![] },
+            zero: false,
+            defrag: false,
+            undo: Some(undo.clone()),
+        })
+        .unwrap();
+
+        // Only location and timestamp entries of slot 33 are cleared, chunk data stays where it was
+        let deleted = std::fs::read(&input).unwrap();
+        let entries = [33 * 4..33 * 4 + 4, 4096 + 33 * 4..4096 + 33 * 4 + 4];
+        for (offset, (a, b)) in original.iter().zip(&deleted).enumerate() {
+            match entries.iter().any(|x| x.contains(&offset)) {
+                true => assert_eq!(*b, 0),
+                false => assert_eq!(a, b, "byte {offset}"),
+            }
+        }
+
+        // Undo archive holds exactly the deleted chunk as it was
+        let mut reader = rpack::RpackReader::new(std::fs::File::open(&undo).unwrap()).unwrap();
+        let mut payload = vec![];
+        let chunk = reader.read_chunk(&mut payload).unwrap().unwrap();
+        assert_eq!((chunk.pos, chunk.timestamp, &payload), (33, before[1].1, &before[1].2));
+        assert!(reader.read_chunk(&mut payload).unwrap().is_none());
+    }
+}
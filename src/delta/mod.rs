@@ -0,0 +1,214 @@
+use std::{
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{ensure, Context};
+
+use crate::{
+    batch,
+    region::{self, RegionReader},
+    rpack::{
+        self,
+        delta::{self, Delta, Snapshot},
+    },
+    Limits,
+};
+
+#[derive(Debug, clap::Args)]
+pub struct ArchiveArgs {
+    /// Compression of chunks in written archives
+    #[arg(long, value_enum, default_value_t = rpack::Compression::None)]
+    pub codec: rpack::Compression,
+
+    /// Compression level
+    #[arg(long, default_value_t = 3)]
+    pub level: i32,
+
+    /// Store CRC32 of every chunk in written archives
+    #[arg(long)]
+    pub checksums: bool,
+}
+
+impl ArchiveArgs {
+    pub fn options(&self) -> rpack::Options {
+        rpack::Options {
+            compression: self.codec,
+            level: self.level,
+            checksums: self.checksums,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, clap::Args)]
+pub struct DeltaArgs {
+    /// Full archive of the older snapshot. With directory --new, a directory of archives made from it
+    #[arg(long)]
+    pub base: PathBuf,
+
+    /// Region file or full archive of the newer snapshot, or a directory of region files
+    #[arg(long)]
+    pub new: PathBuf,
+
+    /// Delta archive, or a directory of them with directory --new
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    #[command(flatten)]
+    pub archive: ArchiveArgs,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct ApplyDeltaArgs {
+    /// Full archive the delta was made against, or a directory of them
+    #[arg(long)]
+    pub base: PathBuf,
+
+    /// Delta archive, or a directory of them
+    #[arg(long)]
+    pub delta: PathBuf,
+
+    /// Full archive of the newer snapshot, or a directory of them
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    #[command(flatten)]
+    pub archive: ArchiveArgs,
+}
+
+/// Reads region file, or full archive if it starts with rpack magic
+pub fn read_snapshot(path: &Path) -> anyhow::Result<Snapshot> {
+    let mut reader = BufReader::new(rpack::volume::open(path)?);
+    let snapshot = match std::io::BufRead::fill_buf(&mut reader)?.starts_with(&rpack::MAGIC) {
+        true => delta::read_snapshot(rpack::RpackReader::from_buf_reader(reader, Limits::default())?, &Limits::default()),
+        false => {
+            let format = region::detect_format(path, &region::providers()).unwrap_or_default();
+            delta::read_region(RegionReader::from_reader_with_format(reader, Limits::default(), format)?.with_external_chunks(path))
+        },
+    };
+    snapshot.with_context(|| format!("Unable to read {}", path.display()))
+}
+
+pub fn read_delta(path: &Path) -> anyhow::Result<Delta> {
+    let reader = rpack::RpackReader::new(rpack::volume::open(path)?)?;
+    delta::read_delta(reader, &Limits::default()).with_context(|| format!("Unable to read {}", path.display()))
+}
+
+fn create_parent(path: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).with_context(|| format!("Unable to create {}", parent.display()))?;
+    }
+    Ok(())
+}
+
+pub fn create(path: &Path) -> anyhow::Result<BufWriter<std::fs::File>> {
+    create_parent(path)?;
+    std::fs::File::create(path)
+        .map(BufWriter::new)
+        .with_context(|| format!("Unable to create {}", path.display()))
+}
+
+fn write_delta(path: &Path, base: &Snapshot, new: &Snapshot, options: &rpack::Options) -> anyhow::Result<()> {
+    let delta = delta::diff(base, new);
+    let added = delta.iter().filter(|(pos, chunk)| chunk.is_some() && !base.contains_key(pos)).count();
+    let removed = delta.values().filter(|chunk| chunk.is_none()).count();
+    eprintln!(
+        "{}: {} changed, {added} added, {removed} removed, {} unchanged",
+        path.display(),
+        delta.len() - added - removed,
+        new.len() + removed - delta.len()
+    );
+
+    delta::write_delta(create(path)?, options.clone(), &delta)?.flush()?;
+    Ok(())
+}
+
+/// Archive path without volume suffix
+fn archive_name(path: &Path) -> PathBuf {
+    rpack::volume::first_volume_base(path).unwrap_or_else(|| path.to_path_buf())
+}
+
+/// Every archive under `dir` relative to it, volumes named by their archive
+pub fn archives(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    Ok(batch::decompact_jobs(dir, dir)?
+        .into_iter()
+        .map(|job| archive_name(job.input.strip_prefix(dir).unwrap()))
+        .collect())
+}
+
+/// Stores chunks added or changed since the base snapshot, and removals of chunks gone since.
+/// With directories, regions without base archive are stored whole and archives without region are removed whole
+pub fn run(args: DeltaArgs) -> anyhow::Result<()> {
+    crate::check_archive_paths(&args.base, &args.output)?;
+    crate::check_paths(&args.new, &args.output, false)?;
+    let options = args.archive.options();
+    if !args.new.is_dir() {
+        let base = read_snapshot(&args.base)?;
+        return write_delta(&args.output, &base, &read_snapshot(&args.new)?, &options);
+    }
+
+    ensure!(args.base.is_dir(), "Base must be a directory of archives when new snapshot is a directory");
+    for job in batch::compact_jobs(&args.new, &args.output)? {
+        let base_path = args.base.join(job.output.strip_prefix(&args.output).unwrap());
+        let base = match base_path.exists() || rpack::volume::volume_path(&base_path, 1).exists() {
+            true => read_snapshot(&base_path)?,
+            false => Snapshot::new(),
+        };
+        write_delta(&job.output, &base, &read_snapshot(&job.input)?, &options)?;
+    }
+
+    for job in batch::decompact_jobs(&args.base, &args.new)? {
+        if !job.output.exists() {
+            let relative = archive_name(job.input.strip_prefix(&args.base).unwrap());
+            write_delta(&args.output.join(relative), &read_snapshot(&job.input)?, &Snapshot::new(), &options)?;
+        }
+    }
+    Ok(())
+}
+
+fn apply_delta(base: Option<&Path>, delta: &Path, output: &Path, options: &rpack::Options) -> anyhow::Result<()> {
+    let mut snapshot = base.map(read_snapshot).transpose()?.unwrap_or_default();
+    delta::apply(&mut snapshot, read_delta(delta)?);
+    delta::write_snapshot(create(output)?, options.clone(), &snapshot)?.flush()?;
+    Ok(())
+}
+
+/// Copies archive, or all its volumes
+fn copy_archive(from: &Path, to: &Path) -> anyhow::Result<()> {
+    let volumes = rpack::volume::volume_paths(from);
+    let pairs = match volumes.is_empty() {
+        true => vec![(from.to_path_buf(), to.to_path_buf())],
+        false => volumes.into_iter().enumerate().map(|(i, x)| (x, rpack::volume::volume_path(to, i + 1))).collect(),
+    };
+    for (from, to) in pairs {
+        create_parent(&to)?;
+        std::fs::copy(&from, &to).with_context(|| format!("Unable to copy {} to {}", from.display(), to.display()))?;
+    }
+    Ok(())
+}
+
+/// Rebuilds the newer full archive. With directories, archives without delta are copied as they are
+pub fn apply(args: ApplyDeltaArgs) -> anyhow::Result<()> {
+    crate::check_archive_paths(&args.base, &args.output)?;
+    crate::check_archive_paths(&args.delta, &args.output)?;
+    let options = args.archive.options();
+    if !args.delta.is_dir() {
+        return apply_delta(Some(&args.base), &args.delta, &args.output, &options);
+    }
+
+    ensure!(args.base.is_dir(), "Base must be a directory of archives when delta is a directory");
+    let deltas = archives(&args.delta)?;
+    for relative in deltas.iter() {
+        let base = args.base.join(relative);
+        let base = (base.exists() || rpack::volume::volume_path(&base, 1).exists()).then_some(base);
+        apply_delta(base.as_deref(), &args.delta.join(relative), &args.output.join(relative), &options)?;
+    }
+
+    for relative in archives(&args.base)? {
+        if !deltas.contains(&relative) {
+            copy_archive(&args.base.join(&relative), &args.output.join(&relative))?;
+        }
+    }
+    Ok(())
+}
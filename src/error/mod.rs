@@ -0,0 +1,140 @@
+//! Stable short codes of errors, like `E013 SectorOverlap`, so scripts and issue reports can tell failures apart
+//! without parsing messages. Codes are never renumbered or reused, new ones are appended.
+//!
+//! Errors get their code where they are made, with [`ErrorCode::error`] or [`ErrorCode::wrap`], and keep it through
+//! any context added later. [`with_code`] puts it in front of the message where errors are printed.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+};
+use core::fmt::Display;
+
+#[cfg(feature = "std")]
+use crate::chunk::{ChecksumMismatch, DecompressError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(u16)]
+pub enum ErrorCode {
+    /// Region file ends inside its header
+    TruncatedHeader = 1,
+    /// Chunk sectors start inside the region header
+    HeaderOverlap = 2,
+    /// Chunk sectors reach beyond the end of the file or the largest possible region file
+    SectorsBeyondEnd = 3,
+    /// Length field of a chunk is zero or does not fit into its sectors
+    BadChunkLength = 4,
+    /// Compression type of a chunk is unknown or has no registered codec
+    UnknownCompression = 5,
+    /// Chunk data does not decompress
+    CorruptChunk = 6,
+    /// Zlib or gzip trailer of a chunk does not match its data
+    ChunkChecksum = 7,
+    /// Decompressed chunk or archive record is larger than the limit
+    SizeLimit = 8,
+    /// Chunk NBT can not be parsed
+    InvalidNbt = 9,
+    /// File is not an rpack archive or stored file
+    NotAnArchive = 10,
+    /// Archive or stored file of a version this build does not read
+    UnsupportedVersion = 11,
+    /// Archive or stored file ends early
+    TruncatedArchive = 12,
+    /// Chunk sectors overlap those of another chunk
+    SectorOverlap = 13,
+    /// CRC32 of an archive record or stored file does not match
+    ArchiveChecksum = 14,
+    /// Chunk kept in a `c.<x>.<z>.mcc` file can not be read
+    ExternalChunk = 15,
+    /// Compression ratio beyond limit with `--strict-ratio`
+    RatioExceeded = 16,
+    /// Archive does not give back the region file it was made from, see `--verify`
+    VerifyFailed = 17,
+}
+
+impl ErrorCode {
+    /// Short code like `E013`
+    pub fn id(self) -> String {
+        format!("E{:03}", self as u16)
+    }
+
+    /// Error with `message` carrying this code
+    pub fn error(self, message: impl Display) -> anyhow::Error {
+        anyhow::Error::new(Coded { code: self, message: message.to_string() })
+    }
+
+    /// `error` with `message` as context carrying this code. It takes precedence over codes of `error`
+    pub fn wrap(self, error: anyhow::Error, message: impl Display) -> anyhow::Error {
+        error.context(Coded { code: self, message: message.to_string() })
+    }
+
+    /// Code of the outermost error in chain having one
+    pub fn of(error: &anyhow::Error) -> Option<Self> {
+        if let Some(coded) = error.downcast_ref::<Coded>() {
+            return Some(coded.code);
+        }
+        #[cfg(not(feature = "std"))]
+        return None;
+        #[cfg(feature = "std")]
+        error.chain().find_map(|x| {
+            if x.is::<ChecksumMismatch>() {
+                return Some(ErrorCode::ChunkChecksum);
+            }
+            match x.downcast_ref::<DecompressError>()? {
+                DecompressError::TooLarge { .. } => Some(ErrorCode::SizeLimit),
+                DecompressError::Checksum(_) => Some(ErrorCode::ChunkChecksum),
+                DecompressError::Failed(e) => Some(Self::of(e).unwrap_or(ErrorCode::CorruptChunk)),
+            }
+        })
+    }
+}
+
+/// `E013 SectorOverlap`
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} {self:?}", self.id())
+    }
+}
+
+/// Error made by [`ErrorCode::error`], displayed as its message alone
+#[derive(Debug)]
+pub struct Coded {
+    pub code: ErrorCode,
+    message: String,
+}
+
+impl Display for Coded {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl core::error::Error for Coded {}
+
+/// Error with its code as outermost context, so it is printed first. Errors without code or with it in front
+/// already are returned as they are
+pub fn with_code(error: anyhow::Error) -> anyhow::Error {
+    match ErrorCode::of(&error) {
+        Some(_) if error.downcast_ref::<ErrorCode>().is_some() => error,
+        Some(code) => error.context(code),
+        None => error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_survive_context() {
+        let error = ErrorCode::SectorOverlap.error("Chunk 1,1 overlaps with another chunk").context("r.0.0.mca");
+        assert_eq!(ErrorCode::of(&error), Some(ErrorCode::SectorOverlap));
+
+        let error = with_code(with_code(error));
+        assert_eq!(format!("{error:#}"), "E013 SectorOverlap: r.0.0.mca: Chunk 1,1 overlaps with another chunk");
+
+        let error = ErrorCode::VerifyFailed.wrap(error, "Archive verification failed");
+        assert_eq!(ErrorCode::of(&error), Some(ErrorCode::VerifyFailed));
+        assert_eq!(ErrorCode::of(&anyhow::anyhow!("plain")), None);
+    }
+}
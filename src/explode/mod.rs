@@ -0,0 +1,195 @@
+use std::{
+    io::{BufRead, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, ensure, Context};
+
+use crate::region::{self, RegionInfo, RegionReader, RegionWriter};
+
+pub const MANIFEST_NAME: &str = "manifest.txt";
+
+#[derive(Debug, clap::Args)]
+pub struct ExplodeArgs {
+    /// Region file
+    #[arg(short, long)]
+    pub input: PathBuf,
+
+    /// Output directory. Will be created if missing
+    #[arg(short, long)]
+    pub output: PathBuf,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct ImplodeArgs {
+    /// Directory made by explode
+    #[arg(short, long)]
+    pub input: PathBuf,
+
+    /// Output region file
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// Re-open region file after writing and check it is valid for the game
+    #[arg(long)]
+    pub validate_output: bool,
+}
+
+/// Writes every chunk as uncompressed `c.<x>.<z>.nbt` plus manifest with header timestamps.
+/// Coordinates are absolute when region file name is vanilla-like, local otherwise.
+pub fn explode(args: ExplodeArgs) -> anyhow::Result<()> {
+    let region = region::region_coords_from_path(&args.input);
+    let mut reader = RegionReader::open(&args.input)?;
+
+    std::fs::create_dir_all(&args.output)
+        .with_context(|| format!("Unable to create {}", args.output.display()))?;
+
+    let mut manifest = vec![];
+    reader.decompress_all(|info, pos, data| {
+        let (x, z) = RegionInfo::chunk_coords(region, pos);
+        let path = args.output.join(chunk_file_name(x, z));
+        std::fs::write(&path, data).with_context(|| format!("Unable to write {}", path.display()))?;
+
+        manifest.push((x, z, info.timestamp.get()));
+        Ok(())
+    })?;
+
+    // Slot order keeps manifest diffs small when the chunk layout in file changes
+    manifest.sort_by_key(|&(x, z, _)| (z.rem_euclid(32), x.rem_euclid(32)));
+
+    let path = args.output.join(MANIFEST_NAME);
+    let mut writer = std::fs::File::create(&path)
+        .map(BufWriter::new)
+        .with_context(|| format!("Unable to write {}", path.display()))?;
+    for (x, z, timestamp) in manifest {
+        writeln!(writer, "{x} {z} {timestamp}")?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Chunks may come in any order and leave any slots empty. Without manifest chunk files are discovered
+/// by name and get modification time as timestamp. Chunks are always laid out in header slot order,
+/// so the same set of chunks gives the same file.
+pub fn implode(args: ImplodeArgs) -> anyhow::Result<()> {
+    let mut chunks = read_manifest(&args.input)?;
+    chunks.sort_by_key(|&(x, z, _)| slot(x, z));
+
+    if let Some(w) = chunks.windows(2).find(|w| slot(w[0].0, w[0].1) == slot(w[1].0, w[1].1)) {
+        bail!(
+            "Chunks {},{} and {},{} share the same header slot. Are they from different regions?",
+            w[0].0,
+            w[0].1,
+            w[1].0,
+            w[1].1
+        );
+    }
+
+    let writer = std::fs::File::options()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&args.output)
+        .map(BufWriter::new)
+        .with_context(|| format!("Unable to create {}", args.output.display()))?;
+
+    let result = (|| {
+        let mut writer = writer;
+        let mut regionwriter = RegionWriter::new(&mut writer)?.with_external_chunks(&args.output);
+
+        for (x, z, timestamp) in chunks {
+            let path = args.input.join(chunk_file_name(x, z));
+            // Deleting chunk file is the way to drop chunk from the region
+            if !path.exists() {
+                eprintln!("{} is missing, slot left empty", path.display());
+                continue;
+            }
+            let data = std::fs::read(&path).with_context(|| format!("Unable to read {}", path.display()))?;
+
+            regionwriter.write_chunk(slot(x, z), timestamp, &data)?;
+        }
+
+        regionwriter.finish()?;
+        writer.flush().context("Unable to flush file")?;
+        drop(writer);
+
+        if args.validate_output {
+            region::validate_region(&args.output)?;
+        }
+
+        Ok(())
+    })();
+
+    result.inspect_err(|_| {
+        std::fs::remove_file(&args.output)
+            .inspect_err(|e| eprintln!("{e}"))
+            .ok();
+    })
+}
+
+fn slot(x: i32, z: i32) -> u16 {
+    (z.rem_euclid(32) * 32 + x.rem_euclid(32)) as u16
+}
+
+/// Chunk coordinates and timestamps from manifest or from chunk files if there is no manifest
+fn read_manifest(dir: &Path) -> anyhow::Result<Vec<(i32, i32, u32)>> {
+    let path = dir.join(MANIFEST_NAME);
+
+    if !path.exists() {
+        let mut chunks = vec![];
+        for entry in std::fs::read_dir(dir).with_context(|| format!("Unable to read {}", dir.display()))? {
+            let entry = entry?;
+            let Some((x, z)) = entry.file_name().to_str().and_then(parse_chunk_file_name) else {
+                continue;
+            };
+
+            let timestamp = entry
+                .metadata()?
+                .modified()?
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|x| x.as_secs() as u32)
+                .unwrap_or_default();
+            chunks.push((x, z, timestamp));
+        }
+        return Ok(chunks);
+    }
+
+    let manifest = std::fs::File::open(&path)
+        .map(std::io::BufReader::new)
+        .with_context(|| format!("Unable to open {}", path.display()))?;
+
+    manifest
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| line.as_ref().map_or(true, |x| !x.trim().is_empty()))
+        .map(|(line_no, line)| {
+            let line = line?;
+            parse_manifest_line(&line)
+                .with_context(|| format!("{}:{}: invalid line {line:?}", path.display(), line_no + 1))
+        })
+        .collect()
+}
+
+fn chunk_file_name(x: i32, z: i32) -> String {
+    format!("c.{x}.{z}.nbt")
+}
+
+fn parse_chunk_file_name(name: &str) -> Option<(i32, i32)> {
+    let mut parts = name.strip_prefix("c.")?.strip_suffix(".nbt")?.split('.');
+    let x = parts.next()?.parse().ok()?;
+    let z = parts.next()?.parse().ok()?;
+    parts.next().is_none().then_some((x, z))
+}
+
+fn parse_manifest_line(line: &str) -> anyhow::Result<(i32, i32, u32)> {
+    let mut parts = line.split_whitespace();
+    let mut next = || parts.next().context("Not enough fields");
+
+    let x = next()?.parse()?;
+    let z = next()?.parse()?;
+    let timestamp = next()?.parse()?;
+    ensure!(parts.next().is_none(), "Too many fields");
+
+    Ok((x, z, timestamp))
+}
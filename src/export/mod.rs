@@ -0,0 +1,103 @@
+use std::{io::Write, path::PathBuf};
+
+use anyhow::{ensure, Context};
+
+use crate::{
+    nbt,
+    region::{self, RegionReader},
+    schematic::{BlockBox, Selection},
+    world,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExportFormat {
+    /// Sponge schematic v2, readable by WorldEdit and most editors
+    #[default]
+    Schem,
+    /// Vanilla structure NBT, loadable by structure blocks
+    Structure,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct ExportArgs {
+    /// Region file or `region` directory of a dimension
+    #[arg(short, long)]
+    pub input: PathBuf,
+
+    /// Output file
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// First corner of the box, absolute block coordinates `x,y,z`
+    #[arg(long, value_parser = parse_pos, allow_hyphen_values = true)]
+    pub from: [i32; 3],
+
+    /// Opposite corner of the box, inclusive
+    #[arg(long, value_parser = parse_pos, allow_hyphen_values = true)]
+    pub to: [i32; 3],
+
+    #[arg(long, value_enum, default_value_t = ExportFormat::Schem)]
+    pub format: ExportFormat,
+}
+
+fn parse_pos(s: &str) -> anyhow::Result<[i32; 3]> {
+    let coords = s
+        .split(',')
+        .map(|x| x.trim().parse::<i32>())
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid coordinates {s:?}"))?;
+    coords
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Expected three coordinates x,y,z, got {s:?}"))
+}
+
+/// Copies blocks of the box from regions into a gzipped schematic or structure file
+pub fn run(args: ExportArgs) -> anyhow::Result<()> {
+    let bounds = BlockBox::new(args.from, args.to);
+    let mut selection = Selection::new(bounds)?;
+
+    let regions = [bounds.min[0], bounds.max[0], bounds.min[2], bounds.max[2]].map(|x| x.div_euclid(512));
+    let mut chunks = 0usize;
+
+    for file in world::region_files(&args.input)?.iter() {
+        let Some((rx, rz)) = region::region_coords_from_path(file) else {
+            continue;
+        };
+        if !(regions[0]..=regions[1]).contains(&rx) || !(regions[2]..=regions[3]).contains(&rz) {
+            continue;
+        }
+
+        RegionReader::open(file)?
+            .decompress_all(|_, pos, data| {
+                let (x, z) = region::RegionInfo::chunk_coords(Some((rx, rz)), pos);
+                if bounds.intersects_chunk(x, z) {
+                    let root = nbt::read_compound(data).with_context(|| format!("Chunk {x},{z}"))?;
+                    selection.add_chunk(&root);
+                    chunks += 1;
+                }
+                Ok(())
+            })
+            .with_context(|| format!("{}", file.display()))?;
+    }
+
+    ensure!(chunks != 0, "No chunks intersect the box");
+
+    let (name, root) = match args.format {
+        ExportFormat::Schem => ("Schematic", selection.to_sponge()),
+        ExportFormat::Structure => ("", selection.to_structure()),
+    };
+
+    let file = std::fs::File::create(&args.output).with_context(|| format!("Unable to create {}", args.output.display()))?;
+    let mut encoder = flate2::write::GzEncoder::new(std::io::BufWriter::new(file), flate2::Compression::default());
+    nbt::write(&mut encoder, name, &nbt::Tag::Compound(root))?;
+    encoder.finish()?.flush()?;
+
+    let [x, y, z] = bounds.size();
+    println!(
+        "{}: {x}x{y}x{z} blocks from {chunks} chunks, {} states, {} block entities",
+        args.output.display(),
+        selection.palette.len(),
+        selection.block_entities.len()
+    );
+    Ok(())
+}
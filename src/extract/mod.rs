@@ -0,0 +1,149 @@
+use std::{
+    io::{BufRead, BufReader, Cursor},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{ensure, Context};
+
+use crate::{
+    manifest::{self, Entry, Kind},
+    region::{self, RegionInfo, RegionWriter},
+    rpack, Limits,
+};
+
+#[derive(Debug, clap::Args)]
+pub struct ExtractAreaArgs {
+    /// Directory of archives written in directory mode
+    pub input: PathBuf,
+
+    /// Block coordinates `x,z` of the center of the area
+    #[arg(long, value_parser = crate::parse_coords, allow_hyphen_values = true)]
+    pub center: (i32, i32),
+
+    /// Radius of the area in blocks. Chunks touching the circle are extracted
+    #[arg(long)]
+    pub radius: u32,
+
+    /// Dimension the area is in. Regions of other dimensions are left out
+    #[arg(long, default_value = "minecraft:overworld")]
+    pub dimension: String,
+
+    /// World directory to create. Gets the chunks of the area and every passthrough file, like level.dat
+    #[arg(short, long)]
+    pub output: PathBuf,
+}
+
+/// Whether circle touches block rectangle `min..=max`
+fn intersects(center: (i64, i64), radius: i64, min: (i64, i64), max: (i64, i64)) -> bool {
+    let dx = center.0.clamp(min.0, max.0) - center.0;
+    let dz = center.1.clamp(min.1, max.1) - center.1;
+    dx * dx + dz * dz <= radius * radius
+}
+
+/// Writes chunks of region entry inside the area, returns their count. No file is written without any
+fn extract_region(args: &ExtractAreaArgs, entry: &Entry, archive: &Path, region: (i32, i32)) -> anyhow::Result<usize> {
+    let center = (args.center.0.into(), args.center.1.into());
+    let inside = |x: i32, z: i32| {
+        let min = (i64::from(x) * 16, i64::from(z) * 16);
+        intersects(center, args.radius.into(), min, (min.0 + 15, min.1 + 15))
+    };
+
+    let output = args.output.join(entry.path.to_path());
+    let options = rpack::DecodeOptions {
+        format: region::detect_format(&output, &region::providers()).unwrap_or_default(),
+        ..Default::default()
+    };
+    if let Some(dir) = output.parent() {
+        std::fs::create_dir_all(dir).with_context(|| format!("Unable to create {}", dir.display()))?;
+    }
+    // Built in memory, so regions without chunks in the area leave no file behind
+    let mut data = Cursor::new(vec![]);
+    let mut regionwriter = RegionWriter::with_format(&mut data, options.format)?.with_external_chunks(&output);
+
+    let mut reader = BufReader::new(rpack::volume::open(archive)?);
+    let mut buffer = vec![];
+    let mut count = 0;
+    while reader.fill_buf()?.starts_with(&rpack::MAGIC) {
+        let mut rpackreader = rpack::RpackReader::from_buf_reader(reader, Limits::default())?;
+        let raw = rpackreader.raw();
+        while let Some(chunk) = rpackreader.read_chunk(&mut buffer)? {
+            let (x, z) = RegionInfo::chunk_coords(Some(region), chunk.pos);
+            if inside(x, z) {
+                rpack::put_chunk(&mut regionwriter, chunk.pos, chunk.timestamp, &buffer, raw, &options)?;
+                count += 1;
+            }
+        }
+        reader = rpackreader.into_inner()?;
+    }
+    regionwriter.finish()?;
+
+    if count > 0 {
+        std::fs::write(&output, data.get_ref()).with_context(|| format!("Unable to write {}", output.display()))?;
+    }
+    Ok(count)
+}
+
+pub fn run(args: ExtractAreaArgs) -> anyhow::Result<()> {
+    let manifest = manifest::read(&args.input)?;
+    ensure!(!args.output.join("level.dat").exists(), "{} already holds a world", args.output.display());
+
+    let (mut regions, mut chunks, mut files) = (0, 0, 0);
+    for entry in manifest.entries.iter() {
+        let archive = args.input.join(entry.stored_path.to_path());
+        if entry.kind == Kind::Passthrough {
+            let output = args.output.join(entry.path.to_path());
+            if let Some(dir) = output.parent() {
+                std::fs::create_dir_all(dir).with_context(|| format!("Unable to create {}", dir.display()))?;
+            }
+            if rpack::file::is_stored(&archive) {
+                rpack::file::decompress(&archive, &output)?;
+            } else {
+                std::fs::copy(&archive, &output).with_context(|| format!("Unable to copy {}", entry.path))?;
+            }
+            files += 1;
+            continue;
+        }
+
+        // Links are not followed, their targets are extracted when inside the area. Duplicates come last
+        if entry.target.is_some() {
+            continue;
+        }
+        let Some(region) = region::region_coords_from_path(entry.path.to_path()) else { continue };
+        if entry.dimension.as_deref() != Some(args.dimension.as_str()) {
+            continue;
+        }
+        // The manifest locates regions, only archives of regions touching the area are read
+        let min = (i64::from(region.0) * 512, i64::from(region.1) * 512);
+        let center = (args.center.0.into(), args.center.1.into());
+        if !intersects(center, args.radius.into(), min, (min.0 + 511, min.1 + 511)) {
+            continue;
+        }
+
+        let count = extract_region(&args, entry, &archive, region).with_context(|| format!("Unable to extract {}", entry.path))?;
+        if count > 0 {
+            println!("  {count:>5} chunks  {}", entry.path);
+            regions += 1;
+            chunks += count;
+        }
+    }
+    // Passthrough files stored once are copied to their other places
+    files += manifest::restore_duplicates(&manifest, &args.output)?;
+
+    println!("Extracted {chunks} chunks of {regions} region files and {files} other files");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn circle_touches_rectangle() {
+        // Chunk 1,1 with centers inside it and off its corner
+        assert!(intersects((20, 20), 4, (16, 16), (31, 31)));
+        assert!(intersects((14, 14), 3, (16, 16), (31, 31)));
+        assert!(!intersects((14, 14), 2, (16, 16), (31, 31)));
+        assert!(intersects((1500, -300), 400, (1024, -512), (1535, -1)));
+        assert!(!intersects((0, 0), 400, (512, 512), (1023, 1023)));
+    }
+}
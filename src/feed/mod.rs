@@ -0,0 +1,185 @@
+//! Chunk NBT of region files and archives alike, for consumers like map renderers that do not care
+//! about the container format.
+
+use std::{
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc,
+    },
+};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    chunk,
+    limits::Limits,
+    region::{self, RegionInfo, RegionReader},
+    rpack::{self, RpackReader},
+};
+
+/// Region files of known format and archives under `path`, or `path` itself if it is a file
+fn input_files(path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let providers = region::providers();
+    let archive = |x: &Path| x.extension().is_some_and(|x| x == "rpack");
+    let mut files = vec![];
+    let mut dirs = vec![path.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir).with_context(|| format!("Unable to read {}", dir.display()))? {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                dirs.push(path);
+            } else if archive(&path) || region::detect_format(&path, &providers).is_some() {
+                files.push(path);
+            } else if let Some(base) = rpack::volume::first_volume_base(&path).filter(|x| archive(x)) {
+                files.push(base);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Decodes every chunk of region file or archive. Coordinates are absolute when region coordinates
+/// are in the file name, local otherwise
+fn decode_file(path: &Path, limits: Limits, mut f: impl FnMut((i32, i32), Vec<u8>) -> anyhow::Result<()>) -> anyhow::Result<()> {
+    let archive = rpack::volume::first_volume_base(path).unwrap_or_else(|| path.to_path_buf());
+    let region = region::region_coords_from_path(&archive);
+    let mut reader = BufReader::new(rpack::volume::open(path)?);
+
+    if !reader.fill_buf()?.starts_with(&rpack::MAGIC) {
+        let format = region::detect_format(path, &region::providers()).unwrap_or_default();
+        let mut regionreader = RegionReader::from_reader_with_format(reader, limits, format)?.with_external_chunks(path);
+        return regionreader.decompress_all(|_, pos, data| f(RegionInfo::chunk_coords(region, pos), std::mem::take(data)));
+    }
+
+    // Concatenated archives are read one after another
+    let mut payload = vec![];
+    while reader.fill_buf()?.starts_with(&rpack::MAGIC) {
+        let mut rpackreader = RpackReader::from_buf_reader(reader, limits)?;
+        if rpackreader.delta() {
+            bail!("Archive is a delta, rebuild the full archive with apply-delta first");
+        }
+        while let Some(record) = rpackreader.read_chunk(&mut payload)? {
+            let nbt = match rpackreader.raw() {
+                true => {
+                    let mut nbt = vec![];
+                    chunk::decompress_stored(&payload, &mut nbt, limits.max_decompressed_size)?;
+                    nbt
+                },
+                false => std::mem::take(&mut payload),
+            };
+            f(RegionInfo::chunk_coords(region, record.pos), nbt)?;
+        }
+        reader = rpackreader.into_inner()?;
+    }
+    Ok(())
+}
+
+/// Calls `f` with chunk coordinates and uncompressed NBT of every chunk of a region file, an archive,
+/// or every region file and archive under a directory.
+///
+/// Files are decoded by `threads` worker threads while `f` runs on the calling thread, so it needs no
+/// synchronization. Chunks come in no particular order. The first error, of decoding or of `f`, stops the walk.
+///
+/// ```no_run
+/// let mut chunks = 0;
+/// anvilregion_repacker::feed::for_each_chunk_nbt("world/region", 4, |(x, z), nbt| {
+///     chunks += 1;
+///     Ok(())
+/// })?;
+/// # anyhow::Ok(())
+/// ```
+pub fn for_each_chunk_nbt(
+    path: impl AsRef<Path>,
+    threads: usize,
+    mut f: impl FnMut((i32, i32), &[u8]) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let files = input_files(path.as_ref())?;
+    let limits = Limits::default();
+    let next = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+    // Bounded, so decoding does not run far ahead of a slow consumer
+    let (sender, receiver) = mpsc::sync_channel::<anyhow::Result<((i32, i32), Vec<u8>)>>(threads.max(1) * 16);
+
+    std::thread::scope(|scope| {
+        for _ in 0..threads.max(1) {
+            let sender = sender.clone();
+            let (files, next, stop) = (&files, &next, &stop);
+            scope.spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let Some(file) = files.get(next.fetch_add(1, Ordering::Relaxed)) else { break };
+                    let result = decode_file(file, limits, |coords, nbt| {
+                        sender.send(Ok((coords, nbt))).context("Consumer stopped")
+                    });
+                    if let Err(e) = result {
+                        sender.send(Err(e.context(format!("Unable to read {}", file.display())))).ok();
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        for item in receiver {
+            if let Err(e) = item.and_then(|(coords, nbt)| f(coords, &nbt)) {
+                // Workers stop at the next file or, blocked on the dropped channel, right away
+                stop.store(true, Ordering::Relaxed);
+                return Err(e);
+            }
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        rpack::RpackWriter,
+        testutil::{RegionGenerator, TempDir},
+    };
+
+    #[test]
+    fn regions_and_archives() {
+        let dir = TempDir::new("feed");
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+
+        let generator = RegionGenerator { region: (1, -2), ..Default::default() };
+        let region = generator.generate(7);
+        std::fs::write(dir.join("r.1.-2.mca"), &region.bytes).unwrap();
+
+        let mut writer = RpackWriter::new(vec![], Default::default()).unwrap();
+        for chunk in region.chunks.iter() {
+            writer.write_chunk(chunk.pos, chunk.timestamp, &chunk.payload).unwrap();
+        }
+        std::fs::write(dir.join("nested/r.1.-2.mca.rpack"), writer.finish().unwrap()).unwrap();
+
+        let mut expected = region
+            .chunks
+            .iter()
+            .map(|x| (RegionInfo::chunk_coords(Some((1, -2)), x.pos), x.payload.clone()))
+            .collect::<Vec<_>>();
+        expected.extend(expected.clone());
+        expected.sort();
+
+        let mut chunks = vec![];
+        for_each_chunk_nbt(dir.path(), 3, |coords, nbt| {
+            chunks.push((coords, nbt.to_vec()));
+            Ok(())
+        })
+        .unwrap();
+        chunks.sort();
+        assert_eq!(chunks, expected);
+
+        // Error of consumer stops the walk
+        let result = for_each_chunk_nbt(dir.path(), 3, |_, _| bail!("enough"));
+        assert_eq!(result.unwrap_err().to_string(), "enough");
+    }
+}
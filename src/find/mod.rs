@@ -0,0 +1,45 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+
+use crate::{
+    nbt,
+    query::Query,
+    region::{self, RegionInfo, RegionReader},
+    world,
+};
+
+#[derive(Debug, clap::Args)]
+pub struct FindArgs {
+    /// Region file or directory to search region files in
+    #[arg(short, long)]
+    pub input: PathBuf,
+
+    /// Predicate over chunk NBT, e.g. `block_entities[].id == "minecraft:beacon"`
+    #[arg(short, long)]
+    pub query: String,
+}
+
+pub fn run(args: FindArgs) -> anyhow::Result<()> {
+    let query = Query::parse(&args.query).context("Invalid query")?;
+    let files = world::region_files(&args.input)?;
+
+    for file in files.iter() {
+        let region = region::region_coords_from_path(file);
+
+        RegionReader::open(file)?
+            .decompress_all(|_, pos, data| {
+                let (x, z) = RegionInfo::chunk_coords(region, pos);
+                let root = nbt::read_compound(data).with_context(|| format!("Chunk {x},{z}"))?;
+
+                if query.matches(&root) {
+                    println!("{} {x},{z}", file.display());
+                }
+
+                Ok(())
+            })
+            .with_context(|| format!("{}", file.display()))?;
+    }
+
+    Ok(())
+}
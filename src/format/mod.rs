@@ -0,0 +1,11 @@
+//! Format layer: region headers and rpack archive framing parsed from bytes, without any IO.
+//!
+//! Needs only `core` and `alloc`, so it builds without the `std` feature for proxies and other constrained
+//! environments parsing regions in flight. Reading files, compression and everything else lives in the modules
+//! built with `std`, which re-export these types where they always were, like [`crate::region::RegionInfo`].
+
+mod region;
+mod rpack;
+
+pub use region::{ChunkInfo, Codec, RegionFormat, RegionInfo};
+pub use rpack::{BinHeader, Compression, RpackChunk, RpackChunkHeader, RpackHeader, MAGIC, VERSION};
@@ -0,0 +1,291 @@
+use alloc::{format, vec, vec::Vec};
+use core::{
+    fmt::Debug,
+    num::{NonZeroU32, NonZeroU64},
+};
+
+use anyhow::{ensure, Context};
+use zerocopy::{try_transmute, BigEndian, IntoBytes, TryFromBytes, U32};
+
+use crate::{error::ErrorCode, limits::Limits};
+
+/// Compression used when writing chunks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    GZip,
+    #[default]
+    Zlib,
+    Uncompressed,
+    /// Codec from [`crate::chunk::CodecRegistry`] with this compression type
+    Registered(u8),
+}
+
+/// Geometry of region files. Vanilla uses 4 KiB sectors and 8 KiB header of location and timestamp
+/// tables, some forks use other sector sizes or reserve extra space after the tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionFormat {
+    pub sector_size: u64,
+    /// Space reserved at the start of file, including location and timestamp tables
+    pub header_size: u64,
+    /// Number of location entries in header
+    pub entries: u16,
+    /// Header has timestamp table after location table
+    pub timestamps: bool,
+    /// `None` if chunk data starts with compression type byte, otherwise every chunk uses this codec
+    pub codec: Option<Codec>,
+}
+
+impl Default for RegionFormat {
+    fn default() -> Self {
+        Self::VANILLA
+    }
+}
+
+impl RegionFormat {
+    pub const VANILLA: Self = Self {
+        sector_size: 4096,
+        header_size: 8192,
+        entries: 1024,
+        timestamps: true,
+        codec: None,
+    };
+    /// Column regions (`<x>.<z>.2dr`) of Cubic Chunks mod: 32x32 columns, 512 byte sectors,
+    /// location table only and length-prefixed gzip NBT
+    pub const CUBIC_CHUNKS_2D: Self = Self {
+        sector_size: 512,
+        header_size: 1024 * 4,
+        entries: 1024,
+        timestamps: false,
+        codec: Some(Codec::GZip),
+    };
+    /// Cube regions (`<x>.<y>.<z>.3dr`) of Cubic Chunks mod: 16x16x16 cubes, otherwise like 2D regions
+    pub const CUBIC_CHUNKS_3D: Self = Self {
+        sector_size: 512,
+        header_size: 4096 * 4,
+        entries: 4096,
+        timestamps: false,
+        codec: Some(Codec::GZip),
+    };
+
+    /// Vanilla layout with another sector or header size
+    pub fn new(sector_size: u64, header_size: u64) -> anyhow::Result<Self> {
+        Self {
+            sector_size,
+            header_size,
+            ..Self::VANILLA
+        }
+        .checked()
+    }
+
+    /// Checks that header fits tables and is aligned to sectors
+    pub fn checked(self) -> anyhow::Result<Self> {
+        ensure!(
+            self.sector_size.is_power_of_two() && self.sector_size >= 256,
+            "Sector size must be a power of two not less than 256"
+        );
+        ensure!(
+            self.header_size >= self.table_size() && self.header_size.is_multiple_of(self.sector_size),
+            "Header size must be a multiple of sector size not less than {}",
+            self.table_size()
+        );
+        Ok(self)
+    }
+
+    /// Location and timestamp tables, 4 bytes per entry each
+    pub fn table_size(&self) -> u64 {
+        self.entries as u64 * 4 * if self.timestamps { 2 } else { 1 }
+    }
+
+    /// Sector count is stored in a single byte
+    pub fn max_chunk_size(&self) -> u64 {
+        0xFF * self.sector_size
+    }
+
+    /// Header plus every chunk taking max sectors
+    pub fn max_file_size(&self) -> u64 {
+        self.header_size + self.entries as u64 * self.max_chunk_size()
+    }
+
+    /// Bytes in front of compressed chunk data: length field and compression type byte if any
+    pub fn chunk_prefix(&self) -> u64 {
+        if self.codec.is_some() { 4 } else { 5 }
+    }
+}
+
+#[derive(TryFromBytes, Clone, Copy)]
+#[repr(C)]
+#[non_exhaustive]
+pub struct ChunkInfo {
+    /// Actually, U32<BigEndian> but NonZeroU32 save Option<ChunkInfo> from bloat
+    /// so size_of::<ChunkInfo>() == size_of::<Option<ChunkInfo>>()
+    pub locdata: NonZeroU32,
+    pub timestamp: U32<BigEndian>,
+}
+
+impl Debug for ChunkInfo {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ChunkInfo")
+            .field("location", &self.location())
+            .field("size", &self.size())
+            .field("(locdata: U32BE, timestamp: U32BE)", &(self.locdata, self.timestamp))
+            .finish()
+    }
+}
+
+impl ChunkInfo {
+    pub const SECTOR_SIZE: u16 = 4096;
+    /// Sector count is stored in a single byte
+    pub const MAX_SIZE: u64 = 0xFF * Self::SECTOR_SIZE as u64;
+
+    pub fn new(location: NonZeroU64, size: NonZeroU64, timestamp: u32) -> anyhow::Result<Self> {
+        Self::new_in(location, size, timestamp, &RegionFormat::VANILLA)
+    }
+
+    /// Location and size are in bytes and must be aligned to sectors of the format
+    pub fn new_in(location: NonZeroU64, size: NonZeroU64, timestamp: u32, format: &RegionFormat) -> anyhow::Result<Self> {
+        let location = location.get();
+        let size = size.get();
+        let sector = format.sector_size;
+
+        ensure!(location.is_multiple_of(sector), "Location must be mod of {sector}");
+        ensure!(size.is_multiple_of(sector), "Size must be mod of {sector}");
+        ensure!(size <= format.max_chunk_size(), "Size must be less or equal than {}", format.max_chunk_size());
+        ensure!(location / sector <= 0xFFFFFF, "Location must be less than {} bytes", (0xFFFFFF + 1) * sector);
+
+        let mut locdata = U32::<BigEndian>::new(0);
+        let locdata_bytes = locdata.as_mut_bytes();
+
+        let location = U32::<BigEndian>::new((location / sector) as u32);
+        let location_bytes = location.as_bytes();
+        locdata_bytes[0] = location_bytes[1];
+        locdata_bytes[1] = location_bytes[2];
+        locdata_bytes[2] = location_bytes[3];
+        locdata_bytes[3] = (size / sector) as u8;
+
+        Ok(Self {
+            locdata: try_transmute!(locdata).ok().context("Location data must be non-zero")?,
+            timestamp: U32::<BigEndian>::new(timestamp),
+        })
+    }
+
+    pub fn location(&self) -> u64 {
+        self.location_in(&RegionFormat::VANILLA)
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size_in(&RegionFormat::VANILLA)
+    }
+
+    pub fn location_in(&self, format: &RegionFormat) -> u64 {
+        let locdata_bytes = self.locdata.as_bytes();
+        let location =
+            u32::from_be_bytes([0, locdata_bytes[0], locdata_bytes[1], locdata_bytes[2]]);
+
+        location as u64 * format.sector_size
+    }
+
+    pub fn size_in(&self, format: &RegionFormat) -> u64 {
+        let locdata_bytes = self.locdata.as_bytes();
+        locdata_bytes[3] as u64 * format.sector_size
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RegionInfo(pub(crate) Vec<(ChunkInfo, u16)>);
+
+impl RegionInfo {
+    pub const SIZE: u16 = 8192;
+    pub const MAX_CHUNK_COUNT: u16 = 1024;
+    /// Header plus every chunk taking max sectors
+    pub const MAX_FILE_SIZE: u64 = Self::SIZE as u64 + Self::MAX_CHUNK_COUNT as u64 * ChunkInfo::MAX_SIZE;
+
+    /// Parses location and timestamp tables at the start of `header`, which holds at least
+    /// [`RegionFormat::table_size`] bytes. Header entries pointing into the header, beyond max region size
+    /// or overlapping previous chunks are errors with strict header, skipped otherwise.
+    pub fn parse(header: &[u8], limits: &Limits, format: &RegionFormat) -> anyhow::Result<Self> {
+        let entries = format.entries as usize;
+        let mut v = vec![0u32; entries * 2];
+        let tables = if format.timestamps { &mut v[..] } else { &mut v[..entries] };
+        let tables = tables.as_mut_bytes();
+        ensure!(header.len() >= tables.len(), ErrorCode::TruncatedHeader.error("Region file ends inside its header"));
+        tables.copy_from_slice(&header[..tables.len()]);
+
+        // Formats without timestamps get zero ones
+        let (locdatas, timestamps) = v.split_at(entries);
+        let mut chunks: Vec<(ChunkInfo, u16)> = locdatas
+            .iter()
+            .copied()
+            .zip(timestamps.iter().copied())
+            .zip(0..)
+            .filter_map(|((a, b), pos)| try_transmute!([a, b]).ok().map(|x| (x, pos as u16)))
+            .collect();
+
+        chunks.sort_by_key(|x| x.0.location_in(format));
+
+        let mut end = format.header_size;
+        let mut problem = None;
+        chunks.retain(|&(info, pos)| {
+            let (x, z) = Self::local_coords(pos);
+            let (location, size) = (info.location_in(format), info.size_in(format));
+            let msg = if location < format.header_size {
+                ErrorCode::HeaderOverlap.error(format!("Chunk {x},{z} overlaps with region header"))
+            } else if location + size > format.max_file_size() {
+                ErrorCode::SectorsBeyondEnd.error(format!("Chunk {x},{z} location {location} is beyond max region size"))
+            } else if location < end {
+                ErrorCode::SectorOverlap.error(format!("Chunk {x},{z} overlaps with another chunk"))
+            } else {
+                end = location + size;
+                return true;
+            };
+
+            problem.get_or_insert(msg);
+            false
+        });
+
+        if let Some(problem) = problem.filter(|_| limits.strict_header) {
+            return Err(problem);
+        }
+
+        Ok(Self(chunks))
+    }
+
+    pub fn chunk_infos(&self) -> &[(ChunkInfo, u16)] {
+        self.0.as_slice()
+    }
+
+    /// Chunk coordinates inside region for header slot
+    pub fn local_coords(pos: u16) -> (u8, u8) {
+        ((pos % 32) as u8, (pos / 32) as u8)
+    }
+
+    /// Absolute chunk coordinates if region coordinates are known, local otherwise
+    pub fn chunk_coords(region: Option<(i32, i32)>, pos: u16) -> (i32, i32) {
+        let (x, z) = Self::local_coords(pos);
+        let (rx, rz) = region.unwrap_or_default();
+        (rx * 32 + x as i32, rz * 32 + z as i32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_header_bytes() {
+        let mut header = vec![0u8; RegionInfo::SIZE as usize];
+        header[4..8].copy_from_slice(&[0, 0, 2, 1]);
+        header[4096 + 4..4096 + 8].copy_from_slice(&1234u32.to_be_bytes());
+        // Points into the header
+        header[8..12].copy_from_slice(&[0, 0, 1, 1]);
+
+        let relaxed = Limits { strict_header: false, ..Limits::STRICT };
+        let info = RegionInfo::parse(&header, &relaxed, &RegionFormat::VANILLA).unwrap();
+        let [(chunk, 1)] = info.chunk_infos() else { panic!("{info:?}") };
+        assert_eq!((chunk.location(), chunk.size(), chunk.timestamp.get()), (8192, 4096, 1234));
+
+        let error = RegionInfo::parse(&header, &Limits::STRICT, &RegionFormat::VANILLA).unwrap_err();
+        assert_eq!(ErrorCode::of(&error), Some(ErrorCode::HeaderOverlap));
+        let error = RegionInfo::parse(&header[..4096], &relaxed, &RegionFormat::VANILLA).unwrap_err();
+        assert_eq!(ErrorCode::of(&error), Some(ErrorCode::TruncatedHeader));
+    }
+}
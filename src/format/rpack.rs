@@ -0,0 +1,187 @@
+use alloc::format;
+
+use anyhow::{bail, ensure, Context};
+use zerocopy::{BigEndian, FromBytes, Immutable, IntoBytes, KnownLayout, LittleEndian, U16, U32, U64};
+
+use crate::{error::ErrorCode, limits::Limits};
+
+pub const MAGIC: [u8; 4] = *b"RPAK";
+/// Version 2 made the codec of every record authoritative. Version 1 archives, where records name
+/// their codec only with [`Compression::Auto`], are still read
+pub const VERSION: u8 = 2;
+
+#[derive(Debug, Clone, FromBytes, IntoBytes, Immutable, KnownLayout)]
+#[repr(C)]
+pub struct RpackHeader {
+    pub magic: [u8; 4],
+    pub version: u8,
+    pub compression: u8,
+    pub flags: u8,
+    pub reserved: u8,
+    pub dictionary_length: U32<LittleEndian>,
+}
+
+impl RpackHeader {
+    pub const FLAG_SOLID: u8 = 1;
+    pub const FLAG_CHECKSUMS: u8 = 2;
+    pub const FLAG_RAW: u8 = 4;
+    /// Solid stream is split into zstd frames at content-defined boundaries
+    pub const FLAG_ROLLING: u8 = 8;
+    /// Archive holds changes since a base archive, see `rpack::delta`
+    pub const FLAG_DELTA: u8 = 16;
+    /// Flags this version knows
+    pub const FLAGS: u8 = Self::FLAG_SOLID | Self::FLAG_CHECKSUMS | Self::FLAG_RAW | Self::FLAG_ROLLING | Self::FLAG_DELTA;
+
+    /// Checks header describes an archive this version reads within `limits`. Returns its compression
+    pub fn check(&self, limits: &Limits) -> anyhow::Result<Compression> {
+        ensure!(self.magic == MAGIC, ErrorCode::NotAnArchive.error("Not an rpack archive"));
+        ensure!(matches!(self.version, 1..=VERSION), ErrorCode::UnsupportedVersion.error(format!("Unsupported archive version {}", self.version)));
+        let compression = Compression::try_from(self.compression)?;
+        ensure!(self.flags & !Self::FLAGS == 0, "Unknown archive flags {:#x}", self.flags);
+
+        let dictionary_length = self.dictionary_length.get() as u64;
+        ensure!(
+            dictionary_length <= limits.max_decompressed_size,
+            "Dictionary length {dictionary_length} exceeds limit of {} bytes",
+            limits.max_decompressed_size
+        );
+
+        let solid = self.flags & Self::FLAG_SOLID != 0;
+        ensure!(
+            !solid || matches!(compression, Compression::None | Compression::Zstd),
+            "Solid archive with {compression:?} compression is not supported"
+        );
+        ensure!(
+            self.flags & Self::FLAG_ROLLING == 0 || (solid && compression == Compression::Zstd),
+            "Rolling layout requires solid zstd archive"
+        );
+        Ok(compression)
+    }
+}
+
+#[derive(Debug, Clone, FromBytes, IntoBytes, Immutable, KnownLayout)]
+#[repr(C)]
+pub struct RpackChunkHeader {
+    /// Header slot in region
+    pub pos: U16<LittleEndian>,
+    /// [`Compression`] of this payload, [`Compression::None`] in solid archives and for stored payloads
+    pub codec: u8,
+    pub flags: u8,
+    pub timestamp: U32<LittleEndian>,
+    /// Size of uncompressed payload
+    pub length: U64<LittleEndian>,
+    /// Size of payload as stored in the archive
+    pub stored_length: U64<LittleEndian>,
+    /// CRC32 of uncompressed payload if archive has checksums, zero otherwise
+    pub checksum: U32<LittleEndian>,
+}
+
+impl RpackChunkHeader {
+    /// Position of the record terminating an archive
+    pub const END_POS: u16 = u16::MAX;
+    /// Payload is stored uncompressed because compression did not make it smaller
+    pub const FLAG_STORED: u8 = 1;
+    /// Chunk is removed from the base archive, record has no payload. Only in delta archives
+    pub const FLAG_DELETED: u8 = 2;
+
+    /// Checks record of `archive`, which passed [`RpackHeader::check`], against `limits`. Returns codec of its
+    /// payload as stored, [`Compression::None`] for payloads of solid archives and removed chunks.
+    /// Terminating records, at [`Self::END_POS`], are not checked
+    pub fn check(&self, archive: &RpackHeader, limits: &Limits) -> anyhow::Result<Compression> {
+        let pos = self.pos.get();
+        let (length, stored_length) = (self.length.get(), self.stored_length.get());
+        let limit = limits.max_decompressed_size;
+        ensure!(length <= limit, ErrorCode::SizeLimit.error(format!("Chunk length {length} exceeds limit of {limit} bytes")));
+        ensure!(stored_length <= limit, ErrorCode::SizeLimit.error(format!("Chunk stored length {stored_length} exceeds limit of {limit} bytes")));
+
+        ensure!(
+            self.flags & !(Self::FLAG_STORED | Self::FLAG_DELETED) == 0,
+            "Chunk at position {pos} has unknown flags {:#x}",
+            self.flags
+        );
+        if self.flags & Self::FLAG_DELETED != 0 {
+            ensure!(archive.flags & RpackHeader::FLAG_DELTA != 0, "Chunk at position {pos} is removed, but archive is not a delta");
+            ensure!(length == 0 && stored_length == 0, "Removed chunk at position {pos} has payload");
+            return Ok(Compression::None);
+        }
+
+        // Payloads of solid zstd archives are compressed by the stream
+        let compression = Compression::try_from(archive.compression)?;
+        let streamed = archive.flags & RpackHeader::FLAG_SOLID != 0 && compression == Compression::Zstd;
+        let payload_compression = if streamed { Compression::None } else { compression };
+        let codec = match payload_compression {
+            _ if self.flags & Self::FLAG_STORED != 0 => Compression::None,
+            // Version 1 records name their codec only with Compression::Auto
+            codec if archive.version == 1 && codec != Compression::Auto => codec,
+            _ => match Compression::try_from(self.codec).with_context(|| format!("Chunk at position {pos}"))? {
+                Compression::Auto => bail!("Chunk at position {pos} has invalid codec"),
+                codec => codec,
+            },
+        };
+        ensure!(
+            codec == Compression::None || !streamed,
+            "Chunk at position {pos} of solid archive is compressed separately"
+        );
+        Ok(codec)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(clap::ValueEnum, serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "std", serde(rename_all = "lowercase"))]
+#[repr(u8)]
+pub enum Compression {
+    #[default]
+    None = 0,
+    Zstd = 1,
+    Lz4 = 2,
+    /// Every chunk is compressed with the codec giving the smallest result
+    Auto = 3,
+}
+
+impl Compression {
+    /// Trial compression of large chunks uses only this many leading bytes
+    pub const AUTO_SAMPLE_SIZE: usize = 64 * 1024;
+}
+
+impl TryFrom<u8> for Compression {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> anyhow::Result<Self> {
+        Ok(match value {
+            0 => Compression::None,
+            1 => Compression::Zstd,
+            2 => Compression::Lz4,
+            3 => Compression::Auto,
+            _ => bail!("Unknown archive compression {value}"),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RpackChunk {
+    pub pos: u16,
+    pub timestamp: u32,
+    /// Chunk is removed from the base archive, payload is empty. Only in delta archives
+    pub deleted: bool,
+}
+
+impl RpackChunk {
+    /// Chunk described by record header
+    pub fn from_header(header: &RpackChunkHeader) -> Self {
+        Self {
+            pos: header.pos.get(),
+            timestamp: header.timestamp.get(),
+            deleted: header.flags & RpackChunkHeader::FLAG_DELETED != 0,
+        }
+    }
+}
+
+/// Chunk record of the archive stream written before rpack format
+#[derive(Debug, Clone, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct BinHeader {
+    pub pos: U32<LittleEndian>,
+    pub timestamp: U32<BigEndian>,
+    pub length: U64<LittleEndian>,
+}
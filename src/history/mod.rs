@@ -0,0 +1,111 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{ensure, Context};
+
+use crate::{
+    delta,
+    prune::{self, Entry},
+    region,
+    rpack::delta::Chunk,
+};
+
+#[derive(Debug, clap::Args)]
+pub struct HistoryArgs {
+    /// Directory of dated snapshots and deltas, as used by prune-snapshots
+    pub dir: PathBuf,
+
+    /// Absolute chunk coordinates `x,z`
+    #[arg(long, value_parser = crate::parse_coords, allow_hyphen_values = true)]
+    pub chunk: (i32, i32),
+
+    /// Write chunk NBT as of this snapshot, given by name or date, instead of listing changes
+    #[arg(long, value_name = "SNAPSHOT", requires = "output")]
+    pub extract: Option<String>,
+
+    /// Uncompressed NBT file for --extract, e.g. `c.<x>.<z>.nbt` to restore it with implode
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+/// Archive of entry holding region. Single archive entries are taken to be of the right region
+fn region_archive(entry: &Entry, region: (i32, i32)) -> anyhow::Result<Option<PathBuf>> {
+    if !entry.path.is_dir() {
+        return Ok(Some(entry.path.clone()));
+    }
+    let archive = delta::archives(&entry.path)?
+        .into_iter()
+        .find(|x| region::region_coords_from_path(x) == Some(region));
+    Ok(archive.map(|x| entry.path.join(x)))
+}
+
+/// Chunk as of entry. `None` if the entry does not touch it: a delta without change or a snapshot without its region
+fn chunk_at(entry: &Entry, region: (i32, i32), pos: u16) -> anyhow::Result<Option<Option<Chunk>>> {
+    let Some(path) = region_archive(entry, region)? else {
+        return Ok((!entry.delta).then_some(None));
+    };
+    Ok(match entry.delta {
+        true => delta::read_delta(&path)?.remove(&pos),
+        false => Some(delta::read_snapshot(&path)?.remove(&pos)),
+    })
+}
+
+fn name(path: &Path) -> String {
+    path.file_name().unwrap().to_string_lossy().into_owned()
+}
+
+/// Lists snapshots where the chunk changed, walking the chain from the oldest one
+pub fn run(args: HistoryArgs) -> anyhow::Result<()> {
+    let (x, z) = args.chunk;
+    let region = (x.div_euclid(32), z.div_euclid(32));
+    let pos = (z.rem_euclid(32) * 32 + x.rem_euclid(32)) as u16;
+
+    let entries = prune::read_entries(&args.dir)?;
+    if let Some(wanted) = &args.extract {
+        let end = entries
+            .iter()
+            .position(|x| name(&x.path) == *wanted || name(&x.path).starts_with(wanted.as_str()))
+            .with_context(|| format!("No snapshot named {wanted}"))?;
+        let start = entries[..=end].iter().rposition(|x| !x.delta).unwrap_or_default();
+
+        let mut chunk = None;
+        for entry in &entries[start..=end] {
+            if let Some(change) = chunk_at(entry, region, pos)? {
+                chunk = change;
+            }
+        }
+        let chunk = chunk.with_context(|| format!("Chunk {x},{z} does not exist in {wanted}"))?;
+        let output = args.output.unwrap();
+        std::fs::write(&output, &chunk.nbt).with_context(|| format!("Unable to write {}", output.display()))?;
+        return Ok(());
+    }
+
+    ensure!(!entries.is_empty(), "No snapshots in {}", args.dir.display());
+    let mut current = None;
+    let mut changes = 0;
+    for entry in &entries {
+        let Some(chunk) = chunk_at(entry, region, pos)? else { continue };
+        let status = match (&current, &chunk) {
+            (None, None) => continue,
+            (Some(old), Some(new)) if old == new => continue,
+            (None, Some(_)) => "added",
+            (Some(_), Some(_)) => "changed",
+            (Some(_), None) => "removed",
+        };
+        match &chunk {
+            Some(chunk) => println!(
+                "{:<24} {status:<8} timestamp {:<11} {} bytes",
+                name(&entry.path),
+                chunk.timestamp,
+                chunk.nbt.len()
+            ),
+            None => println!("{:<24} {status}", name(&entry.path)),
+        }
+        current = chunk;
+        changes += 1;
+    }
+
+    if changes == 0 {
+        eprintln!("Chunk {x},{z} does not exist in any snapshot");
+    }
+    Ok(())
+}
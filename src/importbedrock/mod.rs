@@ -0,0 +1,61 @@
+use std::{io::BufWriter, path::PathBuf};
+
+use anyhow::{ensure, Context};
+
+use crate::{bedrock, nbt, rpack};
+
+#[derive(Debug, clap::Args)]
+pub struct ImportBedrockArgs {
+    /// Bedrock world directory containing `db`
+    #[arg(short, long)]
+    pub input: PathBuf,
+
+    /// Output directory. Archives are written as `<dimension>/region/r.<x>.<z>.rpack`
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// Compression of chunks in archives
+    #[arg(long, value_enum, default_value_t = rpack::Compression::Zstd)]
+    pub codec: rpack::Compression,
+
+    /// Compression level
+    #[arg(long, default_value_t = 3)]
+    pub level: i32,
+}
+
+/// Writes every region of Bedrock world into an rpack archive. Timestamps are zero as Bedrock does not store them.
+pub fn run(args: ImportBedrockArgs) -> anyhow::Result<()> {
+    let db = args.input.join("db");
+    ensure!(db.is_dir(), "{} is not a directory", db.display());
+
+    let regions = bedrock::regions(&bedrock::leveldb::read_db(&db)?);
+
+    let mut chunks = 0usize;
+    let mut payload = vec![];
+    for (region, slots) in regions.iter() {
+        let dir = args.output.join(region.directory());
+        std::fs::create_dir_all(&dir).with_context(|| format!("Unable to create {}", dir.display()))?;
+
+        let path = dir.join(format!("r.{}.{}.rpack", region.x, region.z));
+        let file = std::fs::File::create(&path).with_context(|| format!("Unable to create {}", path.display()))?;
+        let options = rpack::Options {
+            compression: args.codec,
+            level: args.level,
+            ..Default::default()
+        };
+        let mut writer = rpack::RpackWriter::new(BufWriter::new(file), options)?;
+
+        for (&pos, root) in slots.iter() {
+            payload.clear();
+            nbt::write_compound(&mut payload, root)?;
+            writer.write_chunk(pos, 0, &payload)?;
+        }
+        writer.finish()?.into_inner().map_err(|x| x.into_error())?;
+
+        chunks += slots.len();
+        println!("{}: {} chunks", path.display(), slots.len());
+    }
+
+    println!("Total: {} regions, {chunks} chunks", regions.len());
+    Ok(())
+}
@@ -0,0 +1,87 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use anyhow::Context;
+
+use crate::{
+    chunk,
+    meta::RegionMeta,
+    nbt,
+    region::{RegionInfo, RegionReader},
+    world,
+};
+
+#[derive(Debug, clap::Args)]
+pub struct InspectArgs {
+    /// Region file or directory to search region files in
+    #[arg(short, long)]
+    pub input: PathBuf,
+
+    /// Print distribution of chunk DataVersion
+    #[arg(long)]
+    pub data_versions: bool,
+
+    /// Fail on first chunk which DataVersion is lower than specified
+    #[arg(long, value_name = "N")]
+    pub require_min_dataversion: Option<i32>,
+
+    /// Print metadata of every region as a JSON line instead of text
+    #[arg(long, conflicts_with_all = ["data_versions", "require_min_dataversion"])]
+    pub json: bool,
+}
+
+pub fn run(args: InspectArgs) -> anyhow::Result<()> {
+    let files = world::region_files(&args.input)?;
+
+    if args.json {
+        for file in files.iter() {
+            println!("{}", serde_json::to_string(&RegionMeta::read(file)?)?);
+        }
+        return Ok(());
+    }
+
+    let need_chunks = args.data_versions || args.require_min_dataversion.is_some();
+
+    let mut total_chunks = 0usize;
+    // None key counts chunks without DataVersion
+    let mut data_versions = BTreeMap::<Option<i32>, usize>::new();
+
+    for file in files.iter() {
+        let mut reader = RegionReader::open(file)?;
+
+        let chunks = reader.info().chunk_infos().len();
+        total_chunks += chunks;
+        println!("{}: {chunks} chunks", file.display());
+
+        if !need_chunks {
+            continue;
+        }
+
+        reader
+            .decompress_all(|_, pos, data| {
+                let (x, z) = RegionInfo::local_coords(pos);
+                let root = nbt::read_compound(data).with_context(|| format!("Chunk {x},{z}"))?;
+
+                if let Some(min) = args.require_min_dataversion {
+                    chunk::require_min_data_version(&root, min).with_context(|| format!("Chunk {x},{z}"))?;
+                }
+
+                *data_versions.entry(chunk::data_version(&root)).or_default() += 1;
+                Ok(())
+            })
+            .with_context(|| format!("{}", file.display()))?;
+    }
+
+    println!("Total: {} regions, {total_chunks} chunks", files.len());
+
+    if args.data_versions {
+        println!("DataVersion distribution:");
+        for (version, count) in data_versions.iter() {
+            match version {
+                Some(version) => println!("  {version:>6}: {count}"),
+                None => println!("  {:>6}: {count}", "none"),
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,37 @@
+//! Without the default `std` feature only [`format`], [`error`] and [`limits`] are built, on `core` and `alloc`.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "bedrock")]
+pub mod bedrock;
+#[cfg(feature = "std")]
+pub mod audit;
+#[cfg(feature = "std")]
+pub mod chunk;
+pub mod error;
+#[cfg(feature = "std")]
+pub mod feed;
+pub mod format;
+pub mod limits;
+#[cfg(feature = "std")]
+pub mod meta;
+#[cfg(feature = "std")]
+pub mod nbt;
+#[cfg(feature = "std")]
+pub mod query;
+#[cfg(feature = "std")]
+pub mod region;
+#[cfg(feature = "std")]
+pub mod rpack;
+#[cfg(feature = "std")]
+pub mod schematic;
+#[cfg(feature = "std")]
+pub mod scratch;
+#[cfg(feature = "std")]
+pub mod timings;
+#[cfg(feature = "std")]
+pub mod world;
+#[cfg(feature = "std")]
+pub mod testutil;
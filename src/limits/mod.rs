@@ -0,0 +1,39 @@
+/// Bounds applied when parsing untrusted input.
+/// [`Limits::STRICT`] is the default, so user-uploaded files can not make parser panic or allocate unbounded memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Max size of a single decompressed chunk. Also bounds uncompressed chunks in compacted stream
+    pub max_decompressed_size: u64,
+    /// Fail on header entries pointing into the header, beyond max region size or overlapping other chunks.
+    /// Otherwise such entries are skipped like the game does.
+    pub strict_header: bool,
+    /// Keep data of zlib and gzip chunks not matching the checksum in their trailer instead of failing,
+    /// see [`crate::region::RegionReader::checksum_mismatches`]
+    pub ignore_checksums: bool,
+    /// Retry chunks failing to decompress with the other built-in codecs, see
+    /// [`crate::region::RegionReader::codec_corrections`]
+    pub sniff_codecs: bool,
+}
+
+impl Limits {
+    pub const STRICT: Self = Self {
+        max_decompressed_size: 128 * 1024 * 1024,
+        strict_header: true,
+        ignore_checksums: false,
+        sniff_codecs: false,
+    };
+
+    /// For recovering data from damaged files. Still never allocates unbounded memory
+    pub const RELAXED: Self = Self {
+        max_decompressed_size: 1024 * 1024 * 1024,
+        strict_header: false,
+        ignore_checksums: false,
+        sniff_codecs: false,
+    };
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self::STRICT
+    }
+}
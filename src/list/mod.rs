@@ -0,0 +1,175 @@
+use std::{
+    cmp::Reverse,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use crate::{
+    manifest::{self, Entry},
+    nbt::{Compound, Tag},
+    query::Query,
+    region::{self, RegionInfo},
+    rpack,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SortKey {
+    Path,
+    Size,
+    StoredSize,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct ListArgs {
+    /// Directory of archives written in directory mode, or its manifest file
+    pub input: PathBuf,
+
+    /// Predicate over entry fields `kind`, `dimension`, `dim` (dimension without `minecraft:`), `path`, `size`
+    /// and `stored_size`, plus a tag named by kind, e.g. `region && dim == "overworld"`.
+    /// With --chunks also over `x`, `z`, `timestamp` and `chunk_size`
+    #[arg(short, long)]
+    pub filter: Option<String>,
+
+    /// List chunks of region entries instead of entries. Reads their archives
+    #[arg(long)]
+    pub chunks: bool,
+
+    /// Order of listing, sizes largest first
+    #[arg(long, value_enum, default_value_t = SortKey::Path)]
+    pub sort: SortKey,
+}
+
+fn kind_name(entry: &Entry) -> String {
+    serde_json::to_value(entry.kind)
+        .ok()
+        .and_then(|x| x.as_str().map(str::to_owned))
+        .unwrap_or_default()
+}
+
+/// Fields of entry the filter sees
+fn fields(entry: &Entry) -> Compound {
+    let kind = kind_name(entry);
+    let mut root = Compound::default();
+    root.insert(kind.clone(), Tag::Byte(1));
+    root.insert("kind", Tag::String(kind));
+    if let Some(dimension) = &entry.dimension {
+        let short = dimension.strip_prefix("minecraft:").unwrap_or(dimension);
+        root.insert("dim", Tag::String(short.to_owned()));
+        root.insert("dimension", Tag::String(dimension.clone()));
+    }
+    root.insert("path", Tag::String(entry.path.to_string()));
+    root.insert("size", Tag::Long(entry.size as i64));
+    root.insert("stored_size", Tag::Long(entry.stored_size as i64));
+    root
+}
+
+/// Calls `f` with slot, timestamp and payload size of every chunk of archive
+fn read_chunks(path: &Path, mut f: impl FnMut(u16, u32, usize)) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(rpack::volume::open(path)?);
+    let mut payload = vec![];
+    while reader.fill_buf()?.starts_with(&rpack::MAGIC) {
+        let mut rpackreader = rpack::RpackReader::from_buf_reader(reader, Default::default())?;
+        while let Some(chunk) = rpackreader.read_chunk(&mut payload)? {
+            if !chunk.deleted {
+                f(chunk.pos, chunk.timestamp, payload.len());
+            }
+        }
+        reader = rpackreader.into_inner()?;
+    }
+    Ok(())
+}
+
+pub fn run(args: ListArgs) -> anyhow::Result<()> {
+    let query = args.filter.as_deref().map(Query::parse).transpose().context("Invalid filter")?;
+    let manifest = manifest::read(&args.input)?;
+    let dir = match args.input.is_dir() {
+        true => args.input.clone(),
+        false => args.input.parent().map(PathBuf::from).unwrap_or_default(),
+    };
+
+    let mut entries = manifest.entries;
+    match args.sort {
+        SortKey::Path => entries.sort_by(|a, b| a.path.cmp(&b.path)),
+        SortKey::Size => entries.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path))),
+        SortKey::StoredSize => entries.sort_by(|a, b| b.stored_size.cmp(&a.stored_size).then_with(|| a.path.cmp(&b.path))),
+    }
+
+    let (mut count, mut size, mut stored_size) = (0usize, 0u64, 0u64);
+    let mut chunks = vec![];
+    for entry in entries.iter() {
+        let fields = fields(entry);
+        if !args.chunks {
+            if query.as_ref().is_some_and(|x| !x.matches(&fields)) {
+                continue;
+            }
+            println!(
+                "  {:<12} {:<24} {:>12} {:>12} {}",
+                kind_name(entry),
+                entry.dimension.as_deref().unwrap_or("-"),
+                entry.size,
+                entry.stored_size,
+                entry.path
+            );
+            (count, size, stored_size) = (count + 1, size + entry.size, stored_size + entry.stored_size);
+            continue;
+        }
+
+        if !matches!(entry.kind, manifest::Kind::Region | manifest::Kind::Entities | manifest::Kind::Poi) {
+            continue;
+        }
+        let region = region::region_coords_from_path(entry.path.to_path());
+        read_chunks(&dir.join(entry.stored_path.to_path()), |pos, timestamp, chunk_size| {
+            let (x, z) = RegionInfo::chunk_coords(region, pos);
+            let mut fields = fields.clone();
+            fields.insert("x", Tag::Int(x));
+            fields.insert("z", Tag::Int(z));
+            fields.insert("timestamp", Tag::Long(timestamp.into()));
+            fields.insert("chunk_size", Tag::Long(chunk_size as i64));
+            if query.as_ref().is_none_or(|x| x.matches(&fields)) {
+                chunks.push((x, z, timestamp, chunk_size, &entry.path));
+            }
+        })
+        .with_context(|| format!("Unable to read {}", entry.stored_path))?;
+    }
+
+    // Chunks are stored uncompressed or as in the region file, so both size keys order by payload size
+    if args.sort != SortKey::Path {
+        chunks.sort_by_key(|x| Reverse(x.3));
+    }
+    for (x, z, timestamp, chunk_size, path) in chunks {
+        println!("  {:>12} {timestamp:>11} {chunk_size:>12} {path}", format!("{x},{z}"));
+        (count, size) = (count + 1, size + chunk_size as u64);
+    }
+
+    match args.chunks {
+        true => println!("{count} chunks, {size} bytes"),
+        false => println!("{count} entries, {size} bytes, {stored_size} bytes stored"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_fields() {
+        let entry = Entry {
+            kind: manifest::Kind::Region,
+            dimension: Some("minecraft:overworld".into()),
+            path: "region/r.0.0.mca".into(),
+            stored_path: "region/r.0.0.mca.rpack".into(),
+            size: 8192,
+            stored_size: 100,
+            stored_crc32: 0,
+            target: None,
+            perms: None,
+        };
+        let matches = |filter: &str| Query::parse(filter).unwrap().matches(&fields(&entry));
+        assert!(matches(r#"region && dim == "overworld""#));
+        assert!(matches(r#"dimension == "minecraft:overworld" && size > 4096"#));
+        assert!(!matches("passthrough || stored_size > 100"));
+    }
+}
@@ -1,48 +1,573 @@
 use std::{
-    io::{stdin, stdout, BufReader, BufWriter, Read, Seek, Write},
+    io::{stdin, stdout, BufReader, BufWriter, Read, Write},
+    num::NonZeroUsize,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use anyhow::{anyhow, bail, ensure, Context};
-use chunk::ChunkData;
 use clap::Parser;
-use flate2::Compression;
-use region::{ChunkInfo, RegionInfo, RegionReader};
+use region::{RegionFormat, RegionInfo, RegionReader};
+use rpack::{ArchiveFormat, CancellationToken, Cancelled, ChunkCallback, ChunkEvent, DedupePos, PosCheck, RatioLimits};
 use tap::Pipe;
-use zerocopy::{
-    BigEndian, FromBytes, FromZeros, Immutable, IntoBytes, LittleEndian, TryFromBytes, U32, U64
-};
 
-mod chunk;
-mod region;
+#[cfg(feature = "bedrock")]
+use anvilregion_repacker::bedrock;
+use anvilregion_repacker::{
+    audit, chunk,
+    error::{self, ErrorCode},
+    limits::Limits,
+    meta, nbt, query, region, rpack, schematic, timings, world,
+};
+#[cfg(test)]
+use anvilregion_repacker::testutil;
 
-#[derive(Debug, Clone, FromBytes, IntoBytes, Immutable)]
-#[repr(C)]
-struct BinHeader {
-    pub pos: U32<LittleEndian>,
-    pub timestamp: U32<BigEndian>,
-    pub length: U64<LittleEndian>,
-}
+mod applyundo;
+mod batch;
+mod cat;
+mod container;
+mod defrag;
+mod delta;
+mod deletechunks;
+mod explode;
+mod export;
+mod extract;
+mod find;
+mod history;
+#[cfg(feature = "bedrock")]
+mod importbedrock;
+mod inspect;
+mod list;
+mod manifest;
+#[cfg(all(feature = "fuse", target_os = "linux"))]
+mod mount;
+mod prune;
+mod render;
+mod repair;
+mod scan;
+#[cfg(unix)]
+mod serve;
+mod session;
+mod stats;
+mod test;
+mod touchchunks;
+mod transcode;
+mod transform;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+mod uring;
+mod worldstats;
 
 #[derive(Debug, Parser)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
-    /// Input file
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Input file. A directory is processed recursively into the output directory, as is a tar or zip archive
+    /// of one when compacting
     #[arg(short, long)]
     pub input: Option<PathBuf>,
 
-    /// Output file. Required for decompacting
+    /// Output file. Required for decompacting and for directory input
     #[arg(short, long)]
     pub output: Option<PathBuf>,
 
+    /// Number of files processed at once with directory input. Defaults to the number of CPUs
+    #[arg(short, long)]
+    pub jobs: Option<NonZeroUsize>,
+
+    /// Stop directory processing at the first failed file instead of continuing with the rest
+    #[arg(long)]
+    pub fail_fast: bool,
+
+    /// Report every finished file of directory mode as `[N/TOTAL]` line, in the order files were started
+    #[arg(long)]
+    pub progress: bool,
+
+    /// Print what directory input would be processed into, with sizes read from region headers, and stop
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Restore a directory even if the filesystem of the output has less free space than the manifest says it needs
+    #[arg(long)]
+    pub force: bool,
+
+    /// Print time spent reading, decompressing, compressing and writing, with a hint which options may help
+    #[arg(long)]
+    pub timings: bool,
+
     #[arg(short)]
     pub compact: bool,
 
     #[arg(short)]
     pub decompact: bool,
+
+    /// Check that chunk position stored in NBT matches its header slot when compacting
+    #[arg(long)]
+    pub check_pos: bool,
+
+    /// Like --check-pos, but also rewrite mismatched NBT position to match the header slot
+    #[arg(long)]
+    pub fix_pos: bool,
+
+    /// What to do when archive contains several chunks for the same position when decompacting
+    #[arg(long, value_enum, default_value_t = DedupePos::Error)]
+    pub dedupe_pos: DedupePos,
+
+    /// Re-open region file after decompacting and check it is valid for the game
+    #[arg(long)]
+    pub validate_output: bool,
+
+    /// Fail if any chunk has DataVersion lower than specified
+    #[arg(long, value_name = "N")]
+    pub require_min_dataversion: Option<i32>,
+
+    /// Refuse to write chunks with DataVersion higher than N when decompacting, e.g. the DataVersion
+    /// of the server the world is restored for. Older servers silently downgrade newer chunks and corrupt them
+    #[arg(long, value_name = "N")]
+    pub target_dataversion: Option<i32>,
+
+    /// Warn about chunks newer than --target-dataversion and write them anyway instead of failing
+    #[arg(long, requires = "target_dataversion")]
+    pub warn_newer_dataversion: bool,
+
+    /// Generate minimal chunks for empty slots when decompacting, so the region has no gaps.
+    /// The output must be named like r.<x>.<z>.mca
+    #[arg(long, value_enum, value_name = "KIND")]
+    pub fill_missing: Option<chunk::Filler>,
+
+    /// Check every chunk is a well-formed NBT compound before archiving it, so corrupt chunks
+    /// are found at backup time rather than at restore time
+    #[arg(long)]
+    pub check_nbt: bool,
+
+    /// Apply built-in migrations renaming and moving chunk tags when compacting, like the `Level` wrapper
+    /// removal of 1.18. Only chunks of DataVersions the game has no other fixes for are rewritten
+    #[arg(long)]
+    pub migrate: bool,
+
+    /// Leave out chunks fully outside the world border in level.dat of every world when compacting a directory.
+    /// The nether border is centered at 1/8 of the overworld coordinates, mod dimensions are packed whole
+    #[arg(long, conflicts_with = "border_size")]
+    pub prune_border: bool,
+
+    /// Center `x,z` in blocks of the world border to leave out chunks outside of, 0,0 by default
+    #[arg(long, value_name = "X,Z", value_parser = parse_coords, allow_hyphen_values = true, requires = "border_size")]
+    pub border_center: Option<(i32, i32)>,
+
+    /// Side length in blocks of the world border to leave out chunks outside of when compacting,
+    /// instead of the one in level.dat. Applies to every world of directory input
+    #[arg(long, value_name = "BLOCKS")]
+    pub border_size: Option<f64>,
+
+    /// JSON file of settings by dimension overriding the command line when compacting a world directory, like
+    /// `{"the_end": {"codec": "zstd", "level": 19, "prune_border": true}}`. Settings are codec, level, solid, raw,
+    /// migrate, check_nbt and prune_border
+    #[arg(long, value_name = "FILE")]
+    pub profiles: Option<PathBuf>,
+
+    /// Append a JSON line to FILE for every chunk dropped by --prune-border or changed by --migrate or --fix-pos,
+    /// with its coordinates and sizes before and after
+    #[arg(long, value_name = "FILE")]
+    pub audit_log: Option<PathBuf>,
+
+    /// Write chunks left out by --prune-border, --border-size or prune_border of --profiles into an archive at PATH
+    /// for `apply-undo`. With directory input PATH is a directory getting an archive for every region file with
+    /// chunks left out, at the place of its archive in the output
+    #[arg(long, value_name = "PATH")]
+    pub undo: Option<PathBuf>,
+
+    /// Walk into symlinked directories of directory input when compacting. Links to a directory above them are skipped
+    #[arg(long, conflicts_with = "preserve_links")]
+    pub follow_symlinks: bool,
+
+    /// Keep symlinks of directory input as links in the manifest instead of reading them, and store hard linked
+    /// files once. Both are recreated when decompacting
+    #[arg(long)]
+    pub preserve_links: bool,
+
+    /// Record POSIX mode, owner and group of files of directory input in the manifest. When decompacting, restore
+    /// them onto created region files and passthrough files, ownership only when running as root
+    #[arg(long)]
+    pub preserve_perms: bool,
+
+    /// Copy other files of directory input, like level.dat and playerdata, unchanged into the output directory.
+    /// Works both ways, so a whole world is packed and restored
+    #[arg(long)]
+    pub passthrough: bool,
+
+    /// Leave out other files matching GLOB relative to the input directory, like `logs/**`.
+    /// `*` and `?` stay within a directory, `**` crosses them
+    #[arg(long, value_name = "GLOB")]
+    pub skip: Vec<String>,
+
+    /// Copy other files matching GLOB even if skipped or without --passthrough, like `datapacks/**`
+    #[arg(long, value_name = "GLOB")]
+    pub include: Vec<String>,
+
+    /// Store other files compressed with --codec and --level instead of copying them, each with its own header.
+    /// Restoring decompresses them without the flag
+    #[arg(long)]
+    pub compress_passthrough: bool,
+
+    /// Store other files with the same content, like identical playerdata or stats, only once when compacting.
+    /// The manifest lists the others, restoring copies them back
+    #[arg(long)]
+    pub dedupe_passthrough: bool,
+
+    /// Read archive back after compacting and compare every chunk with the region file
+    #[arg(long)]
+    pub verify: bool,
+
+    /// Archive zlib and gzip chunks whose data does not match the checksum in their trailer, warning about each,
+    /// instead of failing. For best-effort recovery of damaged worlds. --raw archives chunks unchecked anyway
+    #[arg(long)]
+    pub ignore_crc: bool,
+
+    /// Retry chunks failing to decompress with the other built-in codecs, for chunks whose compression type was
+    /// written wrong by other tools, like zlib data marked as gzip. Every chunk decoded this way is reported
+    #[arg(long)]
+    pub codec_sniff: bool,
+
+    /// Remove region file after its archive is written and synced to disk (and verified with --verify)
+    #[arg(long, requires = "output")]
+    pub delete_source: bool,
+
+    /// Allow output inside the input directory, like restoring a world next to its archives.
+    /// Otherwise refused, so a batch job can not overwrite its own input
+    #[arg(long)]
+    pub allow_in_place: bool,
+
+    /// Guarantee inputs are only opened for reading: --delete-source, --allow-in-place and subcommands
+    /// changing files in place are refused
+    #[arg(long, global = true)]
+    pub read_only: bool,
+
+    /// Make sure output reached stable storage before reporting success. --delete-source implies at least `file`
+    #[arg(long, value_enum, default_value_t = Fsync::None)]
+    pub fsync: Fsync,
+
+    /// Leave sector padding and zeroed sectors of replaced chunks as holes when decompacting.
+    /// Saves disk space on filesystems with sparse file support, mostly with large --sector-size
+    #[arg(long)]
+    pub sparse: bool,
+
+    /// Report chunks decompressing to more than RATIO times the sectors they occupy, a possible zip bomb
+    #[arg(long, value_name = "RATIO", default_value_t = RatioLimits::default().chunk)]
+    pub max_chunk_ratio: f64,
+
+    /// Report compressed archives larger than RATIO times the region file. Not checked without --codec
+    #[arg(long, value_name = "RATIO", default_value_t = RatioLimits::default().archive)]
+    pub max_archive_ratio: f64,
+
+    /// Fail instead of warning when --max-chunk-ratio or --max-archive-ratio is exceeded
+    #[arg(long)]
+    pub strict_ratio: bool,
+
+    /// Compression of chunks in archive when compacting
+    #[arg(long, value_enum, default_value_t = rpack::Compression::None)]
+    pub codec: rpack::Compression,
+
+    /// Compression level
+    #[arg(long, default_value_t = 3)]
+    pub level: i32,
+
+    /// Compress all chunks as a single stream. Smaller, but chunks can not be decoded separately
+    #[arg(long)]
+    pub solid: bool,
+
+    /// Restart the --solid stream at content-defined boundaries, so a small world change gives a small binary diff
+    /// between archives. Slightly larger, but rsync and deduplicating backup tools transfer and store much less
+    #[arg(long, requires = "solid")]
+    pub rolling: bool,
+
+    /// Store CRC32 of every chunk in archive. Verified when decompacting
+    #[arg(long)]
+    pub checksums: bool,
+
+    /// Split archive into volumes `<OUTPUT>.001`, `<OUTPUT>.002`, ... of at most SIZE bytes, e.g. `4G`.
+    /// Decompacting the first volume or OUTPUT itself reads all volumes
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, requires = "output")]
+    pub split_size: Option<u64>,
+
+    /// Store chunks compressed as they are in region file instead of recompressing NBT.
+    /// Much faster and still drops sector padding, but chunk checks can not be used
+    #[arg(long)]
+    pub raw: bool,
+
+    /// Zstd dictionary to store in archive and compress every chunk with
+    #[arg(long, value_name = "FILE")]
+    pub dictionary: Option<PathBuf>,
+
+    /// Compression of chunks in region file when decompacting, zlib by default. Chunks of --raw archives
+    /// are kept as they are unless this is set, then only chunks already using it are copied without recompression
+    #[arg(long, value_enum)]
+    pub region_codec: Option<RegionCodecArg>,
+
+    /// Format of archives read when decompacting. Streams written before rpack archives are detected by default
+    #[arg(long, value_enum, default_value_t = ArchiveFormat::Auto)]
+    pub from: ArchiveFormat,
+
+    /// Layout of region file. Detected by region file extension by default
+    #[arg(long, value_enum, default_value_t = FormatArg::Auto)]
+    pub region_format: FormatArg,
+
+    /// Override sector size of region files used by some forks
+    #[arg(long, value_name = "BYTES")]
+    pub sector_size: Option<u64>,
+
+    /// Override size of region header including space reserved after location and timestamp tables
+    #[arg(long, value_name = "BYTES")]
+    pub header_size: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Fsync {
+    /// Leave it to the OS
+    #[default]
+    None,
+    /// Sync output file contents
+    File,
+    /// Also sync directory holding the output, so its entry survives a crash
+    Dir,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum RegionCodecArg {
+    Gzip,
+    Zlib,
+    Uncompressed,
+}
+
+impl From<RegionCodecArg> for chunk::Codec {
+    fn from(value: RegionCodecArg) -> Self {
+        match value {
+            RegionCodecArg::Gzip => chunk::Codec::GZip,
+            RegionCodecArg::Zlib => chunk::Codec::Zlib,
+            RegionCodecArg::Uncompressed => chunk::Codec::Uncompressed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum FormatArg {
+    Auto,
+    Vanilla,
+    CubicChunks2d,
+    CubicChunks3d,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Print information about region files
+    Inspect(inspect::InspectArgs),
+
+    /// Summarize region files reading only their headers
+    Scan(scan::ScanArgs),
+
+    /// Summarize every dimension of a world reading only region headers
+    WorldStats(worldstats::WorldStatsArgs),
+
+    /// Collect statistics over chunk contents
+    Stats(stats::StatsArgs),
+
+    /// Print coordinates of chunks matching a query
+    Find(find::FindArgs),
+
+    /// Write every chunk of region into separate uncompressed NBT file
+    Explode(explode::ExplodeArgs),
+
+    /// Assemble region file from directory made by explode
+    Implode(explode::ImplodeArgs),
+
+    /// Export a box of blocks into a schematic or structure file
+    Export(export::ExportArgs),
+
+    /// Draw a PNG map of chunks shaded by presence, InhabitedTime or age
+    Render(render::RenderArgs),
+
+    /// Merge rpack archives into one without recompressing
+    Cat(cat::CatArgs),
+
+    /// Write archive of chunks changed since an older snapshot, plus removals
+    Delta(delta::DeltaArgs),
+
+    /// Rebuild full archive from an older snapshot and a delta made against it
+    ApplyDelta(delta::ApplyDeltaArgs),
+
+    /// Thin out a directory of dated snapshots and deltas, merging dropped deltas into kept ones
+    PruneSnapshots(prune::PruneArgs),
+
+    /// List snapshots where a chunk changed, or extract one of its versions
+    History(history::HistoryArgs),
+
+    /// Print manifest of a world packed in directory mode without unpacking it
+    Manifest(manifest::ManifestArgs),
+
+    /// List entries or chunks of a world packed in directory mode, like `tar -tv`
+    List(list::ListArgs),
+
+    /// Extract chunks around a point from a world packed in directory mode into a playable partial world
+    ExtractArea(extract::ExtractAreaArgs),
+
+    /// Decode every chunk of archives without writing anything, like `gzip -t`
+    Test(test::TestArgs),
+
+    /// Salvage complete chunks of a damaged archive into a new one
+    Repair(repair::RepairArgs),
+
+    /// Delete chunks from a region file in place, so the game generates them again
+    DeleteChunks(deletechunks::DeleteChunksArgs),
+
+    /// Put chunks saved by `delete-chunks --undo` back into their region file
+    ApplyUndo(applyundo::ApplyUndoArgs),
+
+    /// Rewrite a region file in place without gaps between chunks, safe against crashes
+    Defrag(defrag::DefragArgs),
+
+    /// Set header timestamps of chunks without touching their data, protecting them from pruners going by age
+    TouchChunks(touchchunks::TouchChunksArgs),
+
+    /// Convert archive to the current version, another codec or layout without going through a region file
+    Transcode(transcode::TranscodeArgs),
+
+    /// Rotate or mirror a whole region about its center, turning blocks with it
+    Transform(transform::TransformArgs),
+
+    /// Mount archives read-only as a directory of region files, until unmounted
+    #[cfg(all(feature = "fuse", target_os = "linux"))]
+    Mount(mount::MountArgs),
+
+    /// Run as a daemon accepting compact/decompact/verify jobs over a Unix socket
+    #[cfg(unix)]
+    Serve(serve::ServeArgs),
+
+    /// Convert Bedrock Edition world into rpack archives
+    #[cfg(feature = "bedrock")]
+    ImportBedrock(importbedrock::ImportBedrockArgs),
+}
+
+#[derive(Debug, Clone, Default)]
+struct DecompactOptions {
+    pub decode: rpack::DecodeOptions,
+    pub validate_output: bool,
+    pub fsync: Fsync,
+}
+
+#[derive(Debug, Clone, Default)]
+struct CompactOptions {
+    pub encode: rpack::EncodeOptions,
+    pub verify: bool,
+    pub delete_source: bool,
+    pub fsync: Fsync,
+    /// Max size of archive volume, see [`rpack::volume`]
+    pub split_size: Option<u64>,
+    /// Archive of chunks left out by the border, see [`write_pruned`]. A directory in directory mode
+    pub undo: Option<PathBuf>,
+}
+
+/// Prints warnings about chunks to stderr
+fn print_warnings() -> ChunkCallback {
+    ChunkCallback(Arc::new(|event| {
+        if let ChunkEvent::Warning { pos, message } = event {
+            let (x, z) = RegionInfo::local_coords(pos);
+            eprintln!("Chunk {x},{z}: {message}");
+        }
+    }))
+}
+
+/// Prints the value when dropped, also after failures
+struct PrintOnDrop<T: std::fmt::Display>(T);
+
+impl<T: std::fmt::Display> Drop for PrintOnDrop<T> {
+    fn drop(&mut self) {
+        eprint!("{}", self.0);
+    }
+}
+
+/// Prints chunks left out or changed by [`rpack::encode_region`]
+fn print_encoded(encoded: &rpack::Encoded) {
+    if encoded.migrated > 0 {
+        eprintln!("Migrated {} chunks", encoded.migrated);
+    }
+    if encoded.pruned > 0 {
+        eprintln!("Pruned {} chunks outside world border", encoded.pruned);
+    }
+}
+
+/// Parses coordinates `x,z`
+fn parse_coords(s: &str) -> anyhow::Result<(i32, i32)> {
+    let coords = s
+        .split(',')
+        .map(|x| x.trim().parse::<i32>())
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid coordinates {s:?}"))?;
+    match coords[..] {
+        [x, z] => Ok((x, z)),
+        _ => bail!("Expected two coordinates x,z, got {s:?}"),
+    }
+}
+
+/// Parses size with optional binary suffix: `4096`, `512K`, `100M`, `4G`
+fn parse_size(s: &str) -> anyhow::Result<u64> {
+    let (number, shift) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 30),
+        Some((i, 'T' | 't')) => (&s[..i], 40),
+        _ => (s, 0),
+    };
+    let size = number.parse::<u64>().with_context(|| format!("Invalid size {s}"))?;
+    let size = size.checked_shl(shift).filter(|x| x >> shift == size).with_context(|| format!("Size {s} is too large"))?;
+    ensure!(size > 0, "Size must be positive");
+    Ok(size)
 }
 
 fn main() -> anyhow::Result<()> {
-    let args = Cli::parse();
+    // Code of the error is printed first, see [`error::ErrorCode`]
+    run(Cli::parse()).map_err(error::with_code)
+}
+
+fn run(args: Cli) -> anyhow::Result<()> {
+    if let Some(command) = args.command {
+        if args.read_only {
+            check_read_only(&command)?;
+        }
+        return match command {
+            Command::Inspect(args) => inspect::run(args),
+            Command::Scan(args) => scan::run(args),
+            Command::WorldStats(args) => worldstats::run(args),
+            Command::Stats(args) => stats::run(args),
+            Command::Find(args) => find::run(args),
+            Command::Explode(args) => explode::explode(args),
+            Command::Implode(args) => explode::implode(args),
+            Command::Export(args) => export::run(args),
+            Command::Render(args) => render::run(args),
+            Command::Cat(args) => cat::run(args),
+            Command::Delta(args) => delta::run(args),
+            Command::ApplyDelta(args) => delta::apply(args),
+            Command::PruneSnapshots(args) => prune::run(args),
+            Command::History(args) => history::run(args),
+            Command::Manifest(args) => manifest::run(args),
+            Command::List(args) => list::run(args),
+            Command::ExtractArea(args) => extract::run(args),
+            Command::Test(args) => test::run(args),
+            Command::Repair(args) => repair::run(args),
+            Command::DeleteChunks(args) => deletechunks::run(args),
+            Command::ApplyUndo(args) => applyundo::run(args),
+            Command::TouchChunks(args) => touchchunks::run(args),
+            Command::Defrag(args) => defrag::run(args),
+            Command::Transcode(args) => transcode::run(args),
+            Command::Transform(args) => transform::run(args),
+            #[cfg(all(feature = "fuse", target_os = "linux"))]
+            Command::Mount(args) => mount::run(args),
+            #[cfg(unix)]
+            Command::Serve(args) => serve::run(args),
+            #[cfg(feature = "bedrock")]
+            Command::ImportBedrock(args) => importbedrock::run(args),
+        };
+    }
 
     ensure!(
         args.compact != args.decompact || !args.compact,
@@ -52,30 +577,339 @@ fn main() -> anyhow::Result<()> {
         args.compact != args.decompact || args.compact,
         "Operation must be specified!"
     );
+    // Checked here, clap can not check conflicts of a global option with options missing in subcommands
+    ensure!(
+        !args.read_only || !args.delete_source && !args.allow_in_place,
+        "--read-only does not allow --delete-source or --allow-in-place"
+    );
+
+    let region_format = |region_path: Option<&PathBuf>| {
+        let mut format = match args.region_format {
+            FormatArg::Auto => region_path
+                .and_then(|path| region::detect_format(path, &region::providers()))
+                .unwrap_or_default(),
+            FormatArg::Vanilla => RegionFormat::VANILLA,
+            FormatArg::CubicChunks2d => RegionFormat::CUBIC_CHUNKS_2D,
+            FormatArg::CubicChunks3d => RegionFormat::CUBIC_CHUNKS_3D,
+        };
+        format.sector_size = args.sector_size.unwrap_or(format.sector_size);
+        format.header_size = args.header_size.unwrap_or(format.header_size);
+        format.checked()
+    };
+    // Region file is the input when compacting and the output when decompacting
+    let format = region_format(if args.compact { args.input.as_ref() } else { args.output.as_ref() })?;
+    let threads = args
+        .jobs
+        .or_else(|| std::thread::available_parallelism().ok())
+        .unwrap_or(NonZeroUsize::MIN);
+    let passthrough = batch::Passthrough {
+        all: args.passthrough,
+        skip: args.skip,
+        include: args.include,
+        compress: args.compress_passthrough,
+        dedupe: args.dedupe_passthrough,
+    };
+    if let (Some(input), Some(output)) = (&args.input, &args.output) {
+        check_paths(input, output, args.allow_in_place)?;
+    }
+    let _timings = args.timings.then(|| PrintOnDrop(timings::Report::start(args.compact)));
 
     if args.compact {
         let input = args
             .input
             .context("Input file must be specified when compacting")?;
 
-        compact_file(input, args.output)?;
+        let encode = rpack::EncodeOptions {
+            region: region::region_coords_from_path(&input),
+            // Set by compact_file for every file
+            region_path: None,
+            pos_check: match (args.check_pos, args.fix_pos) {
+                (_, true) => PosCheck::Fix,
+                (true, false) => PosCheck::Report,
+                _ => PosCheck::None,
+            },
+            min_data_version: args.require_min_dataversion,
+            check_nbt: args.check_nbt,
+            migrate: args.migrate,
+            // Directory mode finds the border of every file
+            border: args.border_size.map(|size| {
+                let (center_x, center_z) = args.border_center.unwrap_or_default();
+                world::WorldBorder { center_x: center_x as f64, center_z: center_z as f64, size }
+            }),
+            rpack: rpack::Options {
+                compression: args.codec,
+                level: args.level,
+                solid: args.solid,
+                checksums: args.checksums,
+                rolling: args.rolling,
+                raw: args.raw,
+                delta: false,
+                dictionary: args
+                    .dictionary
+                    .map(|path| std::fs::read(&path).with_context(|| format!("Unable to read {}", path.display())))
+                    .transpose()?,
+            },
+            format,
+            ignore_crc: args.ignore_crc,
+            codec_sniff: args.codec_sniff,
+            ratio: RatioLimits {
+                chunk: args.max_chunk_ratio,
+                archive: args.max_archive_ratio,
+                strict: args.strict_ratio,
+            },
+            audit: args.audit_log.as_deref().map(audit::AuditLog::open).transpose()?,
+            on_chunk: Some(print_warnings()),
+            cancel: Default::default(),
+        };
+        let options = CompactOptions {
+            encode,
+            verify: args.verify,
+            delete_source: args.delete_source,
+            fsync: args.fsync,
+            split_size: args.split_size,
+            undo: args.undo.clone(),
+        };
+        ensure!(
+            options.undo.is_none() || args.prune_border || args.border_size.is_some() || args.profiles.is_some(),
+            "--undo saves chunks left out by --prune-border, --border-size or --profiles"
+        );
+        if let Some(undo) = &options.undo {
+            check_paths(&input, undo, args.allow_in_place)?;
+        }
+
+        if input.is_dir() || container::is_container(&input) {
+            let output = args
+                .output
+                .context("Output directory must be specified when compacting a directory")?;
+            let session = session::Session {
+                input,
+                output,
+                border: match args.prune_border {
+                    true => Some(session::Border::LevelDat),
+                    false => options.encode.border.map(session::Border::Fixed),
+                },
+                profiles: args.profiles.as_deref().map(session::read_profiles).transpose()?.unwrap_or_default(),
+                links: match (args.follow_symlinks, args.preserve_links) {
+                    (true, _) => batch::LinkPolicy::Follow,
+                    (_, true) => batch::LinkPolicy::Preserve,
+                    _ => batch::LinkPolicy::Read,
+                },
+                preserve_perms: args.preserve_perms,
+                operation: session::Operation::Compact(options),
+                passthrough,
+                region_format: Box::new(|x| region_format(Some(&x.to_path_buf()))),
+                threads,
+                fail_fast: args.fail_fast,
+                progress: args.progress,
+            };
+            if container::is_container(&session.input) {
+                ensure!(
+                    !args.dry_run && !args.prune_border && session.profiles.is_empty() && !args.preserve_perms,
+                    "--dry-run, --prune-border, --profiles and --preserve-perms need a world directory, not an archive of it"
+                );
+                ensure!(
+                    args.undo.is_none(),
+                    "--undo needs a world directory or region file as input, not an archive"
+                );
+                ensure!(
+                    !args.compress_passthrough && !args.dedupe_passthrough,
+                    "Files of an archive are passed through as they are, without --compress-passthrough or --dedupe-passthrough"
+                );
+                ensure!(
+                    !args.delete_source && args.split_size.is_none(),
+                    "--delete-source and --split-size can not be used with an archive as input"
+                );
+                let report = container::compact(&session)?;
+                report.print();
+                return report.into_result();
+            }
+            let plan = session.plan()?;
+            batch::print_dimensions(&session.input, &plan.jobs[..plan.regions]);
+            if args.dry_run {
+                session::print_worlds(&session.input)?;
+                plan.print();
+                return Ok(());
+            }
+            let report = session.run(plan)?;
+            report.print();
+            return report.into_result();
+        }
+
+        ensure!(!args.prune_border, "--prune-border needs a world directory as input, pass --border-size for a single file");
+        ensure!(args.profiles.is_none(), "--profiles needs a world directory as input");
+        ensure!(!args.preserve_perms, "--preserve-perms needs a directory as input");
+        check_compact_options(&options.encode)?;
+        compact_file(input, args.output, &options)?;
     } else {
         let output = args
             .output
             .context("Output file must be specified when decompacting")?;
+        ensure!(args.audit_log.is_none(), "--audit-log records changes made when compacting");
+        ensure!(args.undo.is_none(), "--undo saves chunks left out when compacting");
+
+        let options = DecompactOptions {
+            decode: rpack::DecodeOptions {
+                dedupe_pos: args.dedupe_pos,
+                format,
+                sparse: args.sparse,
+                codec: args.region_codec.map(Into::into),
+                target_data_version: args.target_dataversion,
+                warn_newer: args.warn_newer_dataversion,
+                fill_missing: args.fill_missing,
+                from: args.from,
+                region_path: None,
+                on_chunk: Some(print_warnings()),
+                cancel: Default::default(),
+            },
+            validate_output: args.validate_output,
+            fsync: args.fsync,
+        };
 
-        decompact_file(args.input, output)?;
+        if let Some(input) = args.input.clone().filter(|x| x.is_dir()) {
+            let session = session::Session {
+                input,
+                output,
+                border: None,
+                profiles: Default::default(),
+                links: Default::default(),
+                preserve_perms: args.preserve_perms,
+                operation: session::Operation::Decompact(options),
+                passthrough,
+                region_format: Box::new(|x| region_format(Some(&x.to_path_buf()))),
+                threads,
+                fail_fast: args.fail_fast,
+                progress: args.progress,
+            };
+            let plan = session.plan()?;
+            if args.dry_run {
+                plan.print();
+                return Ok(());
+            }
+            session.check_space(&plan, args.force)?;
+            let report = session.run(plan)?;
+            report.print();
+            return report.into_result();
+        }
+
+        ensure!(!args.preserve_perms, "--preserve-perms needs a directory as input");
+        check_decompact_options(&options)?;
+        decompact_file(args.input, output, &options)?;
     }
 
     Ok(())
 }
 
-fn decompact_file(input: Option<impl AsRef<Path>>, output: impl AsRef<Path>) -> anyhow::Result<()> {
+/// Absolute path without symlinks, also of files not created yet
+fn resolve(path: &Path) -> PathBuf {
+    if let Ok(path) = path.canonicalize() {
+        return path;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if parent.as_os_str().is_empty() => resolve(Path::new(".")).join(name),
+        (Some(parent), Some(name)) => resolve(parent).join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Refuses output overwriting the input, and output inside input directory unless `allow_in_place`
+fn check_paths(input: &Path, output: &Path, allow_in_place: bool) -> anyhow::Result<()> {
+    let (input, output) = (resolve(input), resolve(output));
+    ensure!(input != output, "Output {} is the input itself", output.display());
+    ensure!(
+        allow_in_place || !input.is_dir() || !output.starts_with(&input),
+        "Output {} is inside input directory {}, pass --allow-in-place to write there anyway",
+        output.display(),
+        input.display()
+    );
+    Ok(())
+}
+
+/// Refuses subcommands changing their input in place with --read-only
+fn check_read_only(command: &Command) -> anyhow::Result<()> {
+    let name = match command {
+        Command::DeleteChunks(_) => "delete-chunks",
+        Command::ApplyUndo(_) => "apply-undo",
+        Command::TouchChunks(_) => "touch-chunks",
+        Command::Defrag(_) => "defrag",
+        Command::PruneSnapshots(args) if !args.dry_run => "prune-snapshots",
+        _ => return Ok(()),
+    };
+    bail!("{name} changes files in place, which --read-only refuses")
+}
+
+/// [`check_paths`] of archive, which may be split, and every one of its volumes
+fn check_archive_paths(input: &Path, output: &Path) -> anyhow::Result<()> {
+    check_paths(input, output, false)?;
+    rpack::volume::volume_paths(input).iter().try_for_each(|x| check_paths(x, output, false))
+}
+
+/// Stops files being processed by other threads after a failure with --fail-fast
+fn cancel_on_failure(error: &anyhow::Error, fail_fast: bool, cancel: &CancellationToken) {
+    if fail_fast && !error.is::<Cancelled>() {
+        cancel.cancel();
+    }
+}
+
+fn check_compact_options(options: &rpack::EncodeOptions) -> anyhow::Result<()> {
+    ensure!(
+        options.pos_check != PosCheck::Fix || options.region.is_some(),
+        "Unable to get region coordinates from input file name. They are required by --fix-pos"
+    );
+    ensure!(
+        options.pos_check == PosCheck::None || options.format.entries == RegionFormat::VANILLA.entries,
+        "Position check supports only regions of 32x32 chunks"
+    );
+    ensure!(
+        !options.rpack.raw
+            || (options.pos_check == PosCheck::None
+                && !options.check_nbt
+                && !options.migrate
+                && !options.codec_sniff
+                && options.min_data_version.is_none()),
+        "Raw archives keep chunks compressed, they can not be combined with --check-pos, --fix-pos, --check-nbt, --migrate, --codec-sniff or --require-min-dataversion"
+    );
+    ensure!(
+        options.border.is_none() || (options.region.is_some() && options.format.entries == RegionFormat::VANILLA.entries),
+        "World border pruning needs region coordinates from input file name like r.<x>.<z>.mca and regions of 32x32 chunks"
+    );
+    ensure!(
+        !options.rpack.raw || options.format.codec.is_none(),
+        "Raw archives require compression type byte in chunks, which this region format lacks"
+    );
+    Ok(())
+}
+
+fn check_decompact_options(options: &DecompactOptions) -> anyhow::Result<()> {
+    ensure!(
+        !options.validate_output || options.decode.format == RegionFormat::VANILLA,
+        "--validate-output supports only vanilla region format"
+    );
+    if options.decode.fill_missing.is_some() {
+        ensure!(options.decode.format == RegionFormat::VANILLA, "--fill-missing supports only vanilla region format");
+        ensure!(
+            options.decode.target_data_version.is_none_or(|x| x >= chunk::Filler::DATA_VERSION),
+            "--fill-missing writes chunks of DataVersion {}, newer than --target-dataversion",
+            chunk::Filler::DATA_VERSION
+        );
+    }
+    Ok(())
+}
+
+fn decompact_file(
+    input: Option<impl AsRef<Path>>,
+    output: impl AsRef<Path>,
+    options: &DecompactOptions,
+) -> anyhow::Result<()> {
     let mut reader: BufReader<Box<dyn Read>> = if let Some(input) = input {
-        std::fs::File::open(input)
-            .map(Box::new)
-            .map(|x| x as Box<dyn Read>)
-            .map(|x| std::io::BufReader::with_capacity(4096, x))?
+        // Split archives are read volume by volume
+        #[cfg(all(feature = "uring", target_os = "linux"))]
+        let archive: Box<dyn Read> = match input.as_ref().is_file() && rpack::volume::first_volume_base(input.as_ref()).is_none() {
+            true => Box::new(std::io::Cursor::new(uring::read(input.as_ref())?)),
+            false => rpack::volume::open(input.as_ref())?,
+        };
+        #[cfg(not(all(feature = "uring", target_os = "linux")))]
+        let archive: Box<dyn Read> = rpack::volume::open(input.as_ref())?;
+        std::io::BufReader::with_capacity(4096, Box::new(timings::Timed::new(archive, timings::Phase::Read)))
     } else {
         (Box::new(stdin()) as Box<dyn Read>).pipe(|x| BufReader::with_capacity(4096, x))
     };
@@ -83,11 +917,22 @@ fn decompact_file(input: Option<impl AsRef<Path>>, output: impl AsRef<Path>) ->
     let mut writer = std::fs::File::options()
         .write(true)
         .create(true)
+        .truncate(true)
         .open(output.as_ref())
-        .map(BufWriter::new)?;
+        .map(|x| BufWriter::new(timings::Timed::new(x, timings::Phase::Write)))?;
 
-    decompact_ws(&mut reader, &mut writer)
-        .and_then(|_| writer.flush().context("Unable to flush file"))
+    let decode = rpack::DecodeOptions { region_path: Some(output.as_ref().to_path_buf()), ..options.decode.clone() };
+    rpack::decode_region(&mut reader, &mut writer, &decode)
+        .and_then(|size| writer.flush().context("Unable to flush file").map(|_| size))
+        .and_then(|size| match decode.sparse {
+            true => make_sparse(output.as_ref(), size),
+            false => Ok(()),
+        })
+        .and_then(|_| sync_output(output.as_ref(), options.fsync))
+        .and_then(|_| match options.validate_output {
+            true => region::validate_region(output.as_ref()),
+            false => Ok(()),
+        })
         .context("Unable to decompact region")
         .inspect_err(|_| {
             std::fs::remove_file(output)
@@ -98,146 +943,426 @@ fn decompact_file(input: Option<impl AsRef<Path>>, output: impl AsRef<Path>) ->
     Ok(())
 }
 
-fn compact_file(input: impl AsRef<Path>, output: Option<impl AsRef<Path>>) -> anyhow::Result<()> {
-    let mut reader = std::fs::File::open(input.as_ref())?.pipe(std::io::BufReader::new);
+fn compact_file(
+    input: impl AsRef<Path>,
+    output: Option<impl AsRef<Path>>,
+    options: &CompactOptions,
+) -> anyhow::Result<usize> {
+    // Changes are recorded by file and written to the audit log once its archive is complete
+    let encode = rpack::EncodeOptions {
+        region_path: Some(region::gunzipped_path(input.as_ref())),
+        audit: options.encode.audit.as_ref().map(|x| x.for_file(input.as_ref())),
+        ..options.encode.clone()
+    };
+    let options = &CompactOptions { encode, ..options.clone() };
+    let commit_audit = || options.encode.audit.as_ref().map_or(Ok(()), audit::AuditLog::commit);
+
+    // Region files gzipped as a whole are unpacked on the fly
+    #[cfg(all(feature = "uring", target_os = "linux"))]
+    let mut reader = match timings::measure(timings::Phase::Read, || uring::read(input.as_ref()))? {
+        data if region::is_gzipped(&data) => {
+            let mut unpacked = vec![];
+            flate2::read::MultiGzDecoder::new(&data[..])
+                .read_to_end(&mut unpacked)
+                .with_context(|| format!("Unable to unpack {}", input.as_ref().display()))?;
+            unpacked
+        },
+        data => data,
+    }
+    .pipe(std::io::Cursor::new);
+    #[cfg(not(all(feature = "uring", target_os = "linux")))]
+    let mut reader = region::open(input.as_ref())?
+        .pipe(|x| timings::Timed::new(x, timings::Phase::Read))
+        .pipe(std::io::BufReader::new);
 
     let mut writer: BufWriter<Box<dyn Write>> = if let Some(output_file) = output.as_ref() {
-        std::fs::File::options()
-            .write(true)
-            .create(true)
-            .open(output_file)?
-            .pipe(Box::new)
-            .pipe(|x| x as Box<dyn Write>)
-            .pipe(std::io::BufWriter::new)
+        if let Some(size) = options.split_size {
+            rpack::volume::VolumeWriter::create(output_file.as_ref(), size)?
+                .pipe(|x| timings::Timed::new(x, timings::Phase::Write))
+                .pipe(Box::new)
+                .pipe(|x| x as Box<dyn Write>)
+                .pipe(std::io::BufWriter::new)
+        } else {
+            // Archive is written at flush with a few submissions
+            #[cfg(all(feature = "uring", target_os = "linux"))]
+            let file = uring::Writer::create(output_file.as_ref())?;
+            #[cfg(not(all(feature = "uring", target_os = "linux")))]
+            let file = std::fs::File::options()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(output_file)?;
+            timings::Timed::new(file, timings::Phase::Write)
+                .pipe(Box::new)
+                .pipe(|x| x as Box<dyn Write>)
+                .pipe(std::io::BufWriter::new)
+        }
     } else {
         (Box::new(stdout()) as Box<dyn Write>).pipe(BufWriter::new)
     };
+    // Archive file or all its volumes, those still being written until they are committed
+    let output_files = |committed: bool| match options.split_size {
+        Some(_) if committed => rpack::volume::volume_paths(output.as_ref().unwrap().as_ref()),
+        Some(_) => rpack::volume::partial_paths(output.as_ref().unwrap().as_ref()),
+        None => output.iter().map(|x| x.as_ref().to_path_buf()).collect(),
+    };
+    // Volumes of a previous archive are kept
+    let remove_output = || match options.split_size {
+        Some(_) => rpack::volume::discard(output.as_ref().unwrap().as_ref()),
+        None => Ok(output_files(false).iter().try_for_each(std::fs::remove_file)?),
+    };
 
-    if let Err(e) = compact(&mut reader, &mut writer).context(anyhow!(
+    let encoded = match rpack::encode_region(&mut reader, &mut writer, &options.encode).context(anyhow!(
         "{:?}",
         output.as_ref().map(|x| x.as_ref().display().to_string())
     )) {
-        writer.flush().ok();
-        drop(writer);
-
-        if let Some(output) = output {
-            let rf = std::fs::remove_file(output);
-            if rf.is_err() {
-                rf.context(anyhow!(e))?;
-            } else {
-                bail!(e);
+        Ok(encoded) => {
+            print_encoded(&encoded);
+            encoded
+        },
+        Err(e) => {
+            writer.flush().ok();
+            drop(writer);
+
+            if let Err(rf) = remove_output() {
+                return Err(rf).context(e);
             }
-        } else {
             bail!(e);
+        },
+    };
+
+    writer.flush()?;
+    drop(writer);
+
+    let fsync = match options.delete_source {
+        true => options.fsync.max(Fsync::File),
+        false => options.fsync,
+    };
+    let write_undo = || match &options.undo {
+        Some(undo) if encoded.pruned > 0 => write_pruned(input.as_ref(), undo, &options.encode, fsync),
+        _ => Ok(()),
+    };
+    if let Some(output) = output.as_ref() {
+        // Uncompressed archives are expected to be larger than region files
+        if options.encode.rpack.compression != rpack::Compression::None {
+            let input_size = std::fs::metadata(input.as_ref())?.len();
+            let output_size = output_files(false).iter().map(std::fs::metadata).try_fold(0, |sum, x| x.map(|x| sum + x.len()))?;
+            let ratio = options.encode.ratio;
+            ratio
+                .check(output_size as f64 / input_size.max(1) as f64, ratio.archive, || {
+                    format!("{}: archive of {output_size} bytes from region file of {input_size} bytes, check codec settings", output.as_ref().display())
+                })
+                .inspect_err(|_| {
+                    remove_output().ok();
+                })?
+                .inspect(|warning| eprintln!("{warning}"));
+        }
+
+        if options.split_size.is_some() {
+            rpack::volume::commit(output.as_ref(), fsync >= Fsync::File)?;
+        }
+        output_files(true).iter().try_for_each(|x| sync_output(x, fsync))?;
+
+        if options.verify {
+            verify_archive(region::open(input.as_ref())?, output.as_ref(), &options.encode)
+                .map_err(|e| ErrorCode::VerifyFailed.wrap(e, "Archive verification failed"))?;
+        }
+        write_undo()?;
+        commit_audit()?;
+        if options.delete_source {
+            std::fs::remove_file(input.as_ref()).with_context(|| format!("Unable to remove {}", input.as_ref().display()))?;
         }
     } else {
-        writer.flush()?;
-        drop(writer);
+        write_undo()?;
+        commit_audit()?;
     }
 
-    Ok(())
+    Ok(encoded.chunks)
 }
 
-fn compact(reader: impl Read, mut writer: impl Write) -> anyhow::Result<u64> {
-    let mut regionreader = RegionReader::from_reader(reader)?;
+/// Writes chunks of region file left out by the border of `options` into undo archive at `path`, which `apply-undo`
+/// puts back. Nothing is written for files without such chunks
+fn write_pruned(input: &Path, path: &Path, options: &rpack::EncodeOptions, fsync: Fsync) -> anyhow::Result<()> {
+    let options = rpack::EncodeOptions { region_path: Some(region::gunzipped_path(input)), ..options.clone() };
+    let mut data = vec![];
+    let pruned = rpack::encode_pruned(std::io::BufReader::new(region::open(input)?), &mut data, &options)
+        .with_context(|| format!("Unable to read chunks left out of {}", input.display()))?;
+    if pruned == 0 {
+        return Ok(());
+    }
+    if let Some(dir) = path.parent().filter(|x| !x.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir).with_context(|| format!("Unable to create {}", dir.display()))?;
+    }
+    std::fs::write(path, &data).with_context(|| format!("Unable to write {}", path.display()))?;
+    sync_output(path, fsync)
+}
 
-    // We need aligned reading due to ChunkData layout
-    let mut chunkbuf = Vec::<u32>::new();
-    let mut databuf = vec![];
-    let mut total_written = 0u64;
-    loop {
-        let Some((info, pos)) = regionreader.next_chunk_info() else {
-            break;
-        };
+/// Flushes written file and, with [`Fsync::Dir`], its directory entry to stable storage
+fn sync_output(path: &Path, fsync: Fsync) -> anyhow::Result<()> {
+    if fsync >= Fsync::File {
+        std::fs::File::open(path)
+            .and_then(|x| x.sync_all())
+            .with_context(|| format!("Unable to sync {}", path.display()))?;
+    }
 
-        chunkbuf.extend((chunkbuf.len()..info.size().div_ceil(4) as usize).map(|_| 0));
-        let Some(_) = regionreader.read_next_chunk(chunkbuf.as_mut_slice().as_mut_bytes())? else {
-            break;
-        };
+    // Directories can be opened and synced only on Unix
+    #[cfg(unix)]
+    if fsync == Fsync::Dir {
+        let dir = path.parent().filter(|x| !x.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        std::fs::File::open(dir)
+            .and_then(|x| x.sync_all())
+            .with_context(|| format!("Unable to sync {}", dir.display()))?;
+    }
+
+    Ok(())
+}
 
-        let data =
-            ChunkData::try_ref_from_bytes(chunkbuf.as_bytes()).map_err(|x| x.map_src(|_| &()))?;
+/// Extends region file to its full size without writing the tail padding and, on Linux,
+/// turns every block of zeros into a hole. Filesystems without hole punching keep the zeros
+fn make_sparse(path: &Path, size: u64) -> anyhow::Result<()> {
+    let file = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("Unable to open {}", path.display()))?;
+    file.set_len(size)?;
 
-        data.decompress(&mut databuf)?;
+    #[cfg(target_os = "linux")]
+    punch_holes(&file).with_context(|| format!("Unable to punch holes in {}", path.display()))?;
 
-        let header = BinHeader {
-            pos: (pos as u32).into(),
-            timestamp: info.timestamp,
-            length: (databuf.len() as u64).into(),
-        };
+    Ok(())
+}
 
-        writer.write_all(header.as_bytes())?;
-        writer.write_all(&databuf)?;
-        total_written += header.as_bytes().len() as u64 + databuf.len() as u64;
+#[cfg(target_os = "linux")]
+fn punch_holes(file: &std::fs::File) -> std::io::Result<()> {
+    use std::os::unix::{
+        fs::{FileExt, MetadataExt},
+        io::AsRawFd,
+    };
+
+    let metadata = file.metadata()?;
+    let block = metadata.blksize().max(512);
+    let mut buffer = vec![0u8; block as usize];
+
+    let punch = |start: u64, end: u64| {
+        let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+        // SAFETY: plain syscall on an open descriptor, no memory is passed
+        match unsafe { libc::fallocate(file.as_raw_fd(), mode, start as libc::off_t, (end - start) as libc::off_t) } {
+            0 => Ok(true),
+            _ => match std::io::Error::last_os_error() {
+                e if e.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(false),
+                e => Err(e),
+            },
+        }
+    };
 
-        databuf.clear();
+    // Start of the current run of zero blocks. A partial block at the end is never punched
+    let mut hole = None;
+    let mut offset = 0;
+    while offset + block <= metadata.len() {
+        file.read_exact_at(&mut buffer, offset)?;
+        match (buffer.iter().all(|&x| x == 0), hole) {
+            (true, None) => hole = Some(offset),
+            (false, Some(start)) => {
+                if !punch(start, offset)? {
+                    return Ok(());
+                }
+                hole = None;
+            },
+            _ => {},
+        }
+        offset += block;
+    }
+    if let Some(start) = hole {
+        punch(start, offset)?;
     }
 
-    Ok(total_written)
+    Ok(())
 }
 
-fn decompact_ws(mut reader: impl Read, mut writer: impl Write + Seek) -> anyhow::Result<u64> {
-    let mut chunkinfos = vec![None; 1024];
-    let mut header = BinHeader::new_zeroed();
+/// Compares every chunk of archive with region file. Payloads are not compared with --fix-pos as it rewrites them
+fn verify_archive(region: impl Read, output: &Path, options: &rpack::EncodeOptions) -> anyhow::Result<()> {
+    let mut expected = vec![];
+    let mut regionreader = RegionReader::from_reader_with_format(region, options.limits(), options.format)?;
+    if let Some(path) = &options.region_path {
+        regionreader = regionreader.with_external_chunks(path);
+    }
+    match options.rpack.raw {
+        true => regionreader.read_all_raw(|info, pos, data| {
+            options.cancel.check()?;
+            if !options.inside_border(pos) {
+                return Ok(());
+            }
+            expected.push((pos, info.timestamp.get(), data.to_vec()));
+            Ok(())
+        })?,
+        false => regionreader.decompress_all(|info, pos, data| {
+            options.cancel.check()?;
+            if !options.inside_border(pos) {
+                return Ok(());
+            }
+            expected.push((pos, info.timestamp.get(), std::mem::take(data)));
+            Ok(())
+        })?,
+    }
+    expected.sort_by_key(|x| x.0);
+
+    let mut actual = vec![];
+    let mut reader = rpack::RpackReader::new(rpack::volume::open(output)?)?;
     let mut buffer = vec![];
-    let mut buffer2 = vec![];
-
-    writer.seek(std::io::SeekFrom::Start(RegionInfo::SIZE as u64))?;
-    let mut location = RegionInfo::SIZE as u64;
-
-    loop {
-        let ret = reader.read_exact(header.as_mut_bytes());
-        if ret
-            .as_ref()
-            .is_err_and(|e| e.kind() == std::io::ErrorKind::UnexpectedEof)
-        {
-            writer.seek(std::io::SeekFrom::Start(0))?;
-
-            chunkinfos
-                .iter()
-                .map(|x| x.as_ref().map(|x: &ChunkInfo| x.locdata.get()).unwrap_or(FromZeros::new_zeroed()))
-                .try_for_each(|x| writer.write_all(x.as_bytes()))?;
-
-            chunkinfos
-                .iter()
-                .map(|x| x.as_ref().map(|x: &ChunkInfo| x.timestamp).unwrap_or(FromZeros::new_zeroed()))
-                .try_for_each(|x| writer.write_all(x.as_bytes()))?;
-
-            return Ok(location);
+    while let Some(chunk) = reader.read_chunk(&mut buffer)? {
+        options.cancel.check()?;
+        actual.push((chunk.pos, chunk.timestamp, std::mem::take(&mut buffer)));
+    }
+    actual.sort_by_key(|x| x.0);
+
+    ensure!(
+        actual.iter().map(|x| x.0).eq(expected.iter().map(|x| x.0)),
+        "Archive has {} chunks at other positions than {} chunks of region file",
+        actual.len(),
+        expected.len()
+    );
+    for ((pos, timestamp, data), (_, expected_timestamp, expected_data)) in actual.iter().zip(expected.iter()) {
+        let (x, z) = RegionInfo::local_coords(*pos);
+        ensure!(timestamp == expected_timestamp, "Chunk {x},{z} timestamp differs");
+        ensure!(options.pos_check == PosCheck::Fix || data == expected_data, "Chunk {x},{z} data differs");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use crate::{
+        check_archive_paths, check_paths, compact_file, decompact_file, make_sparse, parse_size, rpack, run,
+        testutil::{fixture, TempDir},
+        ChunkCallback, ChunkEvent, Cli, CompactOptions, DecompactOptions, ErrorCode, Fsync,
+    };
+    use clap::Parser;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("4G").unwrap(), 4 << 30);
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("20000000T").is_err());
+    }
+
+    #[test]
+    fn read_only_refuses_changes() {
+        // After the subcommand name, --chunks takes everything following it
+        let parse = |args: &[&str]| {
+            let (first, rest) = args.split_first().unwrap();
+            Cli::try_parse_from(["anvilregion-repacker", first, "--read-only"].iter().chain(rest))
+        };
+        let dir = TempDir::new("read-only");
+        let path = dir.copy_fixture("basic", "r.0.0.mca");
+        let path = path.to_str().unwrap();
+        for command in [
+            &["defrag", "-i", path][..],
+            &["delete-chunks", "-i", path, "--chunks", "0,0"],
+            &["touch-chunks", "-i", path, "--set-now", "--chunks", "0,0"],
+            &["apply-undo", path, "-i", path],
+            &["prune-snapshots", dir.path().to_str().unwrap()],
+            &["-c", "-i", path, "-o", "r.0.0.mca.rpack", "--delete-source"],
+            &["-d", "-i", "world.rpack", "-o", "world", "--allow-in-place"],
+        ] {
+            let err = run(parse(command).unwrap()).unwrap_err();
+            assert!(err.to_string().contains("--read-only"), "{err}");
         }
-        ret?;
+        assert_eq!(std::fs::read(path).unwrap(), std::fs::read(fixture("basic")).unwrap());
+    }
 
-        let copied = std::io::copy(&mut reader.by_ref().take(header.length.get()), &mut buffer)?;
-        ensure!(
-            copied == header.length.get(),
-            std::io::Error::from(std::io::ErrorKind::UnexpectedEof)
-        );
+    #[test]
+    fn output_paths() {
+        let dir = TempDir::new("check-paths");
+        std::fs::create_dir_all(dir.join("region")).unwrap();
+        let input = dir.join("region/r.0.0.mca");
+        std::fs::write(&input, []).unwrap();
 
-        let mut compreader = flate2::read::ZlibEncoder::new(&buffer[..], Compression::new(3));
-        let compressed_size =
-            std::io::copy(&mut compreader, &mut buffer2).context("Compression/write failed")?;
+        assert!(check_paths(&input, &dir.join("region/../region/r.0.0.mca"), true).is_err());
+        assert!(check_paths(&input, &dir.join("r.0.0.mca.rpack"), false).is_ok());
+        assert!(check_paths(dir.path(), &dir.join("packed/new"), false).is_err());
+        assert!(check_paths(dir.path(), &dir.join("packed/new"), true).is_ok());
+        assert!(check_paths(&dir.join("region"), &dir.join("packed"), false).is_ok());
 
-        let data_size = compressed_size + 5;
+        // Split archive named by its base path
+        std::fs::write(dir.join("r.0.0.mca.rpack.001"), []).unwrap();
+        assert!(check_archive_paths(&dir.join("r.0.0.mca.rpack"), &dir.join("./r.0.0.mca.rpack.001")).is_err());
+        assert!(check_archive_paths(&dir.join("r.0.0.mca.rpack"), &dir.join("r.0.0.mca.rpack")).is_err());
+        assert!(check_archive_paths(&dir.join("r.0.0.mca.rpack"), &dir.join("new.rpack")).is_ok());
+    }
+
+    #[test]
+    fn sparse_reads_back_the_same() {
+        let dir = TempDir::new("sparse");
+        let path = dir.join("zeros");
+        let data = [vec![1u8; 5000], vec![0; 256 * 1024], vec![2; 3000], vec![0; 70000]].concat();
+        std::fs::write(&path, &data[..data.len() - 70000]).unwrap();
+        make_sparse(&path, data.len() as u64).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+
+        let (region, archive) = (fixture("basic"), dir.join("r.0.0.mca.rpack"));
+        compact_file(&region, Some(&archive), &CompactOptions::default()).unwrap();
+        let mut options = DecompactOptions { validate_output: true, ..Default::default() };
+        decompact_file(Some(&archive), dir.join("dense.mca"), &options).unwrap();
+        options.decode.sparse = true;
+        decompact_file(Some(&archive), dir.join("sparse.mca"), &options).unwrap();
+        assert_eq!(std::fs::read(dir.join("sparse.mca")).unwrap(), std::fs::read(dir.join("dense.mca")).unwrap());
+    }
 
-        writer.write_all(U32::<BigEndian>::new((data_size - 4) as u32).as_bytes())?;
-        writer.write_all(2u8.as_bytes())?;
-        writer.write_all(&buffer2)?;
+    #[test]
+    fn source_kept_on_failure() {
+        let dir = TempDir::new("delete-source");
+        let (input, output) = (dir.copy_fixture("basic", "r.0.0.mca"), dir.join("r.0.0.mca.rpack"));
+        let options = CompactOptions { delete_source: true, verify: true, fsync: Fsync::Dir, ..Default::default() };
 
-        const COPIED_MASK: u64 = const { ChunkInfo::SECTOR_SIZE as u64 - 1 };
-        let left = (ChunkInfo::SECTOR_SIZE as u64 - (data_size & COPIED_MASK)) & COPIED_MASK;
-        writer.seek(std::io::SeekFrom::Current(left as i64))?;
+        // Output can not be created
+        assert!(compact_file(&input, Some(dir.join("missing/r.0.0.mca.rpack")), &options).is_err());
+        assert!(input.exists());
 
-        let chunkinfo = Some(ChunkInfo::new(
-            location.try_into().unwrap(),
-            (data_size + left).try_into().unwrap(),
-            header.timestamp.get(),
-        ));
-        let old = core::mem::replace(&mut chunkinfos[header.pos.get() as usize], chunkinfo);
-        debug_assert!(old.is_none());
+        // Verification stopped after every chunk is archived
+        let archived = Arc::new(AtomicUsize::new(0));
+        // A token of its own, the other runs share the one of options
+        let mut stopped = CompactOptions { encode: rpack::EncodeOptions::default(), ..options.clone() };
+        let (cancel, count) = (stopped.encode.cancel.clone(), archived.clone());
+        stopped.encode.on_chunk = Some(ChunkCallback(Arc::new(move |event| {
+            if matches!(event, ChunkEvent::Archived { .. }) && count.fetch_add(1, Ordering::Relaxed) == 2 {
+                cancel.cancel();
+            }
+        })));
+        let error = compact_file(&input, Some(&output), &stopped).unwrap_err();
+        assert_eq!((ErrorCode::of(&error), archived.load(Ordering::Relaxed)), (Some(ErrorCode::VerifyFailed), 3));
+        assert!(input.exists());
+
+        compact_file(&input, Some(&output), &options).unwrap();
+        assert!(!input.exists() && output.exists());
+
+        // Chunk 1,0 does not decompress
+        std::fs::copy(fixture("corrupted"), &input).unwrap();
+        assert!(compact_file(&input, Some(&output), &options).is_err());
+        assert!(input.exists() && !output.exists());
+    }
 
-        location += data_size + left;
+    #[test]
+    fn failed_split_keeps_previous_volumes() {
+        let dir = TempDir::new("split-failed");
+        let (input, output) = (dir.copy_fixture("basic", "r.0.0.mca"), dir.join("r.0.0.mca.rpack"));
+        let options = CompactOptions { delete_source: true, split_size: Some(100), ..Default::default() };
+        compact_file(&input, Some(&output), &options).unwrap();
+        let volumes = rpack::volume::volume_paths(&output);
+        assert!(volumes.len() > 1 && !input.exists());
+        let previous = volumes.iter().map(|x| std::fs::read(x).unwrap()).collect::<Vec<_>>();
 
-        buffer.clear();
-        buffer2.clear();
+        // Chunk 1,0 does not decompress
+        std::fs::copy(fixture("corrupted"), &input).unwrap();
+        assert!(compact_file(&input, Some(&output), &options).is_err());
+        assert!(input.exists() && rpack::volume::partial_paths(&output).is_empty());
+        assert_eq!(rpack::volume::volume_paths(&output).iter().map(|x| std::fs::read(x).unwrap()).collect::<Vec<_>>(), previous);
     }
 }
@@ -0,0 +1,434 @@
+//! Manifest of a world packed in directory mode, written next to the archives. Lists every entry
+//! so a backup can be checked without unpacking it.
+//! Field names are part of the output schema, rename only with care.
+
+use std::{
+    ffi::{OsStr, OsString},
+    fmt,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    batch::{Job, Link},
+    region, rpack, world,
+};
+
+pub const MANIFEST_NAME: &str = "rpack-manifest.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Region,
+    Entities,
+    Poi,
+    /// Copied unchanged, see `--passthrough`
+    Passthrough,
+    /// Symbolic link, only stored with `--preserve-links`
+    Symlink,
+    /// Another name of a file stored in an earlier entry, only stored with `--preserve-links`
+    Hardlink,
+    /// Passthrough file with the same content as the entry `target`, stored only there, see `--dedupe-passthrough`
+    Duplicate,
+}
+
+/// `/`-separated path of entry. Stored as a string when valid UTF-8, as `{"bytes": [...]}` of the name as the
+/// packing system had it otherwise, like the binary charset of tar PAX headers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EntryPath {
+    Utf8(String),
+    Bytes { bytes: Vec<u8> },
+}
+
+impl EntryPath {
+    /// Path `path` relative to `dir`, components joined by `/`
+    pub fn relative(dir: &Path, path: &Path) -> Self {
+        let parts = path.strip_prefix(dir).unwrap_or(path).components().map(|x| x.as_os_str().as_encoded_bytes());
+        Self::from_bytes(parts.collect::<Vec<_>>().join(&b'/'))
+    }
+
+    /// Name kept as given, like a symlink target
+    pub fn from_os_str(name: &OsStr) -> Self {
+        Self::from_bytes(name.as_encoded_bytes().to_vec())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        match String::from_utf8(bytes) {
+            Ok(x) => Self::Utf8(x),
+            Err(e) => Self::Bytes { bytes: e.into_bytes() },
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Self::Utf8(x) => x.as_bytes(),
+            Self::Bytes { bytes } => bytes,
+        }
+    }
+
+    /// Name as stored. Unix gets the bytes back, elsewhere names that are not UTF-8 are converted lossily
+    pub fn to_os_string(&self) -> OsString {
+        os_string(self.as_bytes())
+    }
+
+    /// Relative path to restore entry at. Components leaving the directory are dropped, and on Windows names it can
+    /// not hold are made valid, see [`windows_name`]
+    pub fn to_path(&self) -> PathBuf {
+        let parts = self.as_bytes().split(|&x| x == b'/').filter(|x| !matches!(*x, b"" | b"." | b".."));
+        #[cfg(windows)]
+        return parts.map(|x| windows_name(&String::from_utf8_lossy(x)).into_owned()).collect();
+        #[cfg(not(windows))]
+        return parts.map(os_string).collect();
+    }
+}
+
+impl From<&str> for EntryPath {
+    fn from(value: &str) -> Self {
+        Self::Utf8(value.to_owned())
+    }
+}
+
+impl fmt::Display for EntryPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&String::from_utf8_lossy(self.as_bytes()))
+    }
+}
+
+impl PartialOrd for EntryPath {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for EntryPath {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+
+fn os_string(bytes: &[u8]) -> OsString {
+    #[cfg(unix)]
+    return <OsStr as std::os::unix::ffi::OsStrExt>::from_bytes(bytes).to_owned();
+    #[cfg(not(unix))]
+    return String::from_utf8_lossy(bytes).into_owned().into();
+}
+
+/// File name Windows accepts for `name` made on another system: reserved characters become `_`, trailing dots
+/// and spaces are dropped and device names like `CON` or `COM1` get a `_` prepended
+#[cfg(any(windows, test))]
+fn windows_name(name: &str) -> std::borrow::Cow<'_, str> {
+    use std::borrow::Cow;
+
+    let reserved = |x: char| x.is_control() || "<>:\"/\\|?*".contains(x);
+    let trimmed = name.trim_end_matches(['.', ' ']);
+    let mut name = match trimmed.contains(reserved) || trimmed.len() != name.len() {
+        true => Cow::Owned(trimmed.replace(reserved, "_")),
+        false => Cow::Borrowed(name),
+    };
+    let stem = name.split('.').next().unwrap_or_default().to_ascii_uppercase();
+    let device = matches!(stem.as_str(), "CON" | "PRN" | "AUX" | "NUL")
+        || (stem.len() == 4
+            && (stem.starts_with("COM") || stem.starts_with("LPT"))
+            && stem.as_bytes()[3].is_ascii_digit()
+            && stem.as_bytes()[3] != b'0');
+    if device {
+        name = Cow::Owned(format!("_{name}"));
+    }
+    if name.is_empty() {
+        name = Cow::Borrowed("_");
+    }
+    name
+}
+
+/// POSIX permissions and ownership of file, only recorded with `--preserve-perms`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Perms {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Perms {
+    /// Permissions of file at `path`, `None` on systems without them
+    pub fn of(path: &Path) -> anyhow::Result<Option<Self>> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            let metadata = std::fs::metadata(path).with_context(|| format!("Unable to read {}", path.display()))?;
+            Ok(Some(Self {
+                mode: metadata.mode() & 0o7777,
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+            }))
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = path;
+            Ok(None)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub kind: Kind,
+    /// Dimension id of region files in world layout
+    pub dimension: Option<String>,
+    /// Path in the world directory
+    pub path: EntryPath,
+    /// Path in the archive directory, empty for links
+    pub stored_path: EntryPath,
+    pub size: u64,
+    /// Size of stored file, all volumes of split archives together
+    pub stored_size: u64,
+    /// CRC32 of stored file, volumes of split archives read back to back
+    pub stored_crc32: u32,
+    /// Target of links as stored for symlinks, or the world path of the entry holding the data for hard links
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<EntryPath>,
+    /// Permissions of the world file, restored onto the file written for it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub perms: Option<Perms>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub tool_version: String,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct ManifestArgs {
+    /// Directory of archives written in directory mode, or its manifest file
+    pub input: PathBuf,
+
+    /// Print the manifest as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// Entry of finished job. Passthrough jobs keep the file name, region jobs get an archive
+pub fn entry(input: &Path, output: &Path, job: &Job, passthrough: bool) -> anyhow::Result<Entry> {
+    let (kind, dimension) = match world::dimension(input, &job.input) {
+        _ if passthrough => (Kind::Passthrough, None),
+        Some((dimension, kind)) => match kind.as_str() {
+            "entities" => (Kind::Entities, Some(dimension)),
+            "poi" => (Kind::Poi, Some(dimension)),
+            _ => (Kind::Region, Some(dimension)),
+        },
+        None => (Kind::Region, None),
+    };
+
+    let mut reader = match passthrough {
+        true => Box::new(std::fs::File::open(&job.output)?),
+        false => rpack::volume::open(&job.output)?,
+    };
+    let mut hasher = crc32fast::Hasher::new();
+    let mut stored_size = 0;
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        let n = reader.read(&mut buffer).with_context(|| format!("Unable to read {}", job.output.display()))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        stored_size += n as u64;
+    }
+
+    Ok(Entry {
+        kind,
+        dimension,
+        // Region files gzipped as a whole are restored unpacked
+        path: match passthrough {
+            true => EntryPath::relative(input, &job.input),
+            false => EntryPath::relative(input, &region::gunzipped_path(&job.input)),
+        },
+        stored_path: EntryPath::relative(output, &job.output),
+        size: job.size,
+        stored_size,
+        stored_crc32: hasher.finalize(),
+        target: None,
+        perms: None,
+    })
+}
+
+/// Entry of link found under `input`, nothing is stored for it
+pub fn link_entry(input: &Path, path: &Path, link: &Link) -> Entry {
+    let (kind, target) = match link {
+        Link::Symlink(target) => (Kind::Symlink, EntryPath::from_os_str(target.as_os_str())),
+        Link::Hardlink(first) => (Kind::Hardlink, EntryPath::relative(input, first)),
+    };
+    Entry {
+        kind,
+        dimension: None,
+        path: EntryPath::relative(input, path),
+        stored_path: "".into(),
+        size: 0,
+        stored_size: 0,
+        stored_crc32: 0,
+        target: Some(target),
+        perms: None,
+    }
+}
+
+/// Entry of passthrough file at `path` with the same content as `holder`, nothing is stored for it
+pub fn duplicate_entry(input: &Path, path: &Path, holder: &Path, size: u64) -> Entry {
+    Entry {
+        kind: Kind::Duplicate,
+        dimension: None,
+        path: EntryPath::relative(input, path),
+        stored_path: "".into(),
+        size,
+        stored_size: 0,
+        stored_crc32: 0,
+        target: Some(EntryPath::relative(input, holder)),
+        perms: None,
+    }
+}
+
+/// Copies restored passthrough files under `output` to the places of their duplicates. Duplicates of files not
+/// restored, like those left out by `--skip`, are skipped too. Returns files made
+pub fn restore_duplicates(manifest: &Manifest, output: &Path) -> anyhow::Result<usize> {
+    let mut count = 0;
+    for entry in manifest.entries.iter().filter(|x| x.kind == Kind::Duplicate) {
+        let Some(target) = &entry.target else { continue };
+        let (source, path) = (output.join(target.to_path()), output.join(entry.path.to_path()));
+        if !source.is_file() {
+            continue;
+        }
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).with_context(|| format!("Unable to create {}", dir.display()))?;
+        }
+        std::fs::copy(&source, &path).with_context(|| format!("Unable to copy {target} to {}", path.display()))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Recreates links of manifest under `output`, after the files they point to are restored. Returns links made
+pub fn restore_links(manifest: &Manifest, output: &Path) -> anyhow::Result<usize> {
+    let mut count = 0;
+    for entry in manifest.entries.iter() {
+        let (Some(target), path) = (&entry.target, output.join(entry.path.to_path())) else { continue };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).with_context(|| format!("Unable to create {}", dir.display()))?;
+        }
+        let result = match entry.kind {
+            Kind::Hardlink => std::fs::hard_link(output.join(target.to_path()), &path),
+            #[cfg(unix)]
+            Kind::Symlink => std::os::unix::fs::symlink(target.to_os_string(), &path),
+            #[cfg(not(unix))]
+            Kind::Symlink => {
+                eprintln!("Skipping {}: symlinks are restored only on Unix", entry.path);
+                continue;
+            },
+            _ => continue,
+        };
+        result.with_context(|| format!("Unable to link {} to {target}", path.display()))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Applies recorded permissions to files restored under `output`. Ownership needs root, when it can not be changed
+/// only the mode is. Returns files whose mode and whose ownership were set
+pub fn restore_perms(manifest: &Manifest, output: &Path) -> anyhow::Result<(usize, usize)> {
+    let (mut modes, mut owners) = (0, 0);
+    #[cfg(unix)]
+    for entry in manifest.entries.iter() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let Some(perms) = entry.perms.filter(|_| !matches!(entry.kind, Kind::Symlink | Kind::Hardlink)) else { continue };
+        let path = output.join(entry.path.to_path());
+        // Regions outside of the border or failed jobs leave no file
+        if !path.is_file() {
+            continue;
+        }
+        // Changing the owner clears setuid bits, so the mode follows it
+        match std::os::unix::fs::chown(&path, Some(perms.uid), Some(perms.gid)) {
+            Ok(()) => owners += 1,
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {},
+            Err(e) => return Err(e).with_context(|| format!("Unable to change owner of {}", path.display())),
+        }
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(perms.mode))
+            .with_context(|| format!("Unable to change mode of {}", path.display()))?;
+        modes += 1;
+    }
+    #[cfg(not(unix))]
+    let _ = (manifest, output);
+    Ok((modes, owners))
+}
+
+/// Writes manifest of entries into archive directory
+pub fn write(output: &Path, mut entries: Vec<Entry>) -> anyhow::Result<()> {
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    let manifest = Manifest {
+        tool_version: env!("CARGO_PKG_VERSION").to_owned(),
+        entries,
+    };
+    let path = output.join(MANIFEST_NAME);
+    let json = serde_json::to_vec_pretty(&manifest)?;
+    std::fs::write(&path, json).with_context(|| format!("Unable to write {}", path.display()))
+}
+
+pub fn read(input: &Path) -> anyhow::Result<Manifest> {
+    let path = match input.is_dir() {
+        true => input.join(MANIFEST_NAME),
+        false => input.to_path_buf(),
+    };
+    let json = std::fs::read(&path).with_context(|| format!("Unable to read {}", path.display()))?;
+    serde_json::from_slice(&json).with_context(|| format!("Invalid manifest {}", path.display()))
+}
+
+pub fn run(args: ManifestArgs) -> anyhow::Result<()> {
+    let manifest = read(&args.input)?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&manifest)?);
+        return Ok(());
+    }
+
+    println!("Packed by version {}, {} entries", manifest.tool_version, manifest.entries.len());
+    for entry in manifest.entries.iter() {
+        let kind = serde_json::to_value(entry.kind)?;
+        println!(
+            "  {:<12} {:<24} {:>12} {:>12} {:08x} {}",
+            kind.as_str().unwrap_or_default(),
+            entry.dimension.as_deref().unwrap_or("-"),
+            entry.size,
+            entry.stored_size,
+            entry.stored_crc32,
+            entry.path
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_paths() {
+        let path = EntryPath::relative(Path::new("world"), Path::new("world/datapacks/pack/data.json"));
+        assert_eq!(serde_json::to_string(&path).unwrap(), r#""datapacks/pack/data.json""#);
+
+        let path = EntryPath::from_bytes(b"datapacks/caf\xe9".to_vec());
+        let json = serde_json::to_string(&path).unwrap();
+        assert_eq!(json, r#"{"bytes":[100,97,116,97,112,97,99,107,115,47,99,97,102,233]}"#);
+        assert_eq!(serde_json::from_str::<EntryPath>(&json).unwrap(), path);
+        assert_eq!(path.to_string(), "datapacks/caf\u{fffd}");
+        #[cfg(unix)]
+        assert_eq!(path.to_path().as_os_str().as_encoded_bytes(), b"datapacks/caf\xe9");
+
+        assert_eq!(EntryPath::from("../a/./b").to_path(), Path::new("a").join("b"));
+        assert_eq!(windows_name("level.dat"), "level.dat");
+        assert_eq!(windows_name("a:b?. "), "a_b_");
+        assert_eq!(windows_name("con.txt"), "_con.txt");
+        assert_eq!(windows_name("COM10"), "COM10");
+    }
+}
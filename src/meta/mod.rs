@@ -0,0 +1,133 @@
+//! Plain metadata of regions and chunks for reports and downstream tools.
+//! Field names are part of the output schema, rename only with care.
+
+use std::{
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chunk::ChunkData,
+    region::{region_coords_from_path, RegionInfo},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChunkCodec {
+    Gzip,
+    Zlib,
+    Uncompressed,
+    Lz4,
+    /// Type 127, algorithm is named inside chunk data
+    Custom,
+    Unknown,
+}
+
+impl From<u8> for ChunkCodec {
+    fn from(compression_type: u8) -> Self {
+        match compression_type & !ChunkData::EXTERNAL_FLAG {
+            1 => ChunkCodec::Gzip,
+            2 => ChunkCodec::Zlib,
+            3 => ChunkCodec::Uncompressed,
+            4 => ChunkCodec::Lz4,
+            127 => ChunkCodec::Custom,
+            _ => ChunkCodec::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkMeta {
+    /// Header slot
+    pub pos: u16,
+    /// Coordinates inside region
+    pub local_x: u8,
+    pub local_z: u8,
+    /// Absolute chunk coordinates. Known only if region coordinates are known
+    pub x: Option<i32>,
+    pub z: Option<i32>,
+    pub timestamp: u32,
+    /// Byte offset of the first sector
+    pub offset: u64,
+    /// Size of sectors allocated to the chunk
+    pub allocated: u64,
+    /// Size of compressed data according to its length field, excluding compression type byte
+    pub length: u64,
+    pub compression_type: u8,
+    pub codec: ChunkCodec,
+    /// Data is stored in `c.<x>.<z>.mcc` file
+    pub external: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegionMeta {
+    pub region_x: Option<i32>,
+    pub region_z: Option<i32>,
+    pub file_size: u64,
+    /// Chunks in file order
+    pub chunks: Vec<ChunkMeta>,
+}
+
+impl RegionMeta {
+    pub fn read(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path).with_context(|| format!("Unable to open {}", path.display()))?;
+        Self::from_reader(std::io::BufReader::new(file), region_coords_from_path(path))
+            .with_context(|| format!("{}", path.display()))
+    }
+
+    /// Reads region header and the first bytes of every chunk
+    pub fn from_reader(mut reader: impl Read + Seek, region: Option<(i32, i32)>) -> anyhow::Result<Self> {
+        let info = RegionInfo::read(&mut reader)?;
+        let file_size = reader.seek(SeekFrom::End(0))?;
+
+        let mut chunks = Vec::with_capacity(info.chunk_infos().len());
+        for &(chunkinfo, pos) in info.chunk_infos() {
+            let (local_x, local_z) = RegionInfo::local_coords(pos);
+            let absolute = region.map(|x| RegionInfo::chunk_coords(Some(x), pos));
+
+            let mut head = [0u8; 5];
+            reader.seek(SeekFrom::Start(chunkinfo.location()))?;
+            reader
+                .read_exact(&mut head)
+                .with_context(|| format!("Chunk {local_x},{local_z} is beyond end of file"))?;
+            let length = u32::from_be_bytes(head[..4].try_into().unwrap()).saturating_sub(1);
+
+            chunks.push(ChunkMeta {
+                pos,
+                local_x,
+                local_z,
+                x: absolute.map(|x| x.0),
+                z: absolute.map(|x| x.1),
+                timestamp: chunkinfo.timestamp.get(),
+                offset: chunkinfo.location(),
+                allocated: chunkinfo.size(),
+                length: length as u64,
+                compression_type: head[4],
+                codec: head[4].into(),
+                external: head[4] & ChunkData::EXTERNAL_FLAG != 0,
+            });
+        }
+
+        Ok(Self {
+            region_x: region.map(|x| x.0),
+            region_z: region.map(|x| x.1),
+            file_size,
+            chunks,
+        })
+    }
+}
+
+/// Summary of a region file built from its header alone
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegionScan {
+    pub chunks: usize,
+    /// Newest chunk timestamp, seconds since Unix epoch
+    pub newest_timestamp: Option<u32>,
+    /// Header plus sectors allocated to chunks
+    pub used_bytes: u64,
+    pub file_size: u64,
+}
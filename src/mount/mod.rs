@@ -0,0 +1,368 @@
+//! Read-only FUSE view of archives: `<MOUNTPOINT>/region/r.<x>.<z>.mca` for every archive.
+//!
+//! Speaks the kernel protocol over `/dev/fuse` directly and mounts with `mount(2)`, so it needs
+//! `CAP_SYS_ADMIN`. A region file is decompacted into memory on first access and the last few are kept.
+//! Runs in the foreground until the mountpoint is unmounted.
+
+use std::{
+    collections::VecDeque,
+    ffi::CString,
+    fs::File,
+    io::{BufReader, Cursor, Read, Write},
+    os::{
+        fd::AsRawFd,
+        unix::ffi::OsStrExt,
+    },
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{bail, ensure, Context};
+use zerocopy::{FromBytes, IntoBytes};
+
+use crate::{batch, region, rpack};
+
+mod abi;
+
+#[derive(Debug, clap::Args)]
+pub struct MountArgs {
+    /// Archive, or a directory of archives of one dimension
+    pub archive: PathBuf,
+
+    /// Existing empty directory to mount at
+    pub mountpoint: PathBuf,
+
+    /// Number of decompacted region files kept in memory
+    #[arg(long, value_name = "N", default_value_t = 16)]
+    pub cache: usize,
+
+    /// Let other users read the mount
+    #[arg(long)]
+    pub allow_other: bool,
+}
+
+const REGION_DIR_ID: u64 = 2;
+/// Inode of the first region file, the rest follow in order
+const FIRST_FILE_ID: u64 = 3;
+/// Largest read or readdir reply
+const MAX_REPLY: u32 = 128 * 1024;
+
+const DT_DIR: u32 = 4;
+const DT_REG: u32 = 8;
+
+struct RegionFile {
+    name: String,
+    archive: PathBuf,
+    /// Known after the first decompaction, which is deterministic
+    size: Option<u64>,
+}
+
+struct Filesystem {
+    files: Vec<RegionFile>,
+    /// Recently used region files, the most recent last
+    cache: VecDeque<(u64, Arc<Vec<u8>>)>,
+    capacity: usize,
+    uid: u32,
+    gid: u32,
+    mtime: u64,
+}
+
+/// Reply payload or errno
+type Reply = Result<Vec<u8>, i32>;
+
+impl Filesystem {
+    fn file(&self, ino: u64) -> Option<&RegionFile> {
+        self.files.get(ino.checked_sub(FIRST_FILE_ID)? as usize)
+    }
+
+    fn content(&mut self, ino: u64) -> anyhow::Result<Arc<Vec<u8>>> {
+        if let Some(i) = self.cache.iter().position(|x| x.0 == ino) {
+            let entry = self.cache.remove(i).unwrap();
+            self.cache.push_back(entry.clone());
+            return Ok(entry.1);
+        }
+
+        let file = &mut self.files[(ino - FIRST_FILE_ID) as usize];
+        // Without a region path oversized chunks are never written as external files
+        let options = rpack::DecodeOptions {
+            format: region::detect_format(&file.name, &region::providers()).unwrap_or_default(),
+            ..Default::default()
+        };
+        let mut region = Cursor::new(vec![]);
+        let reader = BufReader::new(rpack::volume::open(&file.archive)?);
+        let size = rpack::decode_region(reader, &mut region, &options)
+            .with_context(|| format!("Unable to decompact {}", file.archive.display()))?;
+        let mut region = region.into_inner();
+        region.resize(size as usize, 0);
+        file.size = Some(size);
+
+        let region = Arc::new(region);
+        if self.cache.len() >= self.capacity.max(1) {
+            self.cache.pop_front();
+        }
+        self.cache.push_back((ino, region.clone()));
+        Ok(region)
+    }
+
+    fn attr(&mut self, ino: u64) -> Result<abi::Attr, i32> {
+        let (mode, size) = match ino {
+            abi::ROOT_ID | REGION_DIR_ID => (libc::S_IFDIR | 0o555, 0),
+            _ => {
+                let size = match self.file(ino).ok_or(libc::ENOENT)?.size {
+                    Some(size) => size,
+                    None => self.content(ino).map_err(|e| report(e, libc::EIO))?.len() as u64,
+                };
+                (libc::S_IFREG | 0o444, size)
+            },
+        };
+        Ok(abi::Attr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: self.mtime,
+            mtime: self.mtime,
+            ctime: self.mtime,
+            mode,
+            nlink: if mode & libc::S_IFDIR != 0 { 2 } else { 1 },
+            uid: self.uid,
+            gid: self.gid,
+            blksize: 4096,
+            ..Default::default()
+        })
+    }
+
+    fn entry(&mut self, ino: u64) -> Reply {
+        let entry = abi::EntryOut {
+            nodeid: ino,
+            entry_valid: 3600,
+            attr_valid: 3600,
+            attr: self.attr(ino)?,
+            ..Default::default()
+        };
+        Ok(entry.as_bytes().to_vec())
+    }
+
+    fn lookup(&mut self, parent: u64, name: &[u8]) -> Reply {
+        match parent {
+            abi::ROOT_ID if name == b"region" => self.entry(REGION_DIR_ID),
+            REGION_DIR_ID => match self.files.iter().position(|x| x.name.as_bytes() == name) {
+                Some(i) => self.entry(FIRST_FILE_ID + i as u64),
+                None => Err(libc::ENOENT),
+            },
+            _ => Err(libc::ENOENT),
+        }
+    }
+
+    fn readdir(&self, ino: u64, offset: u64, size: u32) -> Reply {
+        let mut entries = vec![(ino, DT_DIR, b".".as_slice()), (abi::ROOT_ID, DT_DIR, b"..")];
+        match ino {
+            abi::ROOT_ID => entries.push((REGION_DIR_ID, DT_DIR, b"region")),
+            REGION_DIR_ID => entries.extend(
+                self.files
+                    .iter()
+                    .enumerate()
+                    .map(|(i, x)| (FIRST_FILE_ID + i as u64, DT_REG, x.name.as_bytes())),
+            ),
+            _ => return Err(libc::ENOTDIR),
+        }
+
+        let mut reply = vec![];
+        for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            let dirent = abi::Dirent {
+                ino,
+                off: i as u64 + 1,
+                namelen: name.len() as u32,
+                kind,
+            };
+            let length = (size_of::<abi::Dirent>() + name.len()).next_multiple_of(8);
+            if reply.len() + length > size as usize {
+                break;
+            }
+            reply.extend_from_slice(dirent.as_bytes());
+            reply.extend_from_slice(name);
+            reply.resize(reply.len().next_multiple_of(8), 0);
+        }
+        Ok(reply)
+    }
+
+    fn read(&mut self, ino: u64, offset: u64, size: u32) -> Reply {
+        self.file(ino).ok_or(libc::ENOENT)?;
+        let content = self.content(ino).map_err(|e| report(e, libc::EIO))?;
+        let start = (offset as usize).min(content.len());
+        let end = start.saturating_add(size as usize).min(content.len());
+        Ok(content[start..end].to_vec())
+    }
+
+    /// Reply to request, `None` for requests without reply
+    fn handle(&mut self, header: &abi::InHeader, body: &[u8]) -> Option<Reply> {
+        let ino = header.nodeid;
+        Some(match header.opcode {
+            abi::INIT => init(body),
+            abi::LOOKUP => self.lookup(ino, body.strip_suffix(b"\0").unwrap_or(body)),
+            abi::GETATTR => self.attr(ino).map(|attr| {
+                let out = abi::AttrOut { attr_valid: 3600, attr, ..Default::default() };
+                out.as_bytes().to_vec()
+            }),
+            abi::OPEN | abi::OPENDIR => {
+                let open_flags = if header.opcode == abi::OPEN { abi::FOPEN_KEEP_CACHE } else { 0 };
+                Ok(abi::OpenOut { open_flags, ..Default::default() }.as_bytes().to_vec())
+            },
+            abi::READ | abi::READDIR => match abi::ReadIn::read_from_prefix(body) {
+                Ok((read, _)) if header.opcode == abi::READ => self.read(ino, read.offset, read.size.min(MAX_REPLY)),
+                Ok((read, _)) => self.readdir(ino, read.offset, read.size.min(MAX_REPLY)),
+                Err(_) => Err(libc::EINVAL),
+            },
+            abi::STATFS => {
+                let out = abi::StatfsOut { bsize: 4096, frsize: 4096, namelen: 255, ..Default::default() };
+                Ok(out.as_bytes().to_vec())
+            },
+            abi::RELEASE | abi::RELEASEDIR | abi::FLUSH | abi::DESTROY => Ok(vec![]),
+            abi::FORGET | abi::BATCH_FORGET | abi::INTERRUPT => return None,
+            _ => Err(libc::ENOSYS),
+        })
+    }
+}
+
+fn report(e: anyhow::Error, errno: i32) -> i32 {
+    eprintln!("{e:#}");
+    errno
+}
+
+fn init(body: &[u8]) -> Reply {
+    let (init, _) = abi::InitIn::read_from_prefix(body).map_err(|_| libc::EINVAL)?;
+    if init.major != abi::KERNEL_VERSION {
+        eprintln!("Unsupported FUSE protocol version {}.{}", init.major, init.minor);
+        return Err(libc::EPROTO);
+    }
+
+    let out = abi::InitOut {
+        major: abi::KERNEL_VERSION,
+        minor: init.minor.min(abi::KERNEL_MINOR_VERSION),
+        max_readahead: init.max_readahead,
+        max_write: MAX_REPLY,
+        time_gran: 1,
+        ..Default::default()
+    };
+    Ok(out.as_bytes().to_vec())
+}
+
+/// Region files named after archives, `r.0.0.mca.rpack` and `r.0.0.rpack` both become `r.0.0.mca`
+fn region_files(archive: &Path) -> anyhow::Result<Vec<RegionFile>> {
+    let pairs = match archive.is_dir() {
+        true => batch::decompact_jobs(archive, archive)?.into_iter().map(|x| (x.output, x.input)).collect(),
+        false => {
+            let name = archive.file_name().context("Archive has no file name")?.to_string_lossy();
+            vec![(PathBuf::from(batch::region_name(&name, &region::providers())), archive.to_path_buf())]
+        },
+    };
+
+    let mut files = pairs
+        .into_iter()
+        .map(|(region, archive)| RegionFile {
+            name: region.file_name().unwrap().to_string_lossy().into_owned(),
+            archive,
+            size: None,
+        })
+        .collect::<Vec<_>>();
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+    if let Some(w) = files.windows(2).find(|w| w[0].name == w[1].name) {
+        bail!("{} and {} are both {}", w[0].archive.display(), w[1].archive.display(), w[0].name);
+    }
+    Ok(files)
+}
+
+fn mount(mountpoint: &Path, fd: i32, allow_other: bool) -> anyhow::Result<()> {
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    let mut data = format!("fd={fd},rootmode=40000,user_id={uid},group_id={gid}");
+    if allow_other {
+        data.push_str(",allow_other");
+    }
+
+    let target = CString::new(mountpoint.as_os_str().as_bytes())?;
+    let data = CString::new(data)?;
+    let result = unsafe {
+        libc::mount(
+            c"rpack".as_ptr(),
+            target.as_ptr(),
+            c"fuse.rpack".as_ptr(),
+            libc::MS_NOSUID | libc::MS_NODEV | libc::MS_RDONLY,
+            data.as_ptr().cast(),
+        )
+    };
+    ensure!(result == 0, "Unable to mount at {}: {}", mountpoint.display(), std::io::Error::last_os_error());
+    Ok(())
+}
+
+pub fn run(args: MountArgs) -> anyhow::Result<()> {
+    let files = region_files(&args.archive)?;
+    ensure!(!files.is_empty(), "No archives in {}", args.archive.display());
+    let mtime = std::fs::metadata(&args.archive)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_secs());
+
+    let mut device = File::options().read(true).write(true).open("/dev/fuse").context("Unable to open /dev/fuse")?;
+    mount(&args.mountpoint, device.as_raw_fd(), args.allow_other)?;
+    eprintln!("Mounted {} region files at {}, unmount to stop", files.len(), args.mountpoint.display());
+
+    let mut filesystem = Filesystem {
+        files,
+        cache: VecDeque::new(),
+        capacity: args.cache,
+        uid: unsafe { libc::getuid() },
+        gid: unsafe { libc::getgid() },
+        mtime,
+    };
+    // Every request must be read whole by a single read
+    let mut buffer = vec![0u8; MAX_REPLY as usize + 4096];
+
+    loop {
+        let length = match device.read(&mut buffer) {
+            Ok(x) => x,
+            Err(e) if e.raw_os_error() == Some(libc::ENODEV) => break,
+            Err(e) if matches!(e.raw_os_error(), Some(libc::EINTR | libc::EAGAIN | libc::ENOENT)) => continue,
+            Err(e) => return Err(e).context("Unable to read FUSE request"),
+        };
+        let Ok((header, body)) = abi::InHeader::read_from_prefix(&buffer[..length]) else {
+            bail!("FUSE request is truncated");
+        };
+
+        let Some(reply) = filesystem.handle(&header, body) else { continue };
+        let (error, payload) = match reply {
+            Ok(payload) => (0, payload),
+            Err(errno) => (-errno, vec![]),
+        };
+        let out = abi::OutHeader {
+            len: (size_of::<abi::OutHeader>() + payload.len()) as u32,
+            error,
+            unique: header.unique,
+        };
+        let message = [out.as_bytes(), &payload].concat();
+        match device.write(&message) {
+            Ok(_) => {},
+            // Request was interrupted meanwhile
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {},
+            Err(e) if e.raw_os_error() == Some(libc::ENODEV) => break,
+            Err(e) => return Err(e).context("Unable to write FUSE reply"),
+        }
+        if header.opcode == abi::DESTROY {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn abi_sizes() {
+        assert_eq!(size_of::<abi::InHeader>(), 40);
+        assert_eq!(size_of::<abi::InitOut>(), 64);
+        assert_eq!(size_of::<abi::EntryOut>(), 128);
+        assert_eq!(size_of::<abi::AttrOut>(), 104);
+        assert_eq!(size_of::<abi::StatfsOut>(), 80);
+    }
+}
@@ -0,0 +1,442 @@
+#![allow(unused)]
+
+use anyhow::{bail, ensure, Context};
+use core::fmt::Debug;
+use std::io::Write;
+
+/// Same limit as vanilla uses to prevent stack overflows on malicious data
+pub const MAX_DEPTH: usize = 512;
+
+pub mod tag_id {
+    pub const END: u8 = 0;
+    pub const BYTE: u8 = 1;
+    pub const SHORT: u8 = 2;
+    pub const INT: u8 = 3;
+    pub const LONG: u8 = 4;
+    pub const FLOAT: u8 = 5;
+    pub const DOUBLE: u8 = 6;
+    pub const BYTE_ARRAY: u8 = 7;
+    pub const STRING: u8 = 8;
+    pub const LIST: u8 = 9;
+    pub const COMPOUND: u8 = 10;
+    pub const INT_ARRAY: u8 = 11;
+    pub const LONG_ARRAY: u8 = 12;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tag {
+    Byte(i8),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    ByteArray(Vec<i8>),
+    String(String),
+    /// Element tag id is kept so empty lists survive round-trip
+    List(u8, Vec<Tag>),
+    Compound(Compound),
+    IntArray(Vec<i32>),
+    LongArray(Vec<i64>),
+}
+
+impl Tag {
+    pub fn id(&self) -> u8 {
+        match self {
+            Tag::Byte(_) => tag_id::BYTE,
+            Tag::Short(_) => tag_id::SHORT,
+            Tag::Int(_) => tag_id::INT,
+            Tag::Long(_) => tag_id::LONG,
+            Tag::Float(_) => tag_id::FLOAT,
+            Tag::Double(_) => tag_id::DOUBLE,
+            Tag::ByteArray(_) => tag_id::BYTE_ARRAY,
+            Tag::String(_) => tag_id::STRING,
+            Tag::List(..) => tag_id::LIST,
+            Tag::Compound(_) => tag_id::COMPOUND,
+            Tag::IntArray(_) => tag_id::INT_ARRAY,
+            Tag::LongArray(_) => tag_id::LONG_ARRAY,
+        }
+    }
+
+    /// Integer value of any integral tag
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Tag::Byte(x) => Some(x as i64),
+            Tag::Short(x) => Some(x as i64),
+            Tag::Int(x) => Some(x as i64),
+            Tag::Long(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Tag::String(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_compound(&self) -> Option<&Compound> {
+        match self {
+            Tag::Compound(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_compound_mut(&mut self) -> Option<&mut Compound> {
+        match self {
+            Tag::Compound(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Tag]> {
+        match self {
+            Tag::List(_, x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+/// Compound tag. Keeps entries in the original order so unmodified data is written back byte-to-byte
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Compound(pub Vec<(String, Tag)>);
+
+impl Compound {
+    pub fn get(&self, name: &str) -> Option<&Tag> {
+        self.0.iter().find(|(n, _)| n == name).map(|(_, x)| x)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Tag> {
+        self.0.iter_mut().find(|(n, _)| n == name).map(|(_, x)| x)
+    }
+
+    /// Replaces existing tag in place or appends a new one
+    pub fn insert(&mut self, name: impl Into<String>, tag: Tag) -> Option<Tag> {
+        let name = name.into();
+        match self.get_mut(&name) {
+            Some(x) => Some(core::mem::replace(x, tag)),
+            None => {
+                self.0.push((name, tag));
+                None
+            },
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Tag> {
+        let idx = self.0.iter().position(|(n, _)| n == name)?;
+        Some(self.0.remove(idx).1)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Tag)> {
+        self.0.iter().map(|(n, x)| (n.as_str(), x))
+    }
+}
+
+/// Reads an uncompressed NBT document. Returns root name and root tag
+pub fn read(data: &[u8]) -> anyhow::Result<(String, Tag)> {
+    let mut reader = NbtReader { data, depth: 0 };
+
+    let id = reader.u8()?;
+    ensure!(id == tag_id::COMPOUND, "Root tag must be a compound, got tag id {id}");
+    let name = reader.string()?;
+    let root = reader.payload(id)?;
+
+    Ok((name, root))
+}
+
+/// Reads root compound
+pub fn read_compound(data: &[u8]) -> anyhow::Result<Compound> {
+    match read(data)?.1 {
+        Tag::Compound(x) => Ok(x),
+        _ => unreachable!(),
+    }
+}
+
+pub fn write(mut writer: impl Write, name: &str, tag: &Tag) -> anyhow::Result<()> {
+    writer.write_all(&[tag.id()])?;
+    write_string(&mut writer, name)?;
+    write_payload(&mut writer, tag)?;
+    Ok(())
+}
+
+pub fn write_compound(mut writer: impl Write, root: &Compound) -> anyhow::Result<()> {
+    // Avoid cloning the whole document just to wrap it into Tag
+    writer.write_all(&[tag_id::COMPOUND])?;
+    write_string(&mut writer, "")?;
+    write_compound_payload(&mut writer, root)?;
+    Ok(())
+}
+
+struct NbtReader<'a> {
+    data: &'a [u8],
+    depth: usize,
+}
+
+impl<'a> NbtReader<'a> {
+    fn take(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        let (head, tail) = self
+            .data
+            .split_at_checked(n)
+            .ok_or(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+            .context("NBT data is truncated")?;
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn len(&mut self, elem_size: usize) -> anyhow::Result<usize> {
+        let len = i32::from_be_bytes(self.array()?);
+        ensure!(len >= 0, "Negative NBT length {len}");
+        // Do not trust the length before checking there is enough data for it
+        ensure!(
+            (len as usize).saturating_mul(elem_size) <= self.data.len(),
+            "NBT length {len} exceeds remaining data"
+        );
+        Ok(len as usize)
+    }
+
+    fn string(&mut self) -> anyhow::Result<String> {
+        let len = u16::from_be_bytes(self.array()?) as usize;
+        let bytes = self.take(len)?;
+        Ok(decode_mutf8(bytes))
+    }
+
+    fn payload(&mut self, id: u8) -> anyhow::Result<Tag> {
+        Ok(match id {
+            tag_id::BYTE => Tag::Byte(self.u8()? as i8),
+            tag_id::SHORT => Tag::Short(i16::from_be_bytes(self.array()?)),
+            tag_id::INT => Tag::Int(i32::from_be_bytes(self.array()?)),
+            tag_id::LONG => Tag::Long(i64::from_be_bytes(self.array()?)),
+            tag_id::FLOAT => Tag::Float(f32::from_be_bytes(self.array()?)),
+            tag_id::DOUBLE => Tag::Double(f64::from_be_bytes(self.array()?)),
+            tag_id::BYTE_ARRAY => {
+                let len = self.len(1)?;
+                Tag::ByteArray(self.take(len)?.iter().map(|&x| x as i8).collect())
+            },
+            tag_id::STRING => Tag::String(self.string()?),
+            tag_id::LIST => {
+                let elem = self.u8()?;
+                // Every element takes at least one byte
+                let len = self.len(1)?;
+                if len != 0 {
+                    ensure!(elem != tag_id::END, "Non-empty list of End tags");
+                }
+                self.enter()?;
+                let items = (0..len)
+                    .map(|_| self.payload(elem))
+                    .collect::<anyhow::Result<_>>()?;
+                self.depth -= 1;
+                Tag::List(elem, items)
+            },
+            tag_id::COMPOUND => {
+                self.enter()?;
+                let mut compound = Compound::default();
+                loop {
+                    let id = self.u8()?;
+                    if id == tag_id::END {
+                        break;
+                    }
+                    let name = self.string()?;
+                    let tag = self.payload(id)?;
+                    compound.0.push((name, tag));
+                }
+                self.depth -= 1;
+                Tag::Compound(compound)
+            },
+            tag_id::INT_ARRAY => {
+                let len = self.len(4)?;
+                Tag::IntArray(
+                    self.take(len * 4)?
+                        .chunks_exact(4)
+                        .map(|x| i32::from_be_bytes(x.try_into().unwrap()))
+                        .collect(),
+                )
+            },
+            tag_id::LONG_ARRAY => {
+                let len = self.len(8)?;
+                Tag::LongArray(
+                    self.take(len * 8)?
+                        .chunks_exact(8)
+                        .map(|x| i64::from_be_bytes(x.try_into().unwrap()))
+                        .collect(),
+                )
+            },
+            id => bail!("Unknown NBT tag id {id}"),
+        })
+    }
+
+    fn enter(&mut self) -> anyhow::Result<()> {
+        self.depth += 1;
+        ensure!(self.depth <= MAX_DEPTH, "NBT is nested deeper than {MAX_DEPTH}");
+        Ok(())
+    }
+}
+
+fn write_string(mut writer: impl Write, s: &str) -> anyhow::Result<()> {
+    let bytes = encode_mutf8(s);
+    let len: u16 = bytes.len().try_into().context("NBT string is too long")?;
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(&bytes)?;
+    Ok(())
+}
+
+fn write_len(mut writer: impl Write, len: usize) -> anyhow::Result<()> {
+    let len: i32 = len.try_into().context("NBT array is too long")?;
+    writer.write_all(&len.to_be_bytes())?;
+    Ok(())
+}
+
+fn write_compound_payload(mut writer: &mut impl Write, compound: &Compound) -> anyhow::Result<()> {
+    for (name, tag) in compound.0.iter() {
+        writer.write_all(&[tag.id()])?;
+        write_string(&mut writer, name)?;
+        write_payload(writer, tag)?;
+    }
+    writer.write_all(&[tag_id::END])?;
+    Ok(())
+}
+
+fn write_payload(mut writer: &mut impl Write, tag: &Tag) -> anyhow::Result<()> {
+    match tag {
+        Tag::Byte(x) => writer.write_all(&x.to_be_bytes())?,
+        Tag::Short(x) => writer.write_all(&x.to_be_bytes())?,
+        Tag::Int(x) => writer.write_all(&x.to_be_bytes())?,
+        Tag::Long(x) => writer.write_all(&x.to_be_bytes())?,
+        Tag::Float(x) => writer.write_all(&x.to_be_bytes())?,
+        Tag::Double(x) => writer.write_all(&x.to_be_bytes())?,
+        Tag::ByteArray(x) => {
+            write_len(&mut writer, x.len())?;
+            x.iter().try_for_each(|x| writer.write_all(&x.to_be_bytes()))?;
+        },
+        Tag::String(x) => write_string(writer, x)?,
+        Tag::List(elem, items) => {
+            ensure!(
+                items.iter().all(|x| x.id() == *elem),
+                "NBT list contains tags of different types"
+            );
+            writer.write_all(&[*elem])?;
+            write_len(&mut writer, items.len())?;
+            items.iter().try_for_each(|x| write_payload(writer, x))?;
+        },
+        Tag::Compound(x) => write_compound_payload(writer, x)?,
+        Tag::IntArray(x) => {
+            write_len(&mut writer, x.len())?;
+            x.iter().try_for_each(|x| writer.write_all(&x.to_be_bytes()))?;
+        },
+        Tag::LongArray(x) => {
+            write_len(&mut writer, x.len())?;
+            x.iter().try_for_each(|x| writer.write_all(&x.to_be_bytes()))?;
+        },
+    }
+    Ok(())
+}
+
+/// Java's "modified UTF-8": NUL is encoded as 2 bytes and supplementary characters as surrogate pairs.
+/// Invalid sequences are replaced with U+FFFD.
+fn decode_mutf8(bytes: &[u8]) -> String {
+    if let Ok(s) = core::str::from_utf8(bytes) {
+        return s.to_owned();
+    }
+
+    let mut units = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let b = bytes[i];
+        let (unit, len) = match b {
+            0x00..=0x7F => (b as u16, 1),
+            0xC0..=0xDF if i + 1 < bytes.len() => {
+                (((b as u16 & 0x1F) << 6) | (bytes[i + 1] as u16 & 0x3F), 2)
+            },
+            0xE0..=0xEF if i + 2 < bytes.len() => (
+                ((b as u16 & 0x0F) << 12)
+                    | ((bytes[i + 1] as u16 & 0x3F) << 6)
+                    | (bytes[i + 2] as u16 & 0x3F),
+                3,
+            ),
+            _ => (0xFFFD, 1),
+        };
+        units.push(unit);
+        i += len;
+    }
+
+    String::from_utf16_lossy(&units)
+}
+
+fn encode_mutf8(s: &str) -> Vec<u8> {
+    if !s.bytes().any(|x| x == 0 || x >= 0xF0) {
+        return s.as_bytes().to_vec();
+    }
+
+    let mut out = Vec::with_capacity(s.len() + 8);
+    for unit in s.encode_utf16() {
+        match unit {
+            0x01..=0x7F => out.push(unit as u8),
+            0x00 | 0x80..=0x7FF => {
+                out.push(0xC0 | (unit >> 6) as u8);
+                out.push(0x80 | (unit & 0x3F) as u8);
+            },
+            _ => {
+                out.push(0xE0 | (unit >> 12) as u8);
+                out.push(0x80 | ((unit >> 6) & 0x3F) as u8);
+                out.push(0x80 | (unit & 0x3F) as u8);
+            },
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read, write, Compound, Tag};
+
+    #[test]
+    fn round_trip() {
+        let root = Tag::Compound(Compound(vec![
+            ("xPos".into(), Tag::Int(-3)),
+            ("zPos".into(), Tag::Int(7)),
+            ("Status".into(), Tag::String("minecraft:full".into())),
+            ("weird\0name😁".into(), Tag::Byte(1)),
+            ("empty".into(), Tag::List(0, vec![])),
+            (
+                "sections".into(),
+                Tag::List(10, vec![Tag::Compound(Compound(vec![(
+                    "data".into(),
+                    Tag::LongArray(vec![1, -2, i64::MAX]),
+                )]))]),
+            ),
+        ]));
+
+        let mut buf = vec![];
+        write(&mut buf, "", &root).unwrap();
+
+        let (name, parsed) = read(&buf).unwrap();
+        assert_eq!(name, "");
+        assert_eq!(parsed, root);
+
+        let mut buf2 = vec![];
+        write(&mut buf2, "", &parsed).unwrap();
+        assert_eq!(buf, buf2);
+    }
+
+    #[test]
+    fn rejects_truncated_and_bogus_lengths() {
+        let mut buf = vec![];
+        write(&mut buf, "", &Tag::Compound(Compound(vec![("a".into(), Tag::IntArray(vec![1, 2]))])))
+            .unwrap();
+
+        assert!(read(&buf[..buf.len() - 3]).is_err());
+
+        // Claim a huge array
+        let pos = buf.len() - 1 - 8 - 4;
+        buf[pos..pos + 4].copy_from_slice(&i32::MAX.to_be_bytes());
+        assert!(read(&buf).is_err());
+    }
+}
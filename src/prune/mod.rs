@@ -0,0 +1,310 @@
+//! Retention of a snapshot chain: a directory of snapshots named by date, each a full archive or a delta
+//! against the snapshot before it (or a directory of them, one per region).
+//!
+//! Dropped deltas are merged into the next kept delta of the chain, so later snapshots still apply.
+//! A dropped full archive is deleted only if no kept snapshot depends on it.
+
+use std::{
+    collections::BTreeSet,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, ensure, Context};
+
+use crate::{
+    delta::{self, ArchiveArgs},
+    rpack::{self, delta::Delta},
+};
+
+#[derive(Debug, clap::Args)]
+pub struct PruneArgs {
+    /// Directory of snapshots with names starting with their date, like `2024-05-01.rpack` or `2024-05-01/`
+    pub dir: PathBuf,
+
+    /// Keep the latest snapshot of each of the last N days having snapshots
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub keep_daily: usize,
+
+    /// Keep the latest snapshot of each of the last N weeks having snapshots. Weeks start on Monday
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub keep_weekly: usize,
+
+    /// Print what would be done without changing anything
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Options of rewritten deltas
+    #[command(flatten)]
+    pub archive: ArchiveArgs,
+}
+
+/// Snapshot of a chain, a full archive or a delta against the previous entry
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub path: PathBuf,
+    /// Days since 1970-01-01
+    pub day: i64,
+    pub delta: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    Keep,
+    /// Dropped, but a kept delta is made against it
+    KeepAsBase,
+    /// Merged into the delta at index and deleted
+    FoldInto(usize),
+    Delete,
+    /// Delta without full snapshot before it, left alone
+    Orphan,
+}
+
+/// Days since 1970-01-01 of a name starting with `YYYY-MM-DD`
+fn parse_day(name: &str) -> Option<i64> {
+    let date = name.get(..10)?.as_bytes();
+    if date[4] != b'-' || date[7] != b'-' {
+        return None;
+    }
+    let number = |x: &[u8]| std::str::from_utf8(x).ok()?.parse::<i64>().ok();
+    let (year, month, day) = (number(&date[..4])?, number(&date[5..7])?, number(&date[8..10])?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // Days from civil, counting years from March so the leap day is the last one
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Some(era * 146097 + day_of_era - 719468)
+}
+
+/// Monday-based week of a day, 1970-01-01 was a Thursday
+fn week(day: i64) -> i64 {
+    (day + 3).div_euclid(7)
+}
+
+/// Indices of entries to keep. Entries are in chronological order, the newest one is always kept
+fn select(entries: &[Entry], keep_daily: usize, keep_weekly: usize) -> BTreeSet<usize> {
+    let mut keep = BTreeSet::from_iter(entries.len().checked_sub(1));
+    for (count, bucket) in [(keep_daily, (|x| x) as fn(i64) -> i64), (keep_weekly, week)] {
+        let mut seen = BTreeSet::new();
+        for (i, entry) in entries.iter().enumerate().rev() {
+            if seen.len() == count {
+                break;
+            }
+            if seen.insert(bucket(entry.day)) {
+                keep.insert(i);
+            }
+        }
+    }
+    keep
+}
+
+fn plan(entries: &[Entry], keep: &BTreeSet<usize>) -> Vec<Action> {
+    let mut actions = entries.iter().map(|_| Action::Orphan).collect::<Vec<_>>();
+    let starts = entries.iter().enumerate().filter(|x| !x.1.delta).map(|x| x.0).collect::<Vec<_>>();
+
+    for (n, &start) in starts.iter().enumerate() {
+        let end = starts.get(n + 1).copied().unwrap_or(entries.len());
+        let last_kept = (start..end).rev().find(|x| keep.contains(x));
+
+        actions[start] = match last_kept {
+            _ if keep.contains(&start) => Action::Keep,
+            Some(_) => Action::KeepAsBase,
+            None => Action::Delete,
+        };
+        // Dropped deltas go into the next kept one, nothing depends on those after the last kept one
+        let mut pending = vec![];
+        for i in start + 1..end {
+            if keep.contains(&i) {
+                actions[i] = Action::Keep;
+                for j in pending.drain(..) {
+                    actions[j] = Action::FoldInto(i);
+                }
+            } else if last_kept.is_some_and(|x| i < x) {
+                pending.push(i);
+            } else {
+                actions[i] = Action::Delete;
+            }
+        }
+    }
+    actions
+}
+
+/// Archive path without volume suffix, `None` for later volumes
+fn archive_path(path: &Path) -> Option<PathBuf> {
+    if let Some(base) = rpack::volume::first_volume_base(path) {
+        return Some(base);
+    }
+    let later_volume = path
+        .extension()
+        .and_then(|x| x.to_str())
+        .is_some_and(|x| x.len() == 3 && x.bytes().all(|x| x.is_ascii_digit()));
+    (!later_volume).then(|| path.to_path_buf())
+}
+
+fn is_delta(path: &Path) -> anyhow::Result<bool> {
+    let reader = rpack::RpackReader::new(rpack::volume::open(path)?).with_context(|| format!("Unable to read {}", path.display()))?;
+    Ok(reader.delta())
+}
+
+/// Snapshots of directory in chronological order
+pub fn read_entries(dir: &Path) -> anyhow::Result<Vec<Entry>> {
+    let mut entries = vec![];
+    for item in std::fs::read_dir(dir).with_context(|| format!("Unable to read {}", dir.display()))? {
+        let path = item?.path();
+        let Some(path) = archive_path(&path) else { continue };
+        let name = path.file_name().unwrap().to_string_lossy();
+        let Some(day) = parse_day(&name) else {
+            eprintln!("Skipping {}: name does not start with a date", path.display());
+            continue;
+        };
+
+        let delta = match path.is_dir() {
+            true => {
+                let kinds = delta::archives(&path)?
+                    .iter()
+                    .map(|x| is_delta(&path.join(x)))
+                    .collect::<anyhow::Result<BTreeSet<_>>>()?;
+                match kinds.into_iter().collect::<Vec<_>>()[..] {
+                    [delta] => delta,
+                    [] => bail!("{} has no archives", path.display()),
+                    _ => bail!("{} mixes full and delta archives", path.display()),
+                }
+            },
+            false => is_delta(&path)?,
+        };
+        entries.push(Entry { path, day, delta });
+    }
+    entries.sort_by(|a, b| (a.day, &a.path).cmp(&(b.day, &b.path)));
+    Ok(entries)
+}
+
+/// Removes archive with all its volumes, or directory of archives
+fn remove(path: &Path) -> anyhow::Result<()> {
+    let result = match path.is_dir() {
+        true => std::fs::remove_dir_all(path),
+        false => rpack::volume::volume_paths(path)
+            .iter()
+            .map(PathBuf::as_path)
+            .chain(path.exists().then_some(path))
+            .try_for_each(std::fs::remove_file),
+    };
+    result.with_context(|| format!("Unable to remove {}", path.display()))
+}
+
+/// Merges delta archives in order and replaces the last one with the result
+fn fold_archive(sources: &[PathBuf], target: &Path, options: &rpack::Options) -> anyhow::Result<()> {
+    let mut merged = Delta::new();
+    for source in sources.iter().chain([&target.to_path_buf()]) {
+        if source.exists() || rpack::volume::volume_path(source, 1).exists() {
+            rpack::delta::merge(&mut merged, delta::read_delta(source)?);
+        }
+    }
+
+    let mut temporary = target.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    rpack::delta::write_delta(delta::create(&temporary)?, options.clone(), &merged)?.flush()?;
+    remove(target).ok();
+    std::fs::rename(&temporary, target).with_context(|| format!("Unable to replace {}", target.display()))
+}
+
+fn fold(sources: &[PathBuf], target: &Path, options: &rpack::Options) -> anyhow::Result<()> {
+    if !target.is_dir() {
+        ensure!(sources.iter().all(|x| !x.is_dir()), "Unable to fold directory into {}", target.display());
+        return fold_archive(sources, target, options);
+    }
+
+    ensure!(sources.iter().all(|x| x.is_dir()), "Unable to fold archive into directory {}", target.display());
+    let mut names = BTreeSet::from_iter(delta::archives(target)?);
+    for source in sources {
+        names.extend(delta::archives(source)?);
+    }
+    for name in names {
+        let sources = sources.iter().map(|x| x.join(&name)).collect::<Vec<_>>();
+        fold_archive(&sources, &target.join(&name), options)?;
+    }
+    Ok(())
+}
+
+pub fn run(args: PruneArgs) -> anyhow::Result<()> {
+    let entries = read_entries(&args.dir)?;
+    let keep = select(&entries, args.keep_daily, args.keep_weekly);
+    let actions = plan(&entries, &keep);
+
+    for (entry, action) in entries.iter().zip(actions.iter()) {
+        let name = entry.path.display();
+        match action {
+            Action::Keep => println!("  keep     {name}"),
+            Action::KeepAsBase => println!("  base     {name}: kept as base of later snapshots"),
+            Action::FoldInto(i) => println!("  fold     {name} into {}", entries[*i].path.display()),
+            Action::Delete => println!("  delete   {name}"),
+            Action::Orphan => println!("  orphan   {name}: no full snapshot before it, left untouched"),
+        }
+    }
+    if args.dry_run {
+        return Ok(());
+    }
+
+    // Targets are rewritten before their sources are deleted
+    let options = args.archive.options();
+    for (target, _) in actions.iter().enumerate().filter(|x| x.1 == &Action::Keep) {
+        let sources = actions
+            .iter()
+            .enumerate()
+            .filter(|x| x.1 == &Action::FoldInto(target))
+            .map(|x| entries[x.0].path.clone())
+            .collect::<Vec<_>>();
+        if !sources.is_empty() {
+            fold(&sources, &entries[target].path, &options)?;
+        }
+    }
+    for (entry, action) in entries.iter().zip(actions.iter()) {
+        if matches!(action, Action::Delete | Action::FoldInto(_)) {
+            remove(&entry.path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn days() {
+        assert_eq!(parse_day("1970-01-01.rpack"), Some(0));
+        assert_eq!(parse_day("2000-03-01"), Some(11017));
+        assert_eq!(parse_day("2024-02-29T03:00"), Some(19782));
+        assert_eq!(parse_day("2024-13-01"), None);
+        assert_eq!(parse_day("latest.rpack"), None);
+        // Monday and Sunday of the same week
+        assert_eq!(week(parse_day("2024-05-06").unwrap()), week(parse_day("2024-05-12").unwrap()));
+        assert_ne!(week(parse_day("2024-05-12").unwrap()), week(parse_day("2024-05-13").unwrap()));
+    }
+
+    #[test]
+    fn chain_plan() {
+        // Full snapshot on Monday, deltas every day after, twice on Friday, then full snapshot on Monday again
+        let kinds = [false, true, true, true, true, true, false, true];
+        let days = [0, 1, 2, 3, 4, 4, 7, 8].map(|x| x + parse_day("2024-05-06").unwrap());
+        let entries = kinds
+            .iter()
+            .zip(days)
+            .enumerate()
+            .map(|(i, (delta, day))| Entry { path: PathBuf::from(i.to_string()), day, delta: *delta })
+            .collect::<Vec<_>>();
+
+        let keep = select(&entries, 2, 2);
+        assert_eq!(keep.iter().copied().collect::<Vec<_>>(), [5, 6, 7]);
+
+        use Action::*;
+        assert_eq!(plan(&entries, &keep), [KeepAsBase, FoldInto(5), FoldInto(5), FoldInto(5), FoldInto(5), Keep, Keep, Keep]);
+        assert_eq!(plan(&entries, &BTreeSet::from([7])), [Delete, Delete, Delete, Delete, Delete, Delete, KeepAsBase, Keep]);
+        assert_eq!(plan(&entries[1..], &BTreeSet::from([6])), [Orphan, Orphan, Orphan, Orphan, Orphan, KeepAsBase, Keep]);
+    }
+}
@@ -0,0 +1,297 @@
+//! Tiny predicate language over NBT.
+//!
+//! ```text
+//! expr    := and ("||" and)*
+//! and     := unary ("&&" unary)*
+//! unary   := "!" unary | "(" expr ")" | path (op literal)?
+//! path    := name ("." name | "[]" | "[" index "]")*
+//! op      := "==" | "!=" | "<" | "<=" | ">" | ">="
+//! literal := "string" | number
+//! ```
+//!
+//! `[]` iterates over all list/array elements, so a comparison is true when any reached value matches.
+//! Path without comparison checks that the path exists.
+
+use anyhow::{bail, ensure, Context};
+
+use crate::nbt::{Compound, Tag};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    Or(Box<Query>, Box<Query>),
+    And(Box<Query>, Box<Query>),
+    Not(Box<Query>),
+    Exists(Vec<Segment>),
+    Compare(Vec<Segment>, Op, Literal),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    Name(String),
+    All,
+    Index(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    String(String),
+    Number(f64),
+}
+
+impl Query {
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let mut parser = Parser { s: s.trim_start() };
+        let query = parser.or()?;
+        ensure!(parser.s.is_empty(), "Unexpected trailing input: {}", parser.s);
+        Ok(query)
+    }
+
+    pub fn matches(&self, root: &Compound) -> bool {
+        match self {
+            Query::Or(a, b) => a.matches(root) || b.matches(root),
+            Query::And(a, b) => a.matches(root) && b.matches(root),
+            Query::Not(x) => !x.matches(root),
+            Query::Exists(path) => {
+                let mut found = false;
+                walk(root, path, &mut |_| found = true);
+                found
+            },
+            Query::Compare(path, op, literal) => {
+                let mut found = false;
+                walk(root, path, &mut |x| found |= compare(x, *op, literal));
+                found
+            },
+        }
+    }
+}
+
+fn walk(root: &Compound, path: &[Segment], f: &mut impl FnMut(&Tag)) {
+    let Some((Segment::Name(name), rest)) = path.split_first() else {
+        return;
+    };
+    if let Some(tag) = root.get(name) {
+        walk_tag(tag, rest, f);
+    }
+}
+
+fn walk_tag(tag: &Tag, path: &[Segment], f: &mut impl FnMut(&Tag)) {
+    let Some((segment, rest)) = path.split_first() else {
+        f(tag);
+        return;
+    };
+
+    match (segment, tag) {
+        (Segment::Name(_), Tag::Compound(x)) => walk(x, path, f),
+        (Segment::All, Tag::List(_, items)) => items.iter().for_each(|x| walk_tag(x, rest, f)),
+        (Segment::Index(i), Tag::List(_, items)) => {
+            if let Some(x) = items.get(*i) {
+                walk_tag(x, rest, f)
+            }
+        },
+        // Arrays hold plain numbers, so there is nothing to descend into
+        (Segment::All, Tag::ByteArray(x)) if rest.is_empty() => x.iter().for_each(|&x| f(&Tag::Byte(x))),
+        (Segment::All, Tag::IntArray(x)) if rest.is_empty() => x.iter().for_each(|&x| f(&Tag::Int(x))),
+        (Segment::All, Tag::LongArray(x)) if rest.is_empty() => x.iter().for_each(|&x| f(&Tag::Long(x))),
+        (Segment::Index(i), Tag::ByteArray(x)) if rest.is_empty() => x.get(*i).iter().for_each(|&&x| f(&Tag::Byte(x))),
+        (Segment::Index(i), Tag::IntArray(x)) if rest.is_empty() => x.get(*i).iter().for_each(|&&x| f(&Tag::Int(x))),
+        (Segment::Index(i), Tag::LongArray(x)) if rest.is_empty() => x.get(*i).iter().for_each(|&&x| f(&Tag::Long(x))),
+        _ => {},
+    }
+}
+
+fn compare(tag: &Tag, op: Op, literal: &Literal) -> bool {
+    let ordering = match (tag, literal) {
+        (Tag::String(a), Literal::String(b)) => a.as_str().cmp(b.as_str()),
+        (tag, Literal::Number(b)) => {
+            let a = match *tag {
+                Tag::Float(x) => x as f64,
+                Tag::Double(x) => x,
+                ref x => match x.as_i64() {
+                    Some(x) => x as f64,
+                    None => return false,
+                },
+            };
+            match a.partial_cmp(b) {
+                Some(x) => x,
+                None => return false,
+            }
+        },
+        _ => return false,
+    };
+
+    match op {
+        Op::Eq => ordering.is_eq(),
+        Op::Ne => ordering.is_ne(),
+        Op::Lt => ordering.is_lt(),
+        Op::Le => ordering.is_le(),
+        Op::Gt => ordering.is_gt(),
+        Op::Ge => ordering.is_ge(),
+    }
+}
+
+struct Parser<'a> {
+    s: &'a str,
+}
+
+impl Parser<'_> {
+    fn eat(&mut self, token: &str) -> bool {
+        match self.s.strip_prefix(token) {
+            Some(rest) => {
+                self.s = rest.trim_start();
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn or(&mut self) -> anyhow::Result<Query> {
+        let mut left = self.and()?;
+        while self.eat("||") {
+            left = Query::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> anyhow::Result<Query> {
+        let mut left = self.unary()?;
+        while self.eat("&&") {
+            left = Query::And(Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> anyhow::Result<Query> {
+        if self.s.starts_with("!=") {
+            bail!("Expected expression, got: {}", self.s);
+        }
+        if self.eat("!") {
+            return Ok(Query::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let query = self.or()?;
+            ensure!(self.eat(")"), "Expected ')', got: {}", self.s);
+            return Ok(query);
+        }
+
+        let path = self.path()?;
+        let op = [
+            ("==", Op::Eq),
+            ("!=", Op::Ne),
+            ("<=", Op::Le),
+            (">=", Op::Ge),
+            ("<", Op::Lt),
+            (">", Op::Gt),
+        ]
+        .into_iter()
+        .find(|(token, _)| self.eat(token));
+
+        match op {
+            Some((_, op)) => Ok(Query::Compare(path, op, self.literal()?)),
+            None => Ok(Query::Exists(path)),
+        }
+    }
+
+    fn name(&mut self) -> anyhow::Result<String> {
+        let len = self
+            .s
+            .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == ':'))
+            .unwrap_or(self.s.len());
+        ensure!(len != 0, "Expected tag name, got: {}", self.s);
+
+        let (name, rest) = self.s.split_at(len);
+        self.s = rest;
+        Ok(name.to_owned())
+    }
+
+    fn path(&mut self) -> anyhow::Result<Vec<Segment>> {
+        let mut path = vec![Segment::Name(self.name()?)];
+        loop {
+            if let Some(rest) = self.s.strip_prefix('.') {
+                self.s = rest;
+                path.push(Segment::Name(self.name()?));
+            } else if let Some(rest) = self.s.strip_prefix("[]") {
+                self.s = rest;
+                path.push(Segment::All);
+            } else if let Some(rest) = self.s.strip_prefix('[') {
+                let (index, rest) = rest.split_once(']').context("Expected ']'")?;
+                let index = index.trim().parse().with_context(|| format!("Invalid index {index:?}"))?;
+                self.s = rest;
+                path.push(Segment::Index(index));
+            } else {
+                break;
+            }
+        }
+        self.s = self.s.trim_start();
+        Ok(path)
+    }
+
+    fn literal(&mut self) -> anyhow::Result<Literal> {
+        if let Some(rest) = self.s.strip_prefix('"') {
+            let mut value = String::new();
+            let mut chars = rest.char_indices();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '"' => {
+                        self.s = rest[i + 1..].trim_start();
+                        return Ok(Literal::String(value));
+                    },
+                    '\\' => value.extend(chars.next().map(|x| x.1)),
+                    c => value.push(c),
+                }
+            }
+            bail!("Unterminated string");
+        }
+
+        let len = self
+            .s
+            .find(|c: char| !(c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E')))
+            .unwrap_or(self.s.len());
+        let (number, rest) = self.s.split_at(len);
+        let number = number
+            .parse()
+            .with_context(|| format!("Expected string or number, got: {}", self.s))?;
+        self.s = rest.trim_start();
+        Ok(Literal::Number(number))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Query;
+    use crate::nbt::{Compound, Tag};
+
+    #[test]
+    fn matches() {
+        let entity = |id: &str| Tag::Compound(Compound(vec![("id".into(), Tag::String(id.into()))]));
+        let root = Compound(vec![
+            ("DataVersion".into(), Tag::Int(3465)),
+            (
+                "block_entities".into(),
+                Tag::List(10, vec![entity("minecraft:chest"), entity("minecraft:beacon")]),
+            ),
+            ("Heights".into(), Tag::LongArray(vec![1, 2, 3])),
+        ]);
+
+        let check = |q: &str| Query::parse(q).unwrap().matches(&root);
+
+        assert!(check(r#"block_entities[].id == "minecraft:beacon""#));
+        assert!(!check(r#"block_entities[0].id == "minecraft:beacon""#));
+        assert!(check(r#"block_entities[1].id == "minecraft:beacon" && DataVersion >= 3000"#));
+        assert!(check(r#"!sections || DataVersion < 100"#));
+        assert!(!check(r#"!(DataVersion != 3465)  && Heights[] > 3"#));
+        assert!(check("Heights[2] == 3"));
+
+        assert!(Query::parse("DataVersion ==").is_err());
+        assert!(Query::parse("a == 1 b").is_err());
+    }
+}
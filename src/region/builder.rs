@@ -0,0 +1,54 @@
+use std::io::{Cursor, Write};
+
+use anyhow::{ensure, Context};
+
+use super::{RegionInfo, RegionWriter};
+use crate::chunk::Codec;
+
+/// Collects chunks in memory and writes a complete region file at once.
+///
+/// ```
+/// # use anvilregion_repacker::{chunk::Codec, region::RegionBuilder};
+/// # let nbt_bytes = [10, 0, 0, 0];
+/// let mut region = vec![];
+/// RegionBuilder::new()
+///     .add_chunk(0, 0, 1700000000, &nbt_bytes, Codec::Zlib)?
+///     .write_to(&mut region)?;
+/// # anyhow::Ok(())
+/// ```
+#[derive(Debug, Default)]
+pub struct RegionBuilder {
+    chunks: Vec<Option<(u32, Vec<u8>, Codec)>>,
+}
+
+impl RegionBuilder {
+    pub fn new() -> Self {
+        Self {
+            chunks: vec![None; RegionInfo::MAX_CHUNK_COUNT as usize],
+        }
+    }
+
+    /// Adds uncompressed chunk NBT at local coordinates `x`, `z` in `0..32`
+    pub fn add_chunk(&mut self, x: u8, z: u8, timestamp: u32, nbt: &[u8], codec: Codec) -> anyhow::Result<&mut Self> {
+        ensure!(x < 32 && z < 32, "Chunk {x},{z} is out of region, local coordinates must be in 0..32");
+        let slot = &mut self.chunks[x as usize + z as usize * 32];
+        ensure!(slot.is_none(), "Chunk {x},{z} is already added");
+        *slot = Some((timestamp, nbt.to_vec(), codec));
+        Ok(self)
+    }
+
+    /// Writes region file in slot order. Returns size of the region file
+    pub fn write_to(&self, mut writer: impl Write) -> anyhow::Result<u64> {
+        let mut buffer = Cursor::new(vec![]);
+        let mut region = RegionWriter::new(&mut buffer)?;
+        for (pos, chunk) in self.chunks.iter().enumerate() {
+            if let Some((timestamp, nbt, codec)) = chunk {
+                region.write_chunk_with(pos as u16, *timestamp, nbt, *codec)?;
+            }
+        }
+
+        let size = region.finish()?;
+        writer.write_all(buffer.get_ref()).context("Unable to write region")?;
+        Ok(size)
+    }
+}
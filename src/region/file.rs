@@ -0,0 +1,298 @@
+use std::{
+    fs::File,
+    io::{IoSlice, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, ensure, Context};
+use zerocopy::{BigEndian, IntoBytes, U32};
+
+use super::{external_chunks, external_path, write_all_vectored, ChunkInfo, RegionFormat, RegionInfo, Sectors};
+use crate::{
+    chunk::{ChunkData, Codec},
+    limits::Limits,
+    scratch::Scratch,
+};
+
+/// Region file edited in place the way the game does it: chunks go into free sectors and their header
+/// entries are updated right away, so the file is complete after every call. Unlike [`super::RegionWriter`]
+/// it keeps chunks already in the file.
+///
+/// ```no_run
+/// # use anvilregion_repacker::{chunk::Codec, region::RegionFile};
+/// # let nbt_bytes = [10, 0, 0, 0];
+/// let mut region = RegionFile::create_empty("r.0.0.mca")?;
+/// region.insert_chunk(5, 1700000000, &nbt_bytes, Codec::Zlib)?;
+/// # anyhow::Ok(())
+/// ```
+#[derive(Debug)]
+pub struct RegionFile<F = File> {
+    file: F,
+    format: RegionFormat,
+    chunkinfos: Vec<Option<ChunkInfo>>,
+    sectors: Sectors,
+    buffer: Scratch,
+    /// Directory and region coordinates for chunks too large for the region file
+    external: Option<(PathBuf, (i32, i32))>,
+}
+
+impl RegionFile {
+    /// Creates vanilla region file without chunks. Fails if the file exists
+    pub fn create_empty(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .with_context(|| format!("Unable to create {}", path.display()))?;
+        Ok(Self::create_with_format(file, RegionFormat::VANILLA)?.with_external_chunks(path))
+    }
+
+    /// Opens vanilla region file for editing. Header must be valid, see [`Limits::strict_header`]
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Unable to open {}", path.display()))?;
+        Self::from_file_with_format(file, RegionFormat::VANILLA)
+            .map(|x| x.with_external_chunks(path))
+            .with_context(|| format!("Unable to read region header of {}", path.display()))
+    }
+}
+
+impl<F: Read + Write + Seek> RegionFile<F> {
+    /// Writes empty header of the format to the start of file
+    pub fn create_with_format(mut file: F, format: RegionFormat) -> anyhow::Result<Self> {
+        file.seek(SeekFrom::Start(0))?;
+        std::io::copy(&mut std::io::repeat(0).take(format.header_size), &mut file)?;
+
+        Ok(Self {
+            file,
+            format,
+            chunkinfos: vec![None; format.entries as usize],
+            sectors: Sectors::new(&format),
+            buffer: Scratch::take(),
+            external: None,
+        })
+    }
+
+    /// Reads header of region file. Sectors not taken by chunks in header are reused by written chunks
+    pub fn from_file_with_format(mut file: F, format: RegionFormat) -> anyhow::Result<Self> {
+        file.seek(SeekFrom::Start(0))?;
+        // Chunks sharing sectors would be damaged by writes, so only strict header is accepted
+        let info = RegionInfo::read_with_format(&mut file, &Limits::STRICT, &format)?;
+
+        let mut chunkinfos = vec![None; format.entries as usize];
+        for &(chunk, pos) in info.chunk_infos() {
+            chunkinfos[pos as usize] = Some(chunk);
+        }
+
+        Ok(Self {
+            file,
+            format,
+            chunkinfos,
+            sectors: Sectors::from_info(&info, &format),
+            buffer: Scratch::take(),
+            external: None,
+        })
+    }
+
+    /// Chunks too large for the region file are written to `c.<x>.<z>.mcc` files next to it, as the game does.
+    /// Set by [`RegionFile::open`] and [`RegionFile::create_empty`] for files named like `r.<x>.<z>.mca`
+    pub fn with_external_chunks(mut self, region_path: impl AsRef<Path>) -> Self {
+        self.external = external_chunks(region_path.as_ref());
+        self
+    }
+
+    pub fn format(&self) -> &RegionFormat {
+        &self.format
+    }
+
+    pub fn chunk_info(&self, pos: u16) -> Option<ChunkInfo> {
+        self.chunkinfos.get(pos as usize).copied().flatten()
+    }
+
+    /// Compresses uncompressed chunk NBT into free sectors and points empty header slot to it
+    pub fn insert_chunk(&mut self, pos: u16, timestamp: u32, nbt: &[u8], codec: Codec) -> anyhow::Result<ChunkInfo> {
+        self.check_pos(pos)?;
+        let (x, z) = RegionInfo::local_coords(pos);
+        ensure!(self.chunkinfos[pos as usize].is_none(), "Chunk {x},{z} is already in region");
+        self.write_chunk(pos, timestamp, nbt, codec)
+    }
+
+    /// Replaces chunk in header slot, or inserts it if the slot is empty. As the game does, new data goes into
+    /// the first free sectors while the old chunk is kept intact until the header points away from it, then
+    /// its sectors become free. The file only grows if no gap fits the chunk.
+    pub fn update_chunk(&mut self, pos: u16, timestamp: u32, nbt: &[u8], codec: Codec) -> anyhow::Result<ChunkInfo> {
+        self.check_pos(pos)?;
+        let old = self.chunkinfos[pos as usize];
+        let info = self.write_chunk(pos, timestamp, nbt, codec)?;
+        if let Some(old) = old {
+            self.sectors.release(old.location_in(&self.format), old.size_in(&self.format));
+        }
+        Ok(info)
+    }
+
+    /// Reads chunk in header slot as stored into `stored`: compression type byte followed by compressed data, the type
+    /// byte of the format's codec for formats without one. Chunks in external files keep the flag in their type byte
+    /// and only have a stub here. Returns `None` for empty slot
+    pub fn read_stored(&mut self, pos: u16, stored: &mut Vec<u8>) -> anyhow::Result<Option<ChunkInfo>> {
+        self.check_pos(pos)?;
+        let Some(info) = self.chunkinfos[pos as usize] else {
+            return Ok(None);
+        };
+        let (x, z) = RegionInfo::local_coords(pos);
+
+        let mut length = U32::<BigEndian>::ZERO;
+        self.file.seek(SeekFrom::Start(info.location_in(&self.format)))?;
+        self.file.read_exact(length.as_mut_bytes())?;
+        let length = length.get() as u64;
+        ensure!(
+            length > 0 && length + 4 <= info.size_in(&self.format),
+            "Chunk {x},{z} length does not fit its sectors"
+        );
+
+        stored.clear();
+        if let Some(codec) = self.format.codec {
+            stored.push(codec.compression_type());
+        }
+        Read::by_ref(&mut self.file).take(length).read_to_end(stored)?;
+        Ok(Some(info))
+    }
+
+    /// Clears header slot. Sectors of the chunk become free but keep their data
+    pub fn remove_chunk(&mut self, pos: u16) -> anyhow::Result<Option<ChunkInfo>> {
+        self.check_pos(pos)?;
+        let Some(info) = self.chunkinfos[pos as usize] else {
+            return Ok(None);
+        };
+
+        self.write_entry(pos, None)?;
+        self.chunkinfos[pos as usize] = None;
+        self.sectors.release(info.location_in(&self.format), info.size_in(&self.format));
+        Ok(Some(info))
+    }
+
+    /// Changes timestamp of chunk in header slot, leaving its data alone. Returns `None` for empty slot
+    pub fn set_timestamp(&mut self, pos: u16, timestamp: u32) -> anyhow::Result<Option<ChunkInfo>> {
+        self.check_pos(pos)?;
+        ensure!(self.format.timestamps, "Region format has no timestamps");
+        let Some(mut info) = self.chunkinfos[pos as usize] else {
+            return Ok(None);
+        };
+
+        info.timestamp = timestamp.into();
+        self.write_entry(pos, Some(info))?;
+        self.chunkinfos[pos as usize] = Some(info);
+        Ok(Some(info))
+    }
+
+    /// Overwrites free sectors with zeros, so data of removed chunks is gone from the file. Returns bytes zeroed
+    pub fn zero_free_sectors(&mut self) -> anyhow::Result<u64> {
+        let mut zeroed = 0;
+        for (location, size) in self.sectors.free() {
+            self.file.seek(SeekFrom::Start(location))?;
+            zeroed += std::io::copy(&mut std::io::repeat(0).take(size), &mut self.file)?;
+        }
+        Ok(zeroed)
+    }
+
+    fn check_pos(&self, pos: u16) -> anyhow::Result<()> {
+        ensure!(
+            pos < self.format.entries,
+            "Chunk position {pos} is out of region (max {})",
+            self.format.entries - 1
+        );
+        Ok(())
+    }
+
+    /// Writes chunk into newly allocated sectors and then its header entry
+    fn write_chunk(&mut self, pos: u16, timestamp: u32, nbt: &[u8], codec: Codec) -> anyhow::Result<ChunkInfo> {
+        let (x, z) = RegionInfo::local_coords(pos);
+
+        // Format may dictate codec
+        let codec = self.format.codec.unwrap_or(codec);
+        self.buffer.clear();
+        codec.compress(nbt, &mut *self.buffer).context("Compression failed")?;
+
+        let mut data_size = self.buffer.len() as u64 + self.format.chunk_prefix();
+        let mut size = data_size.next_multiple_of(self.format.sector_size);
+        let mut compression_type = codec.compression_type();
+        // Only the compression type with the external flag stays in the region file
+        let external = external_path(self.external.as_ref(), pos).filter(|_| self.format.codec.is_none());
+        if size > self.format.max_chunk_size() {
+            let Some(path) = &external else {
+                bail!(
+                    "Chunk {x},{z} takes {} sectors, but region file can hold only {}",
+                    size / self.format.sector_size,
+                    self.format.max_chunk_size() / self.format.sector_size
+                );
+            };
+            let mut file = File::create(path).with_context(|| format!("Unable to create {}", path.display()))?;
+            file.write_all(&self.buffer)
+                .and_then(|_| file.sync_all())
+                .with_context(|| format!("Unable to write external chunk {x},{z} to {}", path.display()))?;
+            self.buffer.clear();
+            compression_type |= ChunkData::EXTERNAL_FLAG;
+            data_size = self.format.chunk_prefix();
+            size = self.format.sector_size;
+        }
+
+        let location = self.sectors.allocate(size);
+        let info = ChunkInfo::new_in(
+            location.try_into().context("Chunk location must be non-zero")?,
+            size.try_into().context("Chunk size must be non-zero")?,
+            timestamp,
+            &self.format,
+        )?;
+
+        // Data first, so the header never points to sectors not written yet. Padding clears whatever was there
+        let mut prefix = [0; 5];
+        prefix[..4].copy_from_slice(U32::<BigEndian>::new((data_size - 4) as u32).as_bytes());
+        prefix[4] = compression_type;
+        let prefix = &prefix[..self.format.chunk_prefix() as usize];
+        let padding = vec![0; (size - data_size) as usize];
+        self.file.seek(SeekFrom::Start(location))?;
+        write_all_vectored(
+            &mut self.file,
+            &mut [IoSlice::new(prefix), IoSlice::new(&self.buffer), IoSlice::new(&padding)],
+        )?;
+        self.buffer.clear();
+
+        self.write_entry(pos, Some(info))?;
+        self.chunkinfos[pos as usize] = Some(info);
+
+        // External file of the chunk this one replaced is not used anymore
+        if let Some(path) = external.filter(|_| compression_type & ChunkData::EXTERNAL_FLAG == 0) {
+            match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(e).with_context(|| format!("Unable to remove {}", path.display()))
+                },
+                _ => {},
+            }
+        }
+        Ok(info)
+    }
+
+    /// Writes location and timestamp entries of header slot
+    fn write_entry(&mut self, pos: u16, info: Option<ChunkInfo>) -> anyhow::Result<()> {
+        let (locdata, timestamp) = info.map(|x| (x.locdata.get(), x.timestamp.get())).unwrap_or_default();
+
+        self.file.seek(SeekFrom::Start(pos as u64 * 4))?;
+        self.file.write_all(locdata.as_bytes())?;
+        if self.format.timestamps {
+            self.file.seek(SeekFrom::Start((self.format.entries as u64 + pos as u64) * 4))?;
+            self.file.write_all(U32::<BigEndian>::new(timestamp).as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Flushes and returns underlying file
+    pub fn into_inner(mut self) -> anyhow::Result<F> {
+        self.file.flush()?;
+        Ok(self.file)
+    }
+}
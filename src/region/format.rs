@@ -0,0 +1,55 @@
+use std::path::Path;
+
+pub use crate::format::RegionFormat;
+
+/// Recognizes region files of some format by path. Lets library users plug in formats of mods.
+pub trait RegionFormatProvider {
+    fn name(&self) -> &str;
+
+    /// `None` if file does not belong to this provider
+    fn detect(&self, path: &Path) -> Option<RegionFormat>;
+}
+
+/// Anvil `.mca` and McRegion `.mcr` files
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VanillaProvider;
+
+impl RegionFormatProvider for VanillaProvider {
+    fn name(&self) -> &str {
+        "vanilla"
+    }
+
+    fn detect(&self, path: &Path) -> Option<RegionFormat> {
+        let extension = path.extension()?;
+        (extension == "mca" || extension == "mcr").then_some(RegionFormat::VANILLA)
+    }
+}
+
+/// `.2dr` and `.3dr` files in `region2d` and `region3d` directories of Cubic Chunks worlds
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CubicChunksProvider;
+
+impl RegionFormatProvider for CubicChunksProvider {
+    fn name(&self) -> &str {
+        "cubic-chunks"
+    }
+
+    fn detect(&self, path: &Path) -> Option<RegionFormat> {
+        match path.extension()?.to_str()? {
+            "2dr" => Some(RegionFormat::CUBIC_CHUNKS_2D),
+            "3dr" => Some(RegionFormat::CUBIC_CHUNKS_3D),
+            _ => None,
+        }
+    }
+}
+
+/// Providers known to the tool
+pub fn providers() -> Vec<Box<dyn RegionFormatProvider>> {
+    vec![Box::new(VanillaProvider), Box::new(CubicChunksProvider)]
+}
+
+/// Format of the first provider recognizing the file. Files gzipped as a whole are recognized by their name unpacked
+pub fn detect_format(path: impl AsRef<Path>, providers: &[Box<dyn RegionFormatProvider>]) -> Option<RegionFormat> {
+    let path = super::gunzipped_path(path.as_ref());
+    providers.iter().find_map(|x| x.detect(&path))
+}
@@ -1,147 +1,209 @@
 #![allow(unused)]
 
-use anyhow::Context;
-use core::{
-    fmt::Debug,
-    num::{NonZeroU32, NonZeroU64},
+use anyhow::{bail, ensure, Context};
+use std::{
+    io::{BufRead, IoSlice, Read, Seek, Write},
+    path::{Path, PathBuf},
 };
-use std::io::{Read, Write};
-use zerocopy::{try_transmute, BigEndian, IntoBytes, TryFromBytes, U32};
+use zerocopy::{BigEndian, FromZeros, IntoBytes, TryFromBytes, U32};
 
-#[derive(TryFromBytes, Clone, Copy)]
-#[repr(C)]
-#[non_exhaustive]
-pub struct ChunkInfo {
-    /// Actually, U32<BigEndian> but NonZeroU32 save Option<ChunkInfo> from bloat
-    /// so size_of::<ChunkInfo>() == size_of::<Option<ChunkInfo>>()
-    pub locdata: NonZeroU32,
-    pub timestamp: U32<BigEndian>,
-}
-
-impl Debug for ChunkInfo {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("ChunkInfo")
-            .field("location", &self.location())
-            .field("size", &self.size())
-            .field("(locdata: U32BE, timestamp: U32BE)", &(self.locdata, self.timestamp))
-            .finish()
-    }
-}
-
-impl ChunkInfo {
-    pub const SECTOR_SIZE: u16 = 4096;
-
-    pub fn new(location: NonZeroU64, size: NonZeroU64, timestamp: u32) -> Self {
-        let location = location.get();
-        let size = size.get();
-
-        assert!(location % Self::SECTOR_SIZE as u64 == 0, "Location must be mod of {}", Self::SECTOR_SIZE);
-        assert!(size % Self::SECTOR_SIZE as u64 == 0, "Size must be mod of {}", Self::SECTOR_SIZE);
-        assert!(size / 4096 <= 0xFF, "Size must be less or equal than 1 MiB");
+use crate::{
+    chunk::{ChecksumMismatch, ChunkData, Codec, DecompressError},
+    error::ErrorCode,
+    limits::Limits,
+    meta::RegionScan,
+    scratch::Scratch,
+};
 
-        let mut locdata = U32::<BigEndian>::new(0);
-        let locdata_bytes = locdata.as_mut_bytes();
+mod builder;
+mod file;
+mod format;
+mod validate;
 
-        let location = U32::<BigEndian>::new((location / 4096) as u32);
-        let location_bytes = location.as_bytes();
-        locdata_bytes[0] = location_bytes[1];
-        locdata_bytes[1] = location_bytes[2];
-        locdata_bytes[2] = location_bytes[3];
-        locdata_bytes[3] = (size / 4096) as u8;
+pub use crate::format::{ChunkInfo, RegionInfo};
+pub use builder::RegionBuilder;
+pub use file::RegionFile;
+pub use format::{detect_format, providers, CubicChunksProvider, RegionFormat, RegionFormatProvider, VanillaProvider};
+pub use validate::validate_region;
 
-        Self {
-            locdata: try_transmute!(locdata).unwrap(),
-            timestamp: U32::<BigEndian>::new(timestamp),
-        }
+impl RegionInfo {
+    pub fn read(reader: impl Read) -> anyhow::Result<Self> {
+        Self::read_with_limits(reader, &Limits::default())
     }
 
-    pub fn location(&self) -> u64 {
-        let locdata_bytes = self.locdata.as_bytes();
-        let location =
-            u32::from_be_bytes([0, locdata_bytes[0], locdata_bytes[1], locdata_bytes[2]]);
-
-        location as u64 * Self::SECTOR_SIZE as u64
+    /// Header entries pointing into the header, beyond max region size or overlapping previous chunks
+    /// are errors with strict header, skipped otherwise.
+    pub fn read_with_limits(reader: impl Read, limits: &Limits) -> anyhow::Result<Self> {
+        Self::read_with_format(reader, limits, &RegionFormat::VANILLA)
     }
 
-    pub fn size(&self) -> u64 {
-        let locdata_bytes = self.locdata.as_bytes();
-        locdata_bytes[3] as u64 * Self::SECTOR_SIZE as u64
+    /// Reads the whole header of the format, so reader is left at its end
+    pub fn read_with_format(mut reader: impl Read, limits: &Limits, format: &RegionFormat) -> anyhow::Result<Self> {
+        let mut tables = vec![0; format.table_size() as usize];
+        let truncated = |e: std::io::Error| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => ErrorCode::TruncatedHeader.error("Region file ends inside its header"),
+            _ => e.into(),
+        };
+        reader.read_exact(&mut tables).map_err(truncated)?;
+        reader.readskip(format.header_size - format.table_size()).map_err(truncated)?;
+        Self::parse(&tables, limits, format)
     }
-}
 
-#[derive(Debug, Clone)]
-pub struct RegionInfo(Vec<(ChunkInfo, u16)>);
+    /// Reads only the header of region file. Empty files, which the game leaves sometimes, have no chunks
+    pub fn scan(path: impl AsRef<Path>) -> anyhow::Result<RegionScan> {
+        let path = path.as_ref();
+        let mut file = std::fs::File::open(path).with_context(|| format!("Unable to open {}", path.display()))?;
+        let file_size = file.metadata()?.len();
+        if file_size == 0 {
+            return Ok(RegionScan::default());
+        }
 
-impl RegionInfo {
-    pub const SIZE: u16 = 8192;
-    pub const MAX_CHUNK_COUNT: u16 = 1024;
+        let info = Self::read(&mut file).with_context(|| format!("{}", path.display()))?;
+        Ok(RegionScan {
+            chunks: info.0.len(),
+            newest_timestamp: info.0.iter().map(|x| x.0.timestamp.get()).max(),
+            used_bytes: Self::SIZE as u64 + info.0.iter().map(|x| x.0.size()).sum::<u64>(),
+            file_size,
+        })
+    }
+}
 
-    pub fn read(mut reader: impl Read) -> anyhow::Result<Self> {
-        let mut v = vec![0u32; 1024 * 2];
-        reader.read_exact(v.as_mut_bytes())?;
+/// Start of gzip stream. Backup scripts sometimes gzip whole region files (`r.0.0.mca.gz`); a region header can not
+/// start like it, the first chunk would lie far beyond the largest possible region file
+pub const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
 
-        let (locdatas, timestamps) = v.split_at(1024);
-        let mut chunks: Vec<(ChunkInfo, u16)> = locdatas
-            .into_iter()
-            .copied()
-            .zip(timestamps.into_iter().copied())
-            .zip(0..)
-            .filter_map(|((a, b), pos)| try_transmute!([a, b]).ok().map(|x| (x, pos as u16)))
-            .collect();
+/// Data of region file gzipped as a whole
+pub fn is_gzipped(data: &[u8]) -> bool {
+    data.starts_with(&GZIP_MAGIC)
+}
 
-        chunks.sort_by_key(|x| x.0.location());
-        Ok(Self(chunks))
+/// Path of region file gzipped as a whole as named unpacked, `r.0.0.mca.gz` becomes `r.0.0.mca`.
+/// Other paths are returned as they are
+pub fn gunzipped_path(path: &Path) -> PathBuf {
+    match path.extension().is_some_and(|x| x == "gz") {
+        true => path.with_extension(""),
+        false => path.to_path_buf(),
     }
+}
 
-    pub fn chunk_infos(&self) -> &[(ChunkInfo, u16)] {
-        self.0.as_slice()
+/// Opens region file for reading, decompressed on the fly when it is gzipped as a whole
+pub fn open(path: &Path) -> anyhow::Result<Box<dyn Read + Send>> {
+    let file = std::fs::File::open(path).with_context(|| format!("Unable to open {}", path.display()))?;
+    let mut reader = std::io::BufReader::new(file);
+    match is_gzipped(reader.fill_buf()?) {
+        true => Ok(Box::new(std::io::BufReader::new(flate2::bufread::MultiGzDecoder::new(reader)))),
+        false => Ok(Box::new(reader)),
     }
 }
 
+/// Parses region coordinates from vanilla file name like `r.-1.2.mca`
+pub fn region_coords_from_path(path: impl AsRef<Path>) -> Option<(i32, i32)> {
+    let name = path.as_ref().file_name()?.to_str()?;
+    let mut parts = name.split('.');
+
+    (parts.next()? == "r").then_some(())?;
+    let x = parts.next()?.parse().ok()?;
+    let z = parts.next()?.parse().ok()?;
+    Some((x, z))
+}
+
 #[derive(Debug, Clone)]
 pub struct RegionReader<R> {
     reader: R,
     info: RegionInfo,
+    limits: Limits,
+    format: RegionFormat,
     pos: u64,
     next_chunk: u16,
     tainted: bool,
+    /// Chunks kept despite checksum mismatch, see [`Limits::ignore_checksums`]
+    mismatches: Vec<(u16, ChecksumMismatch)>,
+    /// Chunks decompressed with another codec than their compression type names, see [`Limits::sniff_codecs`]
+    corrections: Vec<(u16, u8, Codec)>,
+    /// Directory and region coordinates of chunks stored in external files
+    external: Option<(PathBuf, (i32, i32))>,
+}
+
+impl RegionReader<std::io::BufReader<std::fs::File>> {
+    /// Chunks stored in external files are read from next to the region file
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path).with_context(|| format!("Unable to open {}", path.display()))?;
+
+        Self::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("Unable to read region header of {}", path.display()))
+            .map(|x| x.with_external_chunks(path))
+    }
 }
 
 impl<R: Read> RegionReader<R> {
-    pub fn from_reader(mut reader: R) -> anyhow::Result<Self> {
-        let info = RegionInfo::read(&mut reader)?;
+    pub fn from_reader(reader: R) -> anyhow::Result<Self> {
+        Self::from_reader_with_limits(reader, Limits::default())
+    }
+
+    pub fn from_reader_with_limits(reader: R, limits: Limits) -> anyhow::Result<Self> {
+        Self::from_reader_with_format(reader, limits, RegionFormat::VANILLA)
+    }
+
+    pub fn from_reader_with_format(mut reader: R, limits: Limits, format: RegionFormat) -> anyhow::Result<Self> {
+        let info = RegionInfo::read_with_format(&mut reader, &limits, &format)?;
 
         Ok(Self {
             reader,
             info,
-            pos: 8192,
+            limits,
+            format,
+            pos: format.header_size,
             next_chunk: 0,
             tainted: false,
+            mismatches: vec![],
+            corrections: vec![],
+            external: None,
         })
     }
 
+    /// Reads chunks larger than 255 sectors from `c.<x>.<z>.mcc` files next to the region file as vanilla stores them.
+    /// Without it such chunks fail. Does nothing if region coordinates can not be parsed from the file name.
+    pub fn with_external_chunks(mut self, region_path: impl AsRef<Path>) -> Self {
+        self.external = external_chunks(region_path.as_ref());
+        self
+    }
+
+    pub fn info(&self) -> &RegionInfo {
+        &self.info
+    }
+
+    /// Header slots of chunks decompressed by [`RegionReader::decompress_all`] despite not matching their checksum.
+    /// Always empty unless [`Limits::ignore_checksums`] is set
+    pub fn checksum_mismatches(&self) -> &[(u16, ChecksumMismatch)] {
+        &self.mismatches
+    }
+
+    /// Header slots of chunks decompressed by [`RegionReader::decompress_all`] with another codec than their
+    /// compression type names, with that type and the codec used. Always empty unless [`Limits::sniff_codecs`] is set
+    pub fn codec_corrections(&self) -> &[(u16, u8, Codec)] {
+        &self.corrections
+    }
+
     pub fn next_chunk_info(&self) -> Option<(ChunkInfo, u16)> {
         self.info
             .chunk_infos()
             .get(self.next_chunk as usize)
-            .map(|x| *x)
+            .copied()
     }
 
     /// # Errors
     /// If this method gives error, the reader being tainted and must be dropped. Buffer will contain a trash.
-    /// Next call of this method will panic.
-    #[track_caller]
+    /// Next call of this method will fail.
     pub fn read_next_chunk(&mut self, mut writer: impl Write) -> anyhow::Result<Option<(ChunkInfo, u64)>> {
-        if self.tainted {
-            panic!("RegionReader is tainted");
-        }
+        ensure!(!self.tainted, "RegionReader is tainted");
 
         let Some((nextinfo, _)) = self.next_chunk_info() else {
             return Ok(None);
         };
 
-        let location = nextinfo.location();
-        assert!(self.pos <= location);
+        let location = nextinfo.location_in(&self.format);
+        ensure!(self.pos <= location, "Chunks overlap");
 
         if location != self.pos {
             self.reader
@@ -150,7 +212,7 @@ impl<R: Read> RegionReader<R> {
             self.pos = location;
         }
 
-        let size = nextinfo.size();
+        let size = nextinfo.size_in(&self.format);
         let copied = std::io::copy(&mut self.reader.by_ref().take(size), &mut writer)
             .inspect_err(|_| self.tainted = true)
             .context("asd")?;
@@ -160,6 +222,485 @@ impl<R: Read> RegionReader<R> {
 
         Ok(Some((nextinfo, copied)))
     }
+
+    /// Reads and decompresses all remaining chunks in file order.
+    /// Callback receives header info, header slot and decompressed chunk data.
+    pub fn decompress_all(
+        &mut self,
+        mut f: impl FnMut(ChunkInfo, u16, &mut Vec<u8>) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let mut chunkbuf = Scratch::take();
+        let mut databuf = Scratch::take();
+
+        while let Some((info, pos)) = self.next_chunk_info() {
+            chunkbuf.clear();
+            let Some(_) = self.read_next_chunk(&mut *chunkbuf)? else {
+                break;
+            };
+
+            self.read_external(pos, &mut chunkbuf)?;
+            let limit = self.limits.max_decompressed_size;
+            let (x, z) = RegionInfo::local_coords(pos);
+            let decompressed = if let Some(codec) = self.format.codec {
+                let (length, data) = chunkbuf.split_first_chunk::<4>().context("Chunk has no length field")?;
+                let data = data
+                    .get(..u32::from_be_bytes(*length) as usize)
+                    .with_context(|| format!("Chunk {x},{z} length exceeds its sectors"))?;
+                codec.decompress_with_limit(data, &mut *databuf, limit).map(drop)
+            } else {
+                let data = ChunkData::try_ref_from_bytes(&chunkbuf).map_err(|x| x.map_src(|_| &()))?;
+                match data.decompress_into(&mut databuf, limit as usize) {
+                    Ok(_) => Ok(()),
+                    Err(DecompressError::Checksum(mismatch)) => Err(mismatch.into()),
+                    Err(e) if self.limits.sniff_codecs => match data.sniff_codec(&mut databuf, limit) {
+                        Some(codec) => {
+                            self.corrections.push((pos, data.compression_type, codec));
+                            Ok(())
+                        },
+                        None => Err(e.into()),
+                    },
+                    Err(e) => Err(e.into()),
+                }
+            };
+            // Mismatches stay downcastable to ChecksumMismatch under the context
+            match decompressed {
+                Err(e) if self.limits.ignore_checksums && e.is::<ChecksumMismatch>() => {
+                    self.mismatches.push((pos, e.downcast().unwrap()));
+                },
+                result => result.with_context(|| format!("Chunk {x},{z}"))?,
+            }
+
+            f(info, pos, &mut databuf)?;
+
+            databuf.clear();
+        }
+
+        Ok(())
+    }
+
+    /// Reads all remaining chunks in file order without decompressing them.
+    /// Callback receives header info, header slot and chunk data as stored: compression type byte followed by compressed data.
+    /// Chunks stored in external files are passed with their data read from there, without the external flag.
+    pub fn read_all_raw(&mut self, mut f: impl FnMut(ChunkInfo, u16, &[u8]) -> anyhow::Result<()>) -> anyhow::Result<()> {
+        ensure!(self.format.codec.is_none(), "Chunks of this region format have no compression type byte");
+        let mut chunkbuf = Scratch::take();
+
+        while let Some((info, pos)) = self.next_chunk_info() {
+            chunkbuf.clear();
+            let Some(_) = self.read_next_chunk(&mut *chunkbuf)? else {
+                break;
+            };
+            self.read_external(pos, &mut chunkbuf)?;
+
+            let (x, z) = RegionInfo::local_coords(pos);
+            let (length, data) = chunkbuf.split_first_chunk::<4>().context("Chunk has no length field")?;
+            let data = data
+                .get(..u32::from_be_bytes(*length) as usize)
+                .filter(|x| !x.is_empty())
+                .with_context(|| format!("Chunk {x},{z} length does not fit its sectors"))?;
+
+            f(info, pos, data)?;
+        }
+
+        Ok(())
+    }
+
+    /// Replaces stub of chunk stored in external file, read into `chunkbuf` from its sectors, with length field,
+    /// compression type without the external flag and data of the external file
+    fn read_external(&self, pos: u16, chunkbuf: &mut Vec<u8>) -> anyhow::Result<()> {
+        let compression_type = match chunkbuf.get(4) {
+            Some(&x) if self.format.codec.is_none() && x & ChunkData::EXTERNAL_FLAG != 0 => x,
+            _ => return Ok(()),
+        };
+        let (x, z) = RegionInfo::local_coords(pos);
+        let path = external_path(self.external.as_ref(), pos).ok_or_else(|| {
+            ErrorCode::ExternalChunk.error(format!("Chunk {x},{z} is stored in external file, but region file name is unknown"))
+        })?;
+        let external = std::fs::File::open(&path)
+            .map_err(|e| ErrorCode::ExternalChunk.wrap(e.into(), format!("Unable to open external chunk {}", path.display())))?;
+
+        let limit = self.limits.max_decompressed_size;
+        chunkbuf.truncate(4);
+        chunkbuf.push(compression_type & !ChunkData::EXTERNAL_FLAG);
+        external
+            .take(limit + 1)
+            .read_to_end(chunkbuf)
+            .map_err(|e| ErrorCode::ExternalChunk.wrap(e.into(), format!("Unable to read external chunk {}", path.display())))?;
+        let length = chunkbuf.len() as u64 - 4;
+        ensure!(
+            length <= limit,
+            ErrorCode::SizeLimit.error(format!("External chunk {} exceeds limit of {limit} bytes", path.display()))
+        );
+        chunkbuf[..4].copy_from_slice(&(length as u32).to_be_bytes());
+        Ok(())
+    }
+}
+
+/// Directory and coordinates of region file for [`external_path`], if they can be parsed from its name
+fn external_chunks(region_path: &Path) -> Option<(PathBuf, (i32, i32))> {
+    region_coords_from_path(region_path).map(|coords| {
+        let dir = region_path.parent().unwrap_or(Path::new("")).to_path_buf();
+        (dir, coords)
+    })
+}
+
+/// Path of `c.<x>.<z>.mcc` file holding chunk at header slot of region
+fn external_path(external: Option<&(PathBuf, (i32, i32))>, pos: u16) -> Option<PathBuf> {
+    let (dir, region) = external?;
+    let (x, z) = RegionInfo::chunk_coords(Some(*region), pos);
+    Some(dir.join(format!("c.{x}.{z}.mcc")))
+}
+
+/// Writes region file chunk by chunk. Header is written by [`RegionWriter::finish`].
+/// Chunks may be written in any slot order, slots without chunk stay empty.
+#[derive(Debug)]
+pub struct RegionWriter<W> {
+    writer: W,
+    chunkinfos: Vec<Option<ChunkInfo>>,
+    sectors: Sectors,
+    /// Current position of the underlying writer
+    cursor: u64,
+    /// Number of bytes actually written to the underlying writer
+    end: u64,
+    /// Directory and region coordinates for chunks too large to fit into region file
+    external: Option<(PathBuf, (i32, i32))>,
+    /// Leave padding of the last sector unwritten, see [`RegionWriter::with_sparse`]
+    sparse: bool,
+    format: RegionFormat,
+    buffer: Scratch,
+}
+
+impl<W: Write + Seek> RegionWriter<W> {
+    pub fn new(writer: W) -> anyhow::Result<Self> {
+        Self::with_format(writer, RegionFormat::VANILLA)
+    }
+
+    pub fn with_format(mut writer: W, format: RegionFormat) -> anyhow::Result<Self> {
+        writer.seek(std::io::SeekFrom::Start(format.header_size))?;
+
+        Ok(Self {
+            writer,
+            chunkinfos: vec![None; format.entries as usize],
+            sectors: Sectors::new(&format),
+            cursor: format.header_size,
+            // Tables are always written by finish, the rest of header is padded like the last sector
+            end: format.table_size(),
+            external: None,
+            sparse: false,
+            format,
+            buffer: Scratch::take(),
+        })
+    }
+
+    /// Allows to store chunks larger than 255 sectors in `c.<x>.<z>.mcc` files next to the region file
+    /// as vanilla does. Does nothing if region coordinates can not be parsed from the file name.
+    pub fn with_external_chunks(mut self, region_path: impl AsRef<Path>) -> Self {
+        self.external = external_chunks(region_path.as_ref());
+        self
+    }
+
+    /// Makes [`RegionWriter::finish`] skip writing the last padding byte. The caller must extend the output
+    /// to the returned size itself, e.g. with [`std::fs::File::set_len`], which leaves the padding as a hole.
+    pub fn with_sparse(mut self, sparse: bool) -> Self {
+        self.sparse = sparse;
+        self
+    }
+
+    pub fn chunk_info(&self, pos: u16) -> Option<ChunkInfo> {
+        self.chunkinfos.get(pos as usize).copied().flatten()
+    }
+
+    /// Compresses uncompressed chunk data with zlib and writes it into free sectors.
+    /// Slot must be empty, use [`RegionWriter::remove_chunk`] to replace a chunk.
+    pub fn write_chunk(&mut self, pos: u16, timestamp: u32, data: &[u8]) -> anyhow::Result<()> {
+        self.write_chunk_with(pos, timestamp, data, Codec::Zlib)
+    }
+
+    /// Same as [`RegionWriter::write_chunk`] with explicit compression
+    pub fn write_chunk_with(&mut self, pos: u16, timestamp: u32, data: &[u8], codec: Codec) -> anyhow::Result<()> {
+        self.check_slot(pos)?;
+
+        // Format may dictate codec
+        let codec = self.format.codec.unwrap_or(codec);
+        self.buffer.clear();
+        codec.compress(data, &mut *self.buffer).context("Compression/write failed")?;
+        self.write_buffer(pos, timestamp, codec.compression_type())
+    }
+
+    /// Same as [`RegionWriter::write_chunk_with`], compressing uncompressed chunk data as it is read from `reader`
+    pub fn write_chunk_from(&mut self, pos: u16, timestamp: u32, reader: impl Read, codec: Codec) -> anyhow::Result<()> {
+        self.check_slot(pos)?;
+
+        let codec = self.format.codec.unwrap_or(codec);
+        self.buffer.clear();
+        codec.compress_from(reader, &mut *self.buffer).context("Compression/write failed")?;
+        self.write_buffer(pos, timestamp, codec.compression_type())
+    }
+
+    /// Writes chunk data as read by [`RegionReader::read_all_raw`]: compression type byte followed by compressed data
+    pub fn write_raw_chunk(&mut self, pos: u16, timestamp: u32, data: &[u8]) -> anyhow::Result<()> {
+        self.check_slot(pos)?;
+        let (&compression_type, data) = data.split_first().context("Chunk has no compression type")?;
+        ensure!(
+            compression_type & ChunkData::EXTERNAL_FLAG == 0,
+            "Chunk data must not be marked as external"
+        );
+        ensure!(
+            self.format.codec.is_none_or(|x| x.compression_type() == compression_type),
+            "Region format does not allow compression type {compression_type}"
+        );
+
+        self.buffer.clear();
+        self.buffer.extend_from_slice(data);
+        self.write_buffer(pos, timestamp, compression_type)
+    }
+
+    fn check_slot(&self, pos: u16) -> anyhow::Result<()> {
+        ensure!(
+            pos < self.format.entries,
+            "Chunk position {pos} is out of region (max {})",
+            self.format.entries - 1
+        );
+        ensure!(self.chunkinfos[pos as usize].is_none(), "Chunk position {pos} is already written");
+        Ok(())
+    }
+
+    /// Writes compressed chunk from buffer into free sectors
+    fn write_buffer(&mut self, pos: u16, timestamp: u32, compression_type: u8) -> anyhow::Result<()> {
+        let compressed_size = self.buffer.len() as u64;
+
+        let data_size = compressed_size + self.format.chunk_prefix();
+
+        let size = data_size.next_multiple_of(self.format.sector_size);
+
+        if size > self.format.max_chunk_size() {
+            return self.write_external_chunk(pos, timestamp, compression_type);
+        }
+
+        let location = self.sectors.allocate(size);
+        self.seek(location)?;
+
+        // Length, compression type unless format dictates codec, and data go out in one call
+        let mut prefix = [0; 5];
+        prefix[..4].copy_from_slice(U32::<BigEndian>::new((data_size - 4) as u32).as_bytes());
+        prefix[4] = compression_type;
+        let prefix = &prefix[..self.format.chunk_prefix() as usize];
+        write_all_vectored(&mut self.writer, &mut [IoSlice::new(prefix), IoSlice::new(&self.buffer)])?;
+        self.buffer.clear();
+        self.advance(data_size);
+
+        let chunkinfo = ChunkInfo::new_in(
+            location.try_into().context("Chunk location must be non-zero")?,
+            size.try_into().context("Chunk size must be non-zero")?,
+            timestamp,
+            &self.format,
+        )?;
+        self.chunkinfos[pos as usize] = Some(chunkinfo);
+
+        Ok(())
+    }
+
+    /// Writes compressed data from buffer into external file and a stub with external flag into region
+    fn write_external_chunk(&mut self, pos: u16, timestamp: u32, compression_type: u8) -> anyhow::Result<()> {
+        let (local_x, local_z) = RegionInfo::local_coords(pos);
+        let sectors = (self.buffer.len() as u64 + 5).div_ceil(self.format.sector_size);
+
+        let Some(path) = self.external_path(pos).filter(|_| self.format.codec.is_none()) else {
+            self.buffer.clear();
+            bail!(
+                "Chunk {local_x},{local_z} takes {sectors} sectors, but region file can hold only {}. \
+                 Name output file like r.<x>.<z>.mca to store it in external .mcc file",
+                self.format.max_chunk_size() / self.format.sector_size
+            );
+        };
+
+        std::fs::write(&path, &*self.buffer).with_context(|| {
+            format!("Unable to write external chunk {local_x},{local_z} to {}", path.display())
+        })?;
+        self.buffer.clear();
+
+        let size = self.format.sector_size;
+        let location = self.sectors.allocate(size);
+        self.seek(location)?;
+
+        self.writer.write_all(U32::<BigEndian>::new(1).as_bytes())?;
+        self.writer.write_all((compression_type | ChunkData::EXTERNAL_FLAG).as_bytes())?;
+        self.advance(5);
+
+        self.chunkinfos[pos as usize] = Some(ChunkInfo::new_in(
+            location.try_into().context("Chunk location must be non-zero")?,
+            size.try_into().context("Chunk size must be non-zero")?,
+            timestamp,
+            &self.format,
+        )?);
+
+        Ok(())
+    }
+
+    fn external_path(&self, pos: u16) -> Option<PathBuf> {
+        external_path(self.external.as_ref(), pos)
+    }
+
+    /// Clears header slot. Sectors of the chunk are zeroed and reused by following chunks.
+    pub fn remove_chunk(&mut self, pos: u16) -> anyhow::Result<Option<ChunkInfo>> {
+        let Some(info) = self.chunkinfos.get_mut(pos as usize).and_then(Option::take) else {
+            return Ok(None);
+        };
+
+        // Chunk may be stored externally
+        if let Some(path) = self.external_path(pos) {
+            std::fs::remove_file(&path)
+                .or_else(|e| if e.kind() == std::io::ErrorKind::NotFound { Ok(()) } else { Err(e) })
+                .with_context(|| format!("Unable to remove {}", path.display()))?;
+        }
+
+        let (location, size) = (info.location_in(&self.format), info.size_in(&self.format));
+        self.seek(location)?;
+        std::io::copy(&mut std::io::repeat(0).take(size), &mut self.writer)?;
+        self.advance(size);
+        self.sectors.release(location, size);
+
+        Ok(Some(info))
+    }
+
+    fn advance(&mut self, written: u64) {
+        self.cursor += written;
+        self.end = self.end.max(self.cursor);
+    }
+
+    fn seek(&mut self, location: u64) -> anyhow::Result<()> {
+        if self.cursor != location {
+            self.writer.seek(std::io::SeekFrom::Start(location))?;
+            self.cursor = location;
+        }
+        Ok(())
+    }
+
+    /// Pads the last sector and writes region header. Returns size of the region file
+    pub fn finish(mut self) -> anyhow::Result<u64> {
+        if !self.sparse && self.end < self.sectors.end {
+            self.seek(self.sectors.end - 1)?;
+            self.writer.write_all(&[0])?;
+        }
+
+        self.writer.seek(std::io::SeekFrom::Start(0))?;
+
+        // Tables are assembled first to be written with a single call
+        self.buffer.clear();
+        self.chunkinfos
+            .iter()
+            .map(|x| x.as_ref().map(|x| x.locdata.get()).unwrap_or(FromZeros::new_zeroed()))
+            .for_each(|x| self.buffer.extend_from_slice(x.as_bytes()));
+
+        if self.format.timestamps {
+            self.chunkinfos
+                .iter()
+                .map(|x| x.as_ref().map(|x| x.timestamp).unwrap_or(FromZeros::new_zeroed()))
+                .for_each(|x| self.buffer.extend_from_slice(x.as_bytes()));
+        }
+        self.writer.write_all(&self.buffer)?;
+
+        Ok(self.sectors.end)
+    }
+}
+
+/// Sectors of region file in use by header or chunks, a bit per sector like the game keeps it
+#[derive(Debug, Clone)]
+struct Sectors {
+    used: Vec<u64>,
+    sector_size: u64,
+    /// End of the last sector ever in use, the file does not shrink when chunks at its end are removed
+    end: u64,
+}
+
+impl Sectors {
+    fn new(format: &RegionFormat) -> Self {
+        let mut sectors = Self {
+            used: vec![],
+            sector_size: format.sector_size,
+            end: 0,
+        };
+        sectors.mark(0, format.header_size, true);
+        sectors
+    }
+
+    /// Sectors of chunks in header are in use, the rest is free
+    fn from_info(info: &RegionInfo, format: &RegionFormat) -> Self {
+        let mut sectors = Self::new(format);
+        for (chunk, _) in info.chunk_infos() {
+            sectors.mark(chunk.location_in(format), chunk.size_in(format), true);
+        }
+        sectors
+    }
+
+    fn is_used(&self, sector: u64) -> bool {
+        self.used.get((sector / 64) as usize).is_some_and(|x| x & 1 << (sector % 64) != 0)
+    }
+
+    fn mark(&mut self, location: u64, size: u64, used: bool) {
+        let first = location / self.sector_size;
+        for sector in first..first + size / self.sector_size {
+            let (word, bit) = ((sector / 64) as usize, sector % 64);
+            if word >= self.used.len() {
+                self.used.resize(word + 1, 0);
+            }
+            match used {
+                true => self.used[word] |= 1 << bit,
+                false => self.used[word] &= !(1 << bit),
+            }
+        }
+        if used {
+            self.end = self.end.max(location + size);
+        }
+    }
+
+    /// First run of free sectors long enough, possibly running past the end of file
+    fn allocate(&mut self, size: u64) -> u64 {
+        let (count, last) = (size / self.sector_size, self.end / self.sector_size);
+        let mut start = 0;
+        for sector in 0..last {
+            if self.is_used(sector) {
+                start = sector + 1;
+            } else if sector + 1 - start == count {
+                break;
+            }
+        }
+
+        let location = start * self.sector_size;
+        self.mark(location, size, true);
+        location
+    }
+
+    fn release(&mut self, location: u64, size: u64) {
+        self.mark(location, size, false);
+    }
+
+    /// Runs of free sectors before the end of file as (location, size)
+    fn free(&self) -> Vec<(u64, u64)> {
+        let mut runs: Vec<(u64, u64)> = vec![];
+        for sector in (0..self.end / self.sector_size).filter(|&x| !self.is_used(x)) {
+            let location = sector * self.sector_size;
+            match runs.last_mut() {
+                Some(run) if run.0 + run.1 == location => run.1 += self.sector_size,
+                _ => runs.push((location, self.sector_size)),
+            }
+        }
+        runs
+    }
+}
+
+/// Stable counterpart of [`Write::write_all_vectored`]
+fn write_all_vectored(writer: &mut impl Write, mut bufs: &mut [IoSlice]) -> std::io::Result<()> {
+    IoSlice::advance_slices(&mut bufs, 0);
+    while !bufs.is_empty() {
+        match writer.write_vectored(bufs) {
+            Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+            Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
 }
 
 trait ReadSkip {
@@ -187,14 +728,19 @@ impl<R: Read> ReadSkip for R {
 
 #[cfg(test)]
 mod tests {
-    use std::io::Write;
+    use std::io::{Read, Write};
 
     use bytes::{BufMut, BytesMut};
     use zerocopy::IntoBytes;
 
-    use crate::region::ChunkInfo;
+    use crate::{
+        chunk::Codec,
+        limits::Limits,
+        region::ChunkInfo,
+        testutil::{fixture, TempDir},
+    };
 
-    use super::RegionReader;
+    use super::{RegionBuilder, RegionFile, RegionFormat, RegionReader, RegionWriter};
 
     #[test]
     fn chunk_info_new() {
@@ -205,7 +751,8 @@ mod tests {
         (&mut buf).writer().write_all(locdata.as_bytes()).unwrap();
         (&mut buf).writer().write_all(timestamp.as_bytes()).unwrap();
 
-        let region_reader =  RegionReader::from_reader(&buf[..]).unwrap();
+        // Every entry points to the same sectors
+        let region_reader = RegionReader::from_reader_with_limits(&buf[..], Limits::RELAXED).unwrap();
 
         let info = region_reader.next_chunk_info().unwrap();
 
@@ -215,10 +762,280 @@ mod tests {
 
         assert_eq!(info.1, 0);
 
-        let info = ChunkInfo::new(info.0.location().try_into().unwrap(), info.0.size().try_into().unwrap(), info.0.timestamp.get());
+        let info = ChunkInfo::new(info.0.location().try_into().unwrap(), info.0.size().try_into().unwrap(), info.0.timestamp.get()).unwrap();
 
         assert_eq!(info.location(), 16 * 4096);
         assert_eq!(info.size(), 2 * 4096);
         assert_eq!(info.timestamp.get(), 256);
     }
+
+    #[test]
+    fn region_writer_reuses_removed_sectors() {
+        let mut file = std::io::Cursor::new(vec![]);
+        let mut writer = RegionWriter::new(&mut file).unwrap();
+
+        writer.write_chunk(0, 1, &[1; 100]).unwrap();
+        writer.write_chunk(1, 2, &[2; 100]).unwrap();
+        assert!(writer.write_chunk(1, 3, &[3; 100]).is_err());
+        assert!(writer.write_chunk(1024, 3, &[3; 100]).is_err());
+
+        let old = writer.remove_chunk(0).unwrap().unwrap();
+        writer.write_chunk(2, 3, &[3; 100]).unwrap();
+        assert_eq!(writer.chunk_info(2).unwrap().location(), old.location());
+        assert!(writer.chunk_info(0).is_none());
+
+        assert_eq!(writer.finish().unwrap(), 8192 + 2 * 4096);
+
+        let reader = RegionReader::from_reader(&file.get_ref()[..]).unwrap();
+        let slots = reader.info().chunk_infos().iter().map(|x| x.1).collect::<Vec<_>>();
+        assert_eq!(slots, [2, 1]);
+    }
+
+    /// Counts calls reaching the underlying writer
+    struct CountingWriter(std::io::Cursor<Vec<u8>>, usize);
+
+    impl Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.1 += 1;
+            self.0.write(buf)
+        }
+
+        fn write_vectored(&mut self, bufs: &[std::io::IoSlice]) -> std::io::Result<usize> {
+            self.1 += 1;
+            self.0.write_vectored(bufs)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl std::io::Seek for CountingWriter {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    #[test]
+    fn region_writer_batches_writes() {
+        let mut file = CountingWriter(std::io::Cursor::new(vec![]), 0);
+        let mut writer = RegionWriter::new(&mut file).unwrap();
+        for pos in 0..1024 {
+            writer.write_chunk_with(pos, 0, &[pos as u8; 100], Codec::Uncompressed).unwrap();
+        }
+        writer.finish().unwrap();
+
+        // One call per chunk, padding of the last sector and the tables
+        assert_eq!(file.1, 1024 + 2);
+        let mut reader = RegionReader::from_reader(&file.0.get_ref()[..]).unwrap();
+        let mut count = 0;
+        reader.decompress_all(|_, pos, data| {
+            assert_eq!(*data, [pos as u8; 100]);
+            count += 1;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(count, 1024);
+    }
+
+    #[test]
+    fn custom_region_format() {
+        for format in [RegionFormat::new(512, 8192).unwrap(), RegionFormat::new(8192, 16384).unwrap()] {
+            let mut file = std::io::Cursor::new(vec![]);
+            let mut writer = RegionWriter::with_format(&mut file, format).unwrap();
+            writer.write_chunk(3, 1, &[1; 100]).unwrap();
+            writer.write_chunk(0, 2, &[2; 10000]).unwrap();
+            let size = writer.finish().unwrap();
+            assert_eq!(size, file.get_ref().len() as u64);
+            assert!(size.is_multiple_of(format.sector_size));
+
+            let mut chunks = vec![];
+            RegionReader::from_reader_with_format(&file.get_ref()[..], Limits::default(), format)
+                .unwrap()
+                .decompress_all(|info, pos, data| {
+                    assert_eq!(info.location_in(&format) % format.sector_size, 0);
+                    chunks.push((pos, data.len()));
+                    Ok(())
+                })
+                .unwrap();
+            assert_eq!(chunks, [(3, 100), (0, 10000)]);
+        }
+
+        // Cubic Chunks cube regions have 4096 entries and no timestamps
+        let format = RegionFormat::CUBIC_CHUNKS_3D;
+        let mut file = std::io::Cursor::new(vec![]);
+        let mut writer = RegionWriter::with_format(&mut file, format).unwrap();
+        writer.write_chunk(4095, 1, &[1; 100]).unwrap();
+        writer.finish().unwrap();
+        assert_eq!(file.get_ref()[16388], 0x1f, "gzip stream follows length");
+
+        let mut chunks = vec![];
+        RegionReader::from_reader_with_format(&file.get_ref()[..], Limits::default(), format)
+            .unwrap()
+            .decompress_all(|info, pos, data| {
+                chunks.push((pos, info.timestamp.get(), data.clone()));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(chunks, [(4095, 0, vec![1; 100])]);
+
+        assert!(RegionFormat::new(4096, 4096).is_err());
+        assert!(RegionFormat::new(1000, 8000).is_err());
+    }
+
+    #[test]
+    fn region_builder_round_trip() {
+        let chunks = [(0, 0, Codec::GZip), (31, 0, Codec::Zlib), (5, 31, Codec::Uncompressed)];
+
+        let mut builder = RegionBuilder::new();
+        for (i, &(x, z, codec)) in chunks.iter().enumerate() {
+            builder.add_chunk(x, z, i as u32, &[i as u8; 100], codec).unwrap();
+        }
+        assert!(builder.add_chunk(0, 0, 0, &[], Codec::Zlib).is_err());
+        assert!(builder.add_chunk(32, 0, 0, &[], Codec::Zlib).is_err());
+
+        let mut file = vec![];
+        assert_eq!(builder.write_to(&mut file).unwrap(), 8192 + 3 * 4096);
+
+        let mut read = vec![];
+        RegionReader::from_reader(&file[..])
+            .unwrap()
+            .decompress_all(|info, pos, data| {
+                read.push((pos, info.timestamp.get(), data.clone()));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(
+            read,
+            [(0, 0, vec![0; 100]), (31, 1, vec![1; 100]), (5 + 31 * 32, 2, vec![2; 100])]
+        );
+    }
+
+    #[test]
+    fn region_file_inserts_in_place() {
+        let dir = TempDir::new("region-file");
+        let path = dir.join("region.mca");
+
+        let mut region = RegionFile::create_empty(&path).unwrap();
+        region.insert_chunk(0, 1, &[1; 100], Codec::Zlib).unwrap();
+        region.insert_chunk(1, 2, &[2; 10000], Codec::Uncompressed).unwrap();
+        assert!(region.insert_chunk(1, 3, &[3; 100], Codec::Zlib).is_err());
+        drop(region);
+        assert!(RegionFile::create_empty(&path).is_err());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 8192 + 4096 + 3 * 4096);
+
+        // Gap left by a removed chunk is filled before the file grows
+        let mut file = std::io::Cursor::new(vec![]);
+        let mut writer = RegionWriter::new(&mut file).unwrap();
+        writer.write_chunk(0, 1, &[1; 100]).unwrap();
+        writer.write_chunk(1, 2, &[2; 100]).unwrap();
+        writer.remove_chunk(0).unwrap();
+        writer.finish().unwrap();
+
+        let mut region = RegionFile::from_file_with_format(&mut file, RegionFormat::VANILLA).unwrap();
+        assert!(region.chunk_info(0).is_none());
+        let info = region.insert_chunk(5, 3, &[5; 100], Codec::Zlib).unwrap();
+        assert_eq!(info.location(), 8192);
+
+        let mut read = vec![];
+        RegionReader::from_reader(&file.get_ref()[..])
+            .unwrap()
+            .decompress_all(|info, pos, data| {
+                read.push((pos, info.timestamp.get(), data.clone()));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(read, [(5, 3, vec![5; 100]), (1, 2, vec![2; 100])]);
+    }
+
+    #[test]
+    fn region_file_writes_external_chunks() {
+        let dir = TempDir::new("region-file-external");
+        let path = dir.join("r.1.0.mca");
+        let external = dir.join("c.34.0.mcc");
+
+        // Too large for 255 sectors
+        let large = vec![7; 1 << 20];
+        let mut region = RegionFile::create_empty(&path).unwrap();
+        assert_eq!(region.update_chunk(2, 1, &large, Codec::Uncompressed).unwrap().size(), 4096);
+        assert!(external.is_file());
+        let read = |path| {
+            let mut read = vec![];
+            RegionReader::open(path)
+                .unwrap()
+                .decompress_all(|_, pos, data| {
+                    read.push((pos, data.len()));
+                    Ok(())
+                })
+                .unwrap();
+            read
+        };
+        assert_eq!(read(&path), [(2, large.len())]);
+
+        // Replaced by a chunk fitting the region file
+        region.update_chunk(2, 2, &[2; 100], Codec::Zlib).unwrap();
+        assert!(!external.exists());
+        assert_eq!(read(&path), [(2, 100)]);
+
+        // Without a region file name to find external files by
+        let mut region = RegionFile::create_with_format(std::io::Cursor::new(vec![]), RegionFormat::VANILLA).unwrap();
+        assert!(region.update_chunk(2, 1, &large, Codec::Uncompressed).is_err());
+    }
+
+    #[test]
+    fn region_file_reuses_freed_sectors() {
+        let mut file = std::io::Cursor::new(vec![]);
+        let mut region = RegionFile::create_with_format(&mut file, RegionFormat::VANILLA).unwrap();
+        region.insert_chunk(0, 1, &[1; 100], Codec::Zlib).unwrap();
+        region.insert_chunk(1, 1, &[1; 100], Codec::Zlib).unwrap();
+
+        // Old sectors stay in use until the header points away from them
+        let location = |info: ChunkInfo| info.location() / 4096;
+        assert_eq!(location(region.update_chunk(0, 2, &[2; 100], Codec::Zlib).unwrap()), 4);
+        assert_eq!(location(region.update_chunk(1, 2, &[2; 100], Codec::Zlib).unwrap()), 2);
+        assert_eq!(location(region.remove_chunk(0).unwrap().unwrap()), 4);
+        assert_eq!(region.zero_free_sectors().unwrap(), 2 * 4096);
+        assert_eq!(location(region.update_chunk(2, 2, &[3; 10000], Codec::Uncompressed).unwrap()), 3);
+        assert_eq!(file.get_ref().len(), 6 * 4096);
+
+        let mut read = vec![];
+        RegionReader::from_reader(&file.get_ref()[..])
+            .unwrap()
+            .decompress_all(|_, pos, data| {
+                read.push((pos, data.len()));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(read, [(1, 100), (2, 10000)]);
+    }
+
+    #[test]
+    fn strict_header_rejects_bad_entries() {
+        // Chunk 0 overlaps header, chunk 2 overlaps chunk 1
+        let mut header = vec![[0u8; 4]; 2048];
+        header[0] = [0, 0, 1, 1];
+        header[1] = [0, 0, 2, 2];
+        header[2] = [0, 0, 3, 1];
+
+        assert!(RegionReader::from_reader(header.as_bytes()).is_err());
+
+        let reader = RegionReader::from_reader_with_limits(header.as_bytes(), Limits::RELAXED).unwrap();
+        let slots = reader.info().chunk_infos().iter().map(|x| x.1).collect::<Vec<_>>();
+        assert_eq!(slots, [1]);
+    }
+
+    #[test]
+    fn open_gzipped_region() {
+        let dir = TempDir::new("gzipped-region");
+        let path = dir.join("r.0.0.mca.gz");
+        let data = std::fs::read(fixture("basic")).unwrap();
+        let mut encoder = flate2::write::GzEncoder::new(std::fs::File::create(&path).unwrap(), Default::default());
+        encoder.write_all(&data).unwrap();
+        encoder.finish().unwrap();
+
+        let mut read = vec![];
+        super::open(&path).unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+        assert_eq!(super::gunzipped_path(&path).extension().unwrap(), "mca");
+    }
 }
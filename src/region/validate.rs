@@ -0,0 +1,110 @@
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use zerocopy::FromBytes;
+
+use super::{region_coords_from_path, ChunkInfo, RegionInfo};
+use crate::{chunk::ChunkData, error::ErrorCode, nbt};
+
+/// Checks invariants the game relies on: complete header, sector alignment, chunks inside the file
+/// and not overlapping each other, sane length fields, known compression and parseable NBT root.
+/// All problems are collected into a single error, carrying the code of the first one.
+pub fn validate_region(path: impl AsRef<Path>) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let file = std::fs::read(path).with_context(|| format!("Unable to read {}", path.display()))?;
+    let region = region_coords_from_path(path);
+
+    let mut problems = vec![];
+
+    if file.len() < RegionInfo::SIZE as usize {
+        bail!(ErrorCode::TruncatedHeader.error(format!("{}: file is shorter than region header ({} bytes)", path.display(), file.len())));
+    }
+    if file.len() % ChunkInfo::SECTOR_SIZE as usize != 0 {
+        problems.push((ErrorCode::SectorsBeyondEnd, format!("file size {} is not a multiple of sector size", file.len())));
+    }
+
+    let info = RegionInfo::read(&file[..])?;
+    let mut used = vec![false; file.len().div_ceil(ChunkInfo::SECTOR_SIZE as usize)];
+    let mut chunkbuf = vec![];
+    let mut databuf = vec![];
+
+    for &(chunkinfo, pos) in info.chunk_infos() {
+        let (x, z) = RegionInfo::local_coords(pos);
+        let mut problem = |code: ErrorCode, msg: String| problems.push((code, format!("chunk {x},{z}: {msg}")));
+
+        let (location, size) = (chunkinfo.location(), chunkinfo.size());
+        if location < RegionInfo::SIZE as u64 {
+            problem(ErrorCode::HeaderOverlap, format!("sector {} overlaps with header", location / ChunkInfo::SECTOR_SIZE as u64));
+            continue;
+        }
+        if size == 0 {
+            problem(ErrorCode::BadChunkLength, "sector count is zero".to_owned());
+            continue;
+        }
+        if location + size > file.len() as u64 {
+            problem(ErrorCode::SectorsBeyondEnd, format!("sectors end at {} beyond end of file", location + size));
+            continue;
+        }
+
+        let sectors = location / ChunkInfo::SECTOR_SIZE as u64..(location + size) / ChunkInfo::SECTOR_SIZE as u64;
+        if sectors.clone().any(|x| used[x as usize]) {
+            problem(ErrorCode::SectorOverlap, "sectors overlap with another chunk".to_owned());
+        }
+        sectors.for_each(|x| used[x as usize] = true);
+
+        let raw = &file[location as usize..(location + size) as usize];
+        let length = u32::from_be_bytes(raw[..4].try_into().unwrap()) as u64;
+        if length == 0 || length + 4 > size {
+            problem(ErrorCode::BadChunkLength, format!("length field {length} does not fit into {size} bytes of sectors"));
+            continue;
+        }
+
+        let compression_type = raw[4];
+
+        if compression_type & ChunkData::EXTERNAL_FLAG != 0 {
+            let Some((rx, rz)) = region else {
+                problem(ErrorCode::ExternalChunk, "chunk is stored externally, but region coordinates are unknown".to_owned());
+                continue;
+            };
+            let external = path.with_file_name(format!("c.{}.{}.mcc", rx * 32 + x as i32, rz * 32 + z as i32));
+            let data = match std::fs::read(&external) {
+                Ok(x) => x,
+                Err(e) => {
+                    problem(ErrorCode::ExternalChunk, format!("unable to read external chunk {}: {e}", external.display()));
+                    continue;
+                },
+            };
+
+            chunkbuf.clear();
+            chunkbuf.extend_from_slice(&(data.len() as u32 + 1).to_be_bytes());
+            chunkbuf.push(compression_type & !ChunkData::EXTERNAL_FLAG);
+            chunkbuf.extend_from_slice(&data);
+        }
+        let stored = match compression_type & ChunkData::EXTERNAL_FLAG != 0 {
+            true => &chunkbuf[..],
+            false => &raw[..length as usize + 4],
+        };
+
+        let Ok(data) = ChunkData::ref_from_bytes(stored) else {
+            problem(ErrorCode::BadChunkLength, "chunk data is malformed".to_owned());
+            continue;
+        };
+
+        databuf.clear();
+        if let Err(e) = data.decompress(&mut databuf) {
+            problem(ErrorCode::of(&e).unwrap_or(ErrorCode::CorruptChunk), format!("unable to decompress: {e:#}"));
+            continue;
+        }
+
+        if let Err(e) = nbt::read(&databuf) {
+            problem(ErrorCode::InvalidNbt, format!("invalid NBT: {e:#}"));
+        }
+    }
+
+    if let Some(&(code, _)) = problems.first() {
+        let problems = problems.into_iter().map(|x| x.1).collect::<Vec<_>>();
+        bail!(code.error(format!("{} is not a valid region file:\n  {}", path.display(), problems.join("\n  "))));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,135 @@
+use std::{collections::BTreeMap, io::BufWriter, path::PathBuf};
+
+use anyhow::{ensure, Context};
+
+use crate::{
+    chunk, nbt,
+    region::{self, RegionInfo, RegionReader},
+    world,
+};
+
+/// Images larger than this are more likely a mistake than a map
+const MAX_PIXELS: u64 = 1 << 28;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum RenderMode {
+    /// Every existing chunk is white
+    #[default]
+    Presence,
+    /// Brighter chunks were visited longer, on a logarithmic scale. Decompresses every chunk
+    Inhabited,
+    /// Brighter chunks were saved longer ago, relative to the newest chunk
+    Age,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct RenderArgs {
+    /// Region file or directory to search region files in
+    #[arg(short, long)]
+    pub input: PathBuf,
+
+    /// Output PNG. One pixel per chunk, north is up, missing chunks are transparent
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    #[arg(long, value_enum, default_value_t = RenderMode::Presence)]
+    pub mode: RenderMode,
+}
+
+/// Maps values to 1..=255 by their position between min and max, so present chunks never blend with background
+fn intensities(values: &BTreeMap<(i32, i32), f64>) -> BTreeMap<(i32, i32), u8> {
+    let min = values.values().copied().fold(f64::INFINITY, f64::min);
+    let max = values.values().copied().fold(f64::NEG_INFINITY, f64::max);
+    let range = max - min;
+
+    values
+        .iter()
+        .map(|(&pos, &x)| {
+            let scaled = if range > 0.0 { (x - min) / range } else { 1.0 };
+            (pos, (1.0 + scaled * 254.0).round() as u8)
+        })
+        .collect()
+}
+
+/// Draws a top-down chunk map of regions with coordinates in file names
+pub fn run(args: RenderArgs) -> anyhow::Result<()> {
+    let mut values = BTreeMap::<(i32, i32), f64>::new();
+
+    for file in world::region_files(&args.input)?.iter() {
+        let Some(region) = region::region_coords_from_path(file) else {
+            eprintln!("Skipping {}: no region coordinates in file name", file.display());
+            continue;
+        };
+
+        if args.mode == RenderMode::Inhabited {
+            RegionReader::open(file)?
+                .decompress_all(|_, pos, data| {
+                    let (x, z) = RegionInfo::chunk_coords(Some(region), pos);
+                    let root = nbt::read_compound(data).with_context(|| format!("Chunk {x},{z}"))?;
+                    let ticks = chunk::inhabited_time(&root).unwrap_or_default().max(0);
+                    values.insert((x, z), (ticks as f64).ln_1p());
+                    Ok(())
+                })
+                .with_context(|| format!("{}", file.display()))?;
+            continue;
+        }
+
+        let file_reader = std::fs::File::open(file).with_context(|| format!("Unable to open {}", file.display()))?;
+        let info = RegionInfo::read(std::io::BufReader::new(file_reader)).with_context(|| format!("{}", file.display()))?;
+        for &(chunkinfo, pos) in info.chunk_infos() {
+            let value = match args.mode {
+                RenderMode::Age => -(chunkinfo.timestamp.get() as f64),
+                _ => 1.0,
+            };
+            values.insert(RegionInfo::chunk_coords(Some(region), pos), value);
+        }
+    }
+
+    ensure!(!values.is_empty(), "No chunks found in {}", args.input.display());
+
+    let (min_x, max_x) = values.keys().fold((i32::MAX, i32::MIN), |(a, b), &(x, _)| (a.min(x), b.max(x)));
+    let (min_z, max_z) = values.keys().fold((i32::MAX, i32::MIN), |(a, b), &(_, z)| (a.min(z), b.max(z)));
+    let width = min_x.abs_diff(max_x) + 1;
+    let height = min_z.abs_diff(max_z) + 1;
+    ensure!(
+        width as u64 * height as u64 <= MAX_PIXELS,
+        "Map of {width}x{height} chunks is too large, render a smaller directory"
+    );
+
+    // Gray and alpha per pixel
+    let mut pixels = vec![0u8; width as usize * height as usize * 2];
+    for ((x, z), intensity) in intensities(&values) {
+        let at = ((z - min_z) as usize * width as usize + (x - min_x) as usize) * 2;
+        pixels[at] = intensity;
+        pixels[at + 1] = 0xFF;
+    }
+
+    let file = std::fs::File::create(&args.output).with_context(|| format!("Unable to create {}", args.output.display()))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::GrayscaleAlpha);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pixels)?;
+    writer.finish()?;
+
+    println!(
+        "{}: {width}x{height} pixels, {} chunks, top-left chunk {min_x},{min_z}",
+        args.output.display(),
+        values.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intensities_span_visible_range() {
+        let values = BTreeMap::from([((0, 0), -10.0), ((1, 0), 0.0), ((2, 0), 10.0)]);
+        assert_eq!(intensities(&values).into_values().collect::<Vec<_>>(), [1, 128, 255]);
+
+        let single = BTreeMap::from([((0, 0), 5.0)]);
+        assert_eq!(intensities(&single)[&(0, 0)], 255);
+    }
+}
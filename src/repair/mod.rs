@@ -0,0 +1,152 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+};
+
+use anyhow::{bail, ensure, Context};
+
+use crate::{
+    delta::{self, ArchiveArgs},
+    rpack, Limits,
+};
+
+#[derive(Debug, clap::Args)]
+pub struct RepairArgs {
+    /// Damaged archive. Split archives are read from all their volumes
+    pub input: PathBuf,
+
+    /// New archive with every chunk read before the damage
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// Keep complete chunks of an archive cut off mid-chunk or missing its terminating record.
+    /// Without it damaged archives are only reported
+    #[arg(long)]
+    pub truncate_incomplete: bool,
+
+    /// Options of the new archive. Raw and delta archives stay so
+    #[command(flatten)]
+    pub archive: ArchiveArgs,
+}
+
+pub fn run(args: RepairArgs) -> anyhow::Result<()> {
+    crate::check_archive_paths(&args.input, &args.output)?;
+    let mut reader = BufReader::new(rpack::volume::open(&args.input)?);
+    ensure!(reader.fill_buf()?.starts_with(&rpack::MAGIC), "{} is not an rpack archive", args.input.display());
+
+    let mut writer = None;
+    let mut kind = None;
+    let mut payload = vec![];
+    let mut chunks = 0;
+    // Concatenated archives are salvaged into one
+    let damage = loop {
+        if !reader.fill_buf()?.starts_with(&rpack::MAGIC) {
+            break (!reader.fill_buf()?.is_empty()).then(|| anyhow::anyhow!("Unexpected data after archive"));
+        }
+        let mut rpackreader = match rpack::RpackReader::from_buf_reader(reader, Limits::default()) {
+            Ok(x) => x,
+            // Damaged header of a concatenated archive
+            Err(e) if writer.is_some() => break Some(e),
+            Err(e) => return Err(e),
+        };
+        let options = rpack::Options {
+            raw: rpackreader.raw(),
+            delta: rpackreader.delta(),
+            ..args.archive.options()
+        };
+        ensure!(
+            *kind.get_or_insert((options.raw, options.delta)) == (options.raw, options.delta),
+            "Concatenated archives mix raw or delta archives with plain ones"
+        );
+        let writer = match &mut writer {
+            Some(writer) => writer,
+            None => writer.insert(rpack::RpackWriter::new(delta::create(&args.output)?, options)?),
+        };
+
+        let damage = loop {
+            match rpackreader.read_chunk(&mut payload) {
+                Ok(Some(chunk)) if chunk.deleted => writer.write_deletion(chunk.pos)?,
+                Ok(Some(chunk)) => writer.write_chunk(chunk.pos, chunk.timestamp, &payload)?,
+                Ok(None) => break None,
+                Err(e) => break Some(e),
+            }
+            chunks += 1;
+        };
+        if damage.is_some() {
+            break damage;
+        }
+        reader = rpackreader.into_inner()?;
+    };
+
+    let Some(damage) = damage else {
+        writer.context("Archive has no chunks")?.finish()?.flush()?;
+        println!("Archive is intact, {chunks} chunks copied");
+        return Ok(());
+    };
+    if !args.truncate_incomplete {
+        drop(writer);
+        std::fs::remove_file(&args.output).ok();
+        bail!("{damage:#} after {chunks} complete chunks. Pass --truncate-incomplete to keep them");
+    }
+
+    writer.unwrap().finish()?.flush()?;
+    println!("Salvaged {chunks} complete chunks into {}", args.output.display());
+    // Archives have no index, so chunks stored after the damaged one are unknown
+    println!("Lost: {damage:#}, and any chunks stored after it");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn read_chunks(path: &std::path::Path) -> Vec<(u16, u32, Vec<u8>)> {
+        let mut reader = rpack::RpackReader::new(std::fs::File::open(path).unwrap()).unwrap();
+        let (mut chunks, mut payload) = (vec![], vec![]);
+        while let Some(chunk) = reader.read_chunk(&mut payload).unwrap() {
+            chunks.push((chunk.pos, chunk.timestamp, payload.clone()));
+        }
+        chunks
+    }
+
+    #[test]
+    fn salvage_complete_chunks() {
+        let dir = TempDir::new("repair");
+        let chunks = (0..4u16).map(|x| (x * 7, x as u32 + 100, vec![x as u8; 1000])).collect::<Vec<_>>();
+        let mut writer = rpack::RpackWriter::new(vec![], rpack::Options::default()).unwrap();
+        for (pos, timestamp, payload) in &chunks {
+            writer.write_chunk(*pos, *timestamp, payload).unwrap();
+        }
+        let archive = writer.finish().unwrap();
+        let end = std::mem::size_of::<rpack::RpackChunkHeader>();
+
+        let repair = |data: &[u8], truncate_incomplete: bool| {
+            std::fs::write(dir.join("damaged.rpack"), data).unwrap();
+            std::fs::remove_file(dir.join("repaired.rpack")).ok();
+            run(RepairArgs {
+                input: dir.join("damaged.rpack"),
+                output: dir.join("repaired.rpack"),
+                truncate_incomplete,
+                archive: ArchiveArgs { codec: rpack::Compression::None, level: 3, checksums: false },
+            })
+        };
+
+        // Cut off inside the payload of the last chunk
+        let cut = &archive[..archive.len() - end - 500];
+        assert!(repair(cut, false).is_err());
+        assert!(!dir.join("repaired.rpack").exists());
+        repair(cut, true).unwrap();
+        assert_eq!(read_chunks(&dir.join("repaired.rpack")), chunks[..3]);
+
+        // Terminating record missing
+        repair(&archive[..archive.len() - end], true).unwrap();
+        assert_eq!(read_chunks(&dir.join("repaired.rpack")), chunks);
+
+        // Concatenated archive with damaged header
+        let concatenated = [&archive[..], b"RPAK\xff\xff\xff\xff"].concat();
+        assert!(repair(&concatenated, false).is_err());
+        repair(&concatenated, true).unwrap();
+        assert_eq!(read_chunks(&dir.join("repaired.rpack")), chunks);
+    }
+}
@@ -0,0 +1,822 @@
+//! Conversion between region files and archives as streams: [`encode_region`] reads a region file and writes
+//! an archive, [`decode_region`] reads archives and writes a region file. Neither opens files of its own or prints,
+//! problems not failing the conversion go to [`ChunkCallback`], so they serve the command line, the batch pipeline
+//! and anything embedding the library alike.
+
+use std::{
+    io::{BufRead, Read, Seek, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{bail, ensure, Context};
+use zerocopy::{FromZeros, IntoBytes};
+
+use super::{BinHeader, Options, RpackChunk, RpackReader, RpackWriter, MAGIC};
+use crate::{
+    audit, chunk,
+    error::ErrorCode,
+    limits::Limits,
+    nbt,
+    region::{self, RegionFormat, RegionInfo, RegionReader, RegionWriter},
+    scratch, timings, world,
+};
+
+/// Shared flag stopping compaction, decompaction and verification at the next chunk once set.
+/// Stopped operations fail with [`Cancelled`] and remove their output like on any other error
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Fails with [`Cancelled`] once cancelled
+    pub fn check(&self) -> anyhow::Result<()> {
+        match self.0.load(Ordering::Relaxed) {
+            true => Err(Cancelled.into()),
+            false => Ok(()),
+        }
+    }
+}
+
+/// Error of operation stopped by its [`CancellationToken`]
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Chunk handled by [`encode_region`] or [`decode_region`]
+#[derive(Debug, Clone)]
+pub enum ChunkEvent {
+    /// Chunk written to archive, with uncompressed size or size as stored for raw archives
+    Archived { size: usize },
+    /// Problem not stopping conversion, like a mismatching NBT position, a suspicious compression ratio
+    /// or a chunk occurring twice
+    Warning { pos: u16, message: String },
+}
+
+/// Receiver of [`ChunkEvent`]s, called from every thread converting files
+#[derive(Clone)]
+pub struct ChunkCallback(pub Arc<dyn Fn(ChunkEvent) + Send + Sync>);
+
+impl std::fmt::Debug for ChunkCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ChunkCallback")
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PosCheck {
+    #[default]
+    None,
+    Report,
+    Fix,
+}
+
+/// Thresholds of compression ratio anomalies found when compacting
+#[derive(Debug, Clone, Copy)]
+pub struct RatioLimits {
+    /// Max decompressed size of chunk relative to its sectors
+    pub chunk: f64,
+    /// Max size of compressed archive relative to region file
+    pub archive: f64,
+    /// Fail instead of warning
+    pub strict: bool,
+}
+
+impl Default for RatioLimits {
+    fn default() -> Self {
+        Self {
+            chunk: 200.0,
+            archive: 1.0,
+            strict: false,
+        }
+    }
+}
+
+impl RatioLimits {
+    /// Returns warning if `ratio` exceeds `limit`, or fails when strict
+    pub fn check(&self, ratio: f64, limit: f64, what: impl FnOnce() -> String) -> anyhow::Result<Option<String>> {
+        if ratio <= limit {
+            return Ok(None);
+        }
+        let message = format!("{}, ratio {ratio:.1} exceeds {limit}", what());
+        ensure!(!self.strict, ErrorCode::RatioExceeded.error(message));
+        Ok(Some(message))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EncodeOptions {
+    /// Region coordinates. Required to check absolute chunk positions
+    pub region: Option<(i32, i32)>,
+    /// Path the region file is read from, named like `r.<x>.<z>.mca`. Chunks stored in `.mcc` files next to it
+    /// are read from there, they fail the conversion without it
+    pub region_path: Option<PathBuf>,
+    pub pos_check: PosCheck,
+    pub min_data_version: Option<i32>,
+    pub check_nbt: bool,
+    pub migrate: bool,
+    /// Chunks fully outside of it are left out, in coordinates of the region's dimension. Requires `region`
+    pub border: Option<world::WorldBorder>,
+    pub rpack: Options,
+    pub format: RegionFormat,
+    pub ignore_crc: bool,
+    pub codec_sniff: bool,
+    pub ratio: RatioLimits,
+    /// Chunks dropped or changed by pruning, migration or position fixes are recorded in it
+    pub audit: Option<audit::AuditLog>,
+    /// Called for every chunk. Warnings are dropped without it
+    pub on_chunk: Option<ChunkCallback>,
+    /// Checked between chunks
+    pub cancel: CancellationToken,
+}
+
+impl EncodeOptions {
+    fn chunk_event(&self, event: ChunkEvent) {
+        if let Some(callback) = &self.on_chunk {
+            callback.0(event);
+        }
+    }
+
+    fn warn(&self, pos: u16, message: String) {
+        self.chunk_event(ChunkEvent::Warning { pos, message });
+    }
+
+    /// Records chunk at header slot dropped or changed by `rule` in [`Self::audit`]
+    fn audit(&self, pos: u16, rule: audit::Rule, before: usize, after: Option<usize>) {
+        if let Some(audit) = &self.audit {
+            audit.record(RegionInfo::chunk_coords(self.region, pos), rule, before, after);
+        }
+    }
+
+    /// Whether chunk at header slot is kept by [`Self::border`]
+    pub fn inside_border(&self, pos: u16) -> bool {
+        match (self.border, self.region) {
+            (Some(border), Some(region)) => {
+                let (x, z) = RegionInfo::chunk_coords(Some(region), pos);
+                border.touches_chunk(x, z)
+            },
+            _ => true,
+        }
+    }
+
+    /// Limits of reading region files
+    pub fn limits(&self) -> Limits {
+        Limits {
+            ignore_checksums: self.ignore_crc,
+            sniff_codecs: self.codec_sniff,
+            ..Limits::default()
+        }
+    }
+}
+
+/// Chunks written by [`encode_region`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Encoded {
+    pub chunks: usize,
+    /// Total uncompressed size of chunks, or their size as stored for raw archives
+    pub bytes: u64,
+    /// Chunks left out outside of [`EncodeOptions::border`]
+    pub pruned: usize,
+    /// Chunks changed by [`EncodeOptions::migrate`]
+    pub migrated: usize,
+}
+
+/// Writes every chunk of region file read from `reader` into rpack archive
+pub fn encode_region(reader: impl Read, writer: impl Write, options: &EncodeOptions) -> anyhow::Result<Encoded> {
+    let mut regionreader = RegionReader::from_reader_with_format(reader, options.limits(), options.format)?;
+    if let Some(path) = &options.region_path {
+        regionreader = regionreader.with_external_chunks(path);
+    }
+    let mut rpackwriter = RpackWriter::new(writer, options.rpack.clone())?;
+    let mut encoded = Encoded::default();
+
+    if options.rpack.raw {
+        regionreader.read_all_raw(|info, pos, data| {
+            options.cancel.check()?;
+            if !options.inside_border(pos) {
+                options.audit(pos, audit::Rule::PruneBorder, data.len(), None);
+                encoded.pruned += 1;
+                return Ok(());
+            }
+            timings::measure(timings::Phase::Deflate, || rpackwriter.write_chunk(pos, info.timestamp.get(), data))?;
+            options.chunk_event(ChunkEvent::Archived { size: data.len() });
+            encoded.chunks += 1;
+            encoded.bytes += data.len() as u64;
+            Ok(())
+        })?;
+        rpackwriter.finish()?;
+        return Ok(encoded);
+    }
+
+    let migrations = options.migrate.then(chunk::Migrations::builtin);
+    let inflated = timings::measure(timings::Phase::Inflate, || regionreader.decompress_all(|info, pos, databuf| {
+        options.cancel.check()?;
+        if !options.inside_border(pos) {
+            options.audit(pos, audit::Rule::PruneBorder, databuf.len(), None);
+            encoded.pruned += 1;
+            return Ok(());
+        }
+        let stored = info.size_in(&options.format).max(1);
+        let warning = options
+            .ratio
+            .check(databuf.len() as f64 / stored as f64, options.ratio.chunk, || {
+                format!("decompressed to {} bytes from {stored}, possible zip bomb", databuf.len())
+            })
+            .map_err(|e| {
+                let (x, z) = RegionInfo::local_coords(pos);
+                ErrorCode::RatioExceeded.error(format!("Chunk {x},{z}: {e}"))
+            })?;
+        if let Some(warning) = warning {
+            options.warn(pos, warning);
+        }
+
+        timings::measure(timings::Phase::Other, || {
+            if options.check_nbt || options.min_data_version.is_some() {
+                let (x, z) = RegionInfo::local_coords(pos);
+                nbt::read_compound(databuf)
+                    .map_err(|e| ErrorCode::InvalidNbt.wrap(e, "Invalid NBT"))
+                    .and_then(|root| match options.min_data_version {
+                        Some(min) => chunk::require_min_data_version(&root, min),
+                        None => Ok(()),
+                    })
+                    .with_context(|| format!("Chunk {x},{z}"))?;
+            }
+
+            if let Some(migrations) = &migrations {
+                let (x, z) = RegionInfo::local_coords(pos);
+                let mut root = nbt::read_compound(databuf).with_context(|| format!("Chunk {x},{z}"))?;
+                if !migrations.apply(&mut root).with_context(|| format!("Chunk {x},{z}"))?.is_empty() {
+                    let before = databuf.len();
+                    databuf.clear();
+                    nbt::write_compound(&mut *databuf, &root)?;
+                    options.audit(pos, audit::Rule::Migrate, before, Some(databuf.len()));
+                    encoded.migrated += 1;
+                }
+            }
+
+            if options.pos_check != PosCheck::None {
+                check_chunk_pos(pos, databuf, options);
+            }
+            anyhow::Ok(())
+        })?;
+
+        timings::measure(timings::Phase::Deflate, || rpackwriter.write_chunk(pos, info.timestamp.get(), databuf))?;
+        options.chunk_event(ChunkEvent::Archived { size: databuf.len() });
+        encoded.chunks += 1;
+        encoded.bytes += databuf.len() as u64;
+
+        Ok(())
+    }));
+    for (pos, mismatch) in regionreader.checksum_mismatches() {
+        options.warn(*pos, format!("{mismatch}, archived as decompressed"));
+    }
+    for (pos, compression_type, codec) in regionreader.codec_corrections() {
+        options.warn(*pos, format!("compression type {compression_type} is wrong, decompressed as {codec:?}"));
+    }
+    if let Err(e) = inflated {
+        match e.is::<chunk::ChecksumMismatch>() {
+            true => bail!("{e:#}. Pass --ignore-crc to archive its data anyway"),
+            false => return Err(e),
+        }
+    }
+
+    rpackwriter.finish()?;
+    Ok(encoded)
+}
+
+/// Writes chunks of region file left out by [`EncodeOptions::border`] into an undo archive, see [`Options::undo`],
+/// so they can be put back after pruning. Other options apply as in [`encode_region`]. Returns chunks written
+pub fn encode_pruned(reader: impl Read, writer: impl Write, options: &EncodeOptions) -> anyhow::Result<usize> {
+    let mut regionreader = RegionReader::from_reader_with_format(reader, options.limits(), options.format)?;
+    if let Some(path) = &options.region_path {
+        regionreader = regionreader.with_external_chunks(path);
+    }
+    let mut rpackwriter = RpackWriter::new(writer, Options::undo())?;
+    let mut pruned = 0;
+    regionreader.decompress_all(|info, pos, databuf| {
+        options.cancel.check()?;
+        if !options.inside_border(pos) {
+            rpackwriter.write_chunk(pos, info.timestamp.get(), databuf)?;
+            pruned += 1;
+        }
+        Ok(())
+    })?;
+    rpackwriter.finish()?;
+    Ok(pruned)
+}
+
+/// Reports chunks which NBT position differs from header slot and fixes them if requested.
+/// Chunks with unreadable NBT are reported and left untouched.
+fn check_chunk_pos(pos: u16, databuf: &mut Vec<u8>, options: &EncodeOptions) {
+    let (local_x, local_z) = RegionInfo::local_coords(pos);
+
+    let mut root = match nbt::read_compound(databuf) {
+        Ok(x) => x,
+        Err(e) => {
+            options.warn(pos, format!("unable to read NBT: {e:#}"));
+            return;
+        },
+    };
+
+    let Some((x, z)) = chunk::nbt_position(&root) else {
+        options.warn(pos, "no xPos/zPos in NBT".into());
+        return;
+    };
+
+    let expected = options
+        .region
+        .map(|(rx, rz)| (rx * 32 + local_x as i32, rz * 32 + local_z as i32));
+
+    let matches = match expected {
+        Some(expected) => expected == (x, z),
+        None => (x.rem_euclid(32), z.rem_euclid(32)) == (local_x as i32, local_z as i32),
+    };
+
+    if matches {
+        return;
+    }
+
+    match expected {
+        Some((ex, ez)) => options.warn(pos, format!("expected position {ex},{ez} but NBT says {x},{z}")),
+        None => options.warn(pos, format!("NBT position {x},{z} does not match header slot")),
+    }
+
+    if options.pos_check != PosCheck::Fix {
+        return;
+    }
+
+    // Fixing needs region coordinates, checked by the caller
+    let Some((ex, ez)) = expected else {
+        return;
+    };
+    chunk::set_nbt_position(&mut root, ex, ez);
+
+    let mut fixed = vec![];
+    match nbt::write_compound(&mut fixed, &root) {
+        Ok(_) => {
+            options.audit(pos, audit::Rule::FixPos, databuf.len(), Some(fixed.len()));
+            *databuf = fixed;
+            options.warn(pos, "position fixed".into());
+        },
+        Err(e) => options.warn(pos, format!("unable to fix position: {e:#}")),
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DedupePos {
+    /// Fail decompaction
+    #[default]
+    Error,
+    /// Keep chunk with the newest timestamp. Later one wins on equal timestamps
+    Newest,
+    /// Keep the chunk which comes last
+    Last,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ArchiveFormat {
+    /// Told by the first bytes, anything not starting like an rpack archive is read as legacy stream
+    #[default]
+    Auto,
+    Rpack,
+    /// Stream of [`BinHeader`] records written before rpack archives
+    LegacyBin,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DecodeOptions {
+    pub dedupe_pos: DedupePos,
+    pub format: RegionFormat,
+    /// Leave out the zero padding after the last chunk, see [`RegionWriter::with_sparse`]
+    pub sparse: bool,
+    /// Compression of written chunks. Raw chunks are kept as they are if `None`
+    pub codec: Option<chunk::Codec>,
+    /// Highest DataVersion of written chunks
+    pub target_data_version: Option<i32>,
+    /// Warn about chunks above `target_data_version` instead of failing
+    pub warn_newer: bool,
+    /// Generate chunks for slots left empty. Requires region coordinates from `region_path`
+    pub fill_missing: Option<chunk::Filler>,
+    /// Format of the input, see [`decode_region`]
+    pub from: ArchiveFormat,
+    /// Path the region file is written to, named like `r.<x>.<z>.mca`. Oversized chunks are written into
+    /// `.mcc` files next to it, they fail the conversion without it
+    pub region_path: Option<PathBuf>,
+    /// Called with warnings. They are dropped without it
+    pub on_chunk: Option<ChunkCallback>,
+    /// Checked between chunks
+    pub cancel: CancellationToken,
+}
+
+impl DecodeOptions {
+    fn warn(&self, pos: u16, message: String) {
+        if let Some(callback) = &self.on_chunk {
+            callback.0(ChunkEvent::Warning { pos, message });
+        }
+    }
+}
+
+/// Writes region file with every chunk of rpack archives or of a stream of [`BinHeader`] records written by older
+/// versions. Returns size of the region file, which is shorter than written with [`DecodeOptions::sparse`]
+pub fn decode_region(mut reader: impl BufRead, writer: impl Write + Seek, options: &DecodeOptions) -> anyhow::Result<u64> {
+    let mut regionwriter = RegionWriter::with_format(writer, options.format)?.with_sparse(options.sparse);
+    if let Some(path) = &options.region_path {
+        regionwriter = regionwriter.with_external_chunks(path);
+    }
+    let mut buffer = scratch::Scratch::take();
+    // Checked before anything is written
+    let region = match options.fill_missing {
+        Some(_) => Some(options.region_path.as_deref().and_then(region::region_coords_from_path).with_context(|| {
+            let name = options.region_path.as_deref().unwrap_or("region".as_ref());
+            format!("--fill-missing needs region coordinates, name {} like r.<x>.<z>.mca", name.display())
+        })?),
+        None => None,
+    };
+
+    match (options.from, reader.fill_buf()?.starts_with(&MAGIC)) {
+        (ArchiveFormat::Rpack, false) => {
+            bail!(ErrorCode::NotAnArchive.error("Not an rpack archive, pass --from legacy-bin to read a stream of older versions"))
+        },
+        (ArchiveFormat::LegacyBin, true) => bail!("Input is an rpack archive, not a legacy stream"),
+        (ArchiveFormat::Auto | ArchiveFormat::LegacyBin, false) => {
+            regionwriter = decode_legacy(reader, regionwriter, options)?;
+            return fill_missing(regionwriter, region, options);
+        },
+        _ => {},
+    }
+
+    // Concatenated archives are read one after another
+    while reader.fill_buf()?.starts_with(&MAGIC) {
+        let mut rpackreader = RpackReader::from_buf_reader(reader, Limits::default())?;
+        ensure!(!rpackreader.delta(), "Archive is a delta, rebuild the full archive with apply-delta first");
+        let raw = rpackreader.raw();
+        if raw || options.target_data_version.is_some() {
+            while let Some(chunk) = timings::measure(timings::Phase::Inflate, || rpackreader.read_chunk(&mut buffer))? {
+                timings::measure(timings::Phase::Deflate, || put_chunk(&mut regionwriter, chunk.pos, chunk.timestamp, &buffer, raw, options))?;
+            }
+        } else {
+            // NBT is not looked at, so it goes from the archive decompressor straight into the region compressor
+            let mut put = |chunk: RpackChunk, payload: &mut dyn Read| {
+                if claim_slot(&mut regionwriter, chunk.pos, chunk.timestamp, options)? {
+                    let codec = options.format.codec.or(options.codec).unwrap_or_default();
+                    let payload = timings::Timed::new(payload, timings::Phase::Inflate);
+                    timings::measure(timings::Phase::Deflate, || regionwriter.write_chunk_from(chunk.pos, chunk.timestamp, payload, codec))?;
+                }
+                Ok(())
+            };
+            while rpackreader.read_chunk_streaming(&mut put)?.is_some() {}
+        }
+        reader = rpackreader.into_inner()?;
+    }
+    ensure!(reader.fill_buf()?.is_empty(), "Unexpected data after archive");
+
+    fill_missing(regionwriter, region, options)
+}
+
+/// Writes chunks of [`DecodeOptions::fill_missing`] into empty slots of region at `region` coordinates
+/// and finishes the region file
+fn fill_missing(
+    mut regionwriter: RegionWriter<impl Write + Seek>,
+    region: Option<(i32, i32)>,
+    options: &DecodeOptions,
+) -> anyhow::Result<u64> {
+    if let Some(filler) = options.fill_missing {
+        let codec = options.format.codec.or(options.codec).unwrap_or_default();
+        let mut nbt = vec![];
+        for pos in 0..RegionInfo::MAX_CHUNK_COUNT {
+            if regionwriter.chunk_info(pos).is_some() {
+                continue;
+            }
+            options.cancel.check()?;
+            let (x, z) = RegionInfo::chunk_coords(region, pos);
+            nbt.clear();
+            nbt::write_compound(&mut nbt, &filler.chunk(x, z))?;
+            regionwriter.write_chunk_with(pos, 0, &nbt, codec)?;
+        }
+    }
+    regionwriter.finish()
+}
+
+/// Returns region writer with every chunk of the stream, to be finished by the caller
+fn decode_legacy<W: Write + Seek>(
+    mut reader: impl Read,
+    mut regionwriter: RegionWriter<W>,
+    options: &DecodeOptions,
+) -> anyhow::Result<RegionWriter<W>> {
+    let mut header = BinHeader::new_zeroed();
+    let mut buffer = scratch::Scratch::take();
+
+    loop {
+        let ret = reader.read_exact(header.as_mut_bytes());
+        if ret
+            .as_ref()
+            .is_err_and(|e| e.kind() == std::io::ErrorKind::UnexpectedEof)
+        {
+            return Ok(regionwriter);
+        }
+        ret?;
+        options.cancel.check()?;
+
+        ensure!(
+            header.length.get() <= Limits::default().max_decompressed_size,
+            "Chunk length {} exceeds limit of {} bytes",
+            header.length.get(),
+            Limits::default().max_decompressed_size
+        );
+
+        let copied = std::io::copy(&mut reader.by_ref().take(header.length.get()), &mut *buffer)?;
+        ensure!(
+            copied == header.length.get(),
+            std::io::Error::from(std::io::ErrorKind::UnexpectedEof)
+        );
+
+        let pos = header
+            .pos
+            .get()
+            .try_into()
+            .with_context(|| format!("Chunk position {} is out of region", header.pos.get()))?;
+
+        put_chunk(&mut regionwriter, pos, header.timestamp.get(), &buffer, false, options)?;
+
+        buffer.clear();
+    }
+}
+
+/// Writes chunk into region resolving duplicate positions according to options.
+/// Raw data is stored as is, see [`RpackReader::raw`]
+pub fn put_chunk(
+    regionwriter: &mut RegionWriter<impl Write + Seek>,
+    pos: u16,
+    timestamp: u32,
+    data: &[u8],
+    raw: bool,
+    options: &DecodeOptions,
+) -> anyhow::Result<()> {
+    if !claim_slot(regionwriter, pos, timestamp, options)? {
+        return Ok(());
+    }
+
+    if let Some(max) = options.target_data_version {
+        check_data_version(pos, data, raw, max, options)?;
+    }
+
+    let codec = options.format.codec.or(options.codec);
+    match (raw, codec) {
+        (false, codec) => regionwriter.write_chunk_with(pos, timestamp, data, codec.unwrap_or_default()),
+        (true, None) => regionwriter.write_raw_chunk(pos, timestamp, data),
+        // Already compressed as requested, copy without decoding
+        (true, Some(codec)) if data.first() == Some(&codec.compression_type()) && codec.matches_header(&data[1..]) => {
+            regionwriter.write_raw_chunk(pos, timestamp, data)
+        },
+        (true, Some(codec)) => {
+            let (x, z) = RegionInfo::local_coords(pos);
+            let mut nbt = vec![];
+            chunk::decompress_stored(data, &mut nbt, Limits::default().max_decompressed_size)
+                .with_context(|| format!("Unable to transcode chunk {x},{z}"))?;
+            regionwriter.write_chunk_with(pos, timestamp, &nbt, codec)
+        },
+    }
+}
+
+/// Frees slot for chunk at `pos` if it is taken and the new chunk is to be kept. Returns whether to write the new chunk
+fn claim_slot(
+    regionwriter: &mut RegionWriter<impl Write + Seek>,
+    pos: u16,
+    timestamp: u32,
+    options: &DecodeOptions,
+) -> anyhow::Result<bool> {
+    options.cancel.check()?;
+    ensure!(pos < RegionInfo::MAX_CHUNK_COUNT, "Chunk position {pos} is out of region");
+
+    if let Some(old) = regionwriter.chunk_info(pos) {
+        let (x, z) = RegionInfo::local_coords(pos);
+        let replace = match options.dedupe_pos {
+            DedupePos::Error => bail!("Chunk {x},{z} occurs more than once"),
+            DedupePos::Newest => timestamp >= old.timestamp.get(),
+            DedupePos::Last => true,
+        };
+
+        options.warn(pos, format!("occurs more than once, {} one is kept", if replace { "later" } else { "earlier" }));
+        if !replace {
+            return Ok(false);
+        }
+        regionwriter.remove_chunk(pos)?;
+    }
+    Ok(true)
+}
+
+/// Checks chunk is not newer than `max`, failing or only warning about it
+fn check_data_version(pos: u16, data: &[u8], raw: bool, max: i32, options: &DecodeOptions) -> anyhow::Result<()> {
+    let (x, z) = RegionInfo::local_coords(pos);
+    let mut nbt = vec![];
+    let data = match raw {
+        true => {
+            chunk::decompress_stored(data, &mut nbt, Limits::default().max_decompressed_size)
+                .with_context(|| format!("Unable to read DataVersion of chunk {x},{z}"))?;
+            &nbt
+        },
+        false => data,
+    };
+    let root = nbt::read_compound(data).with_context(|| format!("Unable to read DataVersion of chunk {x},{z}"))?;
+    match chunk::require_max_data_version(&root, max) {
+        Err(e) if options.warn_newer => options.warn(pos, e.to_string()),
+        result => result.with_context(|| format!("Chunk {x},{z}"))?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Cursor,
+        sync::{Arc, Mutex},
+    };
+
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::{rpack::Compression, testutil::RegionGenerator};
+
+    /// Region file decoded from `packed` at `path`
+    fn decode(packed: &[u8], path: &str, options: DecodeOptions) -> anyhow::Result<Vec<u8>> {
+        let mut file = Cursor::new(vec![]);
+        let options = DecodeOptions { region_path: Some(path.into()), ..options };
+        decode_region(packed, &mut file, &options).map(|_| file.into_inner())
+    }
+
+    proptest! {
+        #[test]
+        fn encode_decode_round_trip(seed in any::<u64>(), gaps in any::<bool>(), zstd in any::<bool>(), solid in any::<bool>(), raw in any::<bool>()) {
+            let generator = RegionGenerator { gaps, ..Default::default() };
+            let region = generator.generate(seed);
+
+            let options = EncodeOptions {
+                rpack: Options {
+                    compression: if zstd { Compression::Zstd } else { Compression::None },
+                    solid,
+                    checksums: true,
+                    raw,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+
+            let mut packed = vec![];
+            encode_region(&region.bytes[..], &mut packed, &options).unwrap();
+
+            let unpacked = decode(&packed, "r.0.0.mca", DecodeOptions::default()).unwrap();
+
+            let mut chunks = vec![];
+            RegionReader::from_reader(&unpacked[..])
+                .unwrap()
+                .decompress_all(|info, pos, data| {
+                    chunks.push((pos, info.timestamp.get(), data.clone()));
+                    Ok(())
+                })
+                .unwrap();
+            chunks.sort_by_key(|x| x.0);
+
+            let expected = region
+                .chunks
+                .iter()
+                .map(|x| (x.pos, x.timestamp, x.payload.clone()))
+                .collect::<Vec<_>>();
+            prop_assert_eq!(chunks, expected);
+
+            // Decoded region keeps chunk order, so encoding it again gives the same stream
+            let mut repacked = vec![];
+            encode_region(&unpacked[..], &mut repacked, &options).unwrap();
+            prop_assert_eq!(repacked, packed);
+        }
+    }
+
+    #[test]
+    fn strict_ratio_fails_compaction() {
+        let region = RegionGenerator::default().generate(1);
+        let mut options = EncodeOptions {
+            ratio: RatioLimits { chunk: 0.5, ..Default::default() },
+            ..Default::default()
+        };
+        // Reported per chunk without failing
+        let events = Arc::new(Mutex::new(vec![]));
+        options.on_chunk = Some(ChunkCallback(Arc::new({
+            let events = events.clone();
+            move |event| events.lock().unwrap().push(event)
+        })));
+        let encoded = encode_region(&region.bytes[..], &mut vec![], &options).unwrap();
+        let events = events.lock().unwrap();
+        assert_eq!(events.iter().filter(|x| matches!(x, ChunkEvent::Archived { .. })).count(), encoded.chunks);
+        assert!(events.iter().any(|x| matches!(x, ChunkEvent::Warning { message, .. } if message.contains("possible zip bomb"))));
+
+        options.ratio.strict = true;
+        let error = encode_region(&region.bytes[..], &mut vec![], &options).unwrap_err();
+        assert!(error.to_string().contains("possible zip bomb"), "{error}");
+    }
+
+    #[test]
+    fn cancelled_between_chunks() {
+        let region = RegionGenerator::default().generate(2);
+        let options = EncodeOptions::default();
+        options.cancel.cancel();
+        let error = encode_region(&region.bytes[..], &mut vec![], &options).unwrap_err();
+        assert!(error.is::<Cancelled>(), "{error}");
+    }
+
+    #[test]
+    fn check_nbt_rejects_garbage_chunk() {
+        let mut region = Cursor::new(vec![]);
+        let mut regionwriter = RegionWriter::new(&mut region).unwrap();
+        regionwriter.write_chunk(33, 0, b"not nbt at all").unwrap();
+        regionwriter.finish().unwrap();
+
+        let mut options = EncodeOptions::default();
+        assert!(encode_region(&region.get_ref()[..], &mut vec![], &options).is_ok());
+
+        options.check_nbt = true;
+        let error = encode_region(&region.get_ref()[..], &mut vec![], &options).unwrap_err();
+        assert_eq!(format!("{error}"), "Chunk 1,1");
+    }
+
+    #[test]
+    fn target_data_version_gates_decompaction() {
+        // Generated chunks are of DataVersion 3465
+        let region = RegionGenerator::default().generate(1);
+        let mut packed = vec![];
+        encode_region(&region.bytes[..], &mut packed, &EncodeOptions::default()).unwrap();
+
+        let decompact = |target_data_version, warn_newer| {
+            let options = DecodeOptions { target_data_version: Some(target_data_version), warn_newer, ..Default::default() };
+            decode_region(&packed[..], Cursor::new(vec![]), &options)
+        };
+        assert!(decompact(3465, false).is_ok());
+        let error = decompact(3000, false).unwrap_err();
+        assert!(format!("{error:#}").contains("DataVersion 3465 is newer than target 3000"), "{error:#}");
+        assert!(decompact(3000, true).is_ok());
+    }
+
+    #[test]
+    fn legacy_stream_decompacts() {
+        let region = RegionGenerator::default().generate(1);
+        let mut stream = vec![];
+        for chunk in region.chunks.iter() {
+            let header = BinHeader {
+                pos: (chunk.pos as u32).into(),
+                timestamp: chunk.timestamp.into(),
+                length: (chunk.payload.len() as u64).into(),
+            };
+            stream.extend_from_slice(header.as_bytes());
+            stream.extend_from_slice(&chunk.payload);
+        }
+
+        let decompact = |from| decode(&stream, "r.0.0.mca", DecodeOptions { from, ..Default::default() });
+        assert!(decompact(ArchiveFormat::Rpack).is_err());
+        let file = decompact(ArchiveFormat::LegacyBin).unwrap();
+        assert_eq!(decompact(ArchiveFormat::Auto).unwrap(), file);
+
+        let mut chunks = vec![];
+        RegionReader::from_reader(&file[..]).unwrap().decompress_all(|info, pos, data| {
+            chunks.push((pos, info.timestamp.get(), data.clone()));
+            Ok(())
+        }).unwrap();
+        let expected = region.chunks.iter().map(|x| (x.pos, x.timestamp, x.payload.clone())).collect::<Vec<_>>();
+        assert_eq!(chunks, expected);
+    }
+
+    #[test]
+    fn fill_missing_slots() {
+        let region = RegionGenerator::default().generate(1);
+        let mut packed = vec![];
+        encode_region(&region.bytes[..], &mut packed, &EncodeOptions::default()).unwrap();
+
+        let options = DecodeOptions { fill_missing: Some(chunk::Filler::PlainsFlat), ..Default::default() };
+        assert!(decode(&packed, "region.mca", options.clone()).is_err());
+        let file = decode(&packed, "r.1.-1.mca", options).unwrap();
+
+        let mut chunks = 0;
+        RegionReader::from_reader(&file[..]).unwrap().decompress_all(|info, pos, data| {
+            let root = nbt::read_compound(data)?;
+            if !region.chunks.iter().any(|x| x.pos == pos) {
+                assert_eq!(chunk::nbt_position(&root), Some(RegionInfo::chunk_coords(Some((1, -1)), pos)));
+                assert_eq!(chunk::data_version(&root), Some(chunk::Filler::DATA_VERSION));
+                assert_eq!(info.timestamp.get(), 0);
+            }
+            chunks += 1;
+            Ok(())
+        }).unwrap();
+        assert_eq!(chunks, 1024);
+    }
+}
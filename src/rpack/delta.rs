@@ -0,0 +1,152 @@
+//! Snapshots of a region held in memory, and deltas between them.
+//!
+//! A delta archive stores chunks added or changed since its base archive and removal records for chunks
+//! gone since. Applying it to the base gives the newer snapshot, and deltas of a chain can be merged into one.
+
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, Read, Write},
+};
+
+use anyhow::ensure;
+
+use super::{Options, RpackReader, RpackWriter};
+use crate::{chunk, limits::Limits, region::RegionReader};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub timestamp: u32,
+    /// Uncompressed NBT
+    pub nbt: Vec<u8>,
+}
+
+/// Chunks of a region by header slot
+pub type Snapshot = BTreeMap<u16, Chunk>;
+
+/// Chunks added or changed since base snapshot by header slot, `None` for removed ones
+pub type Delta = BTreeMap<u16, Option<Chunk>>;
+
+/// Reads every chunk of region file
+pub fn read_region<R: Read>(mut reader: RegionReader<R>) -> anyhow::Result<Snapshot> {
+    let mut snapshot = Snapshot::new();
+    reader.decompress_all(|info, pos, data| {
+        snapshot.insert(pos, Chunk {
+            timestamp: info.timestamp.get(),
+            nbt: data.clone(),
+        });
+        Ok(())
+    })?;
+    Ok(snapshot)
+}
+
+/// Reads records of archive with NBT payloads, raw payloads are decompressed. Later records of a position win
+fn read_records<R: BufRead>(reader: &mut RpackReader<R>, limits: &Limits) -> anyhow::Result<Delta> {
+    let mut records = Delta::new();
+    let mut payload = vec![];
+    while let Some(record) = reader.read_chunk(&mut payload)? {
+        let chunk = match (record.deleted, reader.raw()) {
+            (true, _) => None,
+            (false, false) => Some(payload.clone()),
+            (false, true) => {
+                let mut nbt = vec![];
+                chunk::decompress_stored(&payload, &mut nbt, limits.max_decompressed_size)?;
+                Some(nbt)
+            },
+        };
+        records.insert(record.pos, chunk.map(|nbt| Chunk { timestamp: record.timestamp, nbt }));
+    }
+    Ok(records)
+}
+
+/// Reads every chunk of a full archive
+pub fn read_snapshot<R: BufRead>(mut reader: RpackReader<R>, limits: &Limits) -> anyhow::Result<Snapshot> {
+    ensure!(!reader.delta(), "Archive is a delta, a full archive is required");
+    let records = read_records(&mut reader, limits)?;
+    Ok(records.into_iter().filter_map(|(pos, chunk)| Some((pos, chunk?))).collect())
+}
+
+/// Reads changes of a delta archive
+pub fn read_delta<R: BufRead>(mut reader: RpackReader<R>, limits: &Limits) -> anyhow::Result<Delta> {
+    ensure!(reader.delta(), "Archive is not a delta");
+    read_records(&mut reader, limits)
+}
+
+/// Chunks of `new` differing from `base` in data or timestamp, and chunks of `base` missing from `new`
+pub fn diff(base: &Snapshot, new: &Snapshot) -> Delta {
+    let changed = new
+        .iter()
+        .filter(|(pos, chunk)| base.get(pos) != Some(chunk))
+        .map(|(pos, chunk)| (*pos, Some(chunk.clone())));
+    let removed = base.keys().filter(|pos| !new.contains_key(pos)).map(|pos| (*pos, None));
+    changed.chain(removed).collect()
+}
+
+pub fn apply(base: &mut Snapshot, delta: Delta) {
+    for (pos, chunk) in delta {
+        match chunk {
+            Some(chunk) => base.insert(pos, chunk),
+            None => base.remove(&pos),
+        };
+    }
+}
+
+/// Folds `newer` into `older`, so applying the result equals applying both in order
+pub fn merge(older: &mut Delta, newer: Delta) {
+    older.extend(newer);
+}
+
+pub fn write_snapshot<W: Write>(writer: W, options: Options, snapshot: &Snapshot) -> anyhow::Result<W> {
+    ensure!(!options.raw, "Snapshots are written as NBT, raw archive is not supported");
+    let mut writer = RpackWriter::new(writer, Options { delta: false, ..options })?;
+    for (pos, chunk) in snapshot {
+        writer.write_chunk(*pos, chunk.timestamp, &chunk.nbt)?;
+    }
+    writer.finish()
+}
+
+pub fn write_delta<W: Write>(writer: W, options: Options, delta: &Delta) -> anyhow::Result<W> {
+    ensure!(!options.raw, "Deltas are written as NBT, raw archive is not supported");
+    let mut writer = RpackWriter::new(writer, Options { delta: true, ..options })?;
+    for (pos, chunk) in delta {
+        match chunk {
+            Some(chunk) => writer.write_chunk(*pos, chunk.timestamp, &chunk.nbt)?,
+            None => writer.write_deletion(*pos)?,
+        }
+    }
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(chunks: &[(u16, u32, &[u8])]) -> Snapshot {
+        chunks.iter().map(|(pos, timestamp, nbt)| (*pos, Chunk { timestamp: *timestamp, nbt: nbt.to_vec() })).collect()
+    }
+
+    #[test]
+    fn diff_apply_merge() {
+        let first = snapshot(&[(0, 1, b"a"), (1, 1, b"b"), (2, 1, b"c")]);
+        let second = snapshot(&[(0, 1, b"a"), (1, 2, b"B"), (3, 2, b"d")]);
+        let third = snapshot(&[(0, 3, b"a"), (2, 3, b"C"), (3, 2, b"d")]);
+
+        let mut delta = diff(&first, &second);
+        assert_eq!(delta.keys().copied().collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(delta[&2], None);
+
+        // Through archive format
+        let archive = write_delta(vec![], Options::default(), &delta).unwrap();
+        let read = read_delta(RpackReader::new(&archive[..]).unwrap(), &Limits::default()).unwrap();
+        assert_eq!(read, delta);
+        assert!(read_snapshot(RpackReader::new(&archive[..]).unwrap(), &Limits::default()).is_err());
+
+        let mut applied = first.clone();
+        apply(&mut applied, read);
+        assert_eq!(applied, second);
+
+        merge(&mut delta, diff(&second, &third));
+        let mut applied = first.clone();
+        apply(&mut applied, delta);
+        assert_eq!(applied, third);
+    }
+}
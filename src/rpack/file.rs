@@ -0,0 +1,192 @@
+//! Other files of a world stored compressed on their own, like `level.dat_old` or logs.
+//!
+//! Layout: [`FileHeader`] followed by the whole file as one stream of its codec, a zstd stream or an lz4 frame,
+//! or the file itself when stored. Files are compressed and restored streaming, never held in memory.
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use anyhow::{bail, ensure, Context};
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout, LittleEndian, U32, U64};
+
+use super::Compression;
+use crate::error::ErrorCode;
+
+pub const MAGIC: [u8; 4] = *b"RPKF";
+pub const VERSION: u8 = 1;
+/// Appended to the name of stored files
+pub const EXTENSION: &str = "rpackf";
+
+/// File named like one written by [`compress`]
+pub fn is_stored(path: &Path) -> bool {
+    path.extension().is_some_and(|x| x == EXTENSION)
+}
+
+#[derive(Debug, Clone, FromBytes, IntoBytes, Immutable, KnownLayout)]
+#[repr(C)]
+pub struct FileHeader {
+    pub magic: [u8; 4],
+    pub version: u8,
+    /// [`Compression`] of the stream, never [`Compression::Auto`]
+    pub codec: u8,
+    pub reserved: [u8; 2],
+    /// Size of the original file
+    pub length: U64<LittleEndian>,
+    /// CRC32 of the original file
+    pub checksum: U32<LittleEndian>,
+    pub reserved2: U32<LittleEndian>,
+}
+
+impl FileHeader {
+    fn new(codec: Compression) -> Self {
+        Self {
+            magic: MAGIC,
+            version: VERSION,
+            codec: codec as u8,
+            reserved: [0; 2],
+            length: 0.into(),
+            checksum: 0.into(),
+            reserved2: 0.into(),
+        }
+    }
+}
+
+/// Counts and hashes bytes passing through
+struct Tally<T> {
+    inner: T,
+    hasher: crc32fast::Hasher,
+    length: u64,
+}
+
+impl<T> Tally<T> {
+    fn new(inner: T) -> Self {
+        Self { inner, hasher: Default::default(), length: 0 }
+    }
+}
+
+impl<T: Read> Read for Tally<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.length += n as u64;
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Tally<T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.length += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Compresses file at `input` into `output` with `compression`, zstd at `level`. [`Compression::Auto`] picks zstd,
+/// a single stream has nothing to choose between. Returns size of `output`
+pub fn compress(input: &Path, output: &Path, compression: Compression, level: i32) -> anyhow::Result<u64> {
+    let compression = match compression {
+        Compression::Auto => Compression::Zstd,
+        x => x,
+    };
+    let mut reader = Tally::new(File::open(input).with_context(|| format!("Unable to open {}", input.display()))?);
+    let file = File::create(output).with_context(|| format!("Unable to create {}", output.display()))?;
+    let mut writer = BufWriter::new(file);
+
+    let mut header = FileHeader::new(compression);
+    writer.write_all(header.as_bytes())?;
+    match compression {
+        Compression::None => {
+            std::io::copy(&mut reader, &mut writer)?;
+        },
+        Compression::Zstd | Compression::Auto => {
+            let mut encoder = zstd::Encoder::new(&mut writer, level)?;
+            std::io::copy(&mut reader, &mut encoder)?;
+            encoder.finish()?;
+        },
+        Compression::Lz4 => {
+            let mut encoder = lz4_flex::frame::FrameEncoder::new(&mut writer);
+            std::io::copy(&mut reader, &mut encoder)?;
+            encoder.finish()?;
+        },
+    }
+
+    // Length and checksum are known only once the file is read, the header is written again with them
+    header.length = reader.length.into();
+    header.checksum = reader.hasher.finalize().into();
+    let mut file = writer.into_inner().map_err(|e| e.into_error())?;
+    let size = file.stream_position()?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(header.as_bytes()).with_context(|| format!("Unable to write {}", output.display()))?;
+    Ok(size)
+}
+
+/// Restores file compressed by [`compress`] at `input` into `output`, checking its length and checksum
+pub fn decompress(input: &Path, output: &Path) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(File::open(input).with_context(|| format!("Unable to open {}", input.display()))?);
+    let mut header = FileHeader::new(Compression::None);
+    reader
+        .read_exact(header.as_mut_bytes())
+        .map_err(|e| ErrorCode::TruncatedArchive.wrap(e.into(), format!("{} is too short for a stored file", input.display())))?;
+    ensure!(header.magic == MAGIC, ErrorCode::NotAnArchive.error(format!("{} is not a stored file", input.display())));
+    ensure!(
+        header.version == VERSION,
+        ErrorCode::UnsupportedVersion.error(format!("Unsupported stored file version {}", header.version))
+    );
+
+    let file = File::create(output).with_context(|| format!("Unable to create {}", output.display()))?;
+    let mut writer = Tally::new(BufWriter::new(file));
+    // Reading stops at the recorded length, so data after the stream is never taken as part of the file
+    let length = header.length.get();
+    match Compression::try_from(header.codec)? {
+        Compression::None => std::io::copy(&mut reader.take(length), &mut writer)?,
+        Compression::Zstd => std::io::copy(&mut zstd::Decoder::with_buffer(reader)?.take(length), &mut writer)?,
+        Compression::Lz4 => std::io::copy(&mut lz4_flex::frame::FrameDecoder::new(reader).take(length), &mut writer)?,
+        Compression::Auto => bail!("Stored file {} names no codec", input.display()),
+    };
+    writer.flush()?;
+
+    ensure!(
+        writer.length == length,
+        ErrorCode::TruncatedArchive.error(format!("{} ends after {} of {length} bytes", input.display(), writer.length))
+    );
+    ensure!(
+        writer.hasher.finalize() == header.checksum.get(),
+        ErrorCode::ArchiveChecksum.error(format!("Checksum mismatch in {}", input.display()))
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn stored_files() {
+        let dir = TempDir::new("rpack-file");
+        let data = b"level.dat_old ".repeat(1000);
+        std::fs::write(dir.join("original"), &data).unwrap();
+
+        for compression in [Compression::None, Compression::Zstd, Compression::Lz4, Compression::Auto] {
+            let size = compress(&dir.join("original"), &dir.join("stored"), compression, 3).unwrap();
+            assert_eq!(size, std::fs::metadata(dir.join("stored")).unwrap().len());
+            decompress(&dir.join("stored"), &dir.join("restored")).unwrap();
+            assert_eq!(std::fs::read(dir.join("restored")).unwrap(), data);
+        }
+
+        // A flipped byte of a stored payload fails the checksum
+        compress(&dir.join("original"), &dir.join("stored"), Compression::None, 3).unwrap();
+        let mut stored = std::fs::read(dir.join("stored")).unwrap();
+        *stored.last_mut().unwrap() ^= 1;
+        std::fs::write(dir.join("stored"), stored).unwrap();
+        assert!(decompress(&dir.join("stored"), &dir.join("restored")).is_err());
+    }
+}
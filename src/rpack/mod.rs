@@ -0,0 +1,819 @@
+//! Archive format holding uncompressed chunk NBT of a region without sector padding.
+//!
+//! Layout: [`RpackHeader`], dictionary bytes, then chunk records terminated by a record with
+//! position [`RpackChunkHeader::END_POS`]. Every record is [`RpackChunkHeader`] followed by
+//! `stored_length` bytes of payload. In solid archives everything after the dictionary is a single
+//! zstd stream, split into frames at content-defined boundaries in rolling archives, see [`Options::rolling`].
+//! Otherwise payloads are compressed one by one and every record names its own codec, so archives can mix
+//! codecs and stored chunks. The header compression only tells what the writer was asked for.
+//! Raw archives keep chunk data as stored in the region file instead of NBT: compression type byte
+//! followed by compressed data.
+//!
+//! Delta archives hold only chunks changed since a base archive, and records with
+//! [`RpackChunkHeader::FLAG_DELETED`] for chunks removed since, see [`delta`].
+//!
+//! Archives can be concatenated: a header right after the terminating record starts the next archive,
+//! see [`RpackReader::into_inner`].
+//!
+//! Region files are turned into archives and back with [`encode_region`] and [`decode_region`].
+
+use std::io::{BufRead, BufReader, Read, Write};
+
+use anyhow::{bail, ensure, Context};
+use zerocopy::{FromZeros, IntoBytes};
+
+use crate::{error::ErrorCode, limits::Limits, scratch::Scratch};
+
+mod convert;
+pub mod delta;
+pub mod file;
+mod rolling;
+pub mod volume;
+
+pub use crate::format::{BinHeader, Compression, RpackChunk, RpackChunkHeader, RpackHeader, MAGIC, VERSION};
+pub use convert::{
+    decode_region, encode_pruned, encode_region, put_chunk, ArchiveFormat, CancellationToken, Cancelled, ChunkCallback, ChunkEvent,
+    DecodeOptions, DedupePos, EncodeOptions, Encoded, PosCheck, RatioLimits,
+};
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub compression: Compression,
+    /// Zstd compression level
+    pub level: i32,
+    /// Compress all chunks as one stream. Smaller, but chunks can not be decoded separately
+    pub solid: bool,
+    /// Store CRC32 of every payload and verify it on reading
+    pub checksums: bool,
+    /// Zstd dictionary stored in the archive and used for every chunk
+    pub dictionary: Option<Vec<u8>>,
+    /// Payloads are chunks as stored in region file, see [`RpackReader::raw`]
+    pub raw: bool,
+    /// Restart solid stream at content-defined boundaries so small changes give small binary diffs between archives
+    pub rolling: bool,
+    /// Archive holds changes since a base archive, see [`delta`]
+    pub delta: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            compression: Compression::None,
+            level: 3,
+            solid: false,
+            checksums: false,
+            dictionary: None,
+            raw: false,
+            rolling: false,
+            delta: false,
+        }
+    }
+}
+
+impl Options {
+    /// Options of undo archives read by `apply-undo`: decompressed chunks, each compressed on its own and checksummed
+    pub fn undo() -> Self {
+        Self { compression: Compression::Zstd, checksums: true, ..Default::default() }
+    }
+
+    fn header(&self) -> RpackHeader {
+        let mut flags = 0;
+        if self.solid {
+            flags |= RpackHeader::FLAG_SOLID;
+        }
+        if self.checksums {
+            flags |= RpackHeader::FLAG_CHECKSUMS;
+        }
+        if self.raw {
+            flags |= RpackHeader::FLAG_RAW;
+        }
+        if self.rolling {
+            flags |= RpackHeader::FLAG_ROLLING;
+        }
+        if self.delta {
+            flags |= RpackHeader::FLAG_DELTA;
+        }
+
+        RpackHeader {
+            magic: MAGIC,
+            version: VERSION,
+            compression: self.compression as u8,
+            flags,
+            reserved: 0,
+            dictionary_length: (self.dictionary.as_deref().unwrap_or_default().len() as u32).into(),
+        }
+    }
+}
+
+enum Sink<W: Write> {
+    Plain(W),
+    Solid(zstd::Encoder<'static, W>),
+    Rolling(rolling::Encoder<W>),
+}
+
+impl<W: Write> Write for Sink<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Sink::Plain(x) => x.write(buf),
+            Sink::Solid(x) => x.write(buf),
+            Sink::Rolling(x) => x.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Sink::Plain(x) => x.flush(),
+            Sink::Solid(x) => x.flush(),
+            Sink::Rolling(x) => x.flush(),
+        }
+    }
+}
+
+/// Writes archive chunk by chunk. Archive is incomplete until [`RpackWriter::finish`] is called.
+pub struct RpackWriter<W: Write> {
+    sink: Sink<W>,
+    /// Compression of separate payloads, [`Compression::None`] for solid archives
+    compression: Compression,
+    level: i32,
+    dictionary: Vec<u8>,
+    /// Created on first zstd payload
+    zstd: Option<zstd::bulk::Compressor<'static>>,
+    checksums: bool,
+    delta: bool,
+}
+
+impl<W: Write> RpackWriter<W> {
+    pub fn new(mut writer: W, options: Options) -> anyhow::Result<Self> {
+        let header = options.header();
+        let dictionary = options.dictionary.unwrap_or_default();
+        ensure!(
+            dictionary.is_empty() || matches!(options.compression, Compression::Zstd | Compression::Auto),
+            "Dictionary requires zstd compression"
+        );
+        ensure!(
+            !options.solid || matches!(options.compression, Compression::None | Compression::Zstd),
+            "Solid archive supports only zstd compression"
+        );
+        ensure!(
+            !options.rolling || (options.solid && options.compression == Compression::Zstd),
+            "Rolling layout requires solid zstd archive"
+        );
+        ensure!(dictionary.len() <= u32::MAX as usize, "Dictionary is too large");
+
+        writer.write_all(header.as_bytes())?;
+        writer.write_all(&dictionary)?;
+
+        if options.solid && options.compression == Compression::Zstd {
+            let sink = match options.rolling {
+                true => Sink::Rolling(rolling::Encoder::new(writer, options.level, dictionary)),
+                false => Sink::Solid(zstd::Encoder::with_dictionary(writer, options.level, &dictionary)?),
+            };
+            return Ok(Self {
+                sink,
+                compression: Compression::None,
+                level: options.level,
+                dictionary: vec![],
+                zstd: None,
+                checksums: options.checksums,
+                delta: options.delta,
+            });
+        }
+
+        Ok(Self {
+            sink: Sink::Plain(writer),
+            compression: options.compression,
+            level: options.level,
+            dictionary,
+            zstd: None,
+            checksums: options.checksums,
+            delta: options.delta,
+        })
+    }
+
+    /// Appends uncompressed chunk NBT, or chunk data as stored in region file for raw archives. Positions are not checked for duplicates
+    pub fn write_chunk(&mut self, pos: u16, timestamp: u32, payload: &[u8]) -> anyhow::Result<()> {
+        self.write_chunk_with(pos, timestamp, payload, self.compression)
+    }
+
+    /// Like [`RpackWriter::write_chunk`], but compresses this payload with `codec` instead of the archive compression.
+    /// Solid archives take only [`Compression::None`]
+    pub fn write_chunk_with(&mut self, pos: u16, timestamp: u32, payload: &[u8], codec: Compression) -> anyhow::Result<()> {
+        ensure!(pos < RpackChunkHeader::END_POS, "Chunk position {pos} is reserved");
+        ensure!(
+            codec == Compression::None || matches!(self.sink, Sink::Plain(_)),
+            "Chunks of solid archive can not be compressed separately"
+        );
+
+        let (mut codec, mut compressed) = match codec {
+            Compression::Auto => self.compress_auto(payload)?,
+            codec => (codec, self.compress(codec, payload)?),
+        };
+
+        let mut flags = 0;
+        if compressed.as_ref().is_some_and(|x| x.len() >= payload.len()) {
+            compressed = None;
+            codec = Compression::None;
+            flags |= RpackChunkHeader::FLAG_STORED;
+        }
+        let stored = compressed.as_deref().unwrap_or(payload);
+
+        let header = RpackChunkHeader {
+            pos: pos.into(),
+            codec: codec as u8,
+            flags,
+            timestamp: timestamp.into(),
+            length: (payload.len() as u64).into(),
+            stored_length: (stored.len() as u64).into(),
+            checksum: if self.checksums { crc32fast::hash(payload) } else { 0 }.into(),
+        };
+
+        self.sink.write_all(header.as_bytes())?;
+        self.sink.write_all(stored)?;
+        Ok(())
+    }
+
+    /// Records removal of chunk at `pos` from the base archive. Only delta archives can hold removals
+    pub fn write_deletion(&mut self, pos: u16) -> anyhow::Result<()> {
+        ensure!(self.delta, "Only delta archives can remove chunks");
+        ensure!(pos < RpackChunkHeader::END_POS, "Chunk position {pos} is reserved");
+
+        let mut header = RpackChunkHeader::new_zeroed();
+        header.pos = pos.into();
+        header.flags = RpackChunkHeader::FLAG_DELETED;
+        self.sink.write_all(header.as_bytes())?;
+        Ok(())
+    }
+
+    /// Returns `None` for [`Compression::None`]
+    fn compress(&mut self, codec: Compression, payload: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(match codec {
+            Compression::None => None,
+            Compression::Zstd => {
+                if self.zstd.is_none() {
+                    self.zstd = Some(zstd::bulk::Compressor::with_dictionary(self.level, &self.dictionary)?);
+                }
+                Some(self.zstd.as_mut().context("Zstd compressor is missing")?.compress(payload)?)
+            },
+            Compression::Lz4 => Some(lz4_flex::block::compress(payload)),
+            Compression::Auto => bail!("Auto is not a codec"),
+        })
+    }
+
+    /// Picks codec by compressing a sample of the chunk with every codec. Payload is stored as is
+    /// if no codec makes it smaller
+    fn compress_auto(&mut self, payload: &[u8]) -> anyhow::Result<(Compression, Option<Vec<u8>>)> {
+        let sample = &payload[..payload.len().min(Compression::AUTO_SAMPLE_SIZE)];
+        let mut best = (Compression::None, sample.len(), None);
+
+        for codec in [Compression::Zstd, Compression::Lz4] {
+            let compressed = self.compress(codec, sample)?.unwrap_or_default();
+            if compressed.len() < best.1 {
+                best = (codec, compressed.len(), Some(compressed));
+            }
+        }
+
+        let (codec, _, compressed) = best;
+        if sample.len() == payload.len() {
+            return Ok((codec, compressed));
+        }
+        Ok((codec, self.compress(codec, payload)?))
+    }
+
+    /// Writes terminating record and returns the underlying writer
+    pub fn finish(mut self) -> anyhow::Result<W> {
+        let mut end = RpackChunkHeader::new_zeroed();
+        end.pos = RpackChunkHeader::END_POS.into();
+        self.sink.write_all(end.as_bytes())?;
+
+        let mut writer = match self.sink {
+            Sink::Plain(x) => x,
+            Sink::Solid(x) => x.finish()?,
+            Sink::Rolling(x) => x.finish()?,
+        };
+        writer.flush()?;
+        Ok(writer)
+    }
+}
+
+enum Source<R: BufRead> {
+    Plain(R),
+    Solid(zstd::Decoder<'static, R>),
+    Rolling(rolling::Decoder<R>),
+}
+
+impl<R: BufRead> Read for Source<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Source::Plain(x) => x.read(buf),
+            Source::Solid(x) => x.read(buf),
+            Source::Rolling(x) => x.read(buf),
+        }
+    }
+}
+
+/// Record read by [`RpackReader::read_record`] with payload not decoded yet
+#[derive(Debug, Clone, Copy)]
+pub struct RpackRecord {
+    pub chunk: RpackChunk,
+    codec: Compression,
+    length: u64,
+    checksum: Option<u32>,
+}
+
+/// Decodes payloads of records read by [`RpackReader::read_record`]. Every thread needs its own
+pub struct RecordDecoder {
+    dictionary: Vec<u8>,
+    /// Created on first zstd payload
+    zstd: Option<zstd::bulk::Decompressor<'static>>,
+}
+
+impl RecordDecoder {
+    /// Returns uncompressed NBT of record, decompressed into `payload` unless stored uncompressed.
+    /// Length and checksum are verified
+    pub fn decode<'a>(&mut self, record: &RpackRecord, stored: &'a [u8], payload: &'a mut Vec<u8>) -> anyhow::Result<&'a [u8]> {
+        let pos = record.chunk.pos;
+        let data = match record.codec {
+            Compression::None | Compression::Auto => stored,
+            codec => {
+                decode_payload(&mut self.zstd, &self.dictionary, codec, pos, record.length, stored, payload)?;
+                &payload[..]
+            },
+        };
+        verify_payload(pos, data, record.length, record.checksum)?;
+        Ok(data)
+    }
+}
+
+/// Replaces contents of `payload` with decompressed `stored` payload of chunk at `pos`
+fn decode_payload(
+    zstd: &mut Option<zstd::bulk::Decompressor<'static>>,
+    dictionary: &[u8],
+    codec: Compression,
+    pos: u16,
+    length: u64,
+    stored: &[u8],
+    payload: &mut Vec<u8>,
+) -> anyhow::Result<()> {
+    payload.clear();
+    match codec {
+        Compression::None | Compression::Auto => payload.extend_from_slice(stored),
+        Compression::Zstd => {
+            if zstd.is_none() {
+                *zstd = Some(zstd::bulk::Decompressor::with_dictionary(dictionary)?);
+            }
+            payload.reserve(length as usize);
+            zstd.as_mut()
+                .context("Zstd decompressor is missing")?
+                .decompress_to_buffer(stored, payload)
+                .with_context(|| format!("Unable to decompress chunk at position {pos}"))?;
+        },
+        Compression::Lz4 => {
+            payload.resize(length as usize, 0);
+            let written = lz4_flex::block::decompress_into(stored, payload)
+                .with_context(|| format!("Unable to decompress chunk at position {pos}"))?;
+            payload.truncate(written);
+        },
+    }
+    Ok(())
+}
+
+/// Checks length and, if archive has checksums, CRC32 of uncompressed payload
+fn verify_payload(pos: u16, payload: &[u8], length: u64, checksum: Option<u32>) -> anyhow::Result<()> {
+    ensure!(
+        payload.len() as u64 == length,
+        "Chunk at position {pos} has length {} instead of {length}",
+        payload.len()
+    );
+    if let Some(checksum) = checksum {
+        ensure!(crc32fast::hash(payload) == checksum, ErrorCode::ArchiveChecksum.error(format!("Checksum mismatch for chunk at position {pos}")));
+    }
+    Ok(())
+}
+
+/// Counts and hashes payload read through it
+struct Verified<R> {
+    inner: R,
+    hasher: crc32fast::Hasher,
+    length: u64,
+}
+
+impl<R: Read> Read for Verified<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.length += n as u64;
+        Ok(n)
+    }
+}
+
+pub struct RpackReader<R: BufRead> {
+    source: Source<R>,
+    compression: Compression,
+    dictionary: Vec<u8>,
+    /// Created on first zstd payload
+    zstd: Option<zstd::bulk::Decompressor<'static>>,
+    /// Created on first zstd payload read as a stream
+    zstd_dictionary: Option<zstd::dict::DecoderDictionary<'static>>,
+    checksums: bool,
+    raw: bool,
+    delta: bool,
+    /// Records are checked against it
+    header: RpackHeader,
+    limits: Limits,
+    stored: Scratch,
+    finished: bool,
+}
+
+impl<R: Read> RpackReader<BufReader<R>> {
+    pub fn new(reader: R) -> anyhow::Result<Self> {
+        Self::from_buf_reader(BufReader::new(reader), Limits::default())
+    }
+}
+
+impl<R: BufRead> RpackReader<R> {
+    pub fn from_buf_reader(mut reader: R, limits: Limits) -> anyhow::Result<Self> {
+        let mut header = RpackHeader::new_zeroed();
+        reader.read_exact(header.as_mut_bytes()).context("Unable to read archive header")?;
+        let compression = header.check(&limits)?;
+
+        let dictionary_length = header.dictionary_length.get() as u64;
+        let mut dictionary = vec![];
+        reader.by_ref().take(dictionary_length).read_to_end(&mut dictionary)?;
+        ensure!(dictionary.len() as u64 == dictionary_length, ErrorCode::TruncatedArchive.error("Archive is truncated inside dictionary"));
+
+        let solid = header.flags & RpackHeader::FLAG_SOLID != 0;
+        let rolling = header.flags & RpackHeader::FLAG_ROLLING != 0;
+        let source = match (compression, solid) {
+            (Compression::Zstd, true) if rolling => Source::Rolling(rolling::Decoder::new(reader, dictionary.clone())?),
+            // Stop at the end of frame, a concatenated archive may follow
+            (Compression::Zstd, true) => Source::Solid(zstd::Decoder::with_dictionary(reader, &dictionary)?.single_frame()),
+            _ => Source::Plain(reader),
+        };
+
+        Ok(Self {
+            source,
+            compression,
+            dictionary,
+            zstd: None,
+            zstd_dictionary: None,
+            checksums: header.flags & RpackHeader::FLAG_CHECKSUMS != 0,
+            raw: header.flags & RpackHeader::FLAG_RAW != 0,
+            delta: header.flags & RpackHeader::FLAG_DELTA != 0,
+            header,
+            limits,
+            stored: Scratch::take(),
+            finished: false,
+        })
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Payloads are chunk data as stored in region file: compression type byte followed by compressed data
+    pub fn raw(&self) -> bool {
+        self.raw
+    }
+
+    /// Archive holds changes since a base archive, see [`delta`]
+    pub fn delta(&self) -> bool {
+        self.delta
+    }
+
+    /// Options the archive was written with, as far as its header tells. The level is not recorded
+    pub fn options(&self) -> Options {
+        Options {
+            compression: self.compression,
+            level: Options::default().level,
+            solid: matches!(self.source, Source::Solid(_) | Source::Rolling(_)),
+            checksums: self.checksums,
+            dictionary: (!self.dictionary.is_empty()).then(|| self.dictionary.clone()),
+            raw: self.raw,
+            rolling: matches!(self.source, Source::Rolling(_)),
+            delta: self.delta,
+        }
+    }
+
+    /// Reads next record header and resolves codec of its payload. Returns `None` after the terminating record
+    fn next_record(&mut self) -> anyhow::Result<Option<(RpackChunkHeader, Compression)>> {
+        if self.finished {
+            return Ok(None);
+        }
+
+        let mut header = RpackChunkHeader::new_zeroed();
+        self.source
+            .read_exact(header.as_mut_bytes())
+            .map_err(|e| ErrorCode::TruncatedArchive.wrap(e.into(), "Archive is truncated: terminating record is missing"))?;
+
+        if header.pos.get() == RpackChunkHeader::END_POS {
+            self.finished = true;
+            return Ok(None);
+        }
+        let codec = header.check(&self.header, &self.limits)?;
+        Ok(Some((header, codec)))
+    }
+
+    /// Reads next chunk replacing contents of `payload` with its uncompressed NBT.
+    /// Returns `None` after the terminating record
+    pub fn read_chunk(&mut self, payload: &mut Vec<u8>) -> anyhow::Result<Option<RpackChunk>> {
+        let Some((header, codec)) = self.next_record()? else {
+            return Ok(None);
+        };
+        let chunk = RpackChunk::from_header(&header);
+        let (pos, length, stored_length) = (chunk.pos, header.length.get(), header.stored_length.get());
+        if chunk.deleted {
+            payload.clear();
+            return Ok(Some(chunk));
+        }
+
+        let target = if codec == Compression::None { &mut *payload } else { &mut *self.stored };
+        target.clear();
+        let copied = (&mut self.source).take(stored_length).read_to_end(target)?;
+        ensure!(copied as u64 == stored_length, ErrorCode::TruncatedArchive.error(format!("Archive is truncated inside chunk at position {pos}")));
+
+        if codec != Compression::None {
+            // Decompressed into the buffer of caller, so it is reused from chunk to chunk
+            decode_payload(&mut self.zstd, &self.dictionary, codec, pos, length, &self.stored, payload)?;
+        }
+        verify_payload(pos, payload, length, self.checksums.then(|| header.checksum.get()))?;
+
+        Ok(Some(chunk))
+    }
+
+    /// Reads next record replacing contents of `stored` with its payload as stored in archive, so it can be
+    /// decoded on another thread by [`RecordDecoder`]. Returns `None` after the terminating record
+    pub fn read_record(&mut self, stored: &mut Vec<u8>) -> anyhow::Result<Option<RpackRecord>> {
+        let Some((header, codec)) = self.next_record()? else {
+            return Ok(None);
+        };
+        let chunk = RpackChunk::from_header(&header);
+        let stored_length = header.stored_length.get();
+
+        stored.clear();
+        let copied = (&mut self.source).take(stored_length).read_to_end(stored)?;
+        ensure!(copied as u64 == stored_length, ErrorCode::TruncatedArchive.error(format!("Archive is truncated inside chunk at position {}", chunk.pos)));
+
+        Ok(Some(RpackRecord {
+            chunk,
+            codec,
+            length: header.length.get(),
+            checksum: (self.checksums && !chunk.deleted).then(|| header.checksum.get()),
+        }))
+    }
+
+    /// Decoder of records of this archive
+    pub fn decoder(&self) -> RecordDecoder {
+        RecordDecoder {
+            dictionary: self.dictionary.clone(),
+            zstd: None,
+        }
+    }
+
+    /// Like [`RpackReader::read_chunk`], but hands uncompressed NBT to `f` as a stream, so it can be compressed
+    /// again without holding the whole chunk in memory. Only lz4 payloads are decompressed into a buffer first.
+    /// Payload `f` leaves unread is skipped, length and checksum are verified after `f` returns
+    pub fn read_chunk_streaming<T>(
+        &mut self,
+        f: impl FnOnce(RpackChunk, &mut dyn Read) -> anyhow::Result<T>,
+    ) -> anyhow::Result<Option<T>> {
+        let Some((header, codec)) = self.next_record()? else {
+            return Ok(None);
+        };
+        let chunk = RpackChunk::from_header(&header);
+        let (pos, length, stored_length) = (chunk.pos, header.length.get(), header.stored_length.get());
+
+        let mut stored = (&mut self.source).take(stored_length);
+        let (result, actual, checksum) = {
+            let decoded;
+            let payload: Box<dyn Read + '_> = match codec {
+                Compression::None | Compression::Auto => Box::new(&mut stored),
+                Compression::Zstd => {
+                    if self.zstd_dictionary.is_none() {
+                        self.zstd_dictionary = Some(zstd::dict::DecoderDictionary::copy(&self.dictionary));
+                    }
+                    let dictionary = self.zstd_dictionary.as_ref().context("Zstd dictionary is missing")?;
+                    let decoder = zstd::stream::read::Decoder::with_prepared_dictionary(BufReader::new(&mut stored), dictionary)?;
+                    Box::new(decoder.single_frame())
+                },
+                Compression::Lz4 => {
+                    self.stored.clear();
+                    stored.read_to_end(&mut self.stored)?;
+                    decoded = lz4_flex::block::decompress(&self.stored, length as usize)
+                        .with_context(|| format!("Unable to decompress chunk at position {pos}"))?;
+                    Box::new(&decoded[..])
+                },
+            };
+
+            // One byte over the length is enough to tell the chunk is longer
+            let mut payload = Verified {
+                inner: payload.take(length + 1),
+                hasher: crc32fast::Hasher::new(),
+                length: 0,
+            };
+            let result = f(chunk, &mut payload).with_context(|| format!("Chunk at position {pos}"))?;
+            std::io::copy(&mut payload, &mut std::io::sink())
+                .with_context(|| format!("Unable to decompress chunk at position {pos}"))?;
+            (result, payload.length, payload.hasher.finalize())
+        };
+        std::io::copy(&mut stored, &mut std::io::sink())?;
+        ensure!(stored.limit() == 0, ErrorCode::TruncatedArchive.error(format!("Archive is truncated inside chunk at position {pos}")));
+
+        ensure!(actual == length, "Chunk at position {pos} has length {actual} instead of {length}");
+        if self.checksums && !chunk.deleted {
+            ensure!(checksum == header.checksum.get(), ErrorCode::ArchiveChecksum.error(format!("Checksum mismatch for chunk at position {pos}")));
+        }
+        Ok(Some(result))
+    }
+
+    /// Returns the underlying reader positioned right after the archive, where a concatenated archive may start.
+    /// All chunks must be read first
+    pub fn into_inner(self) -> anyhow::Result<R> {
+        ensure!(self.finished, "Archive is not read to the end");
+        match self.source {
+            Source::Plain(x) => Ok(x),
+            Source::Solid(mut x) => {
+                let mut rest = [0];
+                ensure!(x.read(&mut rest)? == 0, "Unexpected data after terminating record");
+                Ok(x.finish())
+            },
+            Source::Rolling(x) => x.into_inner(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, Read};
+
+    use super::{Compression, Options, RpackChunkHeader, RpackHeader, RpackReader, RpackWriter};
+
+    #[test]
+    fn round_trip_all_options() {
+        let mut chunks = (0..20u16).map(|x| (x * 7, x as u32, vec![x as u8; x as usize * 100])).collect::<Vec<_>>();
+        // Incompressible chunk is stored as is
+        let mut state = 1u32;
+        let noise = (0..5000).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        });
+        chunks.push((1000, 0, noise.collect()));
+
+        for compression in [Compression::None, Compression::Zstd, Compression::Lz4, Compression::Auto] {
+            for solid in [false, true] {
+                if solid && matches!(compression, Compression::Lz4 | Compression::Auto) {
+                    assert!(RpackWriter::new(vec![], Options { compression, solid, ..Default::default() }).is_err());
+                    continue;
+                }
+
+                for (checksums, rolling) in [(false, false), (true, false), (true, true)] {
+                    if rolling && !(solid && compression == Compression::Zstd) {
+                        assert!(RpackWriter::new(vec![], Options { compression, solid, rolling, ..Default::default() }).is_err());
+                        continue;
+                    }
+
+                    let dictionary = matches!(compression, Compression::Zstd | Compression::Auto).then(|| vec![7; 64]);
+                    let options = Options { compression, level: 3, solid, checksums, dictionary, raw: false, rolling, delta: false };
+
+                    let mut writer = RpackWriter::new(vec![], options.clone()).unwrap();
+                    for (pos, timestamp, payload) in &chunks {
+                        writer.write_chunk(*pos, *timestamp, payload).unwrap();
+                    }
+                    let archive = writer.finish().unwrap();
+
+                    let mut reader = RpackReader::new(&archive[..]).unwrap();
+                    let mut read = vec![];
+                    let mut payload = vec![];
+                    while let Some(chunk) = reader.read_chunk(&mut payload).unwrap() {
+                        read.push((chunk.pos, chunk.timestamp, payload.clone()));
+                    }
+                    assert_eq!(read, chunks, "{options:?}");
+
+                    if !solid {
+                        // No payload grows
+                        let header_size = size_of::<RpackHeader>() + options.dictionary.as_ref().map_or(0, Vec::len);
+                        let records = chunks.iter().map(|x| size_of::<RpackChunkHeader>() + x.2.len()).sum::<usize>();
+                        assert!(archive.len() <= header_size + records + size_of::<RpackChunkHeader>());
+                    }
+
+                    // Concatenated copy is read after the first one
+                    let twice = [&archive[..], &archive[..]].concat();
+                    let mut reader = RpackReader::new(&twice[..]).unwrap();
+                    while reader.read_chunk(&mut payload).unwrap().is_some() {}
+                    let mut reader = RpackReader::from_buf_reader(reader.into_inner().unwrap(), Default::default()).unwrap();
+                    let mut count = 0;
+                    while reader.read_chunk(&mut payload).unwrap().is_some() {
+                        count += 1;
+                    }
+                    assert_eq!(count, chunks.len());
+                    assert!(reader.into_inner().unwrap().fill_buf().unwrap().is_empty());
+
+                    // Without terminating record archive is reported as truncated
+                    let mut reader = RpackReader::new(&archive[..archive.len() - 1]).unwrap();
+                    assert!(std::iter::from_fn(|| reader.read_chunk(&mut payload).transpose()).any(|x| x.is_err()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn codec_per_chunk() {
+        let chunks = [(0, Compression::None), (1, Compression::Zstd), (2, Compression::Lz4), (3, Compression::Auto)];
+        let options = Options { compression: Compression::Zstd, ..Default::default() };
+        let mut writer = RpackWriter::new(vec![], options.clone()).unwrap();
+        for (pos, codec) in chunks {
+            writer.write_chunk_with(pos, 0, &[pos as u8; 1000], codec).unwrap();
+        }
+        let archive = writer.finish().unwrap();
+
+        let mut reader = RpackReader::new(&archive[..]).unwrap();
+        let mut payload = vec![];
+        for (pos, _) in chunks {
+            assert_eq!(reader.read_chunk(&mut payload).unwrap().unwrap().pos, pos);
+            assert_eq!(payload, [pos as u8; 1000]);
+        }
+        assert!(reader.read_chunk(&mut payload).unwrap().is_none());
+
+        // Version 1 records of archives without Compression::Auto have no codec
+        let mut writer = RpackWriter::new(vec![], options).unwrap();
+        writer.write_chunk(5, 0, &[5; 1000]).unwrap();
+        let mut archive = writer.finish().unwrap();
+        archive[4] = 1;
+        archive[size_of::<RpackHeader>() + 2] = 0;
+        let mut reader = RpackReader::new(&archive[..]).unwrap();
+        assert_eq!(reader.read_chunk(&mut payload).unwrap().unwrap().pos, 5);
+        assert_eq!(payload, [5; 1000]);
+
+        let solid = Options { compression: Compression::Zstd, solid: true, ..Default::default() };
+        let mut writer = RpackWriter::new(vec![], solid).unwrap();
+        assert!(writer.write_chunk_with(0, 0, &[0; 1000], Compression::Lz4).is_err());
+    }
+
+    #[test]
+    fn streaming_matches_buffered() {
+        for (compression, solid) in [(Compression::None, false), (Compression::Auto, false), (Compression::Lz4, false), (Compression::Zstd, true)] {
+            let options = Options { compression, solid, checksums: true, dictionary: None, ..Default::default() };
+            let mut writer = RpackWriter::new(vec![], options).unwrap();
+            for pos in 0..10u16 {
+                writer.write_chunk(pos, pos.into(), &vec![pos as u8; pos as usize * 1000]).unwrap();
+            }
+            let mut archive = writer.finish().unwrap();
+
+            let mut reader = RpackReader::new(&archive[..]).unwrap();
+            let mut read = vec![];
+            // Payload left unread is skipped
+            while let Some(chunk) = reader.read_chunk_streaming(|chunk, payload| {
+                let mut data = vec![];
+                payload.take(500).read_to_end(&mut data)?;
+                Ok((chunk.pos, data))
+            })
+            .unwrap()
+            {
+                read.push(chunk);
+            }
+            assert_eq!(read.len(), 10, "{compression:?}");
+            assert!(read.iter().all(|(pos, data)| data.len() == (*pos as usize * 1000).min(500)));
+
+            if !solid {
+                // Damaged last payload byte of the last chunk
+                let end = archive.len() - size_of::<RpackChunkHeader>() - 1;
+                archive[end] ^= 1;
+                let mut reader = RpackReader::new(&archive[..]).unwrap();
+                let result = std::iter::from_fn(|| reader.read_chunk_streaming(|_, payload| Ok(std::io::copy(payload, &mut std::io::sink())?)).transpose());
+                assert!(result.last().unwrap().is_err(), "{compression:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn records_decoded_on_other_thread() {
+        let options = Options { compression: Compression::Auto, checksums: true, ..Default::default() };
+        let mut writer = RpackWriter::new(vec![], options).unwrap();
+        for pos in 0..10u16 {
+            writer.write_chunk(pos, 0, &vec![pos as u8; pos as usize * 1000]).unwrap();
+        }
+        let archive = writer.finish().unwrap();
+
+        let mut reader = RpackReader::new(&archive[..]).unwrap();
+        let mut records = vec![];
+        let mut stored = vec![];
+        while let Some(record) = reader.read_record(&mut stored).unwrap() {
+            records.push((record, std::mem::take(&mut stored)));
+        }
+        let mut decoder = reader.decoder();
+        std::thread::spawn(move || {
+            let mut payload = vec![];
+            for (record, stored) in records.iter() {
+                let pos = record.chunk.pos;
+                assert_eq!(decoder.decode(record, stored, &mut payload).unwrap(), vec![pos as u8; pos as usize * 1000]);
+            }
+            // Checksum is verified by decoder
+            let (record, stored) = records.last_mut().unwrap();
+            *stored.last_mut().unwrap() ^= 1;
+            assert!(decoder.decode(record, stored, &mut payload).is_err());
+        })
+        .join()
+        .unwrap();
+    }
+}
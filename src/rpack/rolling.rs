@@ -0,0 +1,230 @@
+//! Solid zstd stream split into independent frames at content-defined boundaries.
+//!
+//! Boundaries are picked by gear hash of the uncompressed stream, so they depend only on nearby bytes:
+//! a changed chunk changes the frames around it, and every other frame stays byte-identical.
+//! That keeps binary diffs of archives small for rsync and deduplicating backup tools.
+
+use std::io::{BufRead, Read, Write};
+
+/// Frames are never shorter, except the last one
+pub const MIN_FRAME: usize = 16 * 1024;
+/// Frames are never longer
+pub const MAX_FRAME: usize = 256 * 1024;
+/// Boundary is placed where these hash bits are zero, about every 64 KiB past [`MIN_FRAME`]
+const BOUNDARY_MASK: u64 = 0xffff << 48;
+
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64, fixed seed keeps boundaries stable between runs and versions
+    let mut table = [0; 256];
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Finds frame boundaries in a stream fed piece by piece
+#[derive(Debug, Default)]
+struct Chunker {
+    hash: u64,
+    length: usize,
+}
+
+impl Chunker {
+    /// Returns length of the prefix of `data` ending the current frame, if it ends inside `data`
+    fn next_boundary(&mut self, data: &[u8]) -> Option<usize> {
+        for (i, byte) in data.iter().enumerate() {
+            self.hash = (self.hash << 1).wrapping_add(GEAR[*byte as usize]);
+            self.length += 1;
+            if self.length >= MAX_FRAME || (self.length >= MIN_FRAME && self.hash & BOUNDARY_MASK == 0) {
+                *self = Self::default();
+                return Some(i + 1);
+            }
+        }
+        None
+    }
+}
+
+enum Frame<W: Write> {
+    Open(zstd::Encoder<'static, W>),
+    /// Next frame is started by the next write, so no empty frame ends the stream
+    Closed(W),
+}
+
+pub struct Encoder<W: Write> {
+    frame: Option<Frame<W>>,
+    dictionary: Vec<u8>,
+    level: i32,
+    chunker: Chunker,
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(writer: W, level: i32, dictionary: Vec<u8>) -> Self {
+        Self {
+            frame: Some(Frame::Closed(writer)),
+            dictionary,
+            level,
+            chunker: Chunker::default(),
+        }
+    }
+
+    fn encoder(&mut self) -> std::io::Result<&mut zstd::Encoder<'static, W>> {
+        if let Some(Frame::Closed(writer)) = self.frame.take_if(|x| matches!(x, Frame::Closed(_))) {
+            self.frame = Some(Frame::Open(zstd::Encoder::with_dictionary(writer, self.level, &self.dictionary)?));
+        }
+        match &mut self.frame {
+            Some(Frame::Open(x)) => Ok(x),
+            _ => Err(std::io::Error::other("Previous frame failed to finish")),
+        }
+    }
+
+    fn end_frame(&mut self) -> std::io::Result<()> {
+        if let Some(Frame::Open(encoder)) = self.frame.take_if(|x| matches!(x, Frame::Open(_))) {
+            self.frame = Some(Frame::Closed(encoder.finish()?));
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.end_frame()?;
+        match self.frame {
+            Some(Frame::Closed(x)) => Ok(x),
+            _ => Err(std::io::Error::other("Previous frame failed to finish")),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        match self.chunker.next_boundary(buf) {
+            Some(length) => {
+                self.encoder()?.write_all(&buf[..length])?;
+                self.end_frame()?;
+                Ok(length)
+            },
+            None => {
+                self.encoder()?.write_all(buf)?;
+                Ok(buf.len())
+            },
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.frame {
+            Some(Frame::Open(x)) => x.flush(),
+            Some(Frame::Closed(x)) => x.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+enum Input<R: BufRead> {
+    Frame(zstd::Decoder<'static, R>),
+    /// Input ended right after a frame
+    End(R),
+}
+
+/// Reads frames back to back until the end of input. The caller stops at the terminating record,
+/// which the encoder always follows with the end of a frame
+pub struct Decoder<R: BufRead> {
+    input: Option<Input<R>>,
+    dictionary: Vec<u8>,
+}
+
+impl<R: BufRead> Decoder<R> {
+    pub fn new(reader: R, dictionary: Vec<u8>) -> std::io::Result<Self> {
+        Ok(Self {
+            input: Some(Input::Frame(zstd::Decoder::with_dictionary(reader, &dictionary)?.single_frame())),
+            dictionary,
+        })
+    }
+
+    /// Underlying reader positioned after the current frame. Fails if the frame has data left
+    pub fn into_inner(self) -> anyhow::Result<R> {
+        match self.input {
+            Some(Input::Frame(mut x)) => {
+                let mut rest = [0];
+                anyhow::ensure!(x.read(&mut rest)? == 0, "Unexpected data after terminating record");
+                Ok(x.finish())
+            },
+            Some(Input::End(x)) => Ok(x),
+            None => anyhow::bail!("Previous frame failed to start"),
+        }
+    }
+}
+
+impl<R: BufRead> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let decoder = match &mut self.input {
+                Some(Input::Frame(x)) => x,
+                Some(Input::End(_)) => return Ok(0),
+                None => return Err(std::io::Error::other("Previous frame failed to start")),
+            };
+            let read = decoder.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+
+            // Frame ended, the next one continues the stream
+            let Some(Input::Frame(decoder)) = self.input.take() else { unreachable!() };
+            let mut reader = decoder.finish();
+            if reader.fill_buf()?.is_empty() {
+                self.input = Some(Input::End(reader));
+                return Ok(0);
+            }
+            self.input = Some(Input::Frame(zstd::Decoder::with_dictionary(reader, &self.dictionary)?.single_frame()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(data: &[u8]) -> Vec<u8> {
+        let mut encoder = Encoder::new(vec![], 3, vec![]);
+        // Uneven pieces, boundaries must not depend on them
+        for piece in data.chunks(1000) {
+            encoder.write_all(piece).unwrap();
+        }
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn local_change_keeps_other_frames() {
+        let mut state = 1u32;
+        let data = (0..1 << 20)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                // Compressible but not repetitive
+                b"abcdefgh"[state as usize % 8]
+            })
+            .collect::<Vec<_>>();
+        let mut changed = data.clone();
+        changed[data.len() / 2] ^= 1;
+
+        let (a, b) = (encode(&data), encode(&changed));
+        let prefix = a.iter().zip(&b).take_while(|(x, y)| x == y).count();
+        let suffix = a.iter().rev().zip(b.iter().rev()).take_while(|(x, y)| x == y).count();
+        assert!(prefix + suffix > a.len() * 3 / 4, "{prefix} + {suffix} of {}", a.len());
+
+        let mut decoded = vec![];
+        Decoder::new(&a[..], vec![]).unwrap().read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, data);
+    }
+}
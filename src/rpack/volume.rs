@@ -0,0 +1,255 @@
+//! Archives split into volumes `<name>.001`, `<name>.002`, ... of a fixed maximum size.
+//! Volumes are consecutive byte ranges of a single archive, so reading them back to back gives the archive.
+//! They are written as `<name>.001.partial`, ... and get their names only once all are written, see [`commit`].
+
+use std::{
+    fs::File,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+/// Path of volume `index`, counting from 1
+pub fn volume_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{index:03}"));
+    PathBuf::from(name)
+}
+
+/// Existing volumes of archive in order
+pub fn volume_paths(path: &Path) -> Vec<PathBuf> {
+    (1..).map(|x| volume_path(path, x)).take_while(|x| x.exists()).collect()
+}
+
+/// Path of volume `index` while the archive is written
+fn partial_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = volume_path(path, index).into_os_string();
+    name.push(".partial");
+    PathBuf::from(name)
+}
+
+/// Volumes of archive at `path` still being written, in order
+pub fn partial_paths(path: &Path) -> Vec<PathBuf> {
+    (1..).map(|x| partial_path(path, x)).take_while(|x| x.exists()).collect()
+}
+
+/// Gives volumes written by [`VolumeWriter`] their names, so an archive is never found mixing volumes of the new
+/// and the previous one: the previous first volume is moved aside as `<name>.001.old`, its volumes beyond the new ones
+/// are removed, the new ones are named and the new first volume comes last. After a crash in between no archive is
+/// found under the name until the next run commits again. With `sync` contents of volumes reach stable storage
+/// before. Returns volumes named
+pub fn commit(path: &Path, sync: bool) -> anyhow::Result<usize> {
+    let partial = partial_paths(path);
+    if sync {
+        for volume in &partial {
+            File::open(volume)
+                .and_then(|x| x.sync_all())
+                .with_context(|| format!("Unable to sync {}", volume.display()))?;
+        }
+    }
+    commit_steps(path, partial.len()).iter().try_for_each(commit_step)?;
+    Ok(partial.len())
+}
+
+/// Path the previous first volume is moved to by [`commit`]
+fn old_path(path: &Path) -> PathBuf {
+    let mut name = volume_path(path, 1).into_os_string();
+    name.push(".old");
+    PathBuf::from(name)
+}
+
+/// Renames of [`commit`] in order, files without new name are removed
+fn commit_steps(path: &Path, count: usize) -> Vec<(PathBuf, Option<PathBuf>)> {
+    let (first, old) = (volume_path(path, 1), old_path(path));
+    let mut steps = vec![];
+    let moved = first.exists();
+    if moved {
+        steps.push((first.clone(), Some(old.clone())));
+    }
+    let stale = (count + 1..).map(|x| volume_path(path, x)).take_while(|x| x.exists());
+    steps.extend(stale.map(|x| (x, None)));
+    steps.extend((2..=count).map(|x| (partial_path(path, x), Some(volume_path(path, x)))));
+    if count > 0 {
+        steps.push((partial_path(path, 1), Some(first)));
+    }
+    if moved || old.exists() {
+        steps.push((old, None));
+    }
+    steps
+}
+
+fn commit_step((from, to): &(PathBuf, Option<PathBuf>)) -> anyhow::Result<()> {
+    match to {
+        Some(to) => std::fs::rename(from, to).with_context(|| format!("Unable to rename {} to {}", from.display(), to.display())),
+        None => std::fs::remove_file(from).with_context(|| format!("Unable to remove {}", from.display())),
+    }
+}
+
+/// Removes volumes of archive at `path` still being written. Named volumes, of the previous archive until
+/// [`commit`], are kept
+pub fn discard(path: &Path) -> anyhow::Result<()> {
+    for volume in partial_paths(path) {
+        std::fs::remove_file(&volume).with_context(|| format!("Unable to remove {}", volume.display()))?;
+    }
+    Ok(())
+}
+
+/// Archive path without volume suffix if `path` is the first volume
+pub fn first_volume_base(path: &Path) -> Option<PathBuf> {
+    let name = path.to_str()?.strip_suffix(".001")?;
+    Some(PathBuf::from(name))
+}
+
+/// Writes archive into volumes of at most `size` bytes, named once [`commit`] is called. Volumes an interrupted run
+/// was writing are removed first, those of the previous archive stay until [`commit`]
+pub struct VolumeWriter {
+    path: PathBuf,
+    size: u64,
+    current: Option<File>,
+    /// Bytes written into current volume
+    written: u64,
+    count: usize,
+}
+
+impl VolumeWriter {
+    pub fn create(path: impl Into<PathBuf>, size: u64) -> anyhow::Result<Self> {
+        anyhow::ensure!(size > 0, "Volume size must be positive");
+        let path = path.into();
+        discard(&path)?;
+
+        Ok(Self {
+            path,
+            size,
+            current: None,
+            written: 0,
+            count: 0,
+        })
+    }
+}
+
+impl Write for VolumeWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let file = match &mut self.current {
+            Some(file) if self.written < self.size => file,
+            _ => {
+                if let Some(mut file) = self.current.take() {
+                    file.flush()?;
+                }
+                self.count += 1;
+                self.written = 0;
+                self.current.insert(File::create(partial_path(&self.path, self.count))?)
+            },
+        };
+
+        let len = buf.len().min((self.size - self.written) as usize);
+        let written = file.write(&buf[..len])?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.current.as_mut().map_or(Ok(()), Write::flush)
+    }
+}
+
+/// Opens archive at `path`. If `path` is the first volume or does not exist but has volumes,
+/// all volumes are read back to back
+pub fn open(path: &Path) -> anyhow::Result<Box<dyn Read + Send>> {
+    let base = match first_volume_base(path) {
+        Some(base) => base,
+        None if !path.exists() && volume_path(path, 1).exists() => path.to_path_buf(),
+        None => return Ok(Box::new(File::open(path).with_context(|| format!("Unable to open {}", path.display()))?)),
+    };
+
+    let mut reader: Box<dyn Read + Send> = Box::new(std::io::empty());
+    for volume in volume_paths(&base) {
+        let file = File::open(&volume).with_context(|| format!("Unable to open {}", volume.display()))?;
+        reader = Box::new(reader.chain(file));
+    }
+    Ok(reader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn split_and_join() {
+        let dir = TempDir::new("rpack-volumes");
+        let path = dir.join("world.rpack");
+        // Stale volumes of a larger archive
+        for index in 1..=5 {
+            std::fs::write(volume_path(&path, index), b"stale").unwrap();
+        }
+
+        let data = (0..250u8).collect::<Vec<_>>();
+        let mut writer = VolumeWriter::create(&path, 100).unwrap();
+        writer.write_all(&data).unwrap();
+        writer.flush().unwrap();
+        // The previous archive stays as it is until all volumes are written
+        assert_eq!(volume_paths(&path).len(), 5);
+        assert_eq!(std::fs::read(volume_path(&path, 1)).unwrap(), b"stale");
+        discard(&path).unwrap();
+        assert_eq!(volume_paths(&path).len(), 5);
+
+        let mut writer = VolumeWriter::create(&path, 100).unwrap();
+        writer.write_all(&data).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(commit(&path, false).unwrap(), 3);
+
+        let sizes = volume_paths(&path).iter().map(|x| std::fs::metadata(x).unwrap().len()).collect::<Vec<_>>();
+        assert_eq!(sizes, [100, 100, 50]);
+        assert!(!volume_path(&path, 5).exists());
+
+        for input in [path.clone(), volume_path(&path, 1)] {
+            let mut read = vec![];
+            open(&input).unwrap().read_to_end(&mut read).unwrap();
+            assert_eq!(read, data);
+        }
+    }
+
+    #[test]
+    fn interrupted_commit() {
+        let dir = TempDir::new("rpack-volumes-commit");
+        let path = dir.join("world.rpack");
+        let read = || {
+            let mut read = vec![];
+            open(&path).and_then(|mut x| Ok(x.read_to_end(&mut read)?)).ok().map(|_| read)
+        };
+        let (old, new) = ((0..250u8).rev().collect::<Vec<_>>(), (0..250u8).collect::<Vec<_>>());
+
+        // Stopped after every rename or removal
+        for stop in 0.. {
+            std::fs::remove_dir_all(dir.path()).unwrap();
+            std::fs::create_dir_all(dir.path()).unwrap();
+            for (index, volume) in old.chunks(50).enumerate() {
+                std::fs::write(volume_path(&path, index + 1), volume).unwrap();
+            }
+            let mut writer = VolumeWriter::create(&path, 100).unwrap();
+            writer.write_all(&new).unwrap();
+            writer.flush().unwrap();
+            let steps = commit_steps(&path, 3);
+            if stop > steps.len() {
+                break;
+            }
+            steps[..stop].iter().try_for_each(commit_step).unwrap();
+            let found = read();
+            assert!(found.is_none() || found.as_ref() == Some(&old) || found.as_ref() == Some(&new), "stopped at {stop}");
+            assert_eq!(found.as_ref() == Some(&new), stop >= steps.len() - 1, "stopped at {stop}");
+
+            // The next run writes it again
+            let mut writer = VolumeWriter::create(&path, 100).unwrap();
+            writer.write_all(&new).unwrap();
+            writer.flush().unwrap();
+            commit(&path, false).unwrap();
+            assert_eq!(read(), Some(new.clone()));
+            assert!(!old_path(&path).exists());
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,71 @@
+use std::path::PathBuf;
+
+use crate::{error::{self, ErrorCode}, meta::RegionScan, region::RegionInfo, world};
+
+#[derive(Debug, clap::Args)]
+pub struct ScanArgs {
+    /// Region file or directory to search region files in
+    #[arg(short, long)]
+    pub input: PathBuf,
+
+    /// Print summary of every region as a JSON line, and `{"file":..,"error":..,"code":..}` for regions failing
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// Reads only region headers, so it is fast enough for thousands of files
+pub fn run(args: ScanArgs) -> anyhow::Result<()> {
+    let files = world::region_files(&args.input)?;
+
+    let mut total = RegionScan::default();
+    let mut failed = 0usize;
+
+    for file in files.iter() {
+        let scan = match RegionInfo::scan(file) {
+            Ok(scan) => scan,
+            Err(e) if args.json => {
+                let code = ErrorCode::of(&e).map(ErrorCode::id);
+                println!("{}", serde_json::json!({ "file": file, "error": format!("{e:#}"), "code": code }));
+                failed += 1;
+                continue;
+            },
+            Err(e) => {
+                eprintln!("{:#}", error::with_code(e));
+                failed += 1;
+                continue;
+            },
+        };
+
+        if args.json {
+            println!("{}", serde_json::json!({ "file": file, "scan": scan }));
+        } else {
+            println!(
+                "{}: {} chunks, newest {}, {} of {} bytes used",
+                file.display(),
+                scan.chunks,
+                scan.newest_timestamp.map_or("-".to_owned(), |x| x.to_string()),
+                scan.used_bytes,
+                scan.file_size
+            );
+        }
+
+        total.chunks += scan.chunks;
+        total.newest_timestamp = total.newest_timestamp.max(scan.newest_timestamp);
+        total.used_bytes += scan.used_bytes;
+        total.file_size += scan.file_size;
+    }
+
+    if !args.json {
+        println!(
+            "Total: {} regions, {} chunks, newest {}, {} of {} bytes used",
+            files.len() - failed,
+            total.chunks,
+            total.newest_timestamp.map_or("-".to_owned(), |x| x.to_string()),
+            total.used_bytes,
+            total.file_size
+        );
+    }
+
+    anyhow::ensure!(failed == 0, "{failed} region files could not be scanned");
+    Ok(())
+}
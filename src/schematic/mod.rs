@@ -0,0 +1,327 @@
+//! Export of a block box into Sponge schematic (`.schem`, version 2) or vanilla structure NBT.
+//! Only 1.18+ chunks with `sections` are understood, blocks of missing or older chunks are air.
+
+use std::collections::HashMap;
+
+use anyhow::ensure;
+
+use crate::{
+    chunk,
+    nbt::{tag_id, Compound, Tag},
+};
+
+pub const AIR: &str = "minecraft:air";
+
+/// Inclusive box of absolute block coordinates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockBox {
+    pub min: [i32; 3],
+    pub max: [i32; 3],
+}
+
+impl BlockBox {
+    /// Box spanning both corners in any order
+    pub fn new(a: [i32; 3], b: [i32; 3]) -> Self {
+        Self {
+            min: [0, 1, 2].map(|i| a[i].min(b[i])),
+            max: [0, 1, 2].map(|i| a[i].max(b[i])),
+        }
+    }
+
+    pub fn size(&self) -> [u32; 3] {
+        [0, 1, 2].map(|i| self.max[i].abs_diff(self.min[i]) + 1)
+    }
+
+    pub fn contains(&self, pos: [i32; 3]) -> bool {
+        (0..3).all(|i| (self.min[i]..=self.max[i]).contains(&pos[i]))
+    }
+
+    /// Whether any block of chunk column is inside the box
+    pub fn intersects_chunk(&self, x: i32, z: i32) -> bool {
+        x * 16 <= self.max[0] && x * 16 + 15 >= self.min[0] && z * 16 <= self.max[2] && z * 16 + 15 >= self.min[2]
+    }
+}
+
+/// Blocks of a box with a shared palette of block states
+#[derive(Debug, Clone)]
+pub struct Selection {
+    pub bounds: BlockBox,
+    /// Highest DataVersion of added chunks
+    pub data_version: Option<i32>,
+    /// Block state compounds (`Name` and optional `Properties`), air first
+    pub palette: Vec<Compound>,
+    palette_keys: HashMap<String, u32>,
+    /// Palette index of every block in YZX order, like Sponge schematics
+    pub blocks: Vec<u32>,
+    /// Block entities with positions relative to box minimum
+    pub block_entities: Vec<([i32; 3], Compound)>,
+}
+
+impl Selection {
+    /// Air-filled selection. Every side must fit in `u16` as Sponge schematics store sizes as shorts
+    pub fn new(bounds: BlockBox) -> anyhow::Result<Self> {
+        let [x, y, z] = bounds.size();
+        ensure!(
+            x <= u16::MAX as u32 && y <= u16::MAX as u32 && z <= u16::MAX as u32,
+            "Selection {x}x{y}x{z} is too large, every side must be at most {}",
+            u16::MAX
+        );
+        let volume = x as u64 * y as u64 * z as u64;
+        ensure!(volume <= i32::MAX as u64, "Selection of {volume} blocks is too large");
+
+        let mut air = Compound::default();
+        air.insert("Name", Tag::String(AIR.to_owned()));
+
+        Ok(Self {
+            bounds,
+            data_version: None,
+            palette: vec![air],
+            palette_keys: HashMap::from([(AIR.to_owned(), 0)]),
+            blocks: vec![0; volume as usize],
+            block_entities: vec![],
+        })
+    }
+
+    fn index(&self, pos: [i32; 3]) -> usize {
+        let [sx, _, sz] = self.bounds.size().map(|x| x as usize);
+        let [x, y, z] = [0, 1, 2].map(|i| (pos[i] - self.bounds.min[i]) as usize);
+        (y * sz + z) * sx + x
+    }
+
+    /// Palette index of block state, adding it on first use
+    fn state_index(&mut self, state: &Compound) -> u32 {
+        let key = state_key(state);
+        if let Some(&index) = self.palette_keys.get(&key) {
+            return index;
+        }
+        let index = self.palette.len() as u32;
+        self.palette.push(state.clone());
+        self.palette_keys.insert(key, index);
+        index
+    }
+
+    /// Copies blocks and block entities of chunk inside the box
+    pub fn add_chunk(&mut self, root: &Compound) {
+        let Some((cx, cz)) = chunk::nbt_position(root) else {
+            return;
+        };
+        if !self.bounds.intersects_chunk(cx, cz) {
+            return;
+        }
+        self.data_version = self.data_version.max(chunk::data_version(root));
+
+        for section in chunk::sections(root).iter().filter_map(Tag::as_compound) {
+            let Some(sy) = section.get("Y").and_then(Tag::as_i64) else {
+                continue;
+            };
+            let sy = sy as i32;
+            if sy * 16 > self.bounds.max[1] || sy * 16 + 15 < self.bounds.min[1] {
+                continue;
+            }
+            let Some(container) = section.get("block_states").and_then(Tag::as_compound) else {
+                continue;
+            };
+            let palette = container
+                .get("palette")
+                .and_then(Tag::as_list)
+                .unwrap_or_default();
+            let data = match container.get("data") {
+                Some(Tag::LongArray(x)) => Some(x.as_slice()),
+                _ => None,
+            };
+
+            let states = palette
+                .iter()
+                .map(|x| x.as_compound().map_or(0, |x| self.state_index(x)))
+                .collect::<Vec<_>>();
+
+            for (i, index) in chunk::unpack_palette_indices(data, palette.len(), 4096, 4).into_iter().enumerate() {
+                let pos = [cx * 16 + (i % 16) as i32, sy * 16 + (i / 256) as i32, cz * 16 + (i / 16 % 16) as i32];
+                if self.bounds.contains(pos) {
+                    let at = self.index(pos);
+                    self.blocks[at] = states.get(index).copied().unwrap_or(0);
+                }
+            }
+        }
+
+        for entity in block_entities(root).iter().filter_map(Tag::as_compound) {
+            let coord = |name| entity.get(name).and_then(Tag::as_i64).map(|x| x as i32);
+            let (Some(x), Some(y), Some(z)) = (coord("x"), coord("y"), coord("z")) else {
+                continue;
+            };
+            if !self.bounds.contains([x, y, z]) {
+                continue;
+            }
+
+            let mut entity = entity.clone();
+            for name in ["x", "y", "z"] {
+                entity.remove(name);
+            }
+            let relative = [x - self.bounds.min[0], y - self.bounds.min[1], z - self.bounds.min[2]];
+            self.block_entities.push((relative, entity));
+        }
+    }
+
+    /// Sponge schematic version 2. Write with root name `Schematic` and gzip it.
+    pub fn to_sponge(&self) -> Compound {
+        let [x, y, z] = self.bounds.size();
+
+        let mut palette = Compound::default();
+        for (index, state) in self.palette.iter().enumerate() {
+            palette.insert(state_key(state), Tag::Int(index as i32));
+        }
+
+        // Indices are varints
+        let mut data = vec![];
+        for &index in self.blocks.iter() {
+            let mut index = index;
+            while index >= 0x80 {
+                data.push((index as u8 & 0x7F | 0x80) as i8);
+                index >>= 7;
+            }
+            data.push(index as i8);
+        }
+
+        let block_entities = self
+            .block_entities
+            .iter()
+            .map(|(pos, entity)| {
+                let mut entity = entity.clone();
+                let id = entity.remove("id").unwrap_or(Tag::String(String::new()));
+                entity.insert("Id", id);
+                entity.insert("Pos", Tag::IntArray(pos.to_vec()));
+                Tag::Compound(entity)
+            })
+            .collect();
+
+        let mut root = Compound::default();
+        root.insert("Version", Tag::Int(2));
+        root.insert("DataVersion", Tag::Int(self.data_version.unwrap_or_default()));
+        root.insert("Width", Tag::Short(x as u16 as i16));
+        root.insert("Height", Tag::Short(y as u16 as i16));
+        root.insert("Length", Tag::Short(z as u16 as i16));
+        root.insert("Offset", Tag::IntArray(self.bounds.min.to_vec()));
+        root.insert("PaletteMax", Tag::Int(self.palette.len() as i32));
+        root.insert("Palette", Tag::Compound(palette));
+        root.insert("BlockData", Tag::ByteArray(data));
+        root.insert("BlockEntities", Tag::List(tag_id::COMPOUND, block_entities));
+        root
+    }
+
+    /// Vanilla structure as saved by structure blocks. Write with empty root name and gzip it.
+    pub fn to_structure(&self) -> Compound {
+        let [sx, _, sz] = self.bounds.size().map(|x| x as usize);
+        let ints = |pos: [i32; 3]| Tag::List(tag_id::INT, pos.map(Tag::Int).to_vec());
+
+        let mut entities = self
+            .block_entities
+            .iter()
+            .map(|(pos, entity)| {
+                let [x, y, z] = pos.map(|x| x as usize);
+                ((y * sz + z) * sx + x, entity)
+            })
+            .collect::<HashMap<_, _>>();
+
+        let blocks = self
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(i, &state)| {
+                let pos = [(i % sx) as i32, (i / sx / sz) as i32, (i / sx % sz) as i32];
+                let mut block = Compound::default();
+                block.insert("state", Tag::Int(state as i32));
+                block.insert("pos", ints(pos));
+                if let Some(entity) = entities.remove(&i) {
+                    block.insert("nbt", Tag::Compound(entity.clone()));
+                }
+                Tag::Compound(block)
+            })
+            .collect();
+
+        let mut root = Compound::default();
+        root.insert("DataVersion", Tag::Int(self.data_version.unwrap_or_default()));
+        root.insert("size", ints(self.bounds.size().map(|x| x as i32)));
+        root.insert("palette", Tag::List(tag_id::COMPOUND, self.palette.iter().cloned().map(Tag::Compound).collect()));
+        root.insert("blocks", Tag::List(tag_id::COMPOUND, blocks));
+        root.insert("entities", Tag::List(tag_id::COMPOUND, vec![]));
+        root
+    }
+}
+
+/// Block state in `name[key=value,...]` form with sorted properties
+pub fn state_key(state: &Compound) -> String {
+    let name = state.get("Name").and_then(Tag::as_str).unwrap_or(AIR);
+    let mut properties = state
+        .get("Properties")
+        .and_then(Tag::as_compound)
+        .map(|x| x.iter().map(|(k, v)| format!("{k}={}", v.as_str().unwrap_or_default())).collect::<Vec<_>>())
+        .unwrap_or_default();
+    if properties.is_empty() {
+        return name.to_owned();
+    }
+    properties.sort();
+    format!("{name}[{}]", properties.join(","))
+}
+
+fn block_entities(root: &Compound) -> &[Tag] {
+    root.get("block_entities")
+        .and_then(Tag::as_list)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(name: &str) -> Tag {
+        let mut state = Compound::default();
+        state.insert("Name", Tag::String(name.to_owned()));
+        Tag::Compound(state)
+    }
+
+    #[test]
+    fn export_box_from_chunk() {
+        // Palette of two entries takes 4 bits per block: block 1 of section is stone
+        let mut block_states = Compound::default();
+        block_states.insert("palette", Tag::List(tag_id::COMPOUND, vec![state(AIR), state("minecraft:stone")]));
+        block_states.insert("data", Tag::LongArray(vec![0x10; 256]));
+
+        let mut section = Compound::default();
+        section.insert("Y", Tag::Byte(-1));
+        section.insert("block_states", Tag::Compound(block_states));
+
+        let mut chest = Compound::default();
+        chest.insert("id", Tag::String("minecraft:chest".to_owned()));
+        for (name, value) in [("x", 17), ("y", -16), ("z", 0)] {
+            chest.insert(name, Tag::Int(value));
+        }
+
+        let mut root = Compound::default();
+        root.insert("DataVersion", Tag::Int(3465));
+        root.insert("xPos", Tag::Int(1));
+        root.insert("zPos", Tag::Int(0));
+        root.insert("sections", Tag::List(tag_id::COMPOUND, vec![Tag::Compound(section)]));
+        root.insert("block_entities", Tag::List(tag_id::COMPOUND, vec![Tag::Compound(chest)]));
+
+        let mut selection = Selection::new(BlockBox::new([18, -15, 1], [16, -16, 0])).unwrap();
+        selection.add_chunk(&root);
+
+        assert_eq!(selection.bounds.size(), [3, 2, 2]);
+        // Only x = 17 of every row holds stone
+        assert_eq!(selection.blocks, [0, 1, 0].repeat(4));
+        assert_eq!(selection.block_entities.len(), 1);
+        assert_eq!(selection.block_entities[0].0, [1, 0, 0]);
+
+        let sponge = selection.to_sponge();
+        assert_eq!(sponge.get("Width"), Some(&Tag::Short(3)));
+        assert_eq!(sponge.get("DataVersion"), Some(&Tag::Int(3465)));
+        assert_eq!(sponge.get("BlockData"), Some(&Tag::ByteArray([0, 1, 0].repeat(4))));
+
+        let structure = selection.to_structure();
+        let blocks = structure.get("blocks").and_then(Tag::as_list).unwrap();
+        assert_eq!(blocks.len(), 12);
+        let chest = blocks[1].as_compound().unwrap();
+        assert_eq!(chest.get("state"), Some(&Tag::Int(1)));
+        assert!(chest.get("nbt").is_some());
+    }
+}
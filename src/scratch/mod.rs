@@ -0,0 +1,97 @@
+//! Byte buffers reused across files. Every thread keeps a few returned buffers, and new ones start with
+//! the capacity of the largest buffer seen by any thread, so batch processing does not grow buffers
+//! chunk by chunk again for every region file.
+
+use std::{
+    cell::RefCell,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Buffers kept by every thread
+const POOL_SIZE: usize = 4;
+/// Larger buffers are freed instead of kept, a single huge chunk should not pin its memory
+const MAX_RETAINED: usize = 16 * 1024 * 1024;
+
+/// Capacity of the largest buffer returned so far, up to [`MAX_RETAINED`]
+static LARGEST: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static POOL: RefCell<Vec<Vec<u8>>> = const { RefCell::new(vec![]) };
+}
+
+/// Empty buffer from the pool of current thread, returned to it on drop
+#[derive(Debug)]
+pub struct Scratch(Vec<u8>);
+
+impl Scratch {
+    pub fn take() -> Self {
+        let buffer = POOL.with_borrow_mut(Vec::pop);
+        Self(buffer.unwrap_or_else(|| Vec::with_capacity(LARGEST.load(Ordering::Relaxed))))
+    }
+}
+
+impl Default for Scratch {
+    fn default() -> Self {
+        Self::take()
+    }
+}
+
+impl Deref for Scratch {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.0
+    }
+}
+
+impl DerefMut for Scratch {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.0
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let mut buffer = std::mem::take(&mut self.0);
+        if buffer.capacity() > MAX_RETAINED {
+            return;
+        }
+        LARGEST.fetch_max(buffer.capacity(), Ordering::Relaxed);
+        buffer.clear();
+        // Pool is gone while thread-locals of an exiting thread are destroyed
+        POOL.try_with(|pool| {
+            let mut pool = pool.borrow_mut();
+            if pool.len() < POOL_SIZE {
+                pool.push(buffer);
+            }
+        })
+        .ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reused_on_same_thread() {
+        let mut scratch = Scratch::take();
+        scratch.extend_from_slice(&[1; 5000]);
+        let ptr = scratch.as_ptr();
+        drop(scratch);
+
+        let scratch = Scratch::take();
+        assert!(scratch.is_empty());
+        assert_eq!(scratch.as_ptr(), ptr);
+
+        // Other threads start with buffers as large
+        let capacity = std::thread::spawn(|| Scratch::take().capacity()).join().unwrap();
+        assert!(capacity >= 5000);
+
+        let mut huge = Scratch::take();
+        huge.reserve(MAX_RETAINED + 1);
+        drop(huge);
+        assert!(LARGEST.load(Ordering::Relaxed) <= MAX_RETAINED);
+    }
+}
@@ -0,0 +1,172 @@
+//! Job counters in Prometheus text exposition format, served over plain HTTP
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+
+/// Upper bounds of job duration histogram buckets, seconds
+const BUCKETS: [f64; 10] = [0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+#[derive(Debug, Default, Clone)]
+struct OpMetrics {
+    succeeded: u64,
+    failed: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    chunks: u64,
+    /// Cumulative counts per bucket of [`BUCKETS`]
+    buckets: [u64; BUCKETS.len()],
+    duration_sum: f64,
+}
+
+/// Outcome of a single job
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Job {
+    pub ok: bool,
+    pub duration: Duration,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub chunks: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    ops: Mutex<BTreeMap<&'static str, OpMetrics>>,
+    /// Chunks archived and their size, counted while jobs run
+    archived_chunks: AtomicU64,
+    archived_bytes: AtomicU64,
+}
+
+impl Metrics {
+    /// Counts chunk archived by a running compact job, so progress of long jobs shows before they finish
+    pub fn chunk_archived(&self, size: usize) {
+        self.archived_chunks.fetch_add(1, Ordering::Relaxed);
+        self.archived_bytes.fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    pub fn record(&self, op: &'static str, job: Job) {
+        let mut ops = self.ops.lock().unwrap();
+        let metrics = ops.entry(op).or_default();
+
+        if job.ok {
+            metrics.succeeded += 1;
+        } else {
+            metrics.failed += 1;
+        }
+        metrics.bytes_in += job.bytes_in;
+        metrics.bytes_out += job.bytes_out;
+        metrics.chunks += job.chunks;
+
+        let seconds = job.duration.as_secs_f64();
+        metrics.duration_sum += seconds;
+        for (bucket, &bound) in metrics.buckets.iter_mut().zip(BUCKETS.iter()) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let ops = self.ops.lock().unwrap().clone();
+        let mut out = String::new();
+
+        let mut counter = |name: &str, help: &str, value: &dyn Fn(&OpMetrics) -> u64| {
+            writeln!(out, "# HELP repacker_{name} {help}\n# TYPE repacker_{name} counter").unwrap();
+            for (op, metrics) in ops.iter() {
+                writeln!(out, "repacker_{name}{{op=\"{op}\"}} {}", value(metrics)).unwrap();
+            }
+        };
+        counter("jobs_succeeded_total", "Jobs finished without error", &|x| x.succeeded);
+        counter("jobs_failed_total", "Jobs finished with error", &|x| x.failed);
+        counter("input_bytes_total", "Size of input files of successful jobs", &|x| x.bytes_in);
+        counter("output_bytes_total", "Size of output files of successful jobs", &|x| x.bytes_out);
+        counter("chunks_total", "Chunks processed by successful jobs", &|x| x.chunks);
+
+        for (name, help, value) in [
+            ("archived_chunks_total", "Chunks archived by compact jobs, including running ones", &self.archived_chunks),
+            ("archived_chunk_bytes_total", "Uncompressed size of archived chunks, as stored for raw archives", &self.archived_bytes),
+        ] {
+            let value = value.load(Ordering::Relaxed);
+            writeln!(out, "# HELP repacker_{name} {help}\n# TYPE repacker_{name} counter\nrepacker_{name} {value}").unwrap();
+        }
+
+        out.push_str("# HELP repacker_job_duration_seconds Duration of jobs\n# TYPE repacker_job_duration_seconds histogram\n");
+        for (op, metrics) in ops.iter() {
+            for (bucket, bound) in metrics.buckets.iter().zip(BUCKETS.iter()) {
+                writeln!(out, "repacker_job_duration_seconds_bucket{{op=\"{op}\",le=\"{bound}\"}} {bucket}").unwrap();
+            }
+            let count = metrics.succeeded + metrics.failed;
+            writeln!(out, "repacker_job_duration_seconds_bucket{{op=\"{op}\",le=\"+Inf\"}} {count}").unwrap();
+            writeln!(out, "repacker_job_duration_seconds_sum{{op=\"{op}\"}} {}", metrics.duration_sum).unwrap();
+            writeln!(out, "repacker_job_duration_seconds_count{{op=\"{op}\"}} {count}").unwrap();
+        }
+
+        out
+    }
+}
+
+fn respond(metrics: &Metrics, mut stream: TcpStream) -> anyhow::Result<()> {
+    let mut request = String::new();
+    let mut reader = BufReader::new(stream.try_clone()?);
+    reader.read_line(&mut request)?;
+    // Headers are not needed, but must be consumed before replying
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let (status, body) = match request.split_whitespace().nth(1) {
+        Some("/metrics") => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", "Not found\n".to_owned()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    Ok(())
+}
+
+/// Serves `/metrics` on a background thread
+pub fn spawn(metrics: Arc<Metrics>, addr: SocketAddr) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr).with_context(|| format!("Unable to bind metrics endpoint {addr}"))?;
+    eprintln!("Serving metrics on http://{addr}/metrics");
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming().filter_map(Result::ok) {
+            if let Err(e) = respond(&metrics, stream) {
+                eprintln!("Metrics request failed: {e:#}");
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let metrics = Metrics::default();
+        for millis in [5, 200, 60_000] {
+            metrics.record("compact", Job { ok: millis < 1000, duration: Duration::from_millis(millis), ..Default::default() });
+        }
+
+        let text = metrics.render();
+        assert!(text.contains("repacker_jobs_failed_total{op=\"compact\"} 1\n"));
+        assert!(text.contains("repacker_job_duration_seconds_bucket{op=\"compact\",le=\"0.01\"} 1\n"));
+        assert!(text.contains("repacker_job_duration_seconds_bucket{op=\"compact\",le=\"30\"} 2\n"));
+        assert!(text.contains("repacker_job_duration_seconds_bucket{op=\"compact\",le=\"+Inf\"} 3\n"));
+    }
+}
@@ -0,0 +1,344 @@
+//! Daemon accepting jobs over a Unix socket.
+//!
+//! Protocol is newline-delimited JSON: every request line gets exactly one response line, in order.
+//!
+//! ```text
+//! {"op":"compact","input":"r.0.0.mca","output":"r.0.0.rpack","codec":"zstd","dictionary":"dict.zst"}
+//! {"op":"decompact","input":"r.0.0.rpack","output":"r.0.0.mca","dedupe_pos":"newest","fsync":"dir","sparse":true}
+//! {"op":"verify","input":"r.0.0.rpack"}
+//! ```
+//!
+//! Responses are `{"ok":true,"elapsed_ms":..,"input_bytes":..,"output_bytes":..,"chunks":..}` or `{"ok":false,"error":".."}`,
+//! failures with a known cause also naming its code like `"code":"E013"`, see [`crate::error::ErrorCode`].
+//! Compact and decompact responses list problems of single chunks not failing the job in `"warnings":["Chunk 3,7: ..",..]`.
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Write},
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chunk,
+    error::{self, ErrorCode},
+    region::{self, RegionInfo},
+    rpack, ChunkCallback, ChunkEvent, CompactOptions, DecompactOptions, DedupePos, Fsync, Limits,
+};
+
+mod metrics;
+
+#[derive(Debug, clap::Args)]
+pub struct ServeArgs {
+    /// Path of control socket. A stale socket left by a previous run is replaced
+    #[arg(long)]
+    pub socket: PathBuf,
+
+    /// Serve Prometheus metrics on `http://<ADDR>/metrics`
+    #[arg(long, value_name = "ADDR")]
+    pub metrics_addr: Option<SocketAddr>,
+}
+
+fn default_level() -> i32 {
+    3
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Request {
+    Compact {
+        input: PathBuf,
+        output: PathBuf,
+        #[serde(default)]
+        codec: rpack::Compression,
+        #[serde(default = "default_level")]
+        level: i32,
+        #[serde(default)]
+        solid: bool,
+        #[serde(default)]
+        rolling: bool,
+        #[serde(default)]
+        checksums: bool,
+        #[serde(default)]
+        raw: bool,
+        /// Read once and kept in memory for later jobs
+        dictionary: Option<PathBuf>,
+        #[serde(default)]
+        fsync: Fsync,
+    },
+    Decompact {
+        input: PathBuf,
+        output: PathBuf,
+        #[serde(default)]
+        dedupe_pos: DedupePos,
+        #[serde(default)]
+        validate_output: bool,
+        #[serde(default)]
+        fsync: Fsync,
+        #[serde(default)]
+        sparse: bool,
+        target_dataversion: Option<i32>,
+        fill_missing: Option<chunk::Filler>,
+    },
+    /// Reads every chunk of rpack archive or validates region file
+    Verify { input: PathBuf },
+}
+
+impl Request {
+    fn op(&self) -> &'static str {
+        match self {
+            Request::Compact { .. } => "compact",
+            Request::Decompact { .. } => "decompact",
+            Request::Verify { .. } => "verify",
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Response {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    elapsed_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    input_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chunks: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct Server {
+    dictionaries: Mutex<HashMap<PathBuf, Arc<Vec<u8>>>>,
+    metrics: Arc<metrics::Metrics>,
+}
+
+impl Server {
+    fn dictionary(&self, path: &Path) -> anyhow::Result<Arc<Vec<u8>>> {
+        let mut dictionaries = self.dictionaries.lock().unwrap();
+        if let Some(dictionary) = dictionaries.get(path) {
+            return Ok(dictionary.clone());
+        }
+        let dictionary = Arc::new(std::fs::read(path).with_context(|| format!("Unable to read {}", path.display()))?);
+        dictionaries.insert(path.to_path_buf(), dictionary.clone());
+        Ok(dictionary)
+    }
+
+    fn handle(&self, request: Request) -> anyhow::Result<Response> {
+        let warnings = Arc::new(Mutex::new(vec![]));
+        let on_chunk = ChunkCallback(Arc::new({
+            let (warnings, metrics) = (warnings.clone(), self.metrics.clone());
+            move |event| match event {
+                ChunkEvent::Archived { size } => metrics.chunk_archived(size),
+                ChunkEvent::Warning { pos, message } => {
+                    let (x, z) = RegionInfo::local_coords(pos);
+                    warnings.lock().unwrap().push(format!("Chunk {x},{z}: {message}"));
+                },
+            }
+        }));
+        let (input, output, chunks) = match request {
+            Request::Compact {
+                input,
+                output,
+                codec,
+                level,
+                solid,
+                rolling,
+                checksums,
+                raw,
+                dictionary,
+                fsync,
+            } => {
+                let encode = rpack::EncodeOptions {
+                    region: region::region_coords_from_path(&input),
+                    rpack: rpack::Options {
+                        compression: codec,
+                        level,
+                        solid,
+                        rolling,
+                        checksums,
+                        raw,
+                        delta: false,
+                        dictionary: dictionary.map(|x| self.dictionary(&x)).transpose()?.map(|x| x.to_vec()),
+                    },
+                    format: region::detect_format(&input, &region::providers()).unwrap_or_default(),
+                    on_chunk: Some(on_chunk),
+                    ..Default::default()
+                };
+                crate::check_compact_options(&encode)?;
+                let options = CompactOptions { encode, fsync, ..Default::default() };
+                let chunks = crate::compact_file(&input, Some(&output), &options)?;
+                (input, Some(output), chunks)
+            },
+            Request::Decompact {
+                input,
+                output,
+                dedupe_pos,
+                validate_output,
+                fsync,
+                sparse,
+                target_dataversion,
+                fill_missing,
+            } => {
+                let options = DecompactOptions {
+                    decode: rpack::DecodeOptions {
+                        dedupe_pos,
+                        format: region::detect_format(&output, &region::providers()).unwrap_or_default(),
+                        sparse,
+                        target_data_version: target_dataversion,
+                        fill_missing,
+                        on_chunk: Some(on_chunk),
+                        ..Default::default()
+                    },
+                    validate_output,
+                    fsync,
+                };
+                crate::check_decompact_options(&options)?;
+                crate::decompact_file(Some(&input), &output, &options)?;
+                let file = std::fs::File::open(&output).map(BufReader::new)?;
+                let chunks = RegionInfo::read_with_format(file, &Limits::default(), &options.decode.format)?.chunk_infos().len();
+                (input, Some(output), chunks)
+            },
+            Request::Verify { input } => {
+                let chunks = verify(&input)?;
+                (input, None, chunks)
+            },
+        };
+
+        let size = |path: &Path| std::fs::metadata(path).map(|x| x.len()).ok();
+        let warnings = std::mem::take(&mut *warnings.lock().unwrap());
+        Ok(Response {
+            ok: true,
+            input_bytes: size(&input),
+            output_bytes: output.as_deref().and_then(size),
+            chunks: Some(chunks as u64),
+            warnings,
+            ..Default::default()
+        })
+    }
+
+    /// Handles request and records it in metrics
+    fn run_job(&self, request: Request) -> Response {
+        let op = request.op();
+        let started = Instant::now();
+        let mut response = self.handle(request).unwrap_or_else(|e| Response {
+            code: ErrorCode::of(&e).map(ErrorCode::id),
+            error: Some(format!("{:#}", error::with_code(e))),
+            ..Default::default()
+        });
+        let duration = started.elapsed();
+        response.elapsed_ms = Some(duration.as_millis() as u64);
+
+        self.metrics.record(op, metrics::Job {
+            ok: response.ok,
+            duration,
+            bytes_in: response.input_bytes.unwrap_or_default(),
+            bytes_out: response.output_bytes.unwrap_or_default(),
+            chunks: response.chunks.unwrap_or_default(),
+        });
+        response
+    }
+
+    fn connection(&self, stream: UnixStream) -> anyhow::Result<()> {
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let response = match serde_json::from_str::<Request>(&line).context("Invalid request") {
+                Ok(request) => self.run_job(request),
+                Err(e) => Response {
+                    error: Some(format!("{e:#}")),
+                    ..Default::default()
+                },
+            };
+
+            serde_json::to_writer(&mut writer, &response)?;
+            writer.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns number of chunks
+fn verify(input: &Path) -> anyhow::Result<usize> {
+    let mut reader = BufReader::new(std::fs::File::open(input).with_context(|| format!("Unable to open {}", input.display()))?);
+    if !reader.fill_buf()?.starts_with(&rpack::MAGIC) {
+        region::validate_region(input)?;
+        return Ok(RegionInfo::read(reader)?.chunk_infos().len());
+    }
+
+    let mut rpackreader = rpack::RpackReader::from_buf_reader(reader, Default::default())?;
+    let mut buffer = vec![];
+    let mut chunks = 0;
+    while rpackreader.read_chunk(&mut buffer)?.is_some() {
+        chunks += 1;
+    }
+    Ok(chunks)
+}
+
+/// Serves connections until killed. Every connection gets its own thread
+pub fn run(args: ServeArgs) -> anyhow::Result<()> {
+    if std::fs::symlink_metadata(&args.socket).is_ok_and(|x| x.file_type().is_socket()) {
+        std::fs::remove_file(&args.socket).with_context(|| format!("Unable to remove stale {}", args.socket.display()))?;
+    }
+    let listener = UnixListener::bind(&args.socket).with_context(|| format!("Unable to bind {}", args.socket.display()))?;
+    eprintln!("Listening on {}", args.socket.display());
+
+    let server = Arc::new(Server::default());
+    if let Some(addr) = args.metrics_addr {
+        metrics::spawn(server.metrics.clone(), addr)?;
+    }
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("Unable to accept connection: {e}");
+                continue;
+            },
+        };
+
+        let server = server.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = server.connection(stream) {
+                eprintln!("Connection closed: {e:#}");
+            }
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_defaults() {
+        let request = serde_json::from_str::<Request>(r#"{"op":"compact","input":"a","output":"b"}"#).unwrap();
+        let Request::Compact { codec, level, dictionary, .. } = request else {
+            panic!("{request:?}");
+        };
+        assert_eq!((codec, level, dictionary), (rpack::Compression::None, 3, None));
+
+        let request = serde_json::from_str::<Request>(r#"{"op":"decompact","input":"a","output":"b","dedupe_pos":"newest"}"#).unwrap();
+        assert!(matches!(request, Request::Decompact { dedupe_pos: DedupePos::Newest, .. }));
+    }
+}
@@ -0,0 +1,675 @@
+//! Packing and restoring whole directories the way a frontend walks through it: find worlds, plan what a run
+//! will do and how large it is, then run it and collect the outcome of every file. Directory mode of the CLI
+//! is built on it, a backup GUI needs the same steps.
+
+use std::{
+    collections::BTreeMap,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::{
+    audit::{self, AuditLog},
+    batch::{self, FileKind}, check_compact_options, check_decompact_options, compact_file, decompact_file, manifest, region, rpack, write_pruned,
+    world::{self, WorldBorder},
+    CancellationToken, CompactOptions, DecompactOptions, Fsync, Limits, RegionFormat, RegionInfo,
+};
+
+/// Minecraft world found by [`discover`]
+#[derive(Debug, Clone)]
+pub struct World {
+    pub path: PathBuf,
+    /// Region file counts by dimension id and storage kind (`region`, `entities`, `poi`)
+    pub dimensions: BTreeMap<String, BTreeMap<String, usize>>,
+}
+
+/// Worlds under `root`, directories holding `level.dat` including `root` itself. Worlds are not searched for
+/// nested worlds, so backups kept inside a world are not listed
+pub fn discover(root: &Path) -> anyhow::Result<Vec<World>> {
+    let mut worlds = vec![];
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        if dir.join("level.dat").is_file() {
+            let jobs = batch::compact_jobs(&dir, Path::new(""))?;
+            worlds.push(World {
+                dimensions: batch::dimensions(&dir, &jobs).0,
+                path: dir,
+            });
+            continue;
+        }
+        for entry in std::fs::read_dir(&dir).with_context(|| format!("Unable to read {}", dir.display()))? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                dirs.push(entry.path());
+            }
+        }
+    }
+    worlds.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(worlds)
+}
+
+/// What a run does with every region file or archive
+#[derive(Debug, Clone)]
+pub enum Operation {
+    Compact(CompactOptions),
+    Decompact(DecompactOptions),
+}
+
+/// World border chunks outside of which are left out when compacting
+#[derive(Debug, Clone, Copy)]
+pub enum Border {
+    /// Read from `level.dat` of the world holding the region file. Files outside of worlds are packed whole
+    LevelDat,
+    /// The same for every world, in overworld coordinates
+    Fixed(WorldBorder),
+}
+
+/// Settings of one dimension overriding those of the command line when compacting. Dimensions differ a lot,
+/// the End is rarely visited while the overworld changes all the time
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub codec: Option<rpack::Compression>,
+    pub level: Option<i32>,
+    pub solid: Option<bool>,
+    pub raw: Option<bool>,
+    pub migrate: Option<bool>,
+    pub check_nbt: Option<bool>,
+    /// Leave out chunks outside the world border, read from `level.dat` unless given on the command line
+    pub prune_border: Option<bool>,
+}
+
+impl Profile {
+    fn apply(&self, options: &mut rpack::EncodeOptions) {
+        let rpack = &mut options.rpack;
+        rpack.compression = self.codec.unwrap_or(rpack.compression);
+        rpack.level = self.level.unwrap_or(rpack.level);
+        rpack.solid = self.solid.unwrap_or(rpack.solid);
+        rpack.rolling &= rpack.solid;
+        rpack.raw = self.raw.unwrap_or(rpack.raw);
+        options.migrate = self.migrate.unwrap_or(options.migrate);
+        options.check_nbt = self.check_nbt.unwrap_or(options.check_nbt);
+    }
+}
+
+/// Reads JSON object of [`Profile`]s by dimension id, like
+/// `{"the_end": {"codec": "zstd", "level": 19, "prune_border": true}, "minecraft:the_nether": {"raw": true}}`.
+/// Ids without namespace are in `minecraft`
+pub fn read_profiles(path: &Path) -> anyhow::Result<BTreeMap<String, Profile>> {
+    let data = std::fs::read(path).with_context(|| format!("Unable to read {}", path.display()))?;
+    let profiles: BTreeMap<String, Profile> =
+        serde_json::from_slice(&data).with_context(|| format!("Unable to parse profiles of {}", path.display()))?;
+    Ok(profiles
+        .into_iter()
+        .map(|(id, profile)| match id.contains(':') {
+            true => (id, profile),
+            false => (format!("minecraft:{id}"), profile),
+        })
+        .collect())
+}
+
+/// Jobs of a run and their sizes, known before anything is written
+#[derive(Debug)]
+pub struct Plan {
+    pub jobs: Vec<batch::Job>,
+    /// The first jobs are of region files when compacting or archives when decompacting, the rest copy passthrough files
+    pub regions: usize,
+    /// Every file under input by kind
+    pub kinds: BTreeMap<FileKind, usize>,
+    /// Links kept as links, see [`batch::LinkPolicy::Preserve`]
+    pub links: Vec<batch::Found>,
+    pub input_bytes: u64,
+    /// Chunks of region files and the bytes they occupy, read from region headers. Only known when compacting
+    pub chunks: Option<(usize, u64)>,
+    /// Only known when decompacting archives with a manifest
+    pub restore: Option<Restore>,
+}
+
+/// Disk space and memory a restore needs, from sizes of files recorded in the manifest
+#[derive(Debug, Clone, Copy)]
+pub struct Restore {
+    /// Restored files together, links not counted
+    pub bytes: u64,
+    /// Every thread holds at most two chunks, each smaller than the largest region file
+    pub peak_memory: u64,
+}
+
+impl Plan {
+    pub fn print(&self) {
+        let kinds = self.kinds.iter().map(|(kind, count)| format!("{count} {}", kind.name())).collect::<Vec<_>>();
+        println!("Found {} files", kinds.join(", "));
+        println!("{} region files or archives, {} other files", self.regions, self.jobs.len() - self.regions);
+        println!("{} bytes to read", self.input_bytes);
+        if let Some((chunks, bytes)) = self.chunks {
+            println!("{chunks} chunks in {bytes} bytes of sectors");
+        }
+        if let Some(restore) = self.restore {
+            println!("{} bytes to write, up to {} bytes of memory", restore.bytes, restore.peak_memory);
+        }
+    }
+}
+
+/// Region format of region file at path
+pub type FormatOf<'a> = Box<dyn Fn(&Path) -> anyhow::Result<RegionFormat> + Sync + 'a>;
+
+/// Directory compacted into or restored from archives. The run is cancelled through the token of options
+pub struct Session<'a> {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub operation: Operation,
+    /// Only used when compacting
+    pub border: Option<Border>,
+    /// Settings by dimension id overriding those of the compact operation, see [`read_profiles`]
+    pub profiles: BTreeMap<String, Profile>,
+    /// Links in input when compacting. Links kept in the manifest are always restored
+    pub links: batch::LinkPolicy,
+    /// Record permissions and ownership of files in the manifest when compacting, restore them when decompacting
+    pub preserve_perms: bool,
+    pub passthrough: batch::Passthrough,
+    /// Region format of region file, the input when compacting and the output when decompacting
+    pub region_format: FormatOf<'a>,
+    pub threads: NonZeroUsize,
+    pub fail_fast: bool,
+    pub progress: bool,
+}
+
+impl Session<'_> {
+    fn cancel(&self) -> &CancellationToken {
+        match &self.operation {
+            Operation::Compact(options) => &options.encode.cancel,
+            Operation::Decompact(options) => &options.decode.cancel,
+        }
+    }
+
+    /// Finds files to process. Region headers are read when compacting, nothing is written
+    pub fn plan(&self) -> anyhow::Result<Plan> {
+        let (input, output) = (&self.input, &self.output);
+        let files = match self.operation {
+            Operation::Compact(_) => batch::scan(input, self.links)?,
+            Operation::Decompact(_) => batch::scan(input, batch::LinkPolicy::Read)?,
+        };
+        let mut jobs = match self.operation {
+            Operation::Compact(_) => batch::compact_jobs_of(input, output, &files),
+            Operation::Decompact(_) => batch::decompact_jobs_of(input, output, &files),
+        };
+        let regions = jobs.len();
+        for file in files.iter().filter(|x| x.kind == FileKind::Skipped) {
+            let Some(batch::Link::Symlink(target)) = &file.link else { continue };
+            let hint = match self.links {
+                batch::LinkPolicy::Follow => "it is broken or leads to a directory above it",
+                _ => "pass --follow-symlinks or --preserve-links to keep links to directories",
+            };
+            eprintln!("Skipping {}: link to {} not followed, {hint}", file.path.display(), target.display());
+        }
+
+        let chunks = match self.operation {
+            Operation::Compact(_) => Some(self.count_chunks(&jobs)?),
+            Operation::Decompact(_) => None,
+        };
+
+        let manifest = input.join(manifest::MANIFEST_NAME);
+        jobs.extend(batch::passthrough_jobs(input, output, &files, &self.passthrough, |x| match self.operation {
+            Operation::Compact(_) => matches!(x.kind, FileKind::Region | FileKind::Link | FileKind::Skipped),
+            Operation::Decompact(_) => {
+                matches!(x.kind, FileKind::Archive | FileKind::Volume | FileKind::Skipped) || x.path == manifest
+            },
+        }));
+        for job in &mut jobs[regions..] {
+            match self.operation {
+                Operation::Compact(_) if self.passthrough.compress => {
+                    job.output.as_mut_os_string().push(format!(".{}", rpack::file::EXTENSION));
+                },
+                Operation::Decompact(_) if rpack::file::is_stored(&job.input) => {
+                    job.output.set_extension("");
+                },
+                _ => {},
+            }
+        }
+
+        let restore = match self.operation {
+            Operation::Decompact(_) if manifest.is_file() => Some(self.restore(&manifest::read(input)?)),
+            _ => None,
+        };
+
+        Ok(Plan {
+            restore,
+            input_bytes: jobs.iter().map(|x| x.size).sum(),
+            kinds: batch::count_kinds(&files),
+            links: files.into_iter().filter(|x| x.kind == FileKind::Link).collect(),
+            jobs,
+            regions,
+            chunks,
+        })
+    }
+
+    fn restore(&self, manifest: &manifest::Manifest) -> Restore {
+        let files = manifest.entries.iter().filter(|x| !matches!(x.kind, manifest::Kind::Symlink | manifest::Kind::Hardlink));
+        let regions = manifest
+            .entries
+            .iter()
+            .filter(|x| matches!(x.kind, manifest::Kind::Region | manifest::Kind::Entities | manifest::Kind::Poi))
+            .map(|x| x.size)
+            .collect::<Vec<_>>();
+        let threads = self.threads.get().min(regions.len()) as u64;
+        Restore {
+            bytes: files.map(|x| x.size).sum(),
+            peak_memory: regions.iter().max().unwrap_or(&0) * 2 * threads,
+        }
+    }
+
+    /// Refuses to restore when the filesystem of output has less free space than the restore needs
+    pub fn check_space(&self, plan: &Plan, force: bool) -> anyhow::Result<()> {
+        let Some(restore) = plan.restore else { return Ok(()) };
+        println!("Restore needs {} bytes of disk space and up to {} bytes of memory", restore.bytes, restore.peak_memory);
+        let Some(free) = free_space(&self.output) else { return Ok(()) };
+        if free < restore.bytes {
+            let message = format!("Only {free} bytes free on the filesystem of {}", self.output.display());
+            anyhow::ensure!(force, "{message}, pass --force to restore anyway");
+            eprintln!("{message}, restoring anyway");
+        }
+        Ok(())
+    }
+
+    /// Records every chunk of region file dropped whole by the border. Only its header is read, so sizes are those
+    /// of chunks as stored in the region file
+    fn audit_dropped(&self, audit: &AuditLog, options: &rpack::EncodeOptions) -> anyhow::Result<()> {
+        let path = audit.file();
+        let format = (self.region_format)(path)?;
+        let info = RegionInfo::read_with_format(region::open(path)?, &Limits::RELAXED, &format)
+            .with_context(|| format!("Unable to read region header of {}", path.display()))?;
+        for (chunk, pos) in info.chunk_infos() {
+            let coords = RegionInfo::chunk_coords(options.region, *pos);
+            audit.record(coords, audit::Rule::PruneBorder, chunk.size_in(&format) as usize, None);
+        }
+        audit.commit()
+    }
+
+    /// Chunks of region files and the bytes they occupy, headers read by all threads of the session
+    fn count_chunks(&self, jobs: &[batch::Job]) -> anyhow::Result<(usize, u64)> {
+        let count = |job: &batch::Job| {
+            let format = (self.region_format)(&job.input)?;
+            let info = RegionInfo::read_with_format(region::open(&job.input)?, &Limits::RELAXED, &format)
+                .with_context(|| format!("Unable to read region header of {}", job.input.display()))?;
+            let infos = info.chunk_infos();
+            anyhow::Ok((infos.len(), infos.iter().map(|x| x.0.size_in(&format)).sum::<u64>()))
+        };
+        let per_thread = jobs.len().div_ceil(self.threads.get()).max(1);
+        std::thread::scope(|scope| {
+            let handles = jobs
+                .chunks(per_thread)
+                .map(|jobs| scope.spawn(move || jobs.iter().map(count).collect::<anyhow::Result<Vec<_>>>()))
+                .collect::<Vec<_>>();
+            handles.into_iter().try_fold((0, 0), |(chunks, bytes), handle| {
+                let counts = handle.join().unwrap()?;
+                Ok(counts.into_iter().fold((chunks, bytes), |(chunks, bytes), x| (chunks + x.0, bytes + x.1)))
+            })
+        })
+    }
+
+    /// Worlds under input with their borders, read once before compacting. Borders are only read
+    /// when some dimension is pruned
+    fn worlds(&self) -> anyhow::Result<Vec<(PathBuf, Option<WorldBorder>)>> {
+        let read_level_dat = self.border.is_none() && self.profiles.values().any(|x| x.prune_border == Some(true));
+        if self.border.is_none() && self.profiles.is_empty() {
+            return Ok(vec![]);
+        }
+        discover(&self.input)?
+            .into_iter()
+            .map(|world| {
+                let border = match self.border {
+                    Some(Border::Fixed(x)) => Some(x),
+                    Some(Border::LevelDat) => Some(WorldBorder::read(&world.path)?),
+                    None if read_level_dat => Some(WorldBorder::read(&world.path)?),
+                    None => None,
+                };
+                Ok((world.path, border))
+            })
+            .collect()
+    }
+
+    /// Compact options of region file: profile of its dimension applied and border in coordinates of the dimension
+    fn options_for(&self, options: &CompactOptions, worlds: &[(PathBuf, Option<WorldBorder>)], file: &Path) -> anyhow::Result<CompactOptions> {
+        let region = region::region_coords_from_path(file);
+        let format = (self.region_format)(file)?;
+        let mut options = CompactOptions {
+            encode: rpack::EncodeOptions { region, format, border: None, ..options.encode.clone() },
+            ..options.clone()
+        };
+
+        let (dimension, border) = match worlds.iter().find(|x| file.starts_with(&x.0)) {
+            Some((world, border)) => (world::dimension(world, file).map(|x| x.0), *border),
+            None => (None, None),
+        };
+        let profile = dimension.as_ref().and_then(|x| self.profiles.get(x)).cloned().unwrap_or_default();
+        profile.apply(&mut options.encode);
+
+        let border = match dimension {
+            Some(dimension) => border.and_then(|x| x.in_dimension(&dimension)),
+            // Files outside of worlds only get a border given on the command line
+            None => match self.border {
+                Some(Border::Fixed(border)) => Some(border),
+                _ => None,
+            },
+        };
+        // Pruning understands only regions of 32x32 chunks named by their coordinates
+        let prunable = region.is_some() && format.entries == RegionFormat::VANILLA.entries;
+        if profile.prune_border.unwrap_or(self.border.is_some()) && prunable {
+            options.encode.border = border;
+        }
+        Ok(options)
+    }
+
+    /// Runs every job of plan. Archives are written with their manifest into a staging directory next to output,
+    /// which replaces output only once every file succeeded. After failures it is left as it is, manifest included,
+    /// so output never holds a backup missing files. With `delete_source` region files are removed after that,
+    /// as staging is removed by the next run
+    pub fn run(&self, plan: Plan) -> anyhow::Result<batch::Report> {
+        let input = &self.input;
+        let cancel_on_failure = |e: &anyhow::Error| crate::cancel_on_failure(e, self.fail_fast, self.cancel());
+
+        let report = match &self.operation {
+            Operation::Compact(options) => {
+                let (target, output) = &stage(&self.output)?;
+                let jobs = plan.jobs.into_iter().map(|mut job| {
+                    job.output = output.join(job.output.strip_prefix(&self.output).unwrap_or(&job.output));
+                    job
+                });
+
+                let worlds = self.worlds()?;
+                let links = plan.links.iter().filter_map(|x| Some(manifest::link_entry(input, &x.path, x.link.as_ref()?)));
+                let entries = Mutex::new(links.collect::<Vec<_>>());
+                let stored = batch::Stored::default();
+                // Sources are removed only once the backup holding their archives replaced output
+                let (delete_source, sources) = (options.delete_source, Mutex::new(vec![]));
+                let file_options = CompactOptions {
+                    delete_source: false,
+                    fsync: match options.delete_source {
+                        true => options.fsync.max(Fsync::File),
+                        false => options.fsync,
+                    },
+                    ..options.clone()
+                };
+                let report = batch::run(jobs.collect(), self.threads, self.fail_fast, self.progress, |job| {
+                    // Region files get an archive name, other files keep theirs
+                    let passthrough = !batch::is_archive(&job.output);
+                    let holder = match passthrough && self.passthrough.dedupe {
+                        true => stored.holder(job)?,
+                        false => None,
+                    };
+                    let mut entry = match holder {
+                        Some(holder) => manifest::duplicate_entry(input, &job.input, &holder, job.size),
+                        None if passthrough && rpack::file::is_stored(&job.output) => {
+                            rpack::file::compress(&job.input, &job.output, options.encode.rpack.compression, options.encode.rpack.level)?;
+                            manifest::entry(input, output, job, passthrough)?
+                        },
+                        None if passthrough => {
+                            batch::copy(job)?;
+                            manifest::entry(input, output, job, passthrough)?
+                        },
+                        None => {
+                            let mut options = self.options_for(&file_options, &worlds, &job.input)?;
+                            // Chunks left out go where the archive goes in the output
+                            let relative = job.output.strip_prefix(output).unwrap_or(&job.output);
+                            options.undo = options.undo.map(|x| x.join(relative));
+                            // Regions fully outside of the border get no archive at all
+                            let encode = &options.encode;
+                            if encode.border.is_some() && !(0..1024).any(|pos| encode.inside_border(pos)) {
+                                if let Some(undo) = &options.undo {
+                                    write_pruned(&job.input, undo, encode, options.fsync)?;
+                                }
+                                if let Some(audit) = &encode.audit {
+                                    self.audit_dropped(&audit.for_file(&job.input), encode)?;
+                                }
+                                return Ok(());
+                            }
+                            check_compact_options(encode)?;
+                            compact_file(&job.input, Some(&job.output), &options).inspect_err(cancel_on_failure)?;
+                            if delete_source {
+                                sources.lock().unwrap().push(job.input.clone());
+                            }
+                            manifest::entry(input, output, job, passthrough)?
+                        },
+                    };
+                    if self.preserve_perms {
+                        entry.perms = manifest::Perms::of(&job.input)?;
+                    }
+                    entries.lock().unwrap().push(entry);
+                    Ok(())
+                });
+                manifest::write(output, entries.into_inner().unwrap())?;
+                let fsync = match delete_source {
+                    true => options.fsync.max(Fsync::Dir),
+                    false => options.fsync,
+                };
+                if finish(output, target, &report, fsync)? {
+                    for source in sources.into_inner().unwrap() {
+                        std::fs::remove_file(&source).with_context(|| format!("Unable to remove {}", source.display()))?;
+                    }
+                }
+                report
+            },
+            Operation::Decompact(options) => {
+                let output = &self.output;
+                let report = batch::run(plan.jobs, self.threads, self.fail_fast, self.progress, |job| {
+                    if rpack::file::is_stored(&job.input) {
+                        return rpack::file::decompress(&job.input, &job.output);
+                    }
+                    if !batch::is_archive(&job.input) {
+                        return batch::copy(job);
+                    }
+                    let options = DecompactOptions {
+                        decode: rpack::DecodeOptions { format: (self.region_format)(&job.output)?, ..options.decode.clone() },
+                        ..options.clone()
+                    };
+                    check_decompact_options(&options)?;
+                    decompact_file(Some(&job.input), &job.output, &options).inspect_err(cancel_on_failure)
+                });
+                // Hard links need the files they point to, so links come last
+                if input.join(manifest::MANIFEST_NAME).is_file() {
+                    let manifest = manifest::read(input)?;
+                    let duplicates = manifest::restore_duplicates(&manifest, output)?;
+                    if duplicates > 0 {
+                        println!("Restored {duplicates} duplicate files");
+                    }
+                    let links = manifest::restore_links(&manifest, output)?;
+                    if links > 0 {
+                        println!("Restored {links} links");
+                    }
+                    if self.preserve_perms {
+                        let (modes, owners) = manifest::restore_perms(&manifest, output)?;
+                        println!("Restored permissions of {modes} files");
+                        if owners < modes {
+                            eprintln!("Ownership of {} files not restored, changing it needs root", modes - owners);
+                        }
+                    }
+                }
+                report
+            },
+        };
+        Ok(report)
+    }
+}
+
+/// Bytes available to unprivileged users on the filesystem `path` is or would be created on. Only known on Linux
+fn free_space(path: &Path) -> Option<u64> {
+    let existing = path.ancestors().find(|x| x.exists()).filter(|x| !x.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = std::ffi::CString::new(existing.as_os_str().as_bytes()).ok()?;
+        let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+        // SAFETY: path is NUL terminated and stat is written by a successful call
+        let stat = match unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } {
+            0 => unsafe { stat.assume_init() },
+            _ => return None,
+        };
+        Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = existing;
+        None
+    }
+}
+
+/// Path next to `output` named like it with `suffix` appended
+fn sibling(output: &Path, suffix: &str) -> anyhow::Result<PathBuf> {
+    let mut name = output.file_name().context("Output directory needs a name")?.to_owned();
+    name.push(suffix);
+    Ok(output.with_file_name(name))
+}
+
+/// Refuses output holding other files than a previous backup, those would be lost when it is replaced
+fn check_output(output: &Path) -> anyhow::Result<()> {
+    if !output.exists() || output.join(manifest::MANIFEST_NAME).is_file() {
+        return Ok(());
+    }
+    let mut files = std::fs::read_dir(output).with_context(|| format!("Unable to read directory {}", output.display()))?;
+    anyhow::ensure!(
+        files.next().is_none(),
+        "Output directory {} is not empty and holds no backup, pass a new or empty directory",
+        output.display()
+    );
+    Ok(())
+}
+
+/// Output directory of backup, `.` or `..` resolved to their absolute path, and its staging directory
+/// `<output>.partial` created empty. Files are written into staging and moved to the output by [`finish`]
+pub fn stage(output: &Path) -> anyhow::Result<(PathBuf, PathBuf)> {
+    let target = match output.file_name() {
+        Some(_) => output.to_path_buf(),
+        None => output.canonicalize().with_context(|| format!("Unable to resolve {}", output.display()))?,
+    };
+    check_output(&target)?;
+    let staging = sibling(&target, ".partial")?;
+    if staging.exists() {
+        eprintln!("Removing {} left by an unfinished run", staging.display());
+        std::fs::remove_dir_all(&staging).with_context(|| format!("Unable to remove {}", staging.display()))?;
+    }
+    std::fs::create_dir_all(&staging).with_context(|| format!("Unable to create {}", staging.display()))?;
+    Ok((target, staging))
+}
+
+/// Commits backup staged by [`stage`] once every file of `report` succeeded, otherwise leaves the output as it was.
+/// Returns whether output was replaced
+pub fn finish(staging: &Path, output: &Path, report: &batch::Report, fsync: Fsync) -> anyhow::Result<bool> {
+    match report.count(|x| matches!(x, batch::Outcome::Failed(_))) {
+        0 => commit(staging, output, fsync).map(|_| true),
+        _ => {
+            eprintln!("Backup incomplete, {} left as it was. Files packed are in {}", output.display(), staging.display());
+            Ok(false)
+        },
+    }
+}
+
+/// Moves backup staged in `staging` to `output`, see [`Session::run`]. A previous backup there is moved aside first and removed once the
+/// new one is in place, so a crash in between leaves it as `<output>.old`
+fn commit(staging: &Path, output: &Path, fsync: Fsync) -> anyhow::Result<()> {
+    let rename = |from: &Path, to: &Path| {
+        std::fs::rename(from, to).with_context(|| format!("Unable to rename {} to {}", from.display(), to.display()))
+    };
+    let mut old = None;
+    if output.exists() {
+        match output.join(manifest::MANIFEST_NAME).is_file() {
+            true => {
+                let path = sibling(output, ".old")?;
+                if path.exists() {
+                    std::fs::remove_dir_all(&path).with_context(|| format!("Unable to remove {}", path.display()))?;
+                }
+                rename(output, &path)?;
+                old = Some(path);
+            },
+            false => std::fs::remove_dir(output).with_context(|| format!("Unable to remove {}", output.display()))?,
+        }
+    }
+    rename(staging, output)?;
+
+    // Directories can be opened and synced only on Unix
+    #[cfg(unix)]
+    if fsync == Fsync::Dir {
+        let dir = output.parent().filter(|x| !x.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        std::fs::File::open(dir)
+            .and_then(|x| x.sync_all())
+            .with_context(|| format!("Unable to sync {}", dir.display()))?;
+    }
+    #[cfg(not(unix))]
+    let _ = fsync;
+
+    if let Some(old) = old {
+        std::fs::remove_dir_all(&old).with_context(|| format!("Unable to remove previous backup {}", old.display()))?;
+    }
+    Ok(())
+}
+
+/// Prints worlds found under `root` with their dimensions
+pub fn print_worlds(root: &Path) -> anyhow::Result<()> {
+    for world in discover(root)? {
+        let dimensions = world.dimensions.keys().map(String::as_str).collect::<Vec<_>>();
+        println!("World {}: {}", world.path.display(), dimensions.join(", "));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{fixture, TempDir};
+
+    #[test]
+    fn commit_replaces_backup() {
+        let dir = TempDir::new("session-commit");
+        let (output, staging) = (dir.join("backup"), dir.join("backup.partial"));
+        std::fs::create_dir_all(&output).unwrap();
+        std::fs::write(output.join("other"), b"").unwrap();
+        assert!(check_output(&output).is_err());
+
+        std::fs::write(output.join(manifest::MANIFEST_NAME), b"old").unwrap();
+        check_output(&output).unwrap();
+        std::fs::create_dir_all(&staging).unwrap();
+        std::fs::write(staging.join(manifest::MANIFEST_NAME), b"new").unwrap();
+        commit(&staging, &output, Fsync::None).unwrap();
+
+        assert_eq!(std::fs::read(output.join(manifest::MANIFEST_NAME)).unwrap(), b"new");
+        assert!(!output.join("other").exists() && !staging.exists() && !dir.join("backup.old").exists());
+    }
+
+    #[test]
+    fn sources_deleted_after_commit() {
+        let dir = TempDir::new("session-delete-source");
+        let (input, output) = (dir.join("world"), dir.join("backup"));
+        std::fs::create_dir_all(input.join("region")).unwrap();
+        std::fs::copy(fixture("basic"), input.join("region/r.0.0.mca")).unwrap();
+        // Chunk 1,0 does not decompress
+        std::fs::copy(fixture("corrupted"), input.join("region/r.1.0.mca")).unwrap();
+
+        let session = Session {
+            input: input.clone(),
+            output: output.clone(),
+            operation: Operation::Compact(CompactOptions { delete_source: true, ..Default::default() }),
+            border: None,
+            profiles: BTreeMap::new(),
+            links: batch::LinkPolicy::Read,
+            preserve_perms: false,
+            passthrough: batch::Passthrough::default(),
+            region_format: Box::new(|_| Ok(RegionFormat::VANILLA)),
+            threads: NonZeroUsize::MIN,
+            fail_fast: false,
+            progress: false,
+        };
+        let report = session.run(session.plan().unwrap()).unwrap();
+        assert_eq!(report.count(|x| matches!(x, batch::Outcome::Failed(_))), 1);
+        assert!(input.join("region/r.0.0.mca").exists() && input.join("region/r.1.0.mca").exists());
+        assert!(dir.join("backup.partial/region/r.0.0.mca.rpack").exists() && !output.exists());
+
+        // The next run removes staging of the failed one
+        std::fs::remove_file(input.join("region/r.1.0.mca")).unwrap();
+        let report = session.run(session.plan().unwrap()).unwrap();
+        assert_eq!(report.count(|x| matches!(x, batch::Outcome::Failed(_))), 0);
+        assert!(!input.join("region/r.0.0.mca").exists() && output.join("region/r.0.0.mca.rpack").exists());
+    }
+}
@@ -0,0 +1,218 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use crate::{
+    chunk,
+    nbt::{self, Compound, Tag},
+    region::{self, RegionInfo, RegionReader},
+    world,
+};
+
+#[derive(Debug, clap::Args)]
+pub struct StatsArgs {
+    /// Region file or directory to search region files in
+    #[arg(short, long)]
+    pub input: PathBuf,
+
+    /// Count biome occurrences. Since 1.18 each section holds 4x4x4 biome cells
+    #[arg(long)]
+    pub biomes: bool,
+
+    /// Count block entities (chests, hoppers, spawners, ...)
+    #[arg(long)]
+    pub block_entities: bool,
+
+    /// Count entities. Since 1.17 entities are stored in separate `entities` region files
+    #[arg(long)]
+    pub entities: bool,
+
+    /// How many of the busiest chunks to list when counting block entities or entities
+    #[arg(long, default_value_t = 20)]
+    pub top: usize,
+}
+
+#[derive(Debug, Default)]
+struct ChunkCounts {
+    block_entities: HashMap<String, u64>,
+    entities: HashMap<String, u64>,
+}
+
+impl ChunkCounts {
+    fn total(&self) -> u64 {
+        self.block_entities.values().sum::<u64>() + self.entities.values().sum::<u64>()
+    }
+}
+
+pub fn run(args: StatsArgs) -> anyhow::Result<()> {
+    let files = world::region_files(&args.input)?;
+
+    let mut biomes = HashMap::<String, u64>::new();
+    let mut aggregate = ChunkCounts::default();
+    let mut chunks = Vec::<(&Path, (i32, i32), ChunkCounts)>::new();
+
+    for file in files.iter() {
+        let region = region::region_coords_from_path(file);
+        let mut reader = RegionReader::open(file)?;
+
+        reader
+            .decompress_all(|_, pos, data| {
+                let (x, z) = RegionInfo::chunk_coords(region, pos);
+                let root = nbt::read_compound(data).with_context(|| format!("Chunk {x},{z}"))?;
+
+                if args.biomes {
+                    count_biomes(&root, &mut biomes);
+                }
+
+                if args.block_entities || args.entities {
+                    let mut counts = ChunkCounts::default();
+                    if args.block_entities {
+                        count_ids(block_entities(&root), &mut counts.block_entities);
+                        count_ids(block_entities(&root), &mut aggregate.block_entities);
+                    }
+                    if args.entities {
+                        count_ids(entities(&root), &mut counts.entities);
+                        count_ids(entities(&root), &mut aggregate.entities);
+                    }
+                    if counts.total() != 0 {
+                        chunks.push((file, (x, z), counts));
+                    }
+                }
+
+                Ok(())
+            })
+            .with_context(|| format!("{}", file.display()))?;
+    }
+
+    if args.biomes {
+        let total = biomes.values().sum::<u64>();
+        println!("Biomes ({total} cells):");
+        for (name, count) in sorted(&biomes) {
+            println!("  {name:<40} {count:>12} {:>6.2}%", count as f64 * 100.0 / total as f64);
+        }
+    }
+
+    if args.block_entities || args.entities {
+        chunks.sort_by_key(|x| core::cmp::Reverse(x.2.total()));
+
+        println!("Busiest chunks:");
+        for (file, (x, z), counts) in chunks.iter().take(args.top) {
+            let ids = sorted(&counts.block_entities)
+                .into_iter()
+                .chain(sorted(&counts.entities))
+                .map(|(name, count)| format!("{name} {count}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            println!("  {} chunk {x},{z}: {} ({ids})", file.display(), counts.total());
+        }
+
+        if args.block_entities {
+            let total = aggregate.block_entities.values().sum::<u64>();
+            println!("Block entities ({total}):");
+            for (name, count) in sorted(&aggregate.block_entities) {
+                println!("  {name:<40} {count:>12}");
+            }
+        }
+
+        if args.entities {
+            let total = aggregate.entities.values().sum::<u64>();
+            println!("Entities ({total}):");
+            for (name, count) in sorted(&aggregate.entities) {
+                println!("  {name:<40} {count:>12}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Most frequent first
+fn sorted(counts: &HashMap<String, u64>) -> Vec<(&str, u64)> {
+    let mut counts = counts
+        .iter()
+        .map(|(name, count)| (name.as_str(), *count))
+        .collect::<Vec<_>>();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+    counts
+}
+
+fn count_ids(list: &[Tag], counts: &mut HashMap<String, u64>) {
+    for item in list.iter().filter_map(Tag::as_compound) {
+        let id = item.get("id").and_then(Tag::as_str).unwrap_or("<unknown>");
+        *counts.entry(id.to_owned()).or_default() += 1;
+    }
+}
+
+fn block_entities(root: &Compound) -> &[Tag] {
+    let legacy = root
+        .get("Level")
+        .and_then(Tag::as_compound)
+        .and_then(|x| x.get("TileEntities"));
+
+    legacy
+        .or_else(|| root.get("block_entities"))
+        .and_then(Tag::as_list)
+        .unwrap_or_default()
+}
+
+/// Entities of entity chunk (1.17+) or legacy terrain chunk
+fn entities(root: &Compound) -> &[Tag] {
+    let legacy = root
+        .get("Level")
+        .and_then(Tag::as_compound)
+        .and_then(|x| x.get("Entities"));
+
+    legacy
+        .or_else(|| root.get("Entities"))
+        .and_then(Tag::as_list)
+        .unwrap_or_default()
+}
+
+fn count_biomes(root: &Compound, biomes: &mut HashMap<String, u64>) {
+    let legacy = root
+        .get("Level")
+        .and_then(Tag::as_compound)
+        .and_then(|x| x.get("Biomes"));
+
+    // Before 1.18 biomes were numeric ids stored per column (or per 4x4x4 cell since 1.15)
+    match legacy {
+        Some(Tag::IntArray(ids)) => {
+            ids.iter().for_each(|id| *biomes.entry(format!("#{id}")).or_default() += 1);
+            return;
+        },
+        Some(Tag::ByteArray(ids)) => {
+            ids.iter().for_each(|&id| *biomes.entry(format!("#{}", id as u8)).or_default() += 1);
+            return;
+        },
+        _ => {},
+    }
+
+    for section in chunk::sections(root).iter().filter_map(Tag::as_compound) {
+        let Some(container) = section.get("biomes").and_then(Tag::as_compound) else {
+            continue;
+        };
+        let palette = container
+            .get("palette")
+            .and_then(Tag::as_list)
+            .unwrap_or_default();
+        let data = match container.get("data") {
+            Some(Tag::LongArray(x)) => Some(x.as_slice()),
+            _ => None,
+        };
+
+        let mut counts = vec![0u64; palette.len()];
+        for index in chunk::unpack_palette_indices(data, palette.len(), 64, 0) {
+            if let Some(x) = counts.get_mut(index) {
+                *x += 1;
+            }
+        }
+
+        for (entry, count) in palette.iter().zip(counts).filter(|x| x.1 != 0) {
+            let name = entry.as_str().unwrap_or("<invalid>");
+            *biomes.entry(name.to_owned()).or_default() += count;
+        }
+    }
+}
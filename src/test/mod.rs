@@ -0,0 +1,206 @@
+use std::{
+    io::{BufRead, BufReader},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Mutex,
+    },
+};
+
+use anyhow::{bail, ensure, Context};
+
+use crate::{batch, chunk, region::RegionInfo, rpack, Limits};
+
+#[derive(Debug, clap::Args)]
+pub struct TestArgs {
+    /// Archives to test. Directories are searched for archives recursively
+    #[arg(required = true)]
+    pub inputs: Vec<PathBuf>,
+
+    /// Test every archive and report all failures instead of stopping at the first one
+    #[arg(long)]
+    pub keep_going: bool,
+
+    /// Number of threads decoding chunks. Defaults to the number of CPUs
+    #[arg(short, long)]
+    pub jobs: Option<NonZeroUsize>,
+
+    /// Decode only PERCENT of chunks picked at random, like `5%`, for a quick check of large backups.
+    /// Archives are still read to the end, so a broken stream is found anyway
+    #[arg(long, value_name = "PERCENT", value_parser = parse_percent)]
+    pub sample: Option<f64>,
+}
+
+/// Parses percentage `5%` or `5` into fraction
+fn parse_percent(s: &str) -> anyhow::Result<f64> {
+    let percent = s.strip_suffix('%').unwrap_or(s).trim().parse::<f64>().with_context(|| format!("Invalid percentage {s}"))?;
+    ensure!(percent > 0.0 && percent <= 100.0, "Percentage must be above 0 and at most 100");
+    Ok(percent / 100.0)
+}
+
+/// Chunks of archive
+#[derive(Debug, Default)]
+struct Tested {
+    chunks: usize,
+    /// Chunks decoded, less than `chunks` with `--sample`
+    decoded: usize,
+    errors: Vec<anyhow::Error>,
+}
+
+/// Picks chunks for `--sample` with xorshift, seeded differently every run
+struct Sampler {
+    fraction: Option<f64>,
+    state: u64,
+}
+
+impl Sampler {
+    fn new(fraction: Option<f64>) -> Self {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |x| x.as_nanos() as u64);
+        Self {
+            fraction,
+            // Xorshift state must not be zero
+            state: (nanos ^ (std::process::id() as u64) << 32) | 1,
+        }
+    }
+
+    fn pick(&mut self) -> bool {
+        let Some(fraction) = self.fraction else {
+            return true;
+        };
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        ((self.state >> 11) as f64 / (1u64 << 53) as f64) < fraction
+    }
+}
+
+/// Decodes every chunk of archive without writing anything. Checksums are verified when present,
+/// chunks of raw archives are decompressed too. The archive is read on the calling thread, chunks are decoded
+/// by `threads` workers and their errors are reported in archive order
+fn test_archive(path: &Path, args: &TestArgs, threads: NonZeroUsize) -> anyhow::Result<Tested> {
+    let mut reader = BufReader::new(rpack::volume::open(path)?);
+    ensure!(reader.fill_buf()?.starts_with(&rpack::MAGIC), "Not an rpack archive");
+
+    let limits = Limits::default();
+    let mut sampler = Sampler::new(args.sample);
+    let mut tested = Tested::default();
+    let stop = AtomicBool::new(false);
+    while reader.fill_buf()?.starts_with(&rpack::MAGIC) {
+        let mut rpackreader = rpack::RpackReader::from_buf_reader(reader, limits)?;
+        let raw = rpackreader.raw();
+        // Bounded, so reading does not run far ahead of decoding
+        let (sender, receiver) = mpsc::sync_channel::<(usize, rpack::RpackRecord, Vec<u8>)>(threads.get() * 16);
+        let receiver = Mutex::new(receiver);
+        let errors = Mutex::new(vec![]);
+
+        let read = std::thread::scope(|scope| {
+            for _ in 0..threads.get() {
+                let mut decoder = rpackreader.decoder();
+                let (receiver, errors, stop) = (&receiver, &errors, &stop);
+                scope.spawn(move || {
+                    let (mut payload, mut nbt) = (vec![], vec![]);
+                    loop {
+                        // Lock is released before decoding
+                        let job = receiver.lock().unwrap().recv();
+                        let Ok((index, record, stored)) = job else { break };
+                        let decoded = decoder.decode(&record, &stored, &mut payload).and_then(|data| match raw {
+                            true => chunk::decompress_stored(data, &mut nbt, limits.max_decompressed_size).map(drop),
+                            false => Ok(()),
+                        });
+                        nbt.clear();
+                        if let Err(e) = decoded {
+                            let (x, z) = RegionInfo::local_coords(record.chunk.pos);
+                            errors.lock().unwrap().push((index, e.context(format!("Chunk {x},{z}"))));
+                            if !args.keep_going {
+                                stop.store(true, Ordering::Relaxed);
+                            }
+                        }
+                    }
+                });
+            }
+
+            send_records(&mut rpackreader, sender, &mut sampler, &mut tested, &stop)
+        });
+
+        let mut errors = errors.into_inner().unwrap();
+        errors.sort_by_key(|x| x.0);
+        tested.errors.extend(errors.into_iter().map(|x| x.1));
+        if !args.keep_going && !tested.errors.is_empty() {
+            return Err(tested.errors.remove(0));
+        }
+        read?;
+        reader = rpackreader.into_inner()?;
+    }
+    ensure!(reader.fill_buf()?.is_empty(), "Unexpected data after archive");
+    Ok(tested)
+}
+
+/// Sends records to decoding threads numbered in archive order, except ones left out by `--sample`.
+/// Decoding threads finish once `sender` is dropped on return
+fn send_records<R: BufRead>(
+    reader: &mut rpack::RpackReader<R>,
+    sender: mpsc::SyncSender<(usize, rpack::RpackRecord, Vec<u8>)>,
+    sampler: &mut Sampler,
+    tested: &mut Tested,
+    stop: &AtomicBool,
+) -> anyhow::Result<()> {
+    // A broken stream can not be read past, so only errors inside intact chunk data are collected
+    loop {
+        let mut stored = vec![];
+        let record = reader.read_record(&mut stored).with_context(|| format!("After {} chunks", tested.chunks))?;
+        let Some(record) = record.filter(|_| !stop.load(Ordering::Relaxed)) else {
+            return Ok(());
+        };
+        tested.chunks += 1;
+        if record.chunk.deleted || !sampler.pick() {
+            continue;
+        }
+        tested.decoded += 1;
+        sender.send((tested.chunks, record, stored)).context("Decoding threads stopped")?;
+    }
+}
+
+/// Tests archives like `gzip -t`, failing at the first broken one unless `--keep-going` is set
+pub fn run(args: TestArgs) -> anyhow::Result<()> {
+    let mut archives = vec![];
+    for input in args.inputs.iter() {
+        match input.is_dir() {
+            true => archives.extend(batch::decompact_jobs(input, Path::new(""))?.into_iter().map(|x| x.input)),
+            false => archives.push(input.clone()),
+        }
+    }
+
+    let threads = args
+        .jobs
+        .or_else(|| std::thread::available_parallelism().ok())
+        .unwrap_or(NonZeroUsize::MIN);
+    let mut failed = 0;
+    for archive in archives.iter() {
+        match test_archive(archive, &args, threads) {
+            Ok(tested) if tested.errors.is_empty() && args.sample.is_some() => {
+                println!("  ok       {}: {} of {} chunks sampled", archive.display(), tested.decoded, tested.chunks)
+            },
+            Ok(tested) if tested.errors.is_empty() => println!("  ok       {}: {} chunks", archive.display(), tested.chunks),
+            Ok(tested) => {
+                println!("  failed   {}: {} of {} chunks broken", archive.display(), tested.errors.len(), tested.chunks);
+                for e in tested.errors {
+                    println!("           {e:#}");
+                }
+                failed += 1;
+            },
+            Err(e) if !args.keep_going => return Err(e.context(archive.display().to_string())),
+            Err(e) => {
+                println!("  failed   {}: {e:#}", archive.display());
+                failed += 1;
+            },
+        }
+    }
+
+    if failed > 0 {
+        bail!("{failed} of {} archives failed", archives.len());
+    }
+    Ok(())
+}
@@ -0,0 +1,223 @@
+//! Generator of random but valid region files for round-trip tests.
+//! Deterministic for the same seed and has no dependencies, so it can be driven by any test harness.
+//! Also temporary directories, fixtures and chunk listings shared by tests of the library and the command line.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    nbt::{self, Compound, Tag},
+    region::{ChunkInfo, RegionInfo, RegionReader},
+};
+
+#[derive(Debug, Clone)]
+pub struct RegionGenerator {
+    /// Region coordinates used for xPos/zPos of generated chunks
+    pub region: (i32, i32),
+    pub max_chunks: u16,
+    /// Upper bound of filler bytes in a chunk. Chunks of several sectors need a few kilobytes of noise
+    pub max_filler: usize,
+    /// Leave random unused sectors between chunks like the game does after chunks shrink
+    pub gaps: bool,
+    /// Compression type bytes to choose from
+    pub codecs: Vec<u8>,
+}
+
+impl Default for RegionGenerator {
+    fn default() -> Self {
+        Self {
+            region: (0, 0),
+            max_chunks: 64,
+            max_filler: 16 * 1024,
+            gaps: true,
+            codecs: vec![1, 2, 3],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneratedChunk {
+    pub pos: u16,
+    pub timestamp: u32,
+    pub compression_type: u8,
+    /// Uncompressed NBT
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct GeneratedRegion {
+    pub bytes: Vec<u8>,
+    /// In header slot order
+    pub chunks: Vec<GeneratedChunk>,
+}
+
+impl RegionGenerator {
+    pub fn generate(&self, seed: u64) -> GeneratedRegion {
+        let mut rng = SplitMix64(seed);
+
+        let count = rng.below(self.max_chunks.min(RegionInfo::MAX_CHUNK_COUNT) as u64 + 1) as usize;
+        let mut slots = (0..RegionInfo::MAX_CHUNK_COUNT).collect::<Vec<_>>();
+        rng.shuffle(&mut slots);
+        // Shuffled slots also give random placement order in file
+        slots.truncate(count);
+
+        let mut bytes = vec![0u8; RegionInfo::SIZE as usize];
+        let mut chunks = vec![];
+
+        for pos in slots {
+            if self.gaps && rng.below(4) == 0 {
+                let gap = rng.below(3) as usize + 1;
+                bytes.resize(bytes.len() + gap * ChunkInfo::SECTOR_SIZE as usize, 0);
+            }
+
+            let timestamp = rng.next_u64() as u32;
+            let compression_type = self.codecs[rng.below(self.codecs.len() as u64) as usize];
+            let payload = self.payload(&mut rng, pos);
+            let compressed = compress(compression_type, &payload);
+
+            let location = bytes.len() as u64;
+            bytes.extend((compressed.len() as u32 + 1).to_be_bytes());
+            bytes.push(compression_type);
+            bytes.extend(&compressed);
+            let size = (bytes.len() as u64 - location).next_multiple_of(ChunkInfo::SECTOR_SIZE as u64);
+            bytes.resize((location + size) as usize, 0);
+
+            let info = ChunkInfo::new(location.try_into().unwrap(), size.try_into().unwrap(), timestamp)
+                .expect("Generated chunk must fit into region");
+            bytes[pos as usize * 4..][..4].copy_from_slice(&info.locdata.get().to_ne_bytes());
+            bytes[4096 + pos as usize * 4..][..4].copy_from_slice(&timestamp.to_be_bytes());
+
+            chunks.push(GeneratedChunk {
+                pos,
+                timestamp,
+                compression_type,
+                payload,
+            });
+        }
+
+        chunks.sort_by_key(|x| x.pos);
+        GeneratedRegion { bytes, chunks }
+    }
+
+    fn payload(&self, rng: &mut SplitMix64, pos: u16) -> Vec<u8> {
+        let (local_x, local_z) = RegionInfo::local_coords(pos);
+        let (rx, rz) = self.region;
+
+        // Low entropy noise, so compressed chunks still vary in size
+        let filler_len = rng.below(self.max_filler as u64 + 1) as usize;
+        let filler = (0..filler_len).map(|_| (rng.next_u64() % 4) as i8).collect();
+
+        let root = Compound(vec![
+            ("DataVersion".into(), Tag::Int(3465)),
+            ("xPos".into(), Tag::Int(rx * 32 + local_x as i32)),
+            ("zPos".into(), Tag::Int(rz * 32 + local_z as i32)),
+            ("Status".into(), Tag::String("minecraft:full".into())),
+            ("LastUpdate".into(), Tag::Long(rng.next_u64() as i64)),
+            ("filler".into(), Tag::ByteArray(filler)),
+        ]);
+
+        let mut payload = vec![];
+        nbt::write_compound(&mut payload, &root).expect("Generated NBT must be serializable");
+        payload
+    }
+}
+
+fn compress(compression_type: u8, data: &[u8]) -> Vec<u8> {
+    let level = flate2::Compression::fast();
+    let mut out = vec![];
+    match compression_type {
+        1 => {
+            let mut encoder = flate2::write::GzEncoder::new(&mut out, level);
+            encoder.write_all(data).and_then(|_| encoder.finish()).unwrap();
+        },
+        2 => {
+            let mut encoder = flate2::write::ZlibEncoder::new(&mut out, level);
+            encoder.write_all(data).and_then(|_| encoder.finish()).unwrap();
+        },
+        _ => out.extend_from_slice(data),
+    }
+    out
+}
+
+/// Small PRNG, good enough for test data
+#[derive(Debug, Clone)]
+pub struct SplitMix64(pub u64);
+
+impl SplitMix64 {
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n.max(1)
+    }
+
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            items.swap(i, self.below(i as u64 + 1) as usize);
+        }
+    }
+}
+
+/// Empty directory under the system temp directory, removed with its contents when dropped. Named after the test,
+/// the process and a counter, so tests running in parallel never share one
+#[derive(Debug)]
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        static CREATED: AtomicUsize = AtomicUsize::new(0);
+        let count = CREATED.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("{name}-{}-{count}", std::process::id()));
+        std::fs::remove_dir_all(&path).ok();
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.0.join(path)
+    }
+
+    /// Copies region file of fixture `name` into the directory as `file`, returns its path
+    pub fn copy_fixture(&self, name: &str, file: impl AsRef<Path>) -> PathBuf {
+        let path = self.join(file);
+        std::fs::copy(fixture(name), &path).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+/// Region file of fixture `name` in `tests/fixtures`, see the README there
+pub fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name).join("r.0.0.mca")
+}
+
+/// Slot, timestamp and uncompressed NBT of every chunk of region file, in slot order
+pub fn chunks(path: &Path) -> Vec<(u16, u32, Vec<u8>)> {
+    let mut chunks = vec![];
+    RegionReader::open(path)
+        .unwrap()
+        .decompress_all(|info, pos, nbt| {
+            chunks.push((pos, info.timestamp.get(), nbt.clone()));
+            Ok(())
+        })
+        .unwrap();
+    chunks.sort_by_key(|x| x.0);
+    chunks
+}
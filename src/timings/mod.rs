@@ -0,0 +1,139 @@
+//! Time spent in phases of compacting and decompacting, summed over all files and threads, see `--timings`.
+//! Phases nest: time of a phase measured inside another one is subtracted from the outer phase.
+
+use std::{
+    cell::Cell,
+    io::{Read, Seek, SeekFrom, Write},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Reading input files
+    Read,
+    /// Decompressing chunks
+    Inflate,
+    /// Compressing chunks
+    Deflate,
+    /// Writing output files
+    Write,
+    /// Chunk checks, migrations and other work between decompressing and compressing
+    Other,
+}
+
+impl Phase {
+    const ALL: [Phase; 5] = [Phase::Read, Phase::Inflate, Phase::Deflate, Phase::Write, Phase::Other];
+
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Read => "read",
+            Phase::Inflate => "inflate",
+            Phase::Deflate => "deflate",
+            Phase::Write => "write",
+            Phase::Other => "other",
+        }
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Nanoseconds per phase
+static TOTALS: [AtomicU64; 5] = [const { AtomicU64::new(0) }; 5];
+
+thread_local! {
+    /// Time of phases measured inside the current one
+    static NESTED: Cell<Duration> = const { Cell::new(Duration::ZERO) };
+}
+
+/// Runs `f` counting its time to `phase`, except time of phases measured inside it. Only calls `f` unless enabled
+pub fn measure<T>(phase: Phase, f: impl FnOnce() -> T) -> T {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return f();
+    }
+
+    let start = Instant::now();
+    let outer = NESTED.replace(Duration::ZERO);
+    let result = f();
+    let elapsed = start.elapsed();
+    let nested = NESTED.replace(outer + elapsed);
+    TOTALS[phase as usize].fetch_add(elapsed.saturating_sub(nested).as_nanos() as u64, Ordering::Relaxed);
+    result
+}
+
+/// Reader or writer counting time of every call to a phase
+pub struct Timed<T> {
+    inner: T,
+    phase: Phase,
+}
+
+impl<T> Timed<T> {
+    pub fn new(inner: T, phase: Phase) -> Self {
+        Self { inner, phase }
+    }
+}
+
+impl<T: Read> Read for Timed<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        measure(self.phase, || self.inner.read(buf))
+    }
+}
+
+impl<T: Write> Write for Timed<T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        measure(self.phase, || self.inner.write(buf))
+    }
+
+    fn write_vectored(&mut self, bufs: &[std::io::IoSlice]) -> std::io::Result<usize> {
+        measure(self.phase, || self.inner.write_vectored(bufs))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        measure(self.phase, || self.inner.flush())
+    }
+}
+
+impl<T: Seek> Seek for Timed<T> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        measure(self.phase, || self.inner.seek(pos))
+    }
+}
+
+/// Breakdown of phases, displayed with hints at what bounds the run
+pub struct Report {
+    compact: bool,
+}
+
+impl Report {
+    /// Starts measuring. Hints name options of compacting or decompacting
+    pub fn start(compact: bool) -> Self {
+        ENABLED.store(true, Ordering::Relaxed);
+        Self { compact }
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let totals = TOTALS.each_ref().map(|x| Duration::from_nanos(x.load(Ordering::Relaxed)));
+        let sum = totals.iter().sum::<Duration>().max(Duration::from_nanos(1));
+        writeln!(f, "Time by phase, summed over threads:")?;
+        for phase in Phase::ALL {
+            let time = totals[phase as usize];
+            writeln!(f, "  {:<8} {:>9.3}s {:>5.1}%", phase.name(), time.as_secs_f64(), share(time, sum))?;
+        }
+
+        let compression = totals[Phase::Inflate as usize] + totals[Phase::Deflate as usize];
+        let io = totals[Phase::Read as usize] + totals[Phase::Write as usize];
+        if share(compression, sum) >= 60.0 && self.compact {
+            writeln!(f, "Compression-bound: try more --jobs, a faster --codec like lz4, a lower --level or --raw")?;
+        } else if share(compression, sum) >= 60.0 {
+            writeln!(f, "Compression-bound: try more --jobs, or --region-codec uncompressed if the server accepts it")?;
+        } else if share(io, sum) >= 60.0 {
+            writeln!(f, "IO-bound: more --jobs help on SSDs, otherwise the storage is the limit")?;
+        }
+        Ok(())
+    }
+}
+
+fn share(time: Duration, sum: Duration) -> f64 {
+    time.as_secs_f64() * 100.0 / sum.as_secs_f64()
+}
@@ -0,0 +1,84 @@
+//! Rewriting header timestamps of chunks in place, so pruners going by last save time keep them
+
+use std::path::PathBuf;
+
+use anyhow::{bail, Context};
+
+use crate::{
+    deletechunks::ChunkSelection,
+    region::{self, RegionFile, RegionInfo},
+};
+
+#[derive(Debug, clap::Args)]
+pub struct TouchChunksArgs {
+    /// Region file, edited in place
+    #[arg(short, long)]
+    pub input: PathBuf,
+
+    /// Set timestamps to the current time
+    #[arg(long, conflicts_with = "set")]
+    pub set_now: bool,
+
+    /// Set timestamps to UNIX time in seconds
+    #[arg(long, value_name = "SECONDS")]
+    pub set: Option<u32>,
+
+    /// Chunks to touch
+    #[command(flatten)]
+    pub selection: ChunkSelection,
+}
+
+pub fn run(args: TouchChunksArgs) -> anyhow::Result<()> {
+    let timestamp = match (args.set, args.set_now) {
+        (Some(x), _) => x,
+        (None, true) => std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .context("System clock is before 1970")?
+            .as_secs() as u32,
+        (None, false) => bail!("Pass the new timestamp with --set-now or --set"),
+    };
+    let (listed, slots) = args.selection.slots(&args.input)?;
+    let region = region::region_coords_from_path(&args.input);
+
+    let mut file = RegionFile::open(&args.input)?;
+    let mut touched = 0;
+    for pos in slots {
+        match file.set_timestamp(pos, timestamp)? {
+            Some(_) => touched += 1,
+            None if listed.contains(&pos) => {
+                let (x, z) = RegionInfo::chunk_coords(region, pos);
+                eprintln!("Chunk {x},{z} is not generated");
+            },
+            None => {},
+        }
+    }
+    file.into_inner()?;
+    println!("Set timestamp of {touched} chunks in {} to {timestamp}", args.input.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deletechunks::ChunkSelection, testutil::TempDir};
+
+    #[test]
+    fn only_timestamp_changes() {
+        let dir = TempDir::new("touch-chunks");
+        let input = dir.copy_fixture("basic", "r.0.0.mca");
+        let original = std::fs::read(&input).unwrap();
+
+        run(TouchChunksArgs {
+            input: input.clone(),
+            set_now: false,
+            set: Some(0x12345678),
+            // Chunk 2,2 is not generated and stays so
+            selection: ChunkSelection { chunks: vec![(1, 1), (2, 2)], area: vec![] },
+        })
+        .unwrap();
+
+        let mut expected = original.clone();
+        expected[4096 + 33 * 4..4096 + 33 * 4 + 4].copy_from_slice(&0x12345678u32.to_be_bytes());
+        assert_eq!(std::fs::read(&input).unwrap(), expected);
+    }
+}
@@ -0,0 +1,143 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    path::PathBuf,
+};
+
+use anyhow::{ensure, Context};
+
+use crate::{delta, rpack, Limits};
+
+#[derive(Debug, clap::Args)]
+pub struct TranscodeArgs {
+    /// Archive to convert, of any version. Split archives are read from all their volumes
+    #[arg(short, long)]
+    pub input: PathBuf,
+
+    /// Archive of the current version with the same chunks, timestamps and removals
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// Compression of chunks in the new archive. Keeps the one of the input by default
+    #[arg(long, value_enum)]
+    pub codec: Option<rpack::Compression>,
+
+    /// Compression level
+    #[arg(long, default_value_t = 3)]
+    pub level: i32,
+
+    /// Compress every chunk on its own, so chunks of solid input can be decoded separately
+    #[arg(long, conflicts_with = "solid")]
+    pub per_chunk: bool,
+
+    /// Compress all chunks as one zstd stream
+    #[arg(long)]
+    pub solid: bool,
+}
+
+impl TranscodeArgs {
+    /// Options of the archive replacing one read by `reader`. Layout, checksums and dictionary are kept unless
+    /// arguments change them, the dictionary only while chunks stay zstd compressed
+    fn options<R: BufRead>(&self, reader: &rpack::RpackReader<R>) -> anyhow::Result<rpack::Options> {
+        let kept = reader.options();
+        let solid = (kept.solid || self.solid) && !self.per_chunk;
+        let compression = match self.codec.unwrap_or(kept.compression) {
+            // Chunks of solid archives are compressed by the stream
+            rpack::Compression::None if solid && !kept.solid => rpack::Compression::Zstd,
+            x => x,
+        };
+        ensure!(
+            !solid || matches!(compression, rpack::Compression::None | rpack::Compression::Zstd),
+            "Solid archive supports only zstd compression, pass --per-chunk to use {compression:?}"
+        );
+        let zstd = matches!(compression, rpack::Compression::Zstd | rpack::Compression::Auto);
+        Ok(rpack::Options {
+            compression,
+            level: self.level,
+            solid,
+            rolling: kept.rolling && solid && compression == rpack::Compression::Zstd,
+            dictionary: kept.dictionary.filter(|_| zstd),
+            ..kept
+        })
+    }
+}
+
+/// Rewrites archive with other compression or layout without going through a region file. Payloads are
+/// decompressed and compressed again, so checksums of the input are verified and written anew over the same data.
+/// Concatenated archives stay concatenated, each converted on its own
+pub fn run(args: TranscodeArgs) -> anyhow::Result<()> {
+    crate::check_archive_paths(&args.input, &args.output)?;
+    let mut reader = BufReader::new(rpack::volume::open(&args.input)?);
+    ensure!(reader.fill_buf()?.starts_with(&rpack::MAGIC), "{} is not an rpack archive", args.input.display());
+
+    let transcode = |mut reader: BufReader<Box<dyn Read + Send>>| {
+        let mut output = delta::create(&args.output)?;
+        let mut payload = vec![];
+        let (mut archives, mut chunks) = (0, 0);
+        while reader.fill_buf()?.starts_with(&rpack::MAGIC) {
+            let mut rpackreader = rpack::RpackReader::from_buf_reader(reader, Limits::default())?;
+            let mut writer = rpack::RpackWriter::new(output, args.options(&rpackreader)?)?;
+            while let Some(chunk) = rpackreader.read_chunk(&mut payload)? {
+                match chunk.deleted {
+                    true => writer.write_deletion(chunk.pos)?,
+                    false => writer.write_chunk(chunk.pos, chunk.timestamp, &payload)?,
+                }
+                chunks += 1;
+            }
+            output = writer.finish()?;
+            reader = rpackreader.into_inner()?;
+            archives += 1;
+        }
+        ensure!(reader.fill_buf()?.is_empty(), "Unexpected data after archive");
+        output.flush()?;
+        output.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        anyhow::Ok((archives, chunks))
+    };
+    let (archives, chunks) = transcode(reader).inspect_err(|_| {
+        std::fs::remove_file(&args.output).ok();
+    })?;
+
+    let size = std::fs::metadata(&args.output).with_context(|| format!("Unable to read {}", args.output.display()))?.len();
+    match archives {
+        1 => println!("Transcoded {chunks} chunks into {}, {size} bytes", args.output.display()),
+        _ => println!("Transcoded {chunks} chunks of {archives} archives into {}, {size} bytes", args.output.display()),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn solid_to_per_chunk() {
+        let dir = TempDir::new("transcode");
+        let chunks = (0..10u16).map(|x| (x * 3, x as u32 + 100, vec![x as u8; 2000])).collect::<Vec<_>>();
+        let options = rpack::Options { compression: rpack::Compression::Zstd, solid: true, checksums: true, ..Default::default() };
+        let mut writer = rpack::RpackWriter::new(vec![], options).unwrap();
+        for (pos, timestamp, payload) in &chunks {
+            writer.write_chunk(*pos, *timestamp, payload).unwrap();
+        }
+        std::fs::write(dir.join("old.rpack"), writer.finish().unwrap()).unwrap();
+
+        run(TranscodeArgs {
+            input: dir.join("old.rpack"),
+            output: dir.join("new.rpack"),
+            codec: Some(rpack::Compression::Lz4),
+            level: 3,
+            per_chunk: true,
+            solid: false,
+        })
+        .unwrap();
+
+        let mut reader = rpack::RpackReader::new(std::fs::File::open(dir.join("new.rpack")).unwrap()).unwrap();
+        let options = reader.options();
+        assert!(!options.solid && options.checksums && options.compression == rpack::Compression::Lz4);
+        let mut payload = vec![];
+        let mut read = vec![];
+        while let Some(chunk) = reader.read_chunk(&mut payload).unwrap() {
+            read.push((chunk.pos, chunk.timestamp, payload.clone()));
+        }
+        assert_eq!(read, chunks);
+    }
+}
@@ -0,0 +1,79 @@
+use std::{io::Cursor, path::PathBuf};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    chunk::{self, Transform},
+    nbt,
+    region::{self, RegionInfo, RegionReader, RegionWriter},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Axis {
+    /// Swap east and west
+    X,
+    /// Swap north and south
+    Z,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct TransformArgs {
+    /// Region file named like r.<x>.<z>.mca
+    #[arg(short, long)]
+    pub input: PathBuf,
+
+    /// Transformed region file, covering the same area as the input
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// Clockwise rotation seen from above in degrees: 90, 180 or 270
+    #[arg(long, default_value_t = 0, value_parser = parse_rotation)]
+    pub rotate: u16,
+
+    /// Mirror before rotating
+    #[arg(long)]
+    pub mirror: Option<Axis>,
+}
+
+fn parse_rotation(s: &str) -> anyhow::Result<u16> {
+    match s.parse::<u16>() {
+        Ok(x @ (0 | 90 | 180 | 270)) => Ok(x),
+        _ => bail!("Rotation must be 90, 180 or 270 degrees, got {s:?}"),
+    }
+}
+
+/// Rotates or mirrors whole region about its center. Every chunk moves to its new slot and gets its blocks turned,
+/// see [`Transform`]. Timestamps are kept, chunks are stored with zlib
+pub fn run(args: TransformArgs) -> anyhow::Result<()> {
+    crate::check_paths(&args.input, &args.output, false)?;
+    let region = region::region_coords_from_path(&args.input)
+        .with_context(|| format!("Unable to get region coordinates from {}, name it like r.<x>.<z>.mca", args.input.display()))?;
+    let transform = Transform {
+        quarter_turns: (args.rotate / 90) as u8,
+        mirror_x: args.mirror == Some(Axis::X),
+        mirror_z: args.mirror == Some(Axis::Z),
+    };
+
+    let mut reader = RegionReader::open(&args.input)?;
+    let mut data = Cursor::new(vec![]);
+    let mut writer = RegionWriter::new(&mut data)?.with_external_chunks(&args.output);
+    let mut buffer = vec![];
+    let mut count = 0;
+    reader.decompress_all(|info, pos, nbt| {
+        let (x, z) = RegionInfo::chunk_coords(Some(region), pos);
+        let mut root = nbt::read_compound(nbt).with_context(|| format!("Chunk {x},{z}"))?;
+        let (new_x, new_z) = transform.chunk(&mut root, region).with_context(|| format!("Chunk {x},{z}"))?;
+
+        buffer.clear();
+        nbt::write_compound(&mut buffer, &root)?;
+        let slot = (new_x.rem_euclid(32) + new_z.rem_euclid(32) * 32) as u16;
+        writer.write_chunk_with(slot, info.timestamp.get(), &buffer, chunk::Codec::Zlib)?;
+        count += 1;
+        Ok(())
+    })?;
+    writer.finish()?;
+
+    std::fs::write(&args.output, data.get_ref()).with_context(|| format!("Unable to write {}", args.output.display()))?;
+    println!("Transformed {count} chunks into {}", args.output.display());
+    Ok(())
+}
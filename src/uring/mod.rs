@@ -0,0 +1,155 @@
+//! Whole-file reads and writes over io_uring for the batch pipeline. Files are split into segments
+//! submitted together, so a region file takes a few syscalls instead of one per buffer.
+//! Falls back to plain IO where the kernel refuses io_uring, e.g. under seccomp in containers.
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    fs::File,
+    io::{Read, Write},
+    os::{fd::AsRawFd, unix::fs::FileExt},
+    path::Path,
+};
+
+use io_uring::{opcode, types, IoUring};
+
+/// Bytes per read or write operation
+const SEGMENT: usize = 1 << 20;
+/// Operations in flight
+const DEPTH: u32 = 32;
+
+thread_local! {
+    /// `None` if io_uring is not available
+    static RING: RefCell<Option<IoUring>> = RefCell::new(IoUring::new(DEPTH).ok());
+}
+
+/// Reads or writes `len` bytes at `base` from or to file offset `start`. Returns number of bytes transferred,
+/// less than `len` only if a read hits end of file
+fn transfer(ring: &mut IoUring, file: &File, start: u64, base: *mut u8, len: usize, write: bool) -> std::io::Result<usize> {
+    // Pieces are named by their offset, every piece ends at the end of its segment
+    let end_of = |offset: usize| ((offset / SEGMENT + 1) * SEGMENT).min(len);
+    let mut pending = (0..len).step_by(SEGMENT).collect::<VecDeque<_>>();
+    let mut in_flight = 0;
+    let mut eof = len;
+    let mut error = None;
+
+    while !pending.is_empty() || in_flight > 0 {
+        while in_flight < DEPTH {
+            let Some(offset) = pending.pop_front() else { break };
+            let (fd, ptr, size) = (types::Fd(file.as_raw_fd()), base.wrapping_add(offset), (end_of(offset) - offset) as u32);
+            let entry = match write {
+                true => opcode::Write::new(fd, ptr, size).offset(start + offset as u64).build(),
+                false => opcode::Read::new(fd, ptr, size).offset(start + offset as u64).build(),
+            };
+            // Safety: buffer outlives every operation, all submitted ones are waited for before returning
+            unsafe { ring.submission().push(&entry.user_data(offset as u64)) }
+                .map_err(|_| std::io::Error::other("io_uring submission queue is full"))?;
+            in_flight += 1;
+        }
+
+        ring.submit_and_wait(1)?;
+        let completed = ring.completion().map(|x| (x.user_data() as usize, x.result())).collect::<Vec<_>>();
+        for (offset, result) in completed {
+            in_flight -= 1;
+            match result {
+                e if e == -libc::EINTR || e == -libc::EAGAIN => pending.push_back(offset),
+                e if e < 0 => error = Some(std::io::Error::from_raw_os_error(-e)),
+                0 if write => error = Some(std::io::ErrorKind::WriteZero.into()),
+                // File got shorter since its size was taken
+                0 => eof = eof.min(offset),
+                n if offset + (n as usize) < end_of(offset) => pending.push_back(offset + n as usize),
+                _ => {},
+            }
+        }
+        // Only operations in flight are waited for after an error
+        if error.is_some() {
+            pending.clear();
+        }
+    }
+
+    match error {
+        Some(e) => Err(e),
+        None => Ok(eof),
+    }
+}
+
+/// Reads whole file. A file growing meanwhile is cut at the size it had when opened
+pub fn read(path: &Path) -> std::io::Result<Vec<u8>> {
+    let file = File::open(path)?;
+    let mut data = vec![0; file.metadata()?.len() as usize];
+    let read = RING.with_borrow_mut(|ring| match ring {
+        Some(ring) => transfer(ring, &file, 0, data.as_mut_ptr(), data.len(), false).map(Some),
+        None => Ok(None),
+    })?;
+
+    match read {
+        Some(n) => data.truncate(n),
+        None => {
+            data.clear();
+            (&file).read_to_end(&mut data)?;
+        },
+    }
+    Ok(data)
+}
+
+/// Collects written data and writes it to file on every flush. Like [`std::io::BufWriter`], data not flushed
+/// is lost on drop
+pub struct Writer {
+    file: File,
+    data: Vec<u8>,
+    offset: u64,
+}
+
+impl Writer {
+    /// Creates or truncates file
+    pub fn create(path: &Path) -> std::io::Result<Self> {
+        Ok(Self {
+            file: File::create(path)?,
+            data: vec![],
+            offset: 0,
+        })
+    }
+}
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let (file, data, offset) = (&self.file, &mut self.data, self.offset);
+        let written = RING.with_borrow_mut(|ring| match ring {
+            Some(ring) => transfer(ring, file, offset, data.as_mut_ptr(), data.len(), true).map(Some),
+            None => Ok(None),
+        })?;
+        if written.is_none() {
+            file.write_all_at(data, offset)?;
+        }
+
+        self.offset += data.len() as u64;
+        data.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn round_trip_segments() {
+        let dir = TempDir::new("uring");
+        let path = dir.join("segments");
+        let data = (0..SEGMENT * 3 + 123).map(|x| (x % 251) as u8).collect::<Vec<_>>();
+
+        let mut writer = Writer::create(&path).unwrap();
+        writer.write_all(&data[..SEGMENT + 1]).unwrap();
+        writer.flush().unwrap();
+        writer.write_all(&data[SEGMENT + 1..]).unwrap();
+        writer.flush().unwrap();
+
+        assert_eq!(read(&path).unwrap(), data);
+    }
+}
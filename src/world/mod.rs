@@ -0,0 +1,152 @@
+#![allow(unused)]
+
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use crate::nbt::{self, Tag};
+
+/// Collects region files. Directories are searched recursively, a single file is returned as is.
+/// Result is sorted to make output stable.
+pub fn region_files(path: impl AsRef<Path>) -> anyhow::Result<Vec<PathBuf>> {
+    let path = path.as_ref();
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut files = vec![];
+    let mut dirs = vec![path.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir).with_context(|| format!("Unable to read {}", dir.display()))? {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                dirs.push(path);
+            } else if path.extension().is_some_and(|x| x == "mca") {
+                files.push(path);
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+/// Dimension id and storage kind (`region`, `entities`, `poi`) of region file inside world directory.
+/// Files outside of known layout get `None`.
+pub fn dimension(world: impl AsRef<Path>, file: impl AsRef<Path>) -> Option<(String, String)> {
+    let relative = file.as_ref().strip_prefix(world.as_ref()).ok()?;
+    let parts = relative
+        .parent()?
+        .components()
+        .map(|x| x.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>()?;
+
+    let (kind, dimension) = parts.split_last()?;
+    let dimension = match dimension {
+        [] => "minecraft:overworld".to_owned(),
+        ["DIM-1"] => "minecraft:the_nether".to_owned(),
+        ["DIM1"] => "minecraft:the_end".to_owned(),
+        ["dimensions", namespace, path @ ..] if !path.is_empty() => format!("{namespace}:{}", path.join("/")),
+        _ => return None,
+    };
+
+    Some((dimension, kind.to_string()))
+}
+
+/// World border, a square of `size` blocks around its center. Chunks fully outside of it are never visited
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WorldBorder {
+    pub center_x: f64,
+    pub center_z: f64,
+    pub size: f64,
+}
+
+impl Default for WorldBorder {
+    /// Border of a new world
+    fn default() -> Self {
+        Self { center_x: 0.0, center_z: 0.0, size: 59_999_968.0 }
+    }
+}
+
+impl WorldBorder {
+    /// Reads border of world directory from its `level.dat`. Tags missing in worlds older than 1.8 get defaults
+    pub fn read(world: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = world.as_ref().join("level.dat");
+        let file = std::fs::File::open(&path).with_context(|| format!("Unable to open {}", path.display()))?;
+        let mut data = vec![];
+        flate2::read::GzDecoder::new(file)
+            .take(64 << 20)
+            .read_to_end(&mut data)
+            .with_context(|| format!("Unable to decompress {}", path.display()))?;
+        let root = nbt::read_compound(&data).with_context(|| format!("Unable to read {}", path.display()))?;
+        let level = root.get("Data").and_then(Tag::as_compound).with_context(|| format!("{} has no Data", path.display()))?;
+
+        let default = Self::default();
+        let double = |name, default| match level.get(name) {
+            Some(Tag::Double(x)) => *x,
+            _ => default,
+        };
+        Ok(Self {
+            center_x: double("BorderCenterX", default.center_x),
+            center_z: double("BorderCenterZ", default.center_z),
+            size: double("BorderSize", default.size),
+        })
+    }
+
+    /// Border in coordinates of dimension as returned by [`dimension`]. The game divides the center by
+    /// coordinate scale of dimension, 8 in the nether. `None` for mod dimensions, their scale is unknown
+    pub fn in_dimension(&self, dimension: &str) -> Option<Self> {
+        let scale = match dimension {
+            "minecraft:overworld" | "minecraft:the_end" => 1.0,
+            "minecraft:the_nether" => 8.0,
+            _ => return None,
+        };
+        Some(Self { center_x: self.center_x / scale, center_z: self.center_z / scale, ..*self })
+    }
+
+    /// Whether any block of chunk at chunk coordinates `x`, `z` is inside the border
+    pub fn touches_chunk(&self, x: i32, z: i32) -> bool {
+        let overlaps = |chunk: i32, center: f64| {
+            let start = chunk as f64 * 16.0;
+            start < center + self.size / 2.0 && start + 16.0 > center - self.size / 2.0
+        };
+        overlaps(x, self.center_x) && overlaps(z, self.center_z)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dimension, WorldBorder};
+
+    #[test]
+    fn dimension_of_region_file() {
+        let of = |file: &str| dimension("world", format!("world/{file}"));
+        let some = |dimension: &str, kind: &str| Some((dimension.to_owned(), kind.to_owned()));
+
+        assert_eq!(of("region/r.0.0.mca"), some("minecraft:overworld", "region"));
+        assert_eq!(of("DIM-1/entities/r.0.0.mca"), some("minecraft:the_nether", "entities"));
+        assert_eq!(of("DIM1/poi/r.0.0.mca"), some("minecraft:the_end", "poi"));
+        assert_eq!(of("dimensions/mod/deep/dim/region/r.0.0.mca"), some("mod:deep/dim", "region"));
+        assert_eq!(of("r.0.0.mca"), None);
+        assert_eq!(of("backup/old/region/r.0.0.mca"), None);
+    }
+
+    #[test]
+    fn chunks_inside_border() {
+        let border = WorldBorder { center_x: 8.0, center_z: -100.0, size: 64.0 };
+        // Blocks -24..40 and -132..-68
+        assert!(border.touches_chunk(-2, -9));
+        assert!(border.touches_chunk(2, -5));
+        assert!(!border.touches_chunk(-3, -6));
+        assert!(!border.touches_chunk(0, -4));
+        assert!(!border.touches_chunk(3, -6));
+
+        let nether = border.in_dimension("minecraft:the_nether").unwrap();
+        assert_eq!((nether.center_x, nether.center_z, nether.size), (1.0, -12.5, 64.0));
+        assert_eq!(border.in_dimension("mod:deep/dim"), None);
+    }
+}
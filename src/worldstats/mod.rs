@@ -0,0 +1,75 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use crate::{meta::RegionScan, region::RegionInfo, world};
+
+#[derive(Debug, clap::Args)]
+pub struct WorldStatsArgs {
+    /// World directory
+    pub world: PathBuf,
+}
+
+#[derive(Debug, Default)]
+struct Summary {
+    regions: usize,
+    scan: RegionScan,
+}
+
+impl Summary {
+    fn add(&mut self, scan: &RegionScan) {
+        self.regions += 1;
+        self.scan.chunks += scan.chunks;
+        self.scan.newest_timestamp = self.scan.newest_timestamp.max(scan.newest_timestamp);
+        self.scan.used_bytes += scan.used_bytes;
+        self.scan.file_size += scan.file_size;
+    }
+
+    fn print(&self, name: &str) {
+        println!(
+            "{name}: {} regions, {} chunks, {} of {} bytes used, newest {}",
+            self.regions,
+            self.scan.chunks,
+            self.scan.used_bytes,
+            self.scan.file_size,
+            self.scan.newest_timestamp.map_or("-".to_owned(), |x| x.to_string()),
+        );
+    }
+}
+
+/// Aggregates header scans of every region file in world by dimension and storage kind
+pub fn run(args: WorldStatsArgs) -> anyhow::Result<()> {
+    anyhow::ensure!(args.world.is_dir(), "{} is not a directory", args.world.display());
+    let files = world::region_files(&args.world)?;
+
+    let mut total = Summary::default();
+    let mut dimensions = BTreeMap::<String, (Summary, BTreeMap<String, Summary>)>::new();
+    let mut failed = 0usize;
+
+    for file in files.iter() {
+        let scan = match RegionInfo::scan(file) {
+            Ok(scan) => scan,
+            Err(e) => {
+                eprintln!("{e:#}");
+                failed += 1;
+                continue;
+            },
+        };
+
+        let (dimension, kind) =
+            world::dimension(&args.world, file).unwrap_or_else(|| ("unknown".to_owned(), "region".to_owned()));
+        let (summary, kinds) = dimensions.entry(dimension).or_default();
+        summary.add(&scan);
+        kinds.entry(kind).or_default().add(&scan);
+        total.add(&scan);
+    }
+
+    for (dimension, (summary, kinds)) in dimensions.iter() {
+        summary.print(dimension);
+        for (kind, summary) in kinds.iter() {
+            summary.print(&format!("  {kind}"));
+        }
+    }
+    total.print("Total");
+
+    anyhow::ensure!(failed == 0, "{failed} region files could not be scanned");
+    Ok(())
+}
@@ -0,0 +1,64 @@
+//! Golden-file fixtures shared by integration tests.
+//!
+//! Every fixture is a directory under `tests/fixtures` holding `r.0.0.mca` and, when the region
+//! refers to them, external `c.<x>.<z>.mcc` chunk files. See `tests/fixtures/README.md`.
+
+#![allow(dead_code)]
+
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anvilregion_repacker::region::RegionReader;
+
+#[derive(Debug)]
+pub struct Fixture {
+    pub name: &'static str,
+    dir: PathBuf,
+    bytes: Vec<u8>,
+}
+
+impl Fixture {
+    pub const REGION_FILE: &'static str = "r.0.0.mca";
+
+    /// Loads region file of the fixture. Panics if fixture does not exist
+    pub fn load(name: &'static str) -> Self {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name);
+        let bytes = std::fs::read(dir.join(Self::REGION_FILE))
+            .unwrap_or_else(|e| panic!("Unable to load fixture {name}: {e}"));
+
+        Self { name, dir, bytes }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Path of the region file for APIs which need external chunk files next to it
+    pub fn path(&self) -> PathBuf {
+        self.dir.join(Self::REGION_FILE)
+    }
+
+    pub fn reader(&self) -> anyhow::Result<RegionReader<&[u8]>> {
+        RegionReader::from_reader(&self.bytes[..])
+    }
+
+    /// Copies fixture into a fresh temporary directory so tests may modify it
+    pub fn copy_to_temp(&self) -> PathBuf {
+        static COPIES: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "anvilregion-repacker-fixture-{}-{}-{}",
+            self.name,
+            std::process::id(),
+            COPIES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for entry in std::fs::read_dir(&self.dir).unwrap() {
+            let entry = entry.unwrap();
+            std::fs::copy(entry.path(), dir.join(entry.file_name())).unwrap();
+        }
+        dir.join(Self::REGION_FILE)
+    }
+}
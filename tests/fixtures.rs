@@ -0,0 +1,117 @@
+mod common;
+
+use anvilregion_repacker::{
+    chunk,
+    meta::{ChunkCodec, RegionMeta},
+    nbt,
+    region::{validate_region, RegionInfo},
+    rpack,
+};
+use common::Fixture;
+
+/// (slot, timestamp, NBT position)
+type ChunkSummary = (u16, u32, Option<(i32, i32)>);
+
+fn read_all(fixture: &Fixture) -> anyhow::Result<Vec<ChunkSummary>> {
+    let mut chunks = vec![];
+    fixture.reader()?.decompress_all(|info, pos, data| {
+        let (_, root) = nbt::read(data)?;
+        let nbt::Tag::Compound(root) = root else {
+            anyhow::bail!("root is not a compound");
+        };
+        chunks.push((pos, info.timestamp.get(), chunk::nbt_position(&root)));
+        Ok(())
+    })?;
+    Ok(chunks)
+}
+
+#[test]
+fn basic() {
+    let fixture = Fixture::load("basic");
+    validate_region(fixture.path()).unwrap();
+
+    let mut chunks = read_all(&fixture).unwrap();
+    chunks.sort();
+    assert_eq!(
+        chunks,
+        [
+            (0, 1700000002, Some((0, 0))),
+            (33, 1700000001, Some((1, 1))),
+            (1023, 1700000003, Some((31, 31))),
+        ]
+    );
+}
+
+#[test]
+fn lz4_is_not_supported() {
+    let fixture = Fixture::load("lz4");
+    assert!(validate_region(fixture.path()).is_err());
+    assert!(read_all(&fixture).is_err());
+}
+
+#[test]
+fn oversized_chunk_in_external_file() {
+    let fixture = Fixture::load("oversized");
+    validate_region(fixture.path()).unwrap();
+
+    let path = fixture.copy_to_temp();
+    std::fs::remove_file(path.with_file_name("c.2.0.mcc")).unwrap();
+    assert!(validate_region(&path).is_err());
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn oversized_round_trip() {
+    let fixture = Fixture::load("oversized");
+    let compact = |path: &std::path::Path, raw: bool| {
+        let mut options = rpack::EncodeOptions { region_path: Some(path.to_owned()), ..Default::default() };
+        options.rpack.raw = raw;
+        let mut archive = vec![];
+        rpack::encode_region(std::fs::File::open(path).unwrap(), &mut archive, &options).unwrap();
+        archive
+    };
+    let archive = compact(&fixture.path(), false);
+    compact(&fixture.path(), true);
+
+    let path = fixture.copy_to_temp();
+    let options = rpack::DecodeOptions { region_path: Some(path.clone()), ..Default::default() };
+    let mut region = std::fs::File::create(&path).unwrap();
+    rpack::decode_region(&archive[..], &mut region, &options).unwrap();
+    drop(region);
+    // Small enough to be stored inline again
+    std::fs::remove_file(path.with_file_name("c.2.0.mcc")).unwrap();
+    validate_region(&path).unwrap();
+    assert_eq!(compact(&path, false), archive);
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn corrupted() {
+    let fixture = Fixture::load("corrupted");
+    let err = validate_region(fixture.path()).unwrap_err();
+    assert!(format!("{err:#}").contains("chunk 1,0"), "{err:#}");
+    assert!(read_all(&fixture).is_err());
+}
+
+#[test]
+fn region_meta() {
+    let meta = RegionMeta::read(Fixture::load("oversized").path()).unwrap();
+    let codecs = meta.chunks.iter().map(|x| (x.pos, x.codec, x.external)).collect::<Vec<_>>();
+    assert_eq!(codecs, [(0, ChunkCodec::Zlib, false), (2, ChunkCodec::Zlib, true)]);
+
+    let meta = RegionMeta::read(Fixture::load("lz4").path()).unwrap();
+    assert_eq!(meta.chunks[0].codec, ChunkCodec::Lz4);
+
+    let json = serde_json::to_value(&meta).unwrap();
+    assert_eq!(json["chunks"][0]["codec"], "lz4");
+    assert_eq!(serde_json::from_value::<RegionMeta>(json).unwrap(), meta);
+}
+
+#[test]
+fn header_scan() {
+    let scan = RegionInfo::scan(Fixture::load("basic").path()).unwrap();
+    assert_eq!(scan.chunks, 3);
+    assert_eq!(scan.newest_timestamp, Some(1700000003));
+    // One free sector between chunks
+    assert_eq!(scan.used_bytes + 4096, scan.file_size);
+}